@@ -0,0 +1,39 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use async_wsocket::futures_util::StreamExt;
+use async_wsocket::mux::WsMux;
+use async_wsocket::test_util;
+
+#[tokio::main]
+async fn main() {
+    // Client and server linked over an in-memory pipe
+    let ((client_tx, client_rx), (server_tx, server_rx)) = test_util::pair().await.unwrap();
+
+    // Server side: echo every message back on the same channel
+    let (server, driver) = WsMux::new(server_tx, server_rx);
+    tokio::spawn(driver.run());
+    for id in [1, 2] {
+        let (tx, mut rx) = server.open_channel(id).unwrap();
+        tokio::spawn(async move {
+            while let Some(payload) = rx.next().await {
+                tx.send(&payload).await.unwrap();
+            }
+        });
+    }
+
+    // Client side
+    let (client, driver) = WsMux::new(client_tx, client_rx);
+    tokio::spawn(driver.run());
+
+    let (prices_tx, mut prices_rx) = client.open_channel(1).unwrap();
+    let (trades_tx, mut trades_rx) = client.open_channel(2).unwrap();
+
+    prices_tx.send(b"BTC/USD").await.unwrap();
+    trades_tx.send(b"last 10").await.unwrap();
+
+    let prices: Vec<u8> = prices_rx.next().await.unwrap();
+    let trades: Vec<u8> = trades_rx.next().await.unwrap();
+    println!("prices: {}", String::from_utf8_lossy(&prices));
+    println!("trades: {}", String::from_utf8_lossy(&trades));
+}
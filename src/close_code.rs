@@ -0,0 +1,177 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Close codes
+//!
+//! Native and WASM report close codes differently. Codes are normalized so that the same
+//! server close produces the same code on every platform:
+//!
+//! | Situation                                | Native                   | WASM                      | Normalized             |
+//! |------------------------------------------|--------------------------|---------------------------|------------------------|
+//! | Close frame with a code                  | frame code               | `CloseEvent.code`         | the code               |
+//! | Close frame without a code               | `Message::Close(None)`   | `1005` (or `0`)           | [`NO_STATUS_RECEIVED`] |
+//! | Connection dropped without a close frame | stream error or end      | `1006`                    | [`ABNORMAL_CLOSURE`]   |
+//!
+//! [`NO_STATUS_RECEIVED`] and [`ABNORMAL_CLOSURE`] are reserved: they are never sent on the wire.
+//!
+//! # Clean closes
+//!
+//! The platforms disagree on what a clean close is: the browser's `wasClean` is about the
+//! closing handshake, while native considers clean any termination with a close frame.
+//! By default the platform notion is kept: the WASM `CloseEvent::was_clean` and the `clean`
+//! flag reported to the [`MetricsSink`](crate::metrics::MetricsSink) come from the transport.
+//!
+//! To get the same result everywhere, install a predicate on the normalized code with
+//! [`set_clean_predicate`]: the transport flag is then ignored. [`is_normal`] is a ready-made one.
+
+use std::sync::Mutex;
+
+#[cfg(not(target_arch = "wasm32"))]
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+
+/// Normal closure
+pub const NORMAL_CLOSURE: u16 = 1000;
+/// The endpoint is going away
+pub const GOING_AWAY: u16 = 1001;
+/// The close frame had no code
+pub const NO_STATUS_RECEIVED: u16 = 1005;
+/// The connection was closed without a close frame
+pub const ABNORMAL_CLOSURE: u16 = 1006;
+
+/// Max length of a close reason, in bytes
+pub const MAX_REASON_LEN: usize = 123;
+
+/// What happens to a close reason longer than [`MAX_REASON_LEN`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum CloseReasonPolicy {
+    /// Reject it
+    #[default]
+    Error,
+    /// Truncate it, with [`truncate_reason`]
+    Truncate,
+}
+
+/// Predicate on a normalized close code
+pub type CleanPredicate = fn(u16) -> bool;
+
+static CLEAN_PREDICATE: Mutex<Option<CleanPredicate>> = Mutex::new(None);
+
+/// Normalize a close code reported by the platform
+#[inline]
+pub fn normalize(code: u16) -> u16 {
+    match code {
+        // Some browsers report `0` when the close frame has no code
+        0 => NO_STATUS_RECEIVED,
+        code => code,
+    }
+}
+
+/// Get the normalized close code of a received close frame
+#[cfg(not(target_arch = "wasm32"))]
+pub fn from_close_frame(frame: Option<&CloseFrame<'_>>) -> u16 {
+    match frame {
+        Some(frame) => normalize(frame.code.into()),
+        None => NO_STATUS_RECEIVED,
+    }
+}
+
+/// Human-readable description of a close code, i.e. `Normal Closure`
+pub fn description(code: u16) -> &'static str {
+    match code {
+        NORMAL_CLOSURE => "Normal Closure",
+        GOING_AWAY => "Going Away",
+        1002 => "Protocol Error",
+        1003 => "Unsupported Data",
+        NO_STATUS_RECEIVED => "No Status Received",
+        ABNORMAL_CLOSURE => "Abnormal Closure",
+        1007 => "Invalid Frame Payload Data",
+        1008 => "Policy Violation",
+        1009 => "Message Too Big",
+        1010 => "Mandatory Extension",
+        1011 => "Internal Error",
+        1012 => "Service Restart",
+        1013 => "Try Again Later",
+        1014 => "Bad Gateway",
+        1015 => "TLS Handshake",
+        3000..=3999 => "Registered",
+        4000..=4999 => "Private Use",
+        _ => "Unknown",
+    }
+}
+
+/// Truncate a close reason to [`MAX_REASON_LEN`] bytes, without splitting a multibyte char
+pub fn truncate_reason(reason: &str) -> &str {
+    if reason.len() <= MAX_REASON_LEN {
+        return reason;
+    }
+
+    let mut end: usize = MAX_REASON_LEN;
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    &reason[..end]
+}
+
+/// Whether a code can be sent in a close frame, as per RFC 6455
+///
+/// The reserved codes (i.e. [`NO_STATUS_RECEIVED`] and [`ABNORMAL_CLOSURE`]) can't.
+/// Browsers are stricter: only [`NORMAL_CLOSURE`] and `3000..=4999` are accepted.
+#[inline]
+pub fn is_sendable(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+/// [`NORMAL_CLOSURE`] and [`GOING_AWAY`] are clean, any other code is not
+#[inline]
+pub fn is_normal(code: u16) -> bool {
+    matches!(code, NORMAL_CLOSURE | GOING_AWAY)
+}
+
+/// Decide which normalized close codes are clean, on every platform, regardless of the transport
+pub fn set_clean_predicate(predicate: CleanPredicate) {
+    *CLEAN_PREDICATE.lock().unwrap_or_else(|e| e.into_inner()) = Some(predicate);
+}
+
+/// Remove the predicate: the transport decides again
+pub fn clear_clean_predicate() {
+    *CLEAN_PREDICATE.lock().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+/// Whether a close is clean, given its normalized code and the transport flag
+pub(crate) fn is_clean(code: u16, transport_clean: bool) -> bool {
+    match *CLEAN_PREDICATE.lock().unwrap_or_else(|e| e.into_inner()) {
+        Some(predicate) => predicate(code),
+        None => transport_clean,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate_reason() {
+        assert_eq!(truncate_reason("bye"), "bye");
+
+        let reason = "a".repeat(200);
+        assert_eq!(truncate_reason(&reason).len(), MAX_REASON_LEN);
+
+        // `é` is 2 bytes: the 123rd byte is in the middle of the 62nd char
+        let reason = "é".repeat(100);
+        let truncated = truncate_reason(&reason);
+        assert_eq!(truncated.len(), 122);
+        assert_eq!(truncated.chars().count(), 61);
+    }
+
+    #[test]
+    fn test_is_sendable() {
+        assert!(is_sendable(NORMAL_CLOSURE));
+        assert!(is_sendable(GOING_AWAY));
+        assert!(is_sendable(4000));
+        assert!(!is_sendable(NO_STATUS_RECEIVED));
+        assert!(!is_sendable(ABNORMAL_CLOSURE));
+        assert!(!is_sendable(1015));
+        assert!(!is_sendable(999));
+        assert!(!is_sendable(2000));
+    }
+}
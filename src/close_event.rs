@@ -0,0 +1,196 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Close event
+
+use std::fmt;
+
+#[cfg(not(target_arch = "wasm32"))]
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+
+use crate::close_code;
+
+/// The side that initiated the close of the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde-json", derive(serde::Serialize, serde::Deserialize))]
+pub enum Initiator {
+    /// The close was requested by us
+    Client,
+    /// The close was initiated by the server (or the connection was lost)
+    Server,
+}
+
+/// An event holding information about how/why the connection was closed.
+///
+/// Same shape on every platform: on native, it's built from the received close frame or,
+/// if the connection dropped without one, synthesized as the browser does:
+/// [`ABNORMAL_CLOSURE`](close_code::ABNORMAL_CLOSURE) (`1006`), not clean.
+///
+/// See: [MDN Documentation](https://developer.mozilla.org/en-US/docs/Web/API/WebSocket/close).
+// We use this wrapper because the web_sys version isn't Send and pharos requires events
+// to be Send.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde-json", derive(serde::Serialize, serde::Deserialize))]
+pub struct CloseEvent {
+    /// The close code, normalized (see [`close_code`](crate::close_code)).
+    pub code: u16,
+    /// The reason why the connection was closed.
+    pub reason: String,
+    /// Whether the connection was closed cleanly (see [clean closes](crate::close_code#clean-closes)).
+    pub was_clean: bool,
+    /// The side that initiated the close.
+    pub initiated_by: Initiator,
+}
+
+impl CloseEvent {
+    /// Check if the code is [`NORMAL_CLOSURE`](close_code::NORMAL_CLOSURE) or [`GOING_AWAY`](close_code::GOING_AWAY)
+    #[inline]
+    pub fn is_normal(&self) -> bool {
+        close_code::is_normal(self.code)
+    }
+
+    /// Check if the connection dropped without a close frame ([`ABNORMAL_CLOSURE`](close_code::ABNORMAL_CLOSURE))
+    #[inline]
+    pub fn is_abnormal(&self) -> bool {
+        self.code == close_code::ABNORMAL_CLOSURE
+    }
+
+    /// Connection dropped without a close frame
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn abnormal(initiated_by: Initiator) -> Self {
+        let code: u16 = close_code::ABNORMAL_CLOSURE;
+        Self {
+            code,
+            reason: String::new(),
+            was_clean: close_code::is_clean(code, false),
+            initiated_by,
+        }
+    }
+
+    /// Close frame received
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn from_close_frame(
+        frame: Option<&CloseFrame<'_>>,
+        initiated_by: Initiator,
+    ) -> Self {
+        let code: u16 = close_code::from_close_frame(frame);
+        Self {
+            code,
+            reason: frame.map(|f| f.reason.to_string()).unwrap_or_default(),
+            was_clean: close_code::is_clean(code, true),
+            initiated_by,
+        }
+    }
+
+    /// The close frame, `None` if it had no code or the connection dropped
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn to_close_frame(&self) -> Option<CloseFrame<'static>> {
+        match self.code {
+            close_code::NO_STATUS_RECEIVED | close_code::ABNORMAL_CLOSURE => None,
+            code => Some(CloseFrame {
+                code: code.into(),
+                reason: self.reason.clone().into(),
+            }),
+        }
+    }
+}
+
+/// Normal closure (`1000`), clean, initiated by the [Initiator::Server]
+impl Default for CloseEvent {
+    fn default() -> Self {
+        Self {
+            code: close_code::NORMAL_CLOSURE,
+            reason: String::new(),
+            was_clean: true,
+            initiated_by: Initiator::Server,
+        }
+    }
+}
+
+/// Code, description and reason (if any), i.e. `1000 Normal Closure: bye`
+impl fmt::Display for CloseEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.code, close_code::description(self.code))?;
+        if !self.reason.is_empty() {
+            write!(f, ": {}", self.reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// [`CloseEvent`] builder, i.e. for tests and mock implementations
+///
+/// The fields not set are the ones of [`CloseEvent::default`].
+#[derive(Debug, Clone, Default)]
+pub struct CloseEventBuilder {
+    event: CloseEvent,
+}
+
+impl CloseEventBuilder {
+    /// New builder
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the close code
+    #[inline]
+    pub fn code(mut self, code: u16) -> Self {
+        self.event.code = code;
+        self
+    }
+
+    /// Set the close reason
+    #[inline]
+    pub fn reason<S>(mut self, reason: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.event.reason = reason.into();
+        self
+    }
+
+    /// Set whether the connection was closed cleanly
+    #[inline]
+    pub fn was_clean(mut self, was_clean: bool) -> Self {
+        self.event.was_clean = was_clean;
+        self
+    }
+
+    /// Set the side that initiated the close
+    #[inline]
+    pub fn initiated_by(mut self, initiator: Initiator) -> Self {
+        self.event.initiated_by = initiator;
+        self
+    }
+
+    /// Build the [`CloseEvent`]
+    #[inline]
+    pub fn build(self) -> CloseEvent {
+        self.event
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let event = CloseEventBuilder::new().reason("bye").build();
+        assert_eq!(event.to_string(), "1000 Normal Closure: bye");
+
+        let event = CloseEventBuilder::new().code(4001).build();
+        assert_eq!(event.to_string(), "4001 Private Use");
+    }
+
+    #[test]
+    #[cfg(not(target_arch = "wasm32"))]
+    fn test_abnormal() {
+        let event = CloseEvent::abnormal(Initiator::Server);
+        assert_eq!(event.code, close_code::ABNORMAL_CLOSURE);
+        assert!(!event.was_clean);
+        assert!(event.is_abnormal());
+        assert!(!event.is_normal());
+    }
+}
@@ -0,0 +1,84 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Close callbacks
+
+use std::future::Future;
+use std::mem;
+use std::sync::Mutex;
+
+use futures_channel::oneshot;
+
+#[cfg(not(target_arch = "wasm32"))]
+type Callback<E> = Box<dyn FnOnce(Option<E>) + Send>;
+#[cfg(target_arch = "wasm32")]
+type Callback<E> = Box<dyn FnOnce(Option<E>)>;
+
+enum State<E> {
+    Open(Vec<Callback<E>>),
+    Closed(Option<E>),
+}
+
+/// Callbacks invoked once when the connection terminates
+pub(crate) struct CloseNotifier<E> {
+    state: Mutex<State<E>>,
+}
+
+impl<E> Default for CloseNotifier<E> {
+    fn default() -> Self {
+        Self {
+            state: Mutex::new(State::Open(Vec::new())),
+        }
+    }
+}
+
+impl<E> CloseNotifier<E>
+where
+    E: Clone,
+{
+    /// Register a callback. If the connection is already terminated, it's invoked immediately.
+    pub(crate) fn register(&self, callback: Callback<E>) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        match &mut *state {
+            State::Open(callbacks) => callbacks.push(callback),
+            State::Closed(event) => {
+                let event: Option<E> = event.clone();
+
+                // Don't hold the lock while calling user code
+                drop(state);
+                callback(event);
+            }
+        }
+    }
+
+    /// Wait for the connection termination. If it's already terminated, resolve immediately.
+    pub(crate) fn closed(&self) -> impl Future<Output = Option<E>>
+    where
+        E: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.register(Box::new(move |event| {
+            let _ = tx.send(event);
+        }));
+        async move { rx.await.unwrap_or(None) }
+    }
+
+    /// Mark the connection as terminated and invoke the callbacks (only the first time).
+    ///
+    /// Return `false` if already terminated.
+    pub(crate) fn notify(&self, event: Option<E>) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let callbacks = match &mut *state {
+            State::Open(callbacks) => mem::take(callbacks),
+            State::Closed(..) => return false,
+        };
+        *state = State::Closed(event.clone());
+        drop(state);
+
+        for callback in callbacks.into_iter() {
+            callback(event.clone());
+        }
+
+        true
+    }
+}
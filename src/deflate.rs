@@ -0,0 +1,164 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Negotiated `permessage-deflate` parameters (RFC 7692)
+
+/// Name of the `permessage-deflate` extension
+const PERMESSAGE_DEFLATE: &str = "permessage-deflate";
+
+/// Parameters of the negotiated `permessage-deflate` extension
+///
+/// The window bits are `None` if not negotiated (the default is `15`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct DeflateParams {
+    /// Max LZ77 window of the server compressor, as a base-2 logarithm (`8..=15`)
+    pub server_max_window_bits: Option<u8>,
+    /// Max LZ77 window of the client compressor, as a base-2 logarithm (`8..=15`)
+    pub client_max_window_bits: Option<u8>,
+    /// The server resets its compression context after every message
+    pub server_no_context_takeover: bool,
+    /// The client resets its compression context after every message
+    pub client_no_context_takeover: bool,
+}
+
+impl DeflateParams {
+    /// Parse a `Sec-WebSocket-Extensions` value, i.e. `permessage-deflate; client_max_window_bits=15`
+    ///
+    /// Return `None` if `permessage-deflate` isn't there. The parameters may come in any order:
+    /// the unknown ones and the invalid window bits are ignored. If the extension is listed
+    /// more than once, the first one is taken.
+    pub fn from_extensions(extensions: &str) -> Option<Self> {
+        split_unquoted(extensions, ',').find_map(|extension| {
+            let mut params = split_unquoted(extension, ';');
+            let name: &str = params.next()?.trim();
+            if !name.eq_ignore_ascii_case(PERMESSAGE_DEFLATE) {
+                return None;
+            }
+
+            let mut deflate = Self::default();
+            for param in params {
+                let (name, value) = match param.split_once('=') {
+                    Some((name, value)) => (name.trim(), Some(unquote(value.trim()))),
+                    None => (param.trim(), None),
+                };
+
+                if name.eq_ignore_ascii_case("server_max_window_bits") {
+                    deflate.server_max_window_bits = value.and_then(window_bits);
+                } else if name.eq_ignore_ascii_case("client_max_window_bits") {
+                    deflate.client_max_window_bits = value.and_then(window_bits);
+                } else if name.eq_ignore_ascii_case("server_no_context_takeover") {
+                    deflate.server_no_context_takeover = true;
+                } else if name.eq_ignore_ascii_case("client_no_context_takeover") {
+                    deflate.client_no_context_takeover = true;
+                }
+            }
+
+            Some(deflate)
+        })
+    }
+}
+
+/// Split on `sep`, except inside a quoted string
+fn split_unquoted(s: &str, sep: char) -> impl Iterator<Item = &str> {
+    let mut quoted: bool = false;
+    s.split(move |c: char| {
+        if c == '"' {
+            quoted = !quoted;
+        }
+        c == sep && !quoted
+    })
+}
+
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+fn window_bits(value: &str) -> Option<u8> {
+    value
+        .parse::<u8>()
+        .ok()
+        .filter(|bits| (8..=15).contains(bits))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(
+        server_max_window_bits: Option<u8>,
+        client_max_window_bits: Option<u8>,
+        server_no_context_takeover: bool,
+        client_no_context_takeover: bool,
+    ) -> Option<DeflateParams> {
+        Some(DeflateParams {
+            server_max_window_bits,
+            client_max_window_bits,
+            server_no_context_takeover,
+            client_no_context_takeover,
+        })
+    }
+
+    #[test]
+    fn test_from_extensions() {
+        let corpus = [
+            // Browsers and the common servers
+            (
+                "permessage-deflate; client_max_window_bits=15",
+                params(None, Some(15), false, false),
+            ),
+            ("permessage-deflate", params(None, None, false, false)),
+            (
+                "permessage-deflate; client_max_window_bits",
+                params(None, None, false, false),
+            ),
+            (
+                "permessage-deflate; server_no_context_takeover; client_no_context_takeover",
+                params(None, None, true, true),
+            ),
+            (
+                "permessage-deflate; server_max_window_bits=10; client_max_window_bits=12",
+                params(Some(10), Some(12), false, false),
+            ),
+            // Reordered, without spaces
+            (
+                "permessage-deflate;client_no_context_takeover;server_max_window_bits=9",
+                params(Some(9), None, false, true),
+            ),
+            // Case and quoted value
+            (
+                "Permessage-Deflate; Server_Max_Window_Bits=\"11\"",
+                params(Some(11), None, false, false),
+            ),
+            // Unknown parameters and extensions
+            (
+                "x-custom; a=\"b,c\", permessage-deflate; foo=bar; client_max_window_bits=8",
+                params(None, Some(8), false, false),
+            ),
+            // First one taken
+            (
+                "permessage-deflate; server_max_window_bits=10, permessage-deflate",
+                params(Some(10), None, false, false),
+            ),
+            // Invalid window bits
+            (
+                "permessage-deflate; server_max_window_bits=7; client_max_window_bits=x",
+                params(None, None, false, false),
+            ),
+            // Other extensions only
+            ("x-webkit-deflate-frame", None),
+            ("deflate-frame; no_context_takeover", None),
+            ("", None),
+        ];
+
+        for (extensions, expected) in corpus {
+            assert_eq!(
+                DeflateParams::from_extensions(extensions),
+                expected,
+                "{extensions}"
+            );
+        }
+    }
+}
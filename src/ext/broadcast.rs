@@ -0,0 +1,54 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use futures_sink::Sink as SinkTrait;
+use futures_util::future;
+use futures_util::SinkExt;
+
+use crate::{Error, WsMessage};
+
+/// Send the same message to all the `sinks` concurrently.
+///
+/// The results are in the same order as the `sinks`.
+pub async fn broadcast<S, E>(sinks: &mut [S], msg: WsMessage) -> Vec<Result<(), Error>>
+where
+    S: SinkTrait<WsMessage, Error = E> + Unpin,
+    E: Into<Error>,
+{
+    future::join_all(sinks.iter_mut().map(|sink| {
+        let msg: WsMessage = msg.clone();
+        async move { sink.send(msg).await.map_err(Into::into) }
+    }))
+    .await
+}
+
+#[cfg(all(test, feature = "test-util", not(target_arch = "wasm32")))]
+mod tests {
+    use futures_util::StreamExt;
+
+    use super::*;
+    use crate::native::{Sink, Stream};
+    use crate::test_util;
+
+    #[tokio::test]
+    async fn test_broadcast() {
+        let mut sinks: Vec<Sink> = Vec::new();
+        let mut streams: Vec<Stream> = Vec::new();
+
+        for _ in 0..5 {
+            let ((tx, rx), (server_tx, server_rx)) = test_util::pair().await.unwrap();
+            tokio::spawn(test_util::echo(server_tx, server_rx));
+            sinks.push(tx);
+            streams.push(rx);
+        }
+
+        let msg = WsMessage::Text(String::from("hello"));
+        let results = broadcast(&mut sinks, msg.clone()).await;
+        assert_eq!(results.len(), 5);
+        assert!(results.iter().all(Result::is_ok));
+
+        for rx in streams.iter_mut() {
+            assert_eq!(rx.next().await.unwrap().unwrap(), msg);
+        }
+    }
+}
@@ -0,0 +1,89 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_core::{ready, Stream as StreamTrait};
+
+use crate::{Error, WsMessage};
+
+/// Stream for [`WsStreamExt::chain_on_close`](super::WsStreamExt::chain_on_close)
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct ChainOnClose<S1, S2> {
+    first: Option<S1>,
+    second: S2,
+}
+
+impl<S1, S2> ChainOnClose<S1, S2> {
+    #[inline]
+    pub(super) fn new(first: S1, second: S2) -> Self {
+        Self {
+            first: Some(first),
+            second,
+        }
+    }
+}
+
+impl<S1, S2, E1, E2> StreamTrait for ChainOnClose<S1, S2>
+where
+    S1: StreamTrait<Item = Result<WsMessage, E1>> + Unpin,
+    S2: StreamTrait<Item = Result<WsMessage, E2>> + Unpin,
+    E1: Into<Error>,
+    E2: Into<Error>,
+{
+    type Item = Result<WsMessage, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        if let Some(first) = &mut this.first {
+            match ready!(Pin::new(first).poll_next(cx)) {
+                Some(Ok(msg)) if !super::is_close(&msg) => return Poll::Ready(Some(Ok(msg))),
+                // Close frame, error or end: switch to the second stream
+                _ => this.first = None,
+            }
+        }
+
+        Pin::new(&mut this.second)
+            .poll_next(cx)
+            .map(|item| item.map(|res| res.map_err(Into::into)))
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use futures_util::{stream, StreamExt};
+
+    use super::*;
+
+    fn text(s: &str) -> WsMessage {
+        WsMessage::Text(s.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_chain_on_close() {
+        let first = stream::iter(vec![
+            Ok::<_, Error>(text("a")),
+            Ok(WsMessage::Close(None)),
+            Ok(text("ignored")),
+        ]);
+        let second = stream::iter(vec![Ok::<_, Error>(text("b"))]);
+
+        let messages: Vec<WsMessage> = ChainOnClose::new(first, second)
+            .map(|msg| msg.unwrap())
+            .collect()
+            .await;
+        assert_eq!(messages, vec![text("a"), text("b")]);
+
+        let first = stream::iter(vec![Ok(text("a")), Err(Error::Timeout)]);
+        let second = stream::iter(vec![Ok::<_, Error>(text("b"))]);
+
+        let messages: Vec<WsMessage> = ChainOnClose::new(first, second)
+            .map(|msg| msg.unwrap())
+            .collect()
+            .await;
+        assert_eq!(messages, vec![text("a"), text("b")]);
+    }
+}
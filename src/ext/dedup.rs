@@ -0,0 +1,146 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::collections::{HashSet, VecDeque};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_core::{ready, Stream as StreamTrait};
+use sha2::{Digest, Sha256};
+
+use crate::{Error, WsMessage};
+
+type MessageDigest = [u8; 32];
+
+/// Stream for [`WsStreamExt::deduplicate`](super::WsStreamExt::deduplicate)
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct DeduplicatedStream<S> {
+    stream: S,
+    window: usize,
+    /// Digests of the last messages, oldest first
+    recent: VecDeque<MessageDigest>,
+    seen: HashSet<MessageDigest>,
+    duplicates_dropped: u64,
+}
+
+impl<S> DeduplicatedStream<S> {
+    #[inline]
+    pub(super) fn new(stream: S, window: usize) -> Self {
+        assert!(window > 0, "window size must be greater than 0");
+        Self {
+            stream,
+            window,
+            recent: VecDeque::with_capacity(window),
+            seen: HashSet::with_capacity(window),
+            duplicates_dropped: 0,
+        }
+    }
+
+    /// Number of duplicate messages dropped so far
+    #[inline]
+    pub fn duplicates_dropped(&self) -> u64 {
+        self.duplicates_dropped
+    }
+
+    /// Check if the message was already seen, remembering it otherwise
+    fn is_duplicate(&mut self, digest: MessageDigest) -> bool {
+        if self.seen.contains(&digest) {
+            return true;
+        }
+
+        if self.recent.len() == self.window {
+            if let Some(oldest) = self.recent.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+
+        self.recent.push_back(digest);
+        self.seen.insert(digest);
+        false
+    }
+}
+
+impl<S, E> StreamTrait for DeduplicatedStream<S>
+where
+    S: StreamTrait<Item = Result<WsMessage, E>> + Unpin,
+    E: Into<Error>,
+{
+    type Item = Result<WsMessage, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            match ready!(Pin::new(&mut this.stream).poll_next(cx)) {
+                Some(Ok(msg)) => {
+                    if let Some(digest) = digest(&msg) {
+                        if this.is_duplicate(digest) {
+                            this.duplicates_dropped = this.duplicates_dropped.saturating_add(1);
+                            continue;
+                        }
+                    }
+
+                    return Poll::Ready(Some(Ok(msg)));
+                }
+                Some(Err(e)) => return Poll::Ready(Some(Err(e.into()))),
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+/// SHA-256 of the type and content of a data message: `None` for the control frames
+fn digest(msg: &WsMessage) -> Option<MessageDigest> {
+    let (kind, payload): (u8, &[u8]) = match msg {
+        WsMessage::Text(text) => (0, text.as_bytes()),
+        WsMessage::Binary(data) => (1, data),
+        _ => return None,
+    };
+
+    let mut hasher = Sha256::new();
+    hasher.update([kind]);
+    hasher.update(payload);
+    Some(hasher.finalize().into())
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use futures_util::{stream, StreamExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_deduplicate() {
+        let messages = [
+            WsMessage::text("a"),
+            WsMessage::text("a"),
+            WsMessage::binary(b"a".to_vec()),
+            WsMessage::text("b"),
+            WsMessage::Ping(Vec::new()),
+            WsMessage::Ping(Vec::new()),
+            WsMessage::text("c"),
+            // Out of the window
+            WsMessage::text("a"),
+        ];
+        let mut stream = DeduplicatedStream::new(stream::iter(messages.map(Ok::<_, Error>)), 3);
+
+        let mut received = Vec::new();
+        while let Some(msg) = stream.next().await {
+            received.push(msg.unwrap());
+        }
+
+        assert_eq!(
+            received,
+            vec![
+                WsMessage::text("a"),
+                WsMessage::binary(b"a".to_vec()),
+                WsMessage::text("b"),
+                WsMessage::Ping(Vec::new()),
+                WsMessage::Ping(Vec::new()),
+                WsMessage::text("c"),
+                WsMessage::text("a"),
+            ]
+        );
+        assert_eq!(stream.duplicates_dropped(), 1);
+    }
+}
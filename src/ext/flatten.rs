@@ -0,0 +1,151 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_core::Stream as StreamTrait;
+use futures_util::stream::SelectAll;
+use futures_util::StreamExt;
+
+use crate::{Error, WsMessage};
+
+/// Merge the messages of the connections yielded by `streams`, as they arrive.
+///
+/// Each connection is read until it closes or errors, then removed: its close frame and
+/// error aren't yielded. More connections can be added with [`FlattenMessageStreams::add_stream`].
+/// The stream ends once `streams` is exhausted and all the connections are closed.
+pub fn flatten_message_streams<O, S, E>(streams: O) -> FlattenMessageStreams<O, S>
+where
+    O: StreamTrait<Item = S> + Unpin,
+    S: StreamTrait<Item = Result<WsMessage, E>> + Unpin,
+    E: Into<Error>,
+{
+    FlattenMessageStreams {
+        streams: Some(streams),
+        active: SelectAll::new(),
+    }
+}
+
+/// Stream for [`flatten_message_streams`]
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct FlattenMessageStreams<O, S> {
+    /// `None` once exhausted
+    streams: Option<O>,
+    active: SelectAll<UntilClose<S>>,
+}
+
+impl<O, S, E> FlattenMessageStreams<O, S>
+where
+    S: StreamTrait<Item = Result<WsMessage, E>> + Unpin,
+{
+    /// Add a connection
+    #[inline]
+    pub fn add_stream(&mut self, stream: S) {
+        self.active.push(UntilClose(Some(stream)));
+    }
+
+    /// Number of connections being read
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.active.len()
+    }
+
+    /// Check if no connection is being read
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.active.is_empty()
+    }
+}
+
+impl<O, S, E> StreamTrait for FlattenMessageStreams<O, S>
+where
+    O: StreamTrait<Item = S> + Unpin,
+    S: StreamTrait<Item = Result<WsMessage, E>> + Unpin,
+    E: Into<Error>,
+{
+    type Item = WsMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        // Subscribe to the new connections
+        while let Some(streams) = &mut this.streams {
+            match streams.poll_next_unpin(cx) {
+                Poll::Ready(Some(stream)) => this.add_stream(stream),
+                Poll::Ready(None) => this.streams = None,
+                Poll::Pending => break,
+            }
+        }
+
+        match this.active.poll_next_unpin(cx) {
+            Poll::Ready(Some(msg)) => Poll::Ready(Some(msg)),
+            // No connection left: done only if no more can arrive
+            Poll::Ready(None) if this.streams.is_none() => Poll::Ready(None),
+            Poll::Ready(None) | Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Yield the messages until close or error
+#[derive(Debug)]
+struct UntilClose<S>(Option<S>);
+
+impl<S, E> StreamTrait for UntilClose<S>
+where
+    S: StreamTrait<Item = Result<WsMessage, E>> + Unpin,
+{
+    type Item = WsMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let stream: &mut S = match &mut self.0 {
+            Some(stream) => stream,
+            None => return Poll::Ready(None),
+        };
+
+        match stream.poll_next_unpin(cx) {
+            Poll::Ready(Some(Ok(msg))) if !super::is_close(&msg) => Poll::Ready(Some(msg)),
+            Poll::Ready(..) => {
+                self.0 = None;
+                Poll::Ready(None)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use futures_channel::mpsc;
+    use futures_util::stream::{self, BoxStream};
+
+    use super::*;
+
+    fn text(s: &str) -> WsMessage {
+        WsMessage::Text(s.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_flatten_message_streams() {
+        let (mut tx, rx) = mpsc::unbounded::<BoxStream<'static, Result<WsMessage, Error>>>();
+        let mut flatten = flatten_message_streams(rx);
+
+        tx.unbounded_send(
+            stream::iter(vec![Ok(text("a")), Err(Error::Timeout), Ok(text("lost"))]).boxed(),
+        )
+        .unwrap();
+        assert_eq!(flatten.next().await.unwrap(), text("a"));
+
+        // Appended at runtime
+        flatten.add_stream(stream::iter(vec![Ok(text("b")), Ok(WsMessage::Close(None))]).boxed());
+        assert_eq!(flatten.next().await.unwrap(), text("b"));
+
+        tx.unbounded_send(stream::iter(vec![Ok(text("c"))]).boxed())
+            .unwrap();
+        tx.disconnect();
+        assert_eq!(flatten.next().await.unwrap(), text("c"));
+        assert!(flatten.next().await.is_none());
+        assert!(flatten.is_empty());
+    }
+}
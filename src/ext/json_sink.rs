@@ -0,0 +1,97 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_sink::Sink as SinkTrait;
+use serde::Serialize;
+
+use crate::{Error, WsMessage};
+
+/// Sink for [`WsSinkExt::into_json_lines_sink`](super::WsSinkExt::into_json_lines_sink)
+#[derive(Debug)]
+#[must_use = "sinks do nothing unless polled"]
+pub struct JsonLinesSink<S, T> {
+    sink: S,
+    _marker: PhantomData<fn(T)>,
+}
+
+impl<S, T> JsonLinesSink<S, T> {
+    #[inline]
+    pub(super) fn new(sink: S) -> Self {
+        Self {
+            sink,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Get the inner sink
+    #[inline]
+    pub fn into_inner(self) -> S {
+        self.sink
+    }
+}
+
+// Items are serialized immediately, never pinned
+impl<S, T> Unpin for JsonLinesSink<S, T> where S: Unpin {}
+
+impl<S, T, E> SinkTrait<T> for JsonLinesSink<S, T>
+where
+    S: SinkTrait<WsMessage, Error = E> + Unpin,
+    T: Serialize,
+    E: Into<Error>,
+{
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.sink).poll_ready(cx).map_err(Into::into)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        let json: String = serde_json::to_string(&item)?;
+        Pin::new(&mut self.sink)
+            .start_send(WsMessage::Text(json))
+            .map_err(Into::into)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.sink).poll_flush(cx).map_err(Into::into)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.sink).poll_close(cx).map_err(Into::into)
+    }
+}
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use serde::{Deserialize, Serialize};
+
+    use crate::test_util;
+    use crate::{WsSinkExt, WsStreamExt};
+
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    struct Msg {
+        id: u32,
+        text: String,
+    }
+
+    #[tokio::test]
+    async fn test_json_lines_echo() {
+        let ((tx, rx), (server_tx, server_rx)) = test_util::pair().await.unwrap();
+        tokio::spawn(test_util::echo(server_tx, server_rx));
+
+        let mut tx = tx.into_json_lines_sink::<Msg>();
+        let mut rx = rx.into_ndjson_stream::<Msg>();
+
+        let msg = Msg {
+            id: 1,
+            text: String::from("hello"),
+        };
+        tx.send(msg.clone()).await.unwrap();
+        assert_eq!(rx.next().await.unwrap().unwrap(), msg);
+    }
+}
@@ -0,0 +1,260 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Stream and sink extensions
+
+use futures_core::Stream as StreamTrait;
+use futures_sink::Sink as SinkTrait;
+
+mod broadcast;
+mod chain;
+#[cfg(feature = "dedup")]
+mod dedup;
+mod flatten;
+#[cfg(feature = "serde-json")]
+mod json_sink;
+#[cfg(feature = "serde-json")]
+mod ndjson;
+mod payload;
+mod peek;
+mod retry;
+mod shared;
+mod take_until_close;
+mod transform;
+mod typed;
+mod window;
+
+pub use self::broadcast::broadcast;
+pub use self::chain::ChainOnClose;
+#[cfg(feature = "dedup")]
+pub use self::dedup::DeduplicatedStream;
+pub use self::flatten::{flatten_message_streams, FlattenMessageStreams};
+#[cfg(feature = "serde-json")]
+pub use self::json_sink::JsonLinesSink;
+#[cfg(feature = "serde-json")]
+pub use self::ndjson::NdjsonStream;
+pub use self::payload::{BinaryStream, TextStream, UnexpectedPolicy};
+pub use self::peek::{PeekableStream, SkippedMessages};
+pub use self::retry::{RetryPolicy, RetryingSink};
+pub use self::shared::{SendError, SharedSinkDriver, WsSender};
+pub use self::take_until_close::TakeUntilClose;
+pub use self::transform::TransformedStream;
+#[cfg(feature = "serde-json")]
+pub use self::typed::JsonCodec;
+pub use self::typed::{MessageCodec, TypedError, TypedWsStream};
+pub use self::window::Window;
+use crate::{Error, WsMessage};
+
+/// Extension methods for streams of [`WsMessage`] (i.e. [`Stream`](crate::Stream))
+///
+/// Errors yielded by the stream are converted into the crate [`Error`].
+pub trait WsStreamExt<E>: StreamTrait<Item = Result<WsMessage, E>>
+where
+    E: Into<Error>,
+{
+    /// Read all the messages until the peer closes the connection.
+    ///
+    /// The close frame is not included in the output. A non-close error stops the collection
+    /// and is returned. If `max_total_bytes` is set, the collection stops with
+    /// [`Error::SizeLimitExceeded`] when the total payload exceeds it.
+    #[inline]
+    fn take_until_close(self, max_total_bytes: Option<usize>) -> TakeUntilClose<Self>
+    where
+        Self: Sized + Unpin,
+    {
+        TakeUntilClose::new(self, max_total_bytes)
+    }
+
+    /// Yield only the payload of the text messages.
+    ///
+    /// Control frames are skipped, binary messages are skipped or yield
+    /// [`Error::UnexpectedBinaryFrame`] according to the [`UnexpectedPolicy`].
+    /// Errors are forwarded. On native, the close frame is yielded as the terminal
+    /// `Error::Closed` before the stream ends.
+    #[inline]
+    fn text_stream(self, policy: UnexpectedPolicy) -> TextStream<Self>
+    where
+        Self: Sized + Unpin,
+    {
+        TextStream::new(self, policy)
+    }
+
+    /// Yield only the payload of the binary messages.
+    ///
+    /// Same as [`WsStreamExt::text_stream`], but text messages are the unexpected ones
+    /// and yield [`Error::UnexpectedTextFrame`].
+    #[inline]
+    fn binary_stream(self, policy: UnexpectedPolicy) -> BinaryStream<Self>
+    where
+        Self: Sized + Unpin,
+    {
+        BinaryStream::new(self, policy)
+    }
+
+    /// Yield all the messages of this stream until it closes or errors, then all the
+    /// messages of `other`.
+    ///
+    /// The close frame and the error of this stream are not yielded: i.e. to seamlessly
+    /// continue reading from a new connection after a reconnect. The errors of `other` are forwarded.
+    ///
+    /// Named so to not clash with [`StreamExt::chain`](futures_util::StreamExt::chain),
+    /// which yields the close frame and the errors of the first stream too.
+    #[inline]
+    fn chain_on_close<S, E2>(self, other: S) -> ChainOnClose<Self, S>
+    where
+        Self: Sized + Unpin,
+        S: StreamTrait<Item = Result<WsMessage, E2>> + Unpin,
+        E2: Into<Error>,
+    {
+        ChainOnClose::new(self, other)
+    }
+
+    /// Keep a lookahead buffer, to inspect the next message without consuming it
+    /// (i.e. to route it to a handler) or to wait for a specific one.
+    ///
+    /// Check [`PeekableStream::peek`] and [`PeekableStream::recv_matching`].
+    #[inline]
+    fn into_peekable(self) -> PeekableStream<Self>
+    where
+        Self: Sized + Unpin,
+    {
+        PeekableStream::new(self)
+    }
+
+    /// Group the messages into overlapping windows of the last `size` messages.
+    ///
+    /// Every new message yields a window. If `emit_partial` is `true`, the windows smaller
+    /// than `size` (before `size` messages arrived) are yielded too. The stream ends on close.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is `0`.
+    #[inline]
+    fn window(self, size: usize, emit_partial: bool) -> Window<Self>
+    where
+        Self: Sized + Unpin,
+    {
+        Window::new(self, size, emit_partial)
+    }
+
+    /// Drop the data messages identical to one of the last `window` distinct ones.
+    ///
+    /// The messages are compared by the SHA-256 digest of their type and content.
+    /// Control frames are never dropped. Check [`DeduplicatedStream::duplicates_dropped`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `window` is `0`.
+    #[inline]
+    #[cfg(feature = "dedup")]
+    fn deduplicate(self, window: usize) -> DeduplicatedStream<Self>
+    where
+        Self: Sized + Unpin,
+    {
+        DeduplicatedStream::new(self, window)
+    }
+
+    /// Pass the payload of every binary message through `decode`, i.e. to decompress or decrypt it.
+    ///
+    /// Text messages and control frames pass through unchanged. The errors returned by `decode`
+    /// are yielded as items, without ending the stream. If this is also a sink (i.e. a connection
+    /// not split), the payload of the sent binary messages goes through `encode`, whose errors are
+    /// returned by the send.
+    #[inline]
+    fn transform_binary<D, En>(self, decode: D, encode: En) -> TransformedStream<Self, D, En>
+    where
+        Self: Sized + Unpin,
+        D: Fn(Vec<u8>) -> Result<Vec<u8>, Error>,
+        En: Fn(Vec<u8>) -> Result<Vec<u8>, Error>,
+    {
+        TransformedStream::new(self, decode, encode)
+    }
+
+    /// Deserialize every text frame as newline-delimited JSON.
+    ///
+    /// A text frame containing many newline-separated JSON records yields each of them.
+    /// Binary frames yield [`Error::UnexpectedBinaryFrame`], control frames are skipped.
+    #[inline]
+    #[cfg(feature = "serde-json")]
+    fn into_ndjson_stream<T>(self) -> NdjsonStream<Self, T>
+    where
+        Self: Sized + Unpin,
+        T: serde::de::DeserializeOwned,
+    {
+        NdjsonStream::new(self)
+    }
+}
+
+impl<T, E> WsStreamExt<E> for T
+where
+    T: StreamTrait<Item = Result<WsMessage, E>> + ?Sized,
+    E: Into<Error>,
+{
+}
+
+/// Extension methods for sinks of [`WsMessage`] (i.e. [`Sink`](crate::Sink))
+///
+/// Errors returned by the sink are converted into the crate [`Error`].
+pub trait WsSinkExt<E>: SinkTrait<WsMessage, Error = E>
+where
+    E: Into<Error>,
+{
+    /// Check if the backend can send ping and pong frames.
+    ///
+    /// Always `true` on native and `false` on WASM, since the browser API doesn't expose them.
+    #[inline]
+    fn supports_ping_pong(&self) -> bool {
+        cfg!(not(target_arch = "wasm32"))
+    }
+
+    /// Retry the failed sends according to the [`RetryPolicy`].
+    #[inline]
+    fn retry_errors(self, policy: RetryPolicy) -> RetryingSink<Self>
+    where
+        Self: Sized + Unpin,
+    {
+        RetryingSink::new(self, policy)
+    }
+
+    /// Get a cloneable [`WsSender`] feeding this sink through a queue of `buffer` messages.
+    ///
+    /// The [`SharedSinkDriver`] drains the queue into the sink: it must be polled (i.e. spawned).
+    /// Once the sink fails or the driver is dropped, all the senders get [`SendError::Closed`].
+    #[inline]
+    fn into_shared_sender(self, buffer: usize) -> (WsSender, SharedSinkDriver<Self>)
+    where
+        Self: Sized + Unpin,
+    {
+        shared::new(self, buffer)
+    }
+
+    /// Serialize every item to JSON and send it as a text frame.
+    #[inline]
+    #[cfg(feature = "serde-json")]
+    fn into_json_lines_sink<T>(self) -> JsonLinesSink<Self, T>
+    where
+        Self: Sized + Unpin,
+        T: serde::Serialize,
+    {
+        JsonLinesSink::new(self)
+    }
+}
+
+impl<T, E> WsSinkExt<E> for T
+where
+    T: SinkTrait<WsMessage, Error = E> + ?Sized,
+    E: Into<Error>,
+{
+}
+
+#[inline]
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn is_close(msg: &WsMessage) -> bool {
+    msg.is_close()
+}
+
+#[inline]
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn is_close(_msg: &WsMessage) -> bool {
+    false
+}
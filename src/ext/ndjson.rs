@@ -0,0 +1,74 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_core::{ready, Stream as StreamTrait};
+use serde::de::DeserializeOwned;
+
+use crate::{Error, WsMessage};
+
+/// Stream for [`WsStreamExt::into_ndjson_stream`](super::WsStreamExt::into_ndjson_stream)
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct NdjsonStream<S, T> {
+    stream: S,
+    pending: VecDeque<Result<T, Error>>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<S, T> NdjsonStream<S, T> {
+    #[inline]
+    pub(super) fn new(stream: S) -> Self {
+        Self {
+            stream,
+            pending: VecDeque::new(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+// Deserialized items are never pinned
+impl<S, T> Unpin for NdjsonStream<S, T> where S: Unpin {}
+
+impl<S, T, E> StreamTrait for NdjsonStream<S, T>
+where
+    S: StreamTrait<Item = Result<WsMessage, E>> + Unpin,
+    T: DeserializeOwned,
+    E: Into<Error>,
+{
+    type Item = Result<T, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if let Some(item) = this.pending.pop_front() {
+                return Poll::Ready(Some(item));
+            }
+
+            match ready!(Pin::new(&mut this.stream).poll_next(cx)) {
+                Some(Ok(msg)) => match msg {
+                    WsMessage::Text(text) => {
+                        // A single frame may contain many newline-separated records
+                        this.pending.extend(
+                            text.lines()
+                                .filter(|line| !line.trim().is_empty())
+                                .map(|line| serde_json::from_str(line).map_err(Error::from)),
+                        );
+                    }
+                    WsMessage::Binary(..) => {
+                        return Poll::Ready(Some(Err(Error::UnexpectedBinaryFrame)))
+                    }
+                    // Ping, pong, close or raw frame
+                    #[allow(unreachable_patterns)]
+                    _ => continue,
+                },
+                Some(Err(e)) => return Poll::Ready(Some(Err(e.into()))),
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
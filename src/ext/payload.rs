@@ -0,0 +1,247 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_core::{ready, Stream as StreamTrait};
+
+use crate::{Error, WsMessage};
+
+/// What [`TextStream`] and [`BinaryStream`] do with messages of the other kind
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnexpectedPolicy {
+    /// Skip them
+    Skip,
+    /// Yield [`Error::UnexpectedBinaryFrame`] or [`Error::UnexpectedTextFrame`]
+    #[default]
+    Error,
+}
+
+/// Payload kind to extract
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Text,
+    Binary,
+}
+
+/// Shared implementation of [`TextStream`] and [`BinaryStream`]
+#[derive(Debug)]
+struct Payloads<S> {
+    stream: S,
+    kind: Kind,
+    policy: UnexpectedPolicy,
+    done: bool,
+}
+
+impl<S> Payloads<S> {
+    #[inline]
+    fn new(stream: S, kind: Kind, policy: UnexpectedPolicy) -> Self {
+        Self {
+            stream,
+            kind,
+            policy,
+            done: false,
+        }
+    }
+
+    /// Check what to do with a message of the other kind
+    fn unexpected(&self, error: Error) -> Option<Error> {
+        match self.policy {
+            UnexpectedPolicy::Skip => None,
+            UnexpectedPolicy::Error => Some(error),
+        }
+    }
+}
+
+impl<S, E> Payloads<S>
+where
+    S: StreamTrait<Item = Result<WsMessage, E>> + Unpin,
+    E: Into<Error>,
+{
+    /// Poll the next text or binary message of the wanted kind
+    fn poll_payload(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<WsMessage, Error>>> {
+        while !self.done {
+            match ready!(Pin::new(&mut self.stream).poll_next(cx)) {
+                Some(Ok(msg)) => match (&msg, self.kind) {
+                    (WsMessage::Text(..), Kind::Text) | (WsMessage::Binary(..), Kind::Binary) => {
+                        return Poll::Ready(Some(Ok(msg)))
+                    }
+                    (WsMessage::Binary(..), Kind::Text) => {
+                        if let Some(e) = self.unexpected(Error::UnexpectedBinaryFrame) {
+                            return Poll::Ready(Some(Err(e)));
+                        }
+                    }
+                    (WsMessage::Text(..), Kind::Binary) => {
+                        if let Some(e) = self.unexpected(Error::UnexpectedTextFrame) {
+                            return Poll::Ready(Some(Err(e)));
+                        }
+                    }
+                    // The close frame is forwarded as the terminal error
+                    #[cfg(not(target_arch = "wasm32"))]
+                    (WsMessage::Close(..), _) => {
+                        self.done = true;
+                        if let WsMessage::Close(frame) = msg {
+                            return Poll::Ready(Some(Err(Error::Closed(frame))));
+                        }
+                    }
+                    // Ping, pong or raw frame
+                    #[allow(unreachable_patterns)]
+                    _ => continue,
+                },
+                Some(Err(e)) => return Poll::Ready(Some(Err(e.into()))),
+                None => self.done = true,
+            }
+        }
+
+        Poll::Ready(None)
+    }
+}
+
+/// Stream for [`WsStreamExt::text_stream`](super::WsStreamExt::text_stream)
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct TextStream<S> {
+    inner: Payloads<S>,
+}
+
+impl<S> TextStream<S> {
+    #[inline]
+    pub(super) fn new(stream: S, policy: UnexpectedPolicy) -> Self {
+        Self {
+            inner: Payloads::new(stream, Kind::Text, policy),
+        }
+    }
+}
+
+impl<S, E> StreamTrait for TextStream<S>
+where
+    S: StreamTrait<Item = Result<WsMessage, E>> + Unpin,
+    E: Into<Error>,
+{
+    type Item = Result<String, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Ready(ready!(self.inner.poll_payload(cx)).map(|res| {
+            res.map(|msg| match msg {
+                WsMessage::Text(text) => text,
+                _ => unreachable!("only text messages are yielded"),
+            })
+        }))
+    }
+}
+
+/// Stream for [`WsStreamExt::binary_stream`](super::WsStreamExt::binary_stream)
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct BinaryStream<S> {
+    inner: Payloads<S>,
+}
+
+impl<S> BinaryStream<S> {
+    #[inline]
+    pub(super) fn new(stream: S, policy: UnexpectedPolicy) -> Self {
+        Self {
+            inner: Payloads::new(stream, Kind::Binary, policy),
+        }
+    }
+}
+
+impl<S, E> StreamTrait for BinaryStream<S>
+where
+    S: StreamTrait<Item = Result<WsMessage, E>> + Unpin,
+    E: Into<Error>,
+{
+    type Item = Result<Vec<u8>, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Ready(ready!(self.inner.poll_payload(cx)).map(|res| res.map(WsMessage::into_data)))
+    }
+}
+
+#[cfg(all(test, feature = "test-util", not(target_arch = "wasm32")))]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+    use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+
+    use super::*;
+    use crate::native::{Sink, Stream};
+    use crate::test_util;
+    use crate::WsStreamExt;
+
+    /// Send mixed traffic to the client and return its stream
+    async fn mixed_traffic() -> (Stream, Sink) {
+        let ((_client_tx, client_rx), (mut server_tx, _server_rx)) =
+            test_util::pair().await.unwrap();
+
+        server_tx
+            .send(WsMessage::Text(String::from("a")))
+            .await
+            .unwrap();
+        server_tx.send(WsMessage::Binary(vec![1])).await.unwrap();
+        server_tx.send(WsMessage::Ping(vec![2])).await.unwrap();
+        server_tx
+            .send(WsMessage::Text(String::from("b")))
+            .await
+            .unwrap();
+        server_tx.send(WsMessage::Binary(vec![3])).await.unwrap();
+        server_tx
+            .send(WsMessage::Close(Some(CloseFrame {
+                code: CloseCode::Away,
+                reason: "bye".into(),
+            })))
+            .await
+            .unwrap();
+
+        // The server sink is returned so the connection isn't dropped before the client reads
+        (client_rx, server_tx)
+    }
+
+    fn assert_closed<T>(res: Option<Result<T, Error>>)
+    where
+        T: std::fmt::Debug,
+    {
+        match res {
+            Some(Err(Error::Closed(Some(frame)))) => assert_eq!(frame.code, CloseCode::Away),
+            res => panic!("unexpected {res:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_text_stream() {
+        let (rx, _tx) = mixed_traffic().await;
+        let mut texts = rx.text_stream(UnexpectedPolicy::Skip);
+        assert_eq!(texts.next().await.unwrap().unwrap(), "a");
+        assert_eq!(texts.next().await.unwrap().unwrap(), "b");
+        assert_closed(texts.next().await);
+        assert!(texts.next().await.is_none());
+
+        let (rx, _tx) = mixed_traffic().await;
+        let mut texts = rx.text_stream(UnexpectedPolicy::Error);
+        assert_eq!(texts.next().await.unwrap().unwrap(), "a");
+        assert!(matches!(
+            texts.next().await,
+            Some(Err(Error::UnexpectedBinaryFrame))
+        ));
+        assert_eq!(texts.next().await.unwrap().unwrap(), "b");
+    }
+
+    #[tokio::test]
+    async fn test_binary_stream() {
+        let (rx, _tx) = mixed_traffic().await;
+        let mut binaries = rx.binary_stream(UnexpectedPolicy::Skip);
+        assert_eq!(binaries.next().await.unwrap().unwrap(), vec![1]);
+        assert_eq!(binaries.next().await.unwrap().unwrap(), vec![3]);
+        assert_closed(binaries.next().await);
+        assert!(binaries.next().await.is_none());
+
+        let (rx, _tx) = mixed_traffic().await;
+        let mut binaries = rx.binary_stream(UnexpectedPolicy::Error);
+        assert!(matches!(
+            binaries.next().await,
+            Some(Err(Error::UnexpectedTextFrame))
+        ));
+        assert_eq!(binaries.next().await.unwrap().unwrap(), vec![1]);
+    }
+}
@@ -0,0 +1,222 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_utility::time;
+use futures_core::{ready, Stream as StreamTrait};
+use futures_util::future;
+
+use crate::{Error, WsMessage};
+
+/// What [`PeekableStream::recv_matching`] does with the messages not matching
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SkippedMessages {
+    /// Drop them
+    #[default]
+    Discard,
+    /// Keep them buffered: they are yielded next, in order
+    Requeue,
+}
+
+/// Stream for [`WsStreamExt::into_peekable`](super::WsStreamExt::into_peekable)
+///
+/// Keep a lookahead buffer, so the next message can be inspected without consuming it.
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct PeekableStream<S> {
+    stream: S,
+    /// Received but not consumed yet
+    buffer: VecDeque<Result<WsMessage, Error>>,
+    /// The inner stream ended
+    ended: bool,
+}
+
+impl<S> PeekableStream<S> {
+    #[inline]
+    pub(super) fn new(stream: S) -> Self {
+        Self {
+            stream,
+            buffer: VecDeque::new(),
+            ended: false,
+        }
+    }
+
+    /// Consume the adapter, returning the inner stream and the buffered messages
+    #[inline]
+    pub fn into_inner(self) -> (S, VecDeque<Result<WsMessage, Error>>) {
+        (self.stream, self.buffer)
+    }
+}
+
+impl<S, E> PeekableStream<S>
+where
+    S: StreamTrait<Item = Result<WsMessage, E>> + Unpin,
+    E: Into<Error>,
+{
+    /// Poll the inner stream into the buffer: `false` if it ended
+    fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<bool> {
+        if self.ended {
+            return Poll::Ready(false);
+        }
+
+        match ready!(Pin::new(&mut self.stream).poll_next(cx)) {
+            Some(item) => {
+                self.buffer.push_back(item.map_err(Into::into));
+                Poll::Ready(true)
+            }
+            None => {
+                self.ended = true;
+                Poll::Ready(false)
+            }
+        }
+    }
+
+    /// Poll the next message without consuming it.
+    ///
+    /// The message is buffered as soon as it's received, so it's never lost.
+    pub fn poll_peek(&mut self, cx: &mut Context<'_>) -> Poll<Option<&Result<WsMessage, Error>>> {
+        if self.buffer.is_empty() {
+            ready!(self.poll_fill(cx));
+        }
+
+        Poll::Ready(self.buffer.front())
+    }
+
+    /// Get the next message without consuming it.
+    ///
+    /// Return `None` if the stream ended. This is cancellation safe: if the future is
+    /// dropped after a message is received, the message stays buffered.
+    pub async fn peek(&mut self) -> Option<&Result<WsMessage, Error>> {
+        future::poll_fn(|cx| self.poll_peek(cx).map(|item| item.is_some())).await;
+        self.buffer.front()
+    }
+
+    /// Wait for the first message matching `predicate` (i.e. the server hello), within `timeout`
+    ///
+    /// The messages not matching are dropped or kept buffered according to [`SkippedMessages`]:
+    /// the requeued ones are yielded next, in order. Errors are returned as soon as they are reached.
+    ///
+    /// Return `Ok(None)` if the stream ended and [`Error::Timeout`] if `timeout` expires.
+    /// This is cancellation safe: the messages already received stay buffered, except the discarded ones.
+    pub async fn recv_matching<F>(
+        &mut self,
+        predicate: F,
+        timeout: Duration,
+        skipped: SkippedMessages,
+    ) -> Result<Option<WsMessage>, Error>
+    where
+        F: Fn(&WsMessage) -> bool,
+    {
+        // Position of the next buffered message to check
+        let mut index: usize = 0;
+
+        let find = future::poll_fn(|cx| loop {
+            if index == self.buffer.len() && !ready!(self.poll_fill(cx)) {
+                return Poll::Ready(Ok(None));
+            }
+
+            let matches: Option<bool> = match &self.buffer[index] {
+                Ok(msg) => Some(predicate(msg)),
+                Err(..) => None,
+            };
+
+            match (matches, skipped) {
+                (Some(true), _) | (None, _) => {
+                    let item = self.buffer.remove(index).expect("index within the buffer");
+                    return Poll::Ready(item.map(Some));
+                }
+                (Some(false), SkippedMessages::Discard) => {
+                    self.buffer.remove(index);
+                }
+                (Some(false), SkippedMessages::Requeue) => index += 1,
+            }
+        });
+
+        time::timeout(Some(timeout), find)
+            .await
+            .unwrap_or(Err(Error::Timeout))
+    }
+}
+
+impl<S, E> StreamTrait for PeekableStream<S>
+where
+    S: StreamTrait<Item = Result<WsMessage, E>> + Unpin,
+    E: Into<Error>,
+{
+    type Item = Result<WsMessage, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.buffer.is_empty() {
+            ready!(self.poll_fill(cx));
+        }
+
+        Poll::Ready(self.buffer.pop_front())
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use futures_util::{stream, StreamExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_peek() {
+        let messages = ["a", "b"].map(|s| Ok::<_, Error>(WsMessage::Text(s.to_string())));
+        let mut stream = PeekableStream::new(stream::iter(messages));
+
+        for _ in 0..2 {
+            let peeked = stream.peek().await.unwrap().as_ref().unwrap();
+            assert_eq!(peeked, &WsMessage::Text(String::from("a")));
+        }
+
+        let next = stream.next().await.unwrap().unwrap();
+        assert_eq!(next, WsMessage::Text(String::from("a")));
+        let next = stream.next().await.unwrap().unwrap();
+        assert_eq!(next, WsMessage::Text(String::from("b")));
+
+        assert!(stream.peek().await.is_none());
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_recv_matching() {
+        let messages = ["ping", "a", "hello", "b", "hello"]
+            .map(|s| Ok::<_, Error>(WsMessage::Text(s.to_string())));
+        let mut stream = PeekableStream::new(stream::iter(messages));
+        let is_hello = |msg: &WsMessage| msg == &WsMessage::Text(String::from("hello"));
+        let timeout = Duration::from_secs(1);
+
+        let res = stream.recv_matching(is_hello, timeout, SkippedMessages::Requeue);
+        assert_eq!(
+            res.await.unwrap(),
+            Some(WsMessage::Text(String::from("hello")))
+        );
+
+        // Requeued in order
+        let next = stream.next().await.unwrap().unwrap();
+        assert_eq!(next, WsMessage::Text(String::from("ping")));
+
+        let res = stream.recv_matching(is_hello, timeout, SkippedMessages::Discard);
+        assert_eq!(
+            res.await.unwrap(),
+            Some(WsMessage::Text(String::from("hello")))
+        );
+        assert!(stream.next().await.is_none());
+
+        let res = stream.recv_matching(is_hello, timeout, SkippedMessages::Discard);
+        assert_eq!(res.await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_recv_matching_timeout() {
+        let mut stream = PeekableStream::new(stream::pending::<Result<WsMessage, Error>>());
+        let timeout = Duration::from_millis(10);
+        let res = stream.recv_matching(|_| true, timeout, SkippedMessages::Discard);
+        assert!(matches!(res.await, Err(Error::Timeout)));
+    }
+}
@@ -0,0 +1,249 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::fmt;
+use std::time::Duration;
+
+use async_utility::thread;
+use futures_sink::Sink as SinkTrait;
+use futures_util::SinkExt;
+
+use crate::{Error, WsMessage};
+
+/// Retry policy for [`WsSinkExt::retry_errors`](super::WsSinkExt::retry_errors)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Max number of attempts, including the first one
+    pub max_attempts: u32,
+    /// Backoff before the first retry, doubled at every following retry
+    pub backoff: Duration,
+    /// Max backoff
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Backoff before the retry following the failed `attempt` (1-based)
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor: u32 = 1u32
+            .checked_shl(attempt.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.backoff
+            .checked_mul(factor)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+}
+
+type RetryCallback = Box<dyn FnMut(u32, &Error) + Send>;
+type RetryPredicate = Box<dyn Fn(&Error) -> bool + Send>;
+
+/// Check if an error may go away by retrying the send on the same sink
+fn is_transient(e: &Error) -> bool {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let transient_io = |e: &std::io::Error| {
+            matches!(
+                e.kind(),
+                std::io::ErrorKind::Interrupted
+                    | std::io::ErrorKind::WouldBlock
+                    | std::io::ErrorKind::TimedOut
+            )
+        };
+
+        match e {
+            Error::Timeout | Error::WriteTimeout | Error::SendBufferFull => true,
+            _ => matches!(e.as_io(), Some(e) if transient_io(e)),
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    {
+        matches!(e, Error::Timeout | Error::Ws(crate::wasm::WsError::Timeout))
+    }
+}
+
+/// Sink wrapper retrying failed sends, created with [`WsSinkExt::retry_errors`](super::WsSinkExt::retry_errors)
+pub struct RetryingSink<S> {
+    sink: S,
+    policy: RetryPolicy,
+    on_retry: Option<RetryCallback>,
+    retry_if: Option<RetryPredicate>,
+}
+
+impl<S> fmt::Debug for RetryingSink<S>
+where
+    S: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryingSink")
+            .field("sink", &self.sink)
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+impl<S> RetryingSink<S> {
+    #[inline]
+    pub(super) fn new(sink: S, policy: RetryPolicy) -> Self {
+        Self {
+            sink,
+            policy,
+            on_retry: None,
+            retry_if: None,
+        }
+    }
+
+    /// Call `f` with the failed attempt number (1-based) and its error before each retry
+    pub fn on_retry<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(u32, &Error) + Send + 'static,
+    {
+        self.on_retry = Some(Box::new(f));
+        self
+    }
+
+    /// Retry only the errors for which `f` returns `true`
+    ///
+    /// By default, only the timeouts, a full send buffer and the interrupted I/O are retried:
+    /// any other error would fail the same way on the same sink.
+    pub fn retry_if<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Error) -> bool + Send + 'static,
+    {
+        self.retry_if = Some(Box::new(f));
+        self
+    }
+
+    /// Get the inner sink
+    #[inline]
+    pub fn into_inner(self) -> S {
+        self.sink
+    }
+}
+
+impl<S, E> RetryingSink<S>
+where
+    S: SinkTrait<WsMessage, Error = E> + Unpin,
+    E: Into<Error>,
+{
+    /// Send a message, retrying on failure according to the [`RetryPolicy`].
+    ///
+    /// Return the last error when all the attempts are exhausted, or right away if it isn't
+    /// retryable (check [`RetryingSink::retry_if`]).
+    ///
+    /// **A failed flush is retried too:** if the message was written before the failure,
+    /// the peer may receive it more than once.
+    pub async fn send(&mut self, msg: WsMessage) -> Result<(), Error> {
+        let mut attempt: u32 = 1;
+
+        loop {
+            let e: Error = match self.sink.send(msg.clone()).await {
+                Ok(()) => return Ok(()),
+                Err(e) => e.into(),
+            };
+
+            let retryable: bool = match &self.retry_if {
+                Some(retry_if) => retry_if(&e),
+                None => is_transient(&e),
+            };
+
+            if !retryable || attempt >= self.policy.max_attempts {
+                return Err(e);
+            }
+
+            if let Some(on_retry) = self.on_retry.as_mut() {
+                on_retry(attempt, &e);
+            }
+
+            thread::sleep(self.policy.backoff(attempt)).await;
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use super::*;
+    use crate::WsSinkExt;
+
+    /// Sink failing every send with the same error
+    struct FailingSink {
+        error: fn() -> Error,
+        attempts: u32,
+    }
+
+    impl SinkTrait<WsMessage> for FailingSink {
+        type Error = Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, _item: WsMessage) -> Result<(), Error> {
+            self.attempts += 1;
+            Err((self.error)())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_transient() {
+        let sink = FailingSink {
+            error: || Error::WriteTimeout,
+            attempts: 0,
+        };
+        let mut sink = sink.retry_errors(policy());
+        let res = sink.send(WsMessage::Text(String::from("hello"))).await;
+        assert!(matches!(res, Err(Error::WriteTimeout)));
+        assert_eq!(sink.into_inner().attempts, 3);
+    }
+
+    #[tokio::test]
+    async fn test_dont_retry_permanent() {
+        let sink = FailingSink {
+            error: || Error::Closed(None),
+            attempts: 0,
+        };
+        let mut sink = sink.retry_errors(policy());
+        let res = sink.send(WsMessage::Text(String::from("hello"))).await;
+        assert!(matches!(res, Err(Error::Closed(None))));
+        assert_eq!(sink.into_inner().attempts, 1);
+
+        // Unless told otherwise
+        let sink = FailingSink {
+            error: || Error::Closed(None),
+            attempts: 0,
+        };
+        let mut sink = sink.retry_errors(policy()).retry_if(|_| true);
+        let _ = sink.send(WsMessage::Text(String::from("hello"))).await;
+        assert_eq!(sink.into_inner().attempts, 3);
+    }
+}
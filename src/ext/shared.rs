@@ -0,0 +1,191 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::sync::{Arc, Mutex};
+
+use futures_channel::mpsc;
+use futures_sink::Sink as SinkTrait;
+use futures_util::{future, SinkExt, StreamExt};
+use thiserror::Error;
+
+use crate::{Error, WsMessage};
+
+/// Error that made the connection unusable, shared by all the senders
+type Cause = Arc<Mutex<Option<Arc<Error>>>>;
+
+/// Error returned by [`WsSender`]
+#[derive(Debug, Error)]
+pub enum SendError {
+    /// The queue is full (only returned by [`WsSender::try_send`])
+    #[error("send queue full")]
+    Full(WsMessage),
+    /// The connection is closed
+    #[error("connection closed")]
+    Closed {
+        /// The message that wasn't sent
+        msg: WsMessage,
+        /// The sink error that terminated the connection, if any
+        cause: Option<Arc<Error>>,
+    },
+}
+
+impl SendError {
+    /// Get back the message that wasn't sent
+    #[inline]
+    pub fn into_message(self) -> WsMessage {
+        match self {
+            Self::Full(msg) => msg,
+            Self::Closed { msg, .. } => msg,
+        }
+    }
+}
+
+/// Cloneable sender feeding one connection, for [`WsSinkExt::into_shared_sender`](super::WsSinkExt::into_shared_sender)
+///
+/// Each clone has one more guaranteed slot in the queue: clone it per task, not per message.
+#[derive(Debug, Clone)]
+pub struct WsSender {
+    tx: mpsc::Sender<WsMessage>,
+    cause: Cause,
+}
+
+impl WsSender {
+    /// Queue a message, waiting if the queue is full
+    ///
+    /// Return [`SendError::Closed`] if the connection is closed (blocked senders are woken).
+    pub async fn send(&mut self, msg: WsMessage) -> Result<(), SendError> {
+        if future::poll_fn(|cx| self.tx.poll_ready(cx)).await.is_err() {
+            return Err(self.closed(msg));
+        }
+
+        // A slot is reserved: can only fail if closed in the meantime
+        let res = self.tx.try_send(msg);
+        res.map_err(|e| self.closed(e.into_inner()))
+    }
+
+    /// Queue a message without waiting
+    pub fn try_send(&mut self, msg: WsMessage) -> Result<(), SendError> {
+        let res = self.tx.try_send(msg);
+        res.map_err(|e| {
+            if e.is_full() {
+                SendError::Full(e.into_inner())
+            } else {
+                self.closed(e.into_inner())
+            }
+        })
+    }
+
+    /// Check if the connection is closed
+    #[inline]
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    fn closed(&self, msg: WsMessage) -> SendError {
+        let cause = self.cause.lock().expect("cause mutex poisoned").clone();
+        SendError::Closed { msg, cause }
+    }
+}
+
+/// Drain the [`WsSender`] queue into the sink
+#[derive(Debug)]
+#[must_use = "the driver must be run for the messages to be sent"]
+pub struct SharedSinkDriver<S> {
+    sink: S,
+    rx: mpsc::Receiver<WsMessage>,
+    cause: Cause,
+}
+
+impl<S, E> SharedSinkDriver<S>
+where
+    S: SinkTrait<WsMessage, Error = E> + Unpin,
+    E: Into<Error>,
+{
+    /// Run until all the senders are dropped (then the sink is closed) or the sink fails
+    pub async fn run(mut self) {
+        while let Some(msg) = self.rx.next().await {
+            if let Err(e) = self.sink.send(msg).await {
+                *self.cause.lock().expect("cause mutex poisoned") = Some(Arc::new(e.into()));
+                // Wake the blocked senders
+                self.rx.close();
+                return;
+            }
+        }
+
+        let _ = self.sink.close().await;
+    }
+}
+
+pub(super) fn new<S>(sink: S, buffer: usize) -> (WsSender, SharedSinkDriver<S>) {
+    let (tx, rx) = mpsc::channel(buffer);
+    let cause: Cause = Arc::new(Mutex::new(None));
+    (
+        WsSender {
+            tx,
+            cause: cause.clone(),
+        },
+        SharedSinkDriver { sink, rx, cause },
+    )
+}
+
+#[cfg(all(test, feature = "test-util", not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use crate::WsSinkExt;
+
+    #[tokio::test]
+    async fn test_shared_sender() {
+        let ((tx, _rx), (_server_tx, mut server_rx)) = test_util::pair().await.unwrap();
+        let (mut sender, driver) = tx.into_shared_sender(8);
+        let driver = tokio::spawn(driver.run());
+
+        let tasks: Vec<_> = (0..4)
+            .map(|i| {
+                let mut sender = sender.clone();
+                tokio::spawn(async move { sender.send(WsMessage::Text(i.to_string())).await })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+
+        let mut received: Vec<WsMessage> = Vec::new();
+        for _ in 0..4 {
+            received.push(server_rx.next().await.unwrap().unwrap());
+        }
+        assert_eq!(received.len(), 4);
+
+        // Aborting the driver wakes the senders
+        driver.abort();
+        let _ = driver.await;
+        let msg = WsMessage::Text(String::from("late"));
+        match sender.send(msg.clone()).await {
+            Err(SendError::Closed { msg: unsent, .. }) => assert_eq!(unsent, msg),
+            res => panic!("unexpected {res:?}"),
+        }
+        assert!(sender.is_closed());
+    }
+
+    #[tokio::test]
+    async fn test_shared_sender_backpressure() {
+        use futures_util::FutureExt;
+
+        let ((tx, _rx), _server) = test_util::pair().await.unwrap();
+        // Not driven: nothing leaves the queue
+        let (mut sender, _driver) = tx.into_shared_sender(2);
+
+        // The buffer, plus the slot of the sender
+        for i in 0..3 {
+            sender.try_send(WsMessage::Text(i.to_string())).unwrap();
+        }
+        assert!(matches!(
+            sender.try_send(WsMessage::Text(String::from("full"))),
+            Err(SendError::Full(..))
+        ));
+        assert!(sender
+            .send(WsMessage::Text(String::from("pending")))
+            .now_or_never()
+            .is_none());
+    }
+}
@@ -0,0 +1,66 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_core::{ready, Stream as StreamTrait};
+
+use crate::{Error, WsMessage};
+
+/// Future for [`WsStreamExt::take_until_close`](super::WsStreamExt::take_until_close)
+#[derive(Debug)]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct TakeUntilClose<S> {
+    stream: S,
+    messages: Vec<WsMessage>,
+    total_bytes: usize,
+    max_total_bytes: Option<usize>,
+}
+
+impl<S> TakeUntilClose<S> {
+    #[inline]
+    pub(super) fn new(stream: S, max_total_bytes: Option<usize>) -> Self {
+        Self {
+            stream,
+            messages: Vec::new(),
+            total_bytes: 0,
+            max_total_bytes,
+        }
+    }
+}
+
+impl<S, E> Future for TakeUntilClose<S>
+where
+    S: StreamTrait<Item = Result<WsMessage, E>> + Unpin,
+    E: Into<Error>,
+{
+    type Output = Result<Vec<WsMessage>, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        loop {
+            match ready!(Pin::new(&mut this.stream).poll_next(cx)) {
+                Some(Ok(msg)) => {
+                    if super::is_close(&msg) {
+                        return Poll::Ready(Ok(mem::take(&mut this.messages)));
+                    }
+
+                    this.total_bytes = this.total_bytes.saturating_add(msg.len());
+
+                    if let Some(limit) = this.max_total_bytes {
+                        if this.total_bytes > limit {
+                            return Poll::Ready(Err(Error::SizeLimitExceeded { limit }));
+                        }
+                    }
+
+                    this.messages.push(msg);
+                }
+                Some(Err(e)) => return Poll::Ready(Err(e.into())),
+                None => return Poll::Ready(Ok(mem::take(&mut this.messages))),
+            }
+        }
+    }
+}
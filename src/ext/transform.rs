@@ -0,0 +1,178 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::fmt;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_core::{ready, Stream as StreamTrait};
+use futures_sink::Sink as SinkTrait;
+
+use crate::{Error, WsMessage};
+
+/// Stream (and sink) for [`WsStreamExt::transform_binary`](super::WsStreamExt::transform_binary)
+///
+/// The payload of the received binary messages goes through `decode`, the one of the sent binary
+/// messages through `encode`. Text messages and control frames pass through unchanged.
+#[must_use = "streams do nothing unless polled"]
+pub struct TransformedStream<S, D, En> {
+    inner: S,
+    decode: D,
+    encode: En,
+}
+
+impl<S, D, En> TransformedStream<S, D, En> {
+    /// Wrap a stream and/or a sink of [`WsMessage`]
+    #[inline]
+    pub fn new(inner: S, decode: D, encode: En) -> Self {
+        Self {
+            inner,
+            decode,
+            encode,
+        }
+    }
+
+    /// Get the inner stream
+    #[inline]
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S, D, En> fmt::Debug for TransformedStream<S, D, En>
+where
+    S: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TransformedStream")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+// The transforms are called immediately, never pinned
+impl<S, D, En> Unpin for TransformedStream<S, D, En> where S: Unpin {}
+
+impl<S, D, En, E> StreamTrait for TransformedStream<S, D, En>
+where
+    S: StreamTrait<Item = Result<WsMessage, E>> + Unpin,
+    D: Fn(Vec<u8>) -> Result<Vec<u8>, Error>,
+    E: Into<Error>,
+{
+    type Item = Result<WsMessage, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        Poll::Ready(match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+            // The decode errors are yielded: the stream goes on
+            Some(Ok(WsMessage::Binary(data))) => Some((this.decode)(data).map(WsMessage::Binary)),
+            Some(Ok(msg)) => Some(Ok(msg)),
+            Some(Err(e)) => Some(Err(e.into())),
+            None => None,
+        })
+    }
+}
+
+impl<S, D, En, E> SinkTrait<WsMessage> for TransformedStream<S, D, En>
+where
+    S: SinkTrait<WsMessage, Error = E> + Unpin,
+    En: Fn(Vec<u8>) -> Result<Vec<u8>, Error>,
+    E: Into<Error>,
+{
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_ready(cx).map_err(Into::into)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: WsMessage) -> Result<(), Self::Error> {
+        let msg: WsMessage = match item {
+            WsMessage::Binary(data) => WsMessage::Binary((self.encode)(data)?),
+            msg => msg,
+        };
+        Pin::new(&mut self.inner)
+            .start_send(msg)
+            .map_err(Into::into)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx).map_err(Into::into)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx).map_err(Into::into)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use futures_util::{sink, stream, SinkExt, StreamExt};
+
+    use super::*;
+
+    fn xor(data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        Ok(data.into_iter().map(|b| b ^ 0xff).collect())
+    }
+
+    fn reject(_: Vec<u8>) -> Result<Vec<u8>, Error> {
+        Err(Error::UnexpectedBinaryFrame)
+    }
+
+    #[tokio::test]
+    async fn test_decode() {
+        let messages = stream::iter([
+            Ok::<_, Error>(WsMessage::Binary(vec![0x00, 0x0f])),
+            Ok(WsMessage::Text(String::from("a"))),
+        ]);
+        let out: Vec<WsMessage> = TransformedStream::new(messages, xor, xor)
+            .map(|msg| msg.unwrap())
+            .collect()
+            .await;
+        assert_eq!(
+            out,
+            vec![
+                WsMessage::Binary(vec![0xff, 0xf0]),
+                WsMessage::Text(String::from("a"))
+            ]
+        );
+
+        // The error is yielded, the stream goes on
+        let messages = stream::iter([
+            Ok::<_, Error>(WsMessage::Binary(vec![1])),
+            Ok(WsMessage::Text(String::from("a"))),
+        ]);
+        let mut rx = TransformedStream::new(messages, reject, xor);
+        assert!(matches!(
+            rx.next().await,
+            Some(Err(Error::UnexpectedBinaryFrame))
+        ));
+        assert_eq!(
+            rx.next().await.unwrap().unwrap(),
+            WsMessage::Text(String::from("a"))
+        );
+        assert!(rx.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_encode() {
+        let sent: Arc<Mutex<Vec<WsMessage>>> = Arc::default();
+        let sent2 = sent.clone();
+        let inner = Box::pin(sink::unfold((), move |(), msg: WsMessage| {
+            sent2.lock().unwrap().push(msg);
+            async { Ok::<_, Error>(()) }
+        }));
+
+        let mut tx = TransformedStream::new(inner, reject, xor);
+        tx.send(WsMessage::Binary(vec![0x00])).await.unwrap();
+        tx.send(WsMessage::Text(String::from("a"))).await.unwrap();
+        assert_eq!(
+            *sent.lock().unwrap(),
+            vec![
+                WsMessage::Binary(vec![0xff]),
+                WsMessage::Text(String::from("a"))
+            ]
+        );
+    }
+}
@@ -0,0 +1,256 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_core::{ready, Stream as StreamTrait};
+use futures_sink::Sink as SinkTrait;
+use thiserror::Error;
+
+use crate::{Error, WsMessage};
+
+/// Encode and decode the items of a [`TypedWsStream`]
+pub trait MessageCodec<Tx, Rx> {
+    /// Encode an outgoing item, choosing between a text and a binary message
+    fn encode(&self, item: &Tx) -> Result<WsMessage, Error>;
+
+    /// Decode an incoming text or binary message
+    fn decode(&self, msg: &WsMessage) -> Result<Rx, Error>;
+}
+
+/// JSON codec: items are sent as text messages, both text and binary messages are decoded
+#[cfg(feature = "serde-json")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+#[cfg(feature = "serde-json")]
+impl<Tx, Rx> MessageCodec<Tx, Rx> for JsonCodec
+where
+    Tx: serde::Serialize,
+    Rx: serde::de::DeserializeOwned,
+{
+    fn encode(&self, item: &Tx) -> Result<WsMessage, Error> {
+        Ok(WsMessage::Text(serde_json::to_string(item)?))
+    }
+
+    fn decode(&self, msg: &WsMessage) -> Result<Rx, Error> {
+        match msg {
+            WsMessage::Text(text) => Ok(serde_json::from_str(text)?),
+            WsMessage::Binary(data) => Ok(serde_json::from_slice(data)?),
+            #[cfg(not(target_arch = "wasm32"))]
+            _ => Err(Error::Unsupported("decoding control frames")),
+            #[cfg(target_arch = "wasm32")]
+            WsMessage::Close(..) => Err(Error::Closed),
+        }
+    }
+}
+
+/// Error yielded by a [`TypedWsStream`]
+#[derive(Debug, Error)]
+pub enum TypedError {
+    /// Connection error
+    #[error(transparent)]
+    Ws(#[from] Error),
+    /// The message can't be decoded. The stream isn't terminated.
+    #[error("can't decode message: {error}")]
+    Decode {
+        /// Codec error
+        error: Error,
+        /// The raw message
+        raw: WsMessage,
+    },
+}
+
+/// Typed duplex channel over one connection: a `Sink<Tx>` and a `Stream<Item = Result<Rx, TypedError>>`
+///
+/// Control frames are skipped. On native, the close frame is yielded as the terminal `Error::Closed`.
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct TypedWsStream<Tx, Rx, C, Si, St> {
+    sink: Si,
+    stream: St,
+    codec: C,
+    done: bool,
+    _marker: PhantomData<fn(Tx) -> Rx>,
+}
+
+impl<Tx, Rx, C, Si, St> TypedWsStream<Tx, Rx, C, Si, St> {
+    /// Wrap the two halves of a connection
+    #[inline]
+    pub fn new(sink: Si, stream: St, codec: C) -> Self {
+        Self {
+            sink,
+            stream,
+            codec,
+            done: false,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Get the inner sink and stream
+    #[inline]
+    pub fn into_inner(self) -> (Si, St) {
+        (self.sink, self.stream)
+    }
+}
+
+// Items are encoded/decoded immediately, never pinned
+impl<Tx, Rx, C, Si, St> Unpin for TypedWsStream<Tx, Rx, C, Si, St>
+where
+    Si: Unpin,
+    St: Unpin,
+{
+}
+
+impl<Tx, Rx, C, Si, St, E> SinkTrait<Tx> for TypedWsStream<Tx, Rx, C, Si, St>
+where
+    Si: SinkTrait<WsMessage, Error = E> + Unpin,
+    St: Unpin,
+    C: MessageCodec<Tx, Rx>,
+    E: Into<Error>,
+{
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.sink).poll_ready(cx).map_err(Into::into)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Tx) -> Result<(), Self::Error> {
+        let msg: WsMessage = self.codec.encode(&item)?;
+        Pin::new(&mut self.sink).start_send(msg).map_err(Into::into)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.sink).poll_flush(cx).map_err(Into::into)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.sink).poll_close(cx).map_err(Into::into)
+    }
+}
+
+impl<Tx, Rx, C, Si, St, E> StreamTrait for TypedWsStream<Tx, Rx, C, Si, St>
+where
+    Si: Unpin,
+    St: StreamTrait<Item = Result<WsMessage, E>> + Unpin,
+    C: MessageCodec<Tx, Rx>,
+    E: Into<Error>,
+{
+    type Item = Result<Rx, TypedError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        while !this.done {
+            match ready!(Pin::new(&mut this.stream).poll_next(cx)) {
+                Some(Ok(msg)) => match msg {
+                    WsMessage::Text(..) | WsMessage::Binary(..) => {
+                        return Poll::Ready(Some(match this.codec.decode(&msg) {
+                            Ok(item) => Ok(item),
+                            Err(error) => Err(TypedError::Decode { error, raw: msg }),
+                        }));
+                    }
+                    // The close frame is forwarded as the terminal error
+                    #[cfg(not(target_arch = "wasm32"))]
+                    WsMessage::Close(frame) => {
+                        this.done = true;
+                        return Poll::Ready(Some(Err(Error::Closed(frame).into())));
+                    }
+                    // Ping, pong or raw frame
+                    #[allow(unreachable_patterns)]
+                    _ => continue,
+                },
+                Some(Err(e)) => return Poll::Ready(Some(Err(TypedError::Ws(e.into())))),
+                None => this.done = true,
+            }
+        }
+
+        Poll::Ready(None)
+    }
+}
+
+#[cfg(all(
+    test,
+    feature = "serde-json",
+    feature = "test-util",
+    not(target_arch = "wasm32")
+))]
+mod tests {
+    use std::time::Duration;
+
+    use futures_util::{SinkExt, StreamExt};
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::native::{self, Message};
+    use crate::test_util::MockServer;
+    use crate::ConnectionMode;
+
+    #[derive(Debug, Serialize)]
+    struct Request {
+        id: u32,
+        method: String,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Response {
+        id: u32,
+        result: String,
+    }
+
+    #[tokio::test]
+    async fn test_typed_round_trip() {
+        let server = MockServer::builder()
+            .expect(|msg| {
+                msg.to_text()
+                    .map(|t| t.contains("\"ping\""))
+                    .unwrap_or(false)
+            })
+            .send("not json")
+            .send(r#"{"id":1,"result":"pong"}"#)
+            .close(1000, "")
+            .start()
+            .await
+            .unwrap();
+
+        let (tx, rx) = native::connect(
+            server.url(),
+            ConnectionMode::Direct,
+            Duration::from_secs(10),
+        )
+        .await
+        .unwrap();
+        let mut typed = TypedWsStream::<Request, Response, _, _, _>::new(tx, rx, JsonCodec);
+
+        typed
+            .send(Request {
+                id: 1,
+                method: String::from("ping"),
+            })
+            .await
+            .unwrap();
+
+        // A decode failure carries the raw message and doesn't end the stream
+        match typed.next().await {
+            Some(Err(TypedError::Decode { raw, .. })) => assert_eq!(raw, Message::text("not json")),
+            res => panic!("unexpected {res:?}"),
+        }
+
+        let response = typed.next().await.unwrap().unwrap();
+        assert_eq!(
+            response,
+            Response {
+                id: 1,
+                result: String::from("pong")
+            }
+        );
+
+        assert!(matches!(
+            typed.next().await,
+            Some(Err(TypedError::Ws(Error::Closed(..))))
+        ));
+        assert!(typed.next().await.is_none());
+        typed.flush().await.unwrap();
+    }
+}
@@ -0,0 +1,109 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_core::{ready, Stream as StreamTrait};
+
+use crate::{Error, WsMessage};
+
+/// Stream for [`WsStreamExt::window`](super::WsStreamExt::window)
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct Window<S> {
+    stream: S,
+    size: usize,
+    emit_partial: bool,
+    window: VecDeque<WsMessage>,
+    done: bool,
+}
+
+impl<S> Window<S> {
+    #[inline]
+    pub(super) fn new(stream: S, size: usize, emit_partial: bool) -> Self {
+        assert!(size > 0, "window size must be greater than 0");
+        Self {
+            stream,
+            size,
+            emit_partial,
+            window: VecDeque::with_capacity(size),
+            done: false,
+        }
+    }
+}
+
+impl<S, E> StreamTrait for Window<S>
+where
+    S: StreamTrait<Item = Result<WsMessage, E>> + Unpin,
+    E: Into<Error>,
+{
+    type Item = Result<Vec<WsMessage>, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        while !this.done {
+            match ready!(Pin::new(&mut this.stream).poll_next(cx)) {
+                Some(Ok(msg)) => {
+                    if super::is_close(&msg) {
+                        this.done = true;
+                        break;
+                    }
+
+                    if this.window.len() == this.size {
+                        this.window.pop_front();
+                    }
+
+                    this.window.push_back(msg);
+
+                    if this.emit_partial || this.window.len() == this.size {
+                        return Poll::Ready(Some(Ok(this.window.iter().cloned().collect())));
+                    }
+                }
+                Some(Err(e)) => return Poll::Ready(Some(Err(e.into()))),
+                None => this.done = true,
+            }
+        }
+
+        Poll::Ready(None)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use futures_util::{stream, StreamExt};
+
+    use super::*;
+
+    fn text(s: &str) -> WsMessage {
+        WsMessage::Text(s.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_window() {
+        let messages = || stream::iter(["a", "b", "c"].map(|s| Ok::<_, Error>(text(s))));
+
+        let windows: Vec<Vec<WsMessage>> = Window::new(messages(), 2, false)
+            .map(|w| w.unwrap())
+            .collect()
+            .await;
+        assert_eq!(
+            windows,
+            vec![vec![text("a"), text("b")], vec![text("b"), text("c")]]
+        );
+
+        let windows: Vec<Vec<WsMessage>> = Window::new(messages(), 2, true)
+            .map(|w| w.unwrap())
+            .collect()
+            .await;
+        assert_eq!(
+            windows,
+            vec![
+                vec![text("a")],
+                vec![text("a"), text("b")],
+                vec![text("b"), text("c")]
+            ]
+        );
+    }
+}
@@ -0,0 +1,246 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! JSON-RPC 2.0 client
+
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_core::Stream as StreamTrait;
+use futures_sink::Sink as SinkTrait;
+use futures_util::StreamExt;
+use serde_json::{json, Value};
+use thiserror::Error;
+
+use crate::rpc::{Correlator, RpcClient, RpcDriver, RpcError, UnmatchedStream};
+use crate::{Error, WsMessage};
+
+/// Default timeout of a call
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// [`JsonRpcClient`] error
+#[derive(Debug, Error)]
+pub enum JsonRpcError {
+    /// Transport error
+    #[error(transparent)]
+    Rpc(#[from] RpcError),
+    /// The server returned an error object
+    #[error("JSON-RPC error {code}: {message}")]
+    Remote {
+        /// Error code
+        code: i64,
+        /// Error message
+        message: String,
+        /// Additional data
+        data: Option<Value>,
+    },
+    /// The response is neither text, nor a valid JSON-RPC response
+    #[error("invalid JSON-RPC response")]
+    InvalidResponse,
+}
+
+/// Correlate the JSON-RPC requests and responses by their numeric `id`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonRpcCorrelator;
+
+impl Correlator for JsonRpcCorrelator {
+    type Id = u64;
+
+    fn request_id(&self, msg: &WsMessage) -> Option<u64> {
+        parse(msg)?.get("id")?.as_u64()
+    }
+
+    fn response_id(&self, msg: &WsMessage) -> Option<u64> {
+        let value: Value = parse(msg)?;
+        if value.get("result").is_none() && value.get("error").is_none() {
+            return None;
+        }
+        value.get("id")?.as_u64()
+    }
+}
+
+/// JSON-RPC 2.0 client
+///
+/// The request IDs are generated and the responses matched to their calls.
+/// The server notifications (without ID) are yielded by the [`Notifications`] stream.
+#[derive(Debug)]
+pub struct JsonRpcClient<Si> {
+    client: RpcClient<Si, JsonRpcCorrelator>,
+    next_id: AtomicU64,
+    timeout: Duration,
+}
+
+impl<Si> JsonRpcClient<Si> {
+    /// Wrap the two halves of a connection
+    ///
+    /// The [`RpcDriver`] must be polled (i.e. spawned) for the calls to complete.
+    pub fn new<St>(
+        sink: Si,
+        stream: St,
+    ) -> (Self, RpcDriver<St, JsonRpcCorrelator>, Notifications) {
+        let (client, driver, unmatched) = RpcClient::new(sink, stream, JsonRpcCorrelator);
+        let client = Self {
+            client,
+            next_id: AtomicU64::new(1),
+            timeout: DEFAULT_TIMEOUT,
+        };
+        (client, driver, Notifications { unmatched })
+    }
+
+    /// Set the timeout of every call (default: 30 secs)
+    #[inline]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+impl<Si, E> JsonRpcClient<Si>
+where
+    Si: SinkTrait<WsMessage, Error = E> + Unpin,
+    E: Into<Error>,
+{
+    /// Call a method and wait for its result
+    ///
+    /// With [`Value::Null`] params, the `params` member is omitted from the request.
+    pub async fn call<S>(&self, method: S, params: Value) -> Result<Value, JsonRpcError>
+    where
+        S: AsRef<str>,
+    {
+        let id: u64 = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut request = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method.as_ref(),
+        });
+
+        // Null isn't a valid value for `params`: omit it
+        if !params.is_null() {
+            request["params"] = params;
+        }
+
+        let res: WsMessage = self
+            .client
+            .call(WsMessage::Text(request.to_string()), self.timeout)
+            .await?;
+        let mut res: Value = parse(&res).ok_or(JsonRpcError::InvalidResponse)?;
+
+        if let Some(error) = res.get_mut("error") {
+            return Err(JsonRpcError::Remote {
+                code: error
+                    .get("code")
+                    .and_then(Value::as_i64)
+                    .ok_or(JsonRpcError::InvalidResponse)?,
+                message: error
+                    .get("message")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_string(),
+                data: error.get_mut("data").map(Value::take),
+            });
+        }
+
+        res.get_mut("result")
+            .map(Value::take)
+            .ok_or(JsonRpcError::InvalidResponse)
+    }
+}
+
+/// Server notifications: the JSON messages without `id`
+///
+/// The other unmatched messages (i.e. late responses) are discarded.
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct Notifications {
+    unmatched: UnmatchedStream,
+}
+
+impl StreamTrait for Notifications {
+    type Item = Value;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let msg: WsMessage = match futures_core::ready!(self.unmatched.poll_next_unpin(cx)) {
+                Some(msg) => msg,
+                None => return Poll::Ready(None),
+            };
+
+            if let Some(value) = parse(&msg) {
+                match value.get("id") {
+                    None | Some(Value::Null) => return Poll::Ready(Some(value)),
+                    Some(..) => continue,
+                }
+            }
+        }
+    }
+}
+
+fn parse(msg: &WsMessage) -> Option<Value> {
+    match msg {
+        WsMessage::Text(text) => serde_json::from_str(text).ok(),
+        _ => None,
+    }
+}
+
+#[cfg(all(test, feature = "test-util", not(target_arch = "wasm32")))]
+mod tests {
+    use futures_util::SinkExt;
+
+    use super::*;
+    use crate::test_util;
+
+    #[tokio::test]
+    async fn test_json_rpc_client() {
+        let ((client_tx, client_rx), (mut server_tx, mut server_rx)) =
+            test_util::pair().await.unwrap();
+
+        let (client, driver, mut notifications) = JsonRpcClient::new(client_tx, client_rx);
+        tokio::spawn(driver.run());
+
+        // Server: notify, then answer `echo` and fail anything else
+        tokio::spawn(async move {
+            let notification = json!({"jsonrpc": "2.0", "method": "tick", "params": [1]});
+            server_tx
+                .send(WsMessage::Text(notification.to_string()))
+                .await
+                .unwrap();
+
+            while let Some(Ok(WsMessage::Text(text))) = server_rx.next().await {
+                let req: Value = serde_json::from_str(&text).unwrap();
+                let res = match req["method"].as_str() {
+                    Some("echo") => {
+                        json!({"jsonrpc": "2.0", "id": req["id"], "result": req["params"]})
+                    }
+                    Some("has_params") => {
+                        json!({"jsonrpc": "2.0", "id": req["id"], "result": req.get("params").is_some()})
+                    }
+                    _ => {
+                        json!({"jsonrpc": "2.0", "id": req["id"], "error": {"code": -32601, "message": "Method not found"}})
+                    }
+                };
+                server_tx
+                    .send(WsMessage::Text(res.to_string()))
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let res = client.call("echo", json!(["hello"])).await.unwrap();
+        assert_eq!(res, json!(["hello"]));
+
+        let res = client.call("has_params", Value::Null).await.unwrap();
+        assert_eq!(res, json!(false));
+        let res = client.call("has_params", json!([])).await.unwrap();
+        assert_eq!(res, json!(true));
+
+        match client.call("unknown", Value::Null).await {
+            Err(JsonRpcError::Remote { code, .. }) => assert_eq!(code, -32601),
+            res => panic!("unexpected result: {res:?}"),
+        }
+
+        let notification = notifications.next().await.unwrap();
+        assert_eq!(notification["method"], "tick");
+    }
+}
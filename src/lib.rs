@@ -3,41 +3,157 @@
 
 //! Async WebSocket
 
-#![forbid(unsafe_code)]
+#![cfg_attr(not(target_os = "linux"), forbid(unsafe_code))]
+// Linux socket calls without a safe binding (TCP Fast Open, send queue), confined to `native::sys`
+#![cfg_attr(target_os = "linux", deny(unsafe_code))]
 #![cfg_attr(feature = "default", doc = include_str!("../README.md"))]
 
+#[cfg(all(feature = "socks", not(target_arch = "wasm32")))]
+use std::fmt;
 #[cfg(all(feature = "socks", not(target_arch = "wasm32")))]
 use std::net::SocketAddr;
 use std::time::Duration;
 
 pub use futures_util;
+/// The extension traits of the [`Sink`] and [`Stream`] halves
+#[cfg(feature = "futures-ext")]
+pub use futures_util::{SinkExt, StreamExt};
 pub use url::{self, Url};
 
+pub mod close_code;
+mod close_event;
+mod close_notifier;
+mod deflate;
+mod ext;
+#[cfg(feature = "serde-json")]
+pub mod jsonrpc;
+pub mod metrics;
+#[cfg(feature = "mux")]
+pub mod multiplex;
+#[cfg(feature = "mux")]
+pub mod mux;
 #[cfg(not(target_arch = "wasm32"))]
 pub mod native;
+mod pending;
+mod redact;
+mod request;
+#[cfg(feature = "mux")]
+mod route;
+mod rpc;
+mod shutdown;
+#[cfg(all(feature = "test-util", not(target_arch = "wasm32")))]
+pub mod test_util;
 #[cfg(target_arch = "wasm32")]
 pub mod wasm;
 
+pub use self::close_event::{CloseEvent, CloseEventBuilder, Initiator};
+pub use self::deflate::DeflateParams;
+#[cfg(feature = "dedup")]
+pub use self::ext::DeduplicatedStream;
+pub use self::ext::{
+    broadcast, flatten_message_streams, BinaryStream, ChainOnClose, FlattenMessageStreams,
+    MessageCodec, PeekableStream, RetryPolicy, RetryingSink, SendError, SharedSinkDriver,
+    SkippedMessages, TakeUntilClose, TextStream, TransformedStream, TypedError, TypedWsStream,
+    UnexpectedPolicy, Window, WsSender, WsSinkExt, WsStreamExt,
+};
+#[cfg(feature = "serde-json")]
+pub use self::ext::{JsonCodec, JsonLinesSink, NdjsonStream};
 #[cfg(not(target_arch = "wasm32"))]
 pub use self::native::{Error, Message as WsMessage, Sink, Stream};
+pub use self::pending::{connect_pending, PendingConnection};
+pub use self::redact::Redacted;
+pub use self::request::{request, request_keep_alive};
+pub use self::rpc::{Correlator, RpcClient, RpcDriver, RpcError, UnmatchedStream};
+pub use self::shutdown::{shutdown_all, ShutdownReport};
 #[cfg(target_arch = "wasm32")]
 pub use self::wasm::{Error, Sink, Stream, WsMessage};
 
-#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// SOCKS5 proxy address
+#[cfg(all(feature = "socks", not(target_arch = "wasm32")))]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ProxyAddr {
+    /// Socket address
+    Socket(SocketAddr),
+    /// Hostname and port, resolved at connect time
+    Host {
+        /// Hostname
+        host: String,
+        /// Port
+        port: u16,
+    },
+}
+
+#[cfg(all(feature = "socks", not(target_arch = "wasm32")))]
+impl From<SocketAddr> for ProxyAddr {
+    fn from(addr: SocketAddr) -> Self {
+        Self::Socket(addr)
+    }
+}
+
+#[cfg(all(feature = "socks", not(target_arch = "wasm32")))]
+impl<S> From<(S, u16)> for ProxyAddr
+where
+    S: Into<String>,
+{
+    fn from((host, port): (S, u16)) -> Self {
+        Self::Host {
+            host: host.into(),
+            port,
+        }
+    }
+}
+
+#[cfg(all(feature = "socks", not(target_arch = "wasm32")))]
+impl fmt::Display for ProxyAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Socket(addr) => write!(f, "{addr}"),
+            Self::Host { host, port } => write!(f, "{host}:{port}"),
+        }
+    }
+}
+
+/// How to reach the server
+///
+/// **Not `Copy`:** since the proxy address can be a hostname (see `ProxyAddr`), the mode must be
+/// cloned to be reused, also without the `socks` feature (a feature can't remove a trait impl).
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum ConnectionMode {
     /// Direct
     #[default]
     Direct,
     /// Custom proxy
     #[cfg(all(feature = "socks", not(target_arch = "wasm32")))]
-    Proxy(SocketAddr),
+    Proxy(ProxyAddr),
     /// Embedded tor client
     #[cfg(all(feature = "tor", not(target_arch = "wasm32")))]
     Tor,
 }
 
+#[cfg(all(feature = "socks", not(target_arch = "wasm32")))]
+impl ConnectionMode {
+    /// Connect through a SOCKS5 proxy
+    #[inline]
+    pub fn proxy<A>(addr: A) -> Self
+    where
+        A: Into<ProxyAddr>,
+    {
+        Self::Proxy(addr.into())
+    }
+}
+
+#[cfg(all(feature = "socks", not(target_arch = "wasm32")))]
+impl From<SocketAddr> for ConnectionMode {
+    fn from(addr: SocketAddr) -> Self {
+        Self::Proxy(ProxyAddr::Socket(addr))
+    }
+}
+
 /// Connect
 ///
+/// On native, a direct connection races the IPv6 and IPv4 addresses of the host (Happy Eyeballs,
+/// RFC 8305). In the browser, the address selection is up to the platform.
+///
 /// **Proxy is ignored for WASM targets!**
 pub async fn connect(
     url: &Url,
@@ -0,0 +1,271 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Connection metrics
+//!
+//! A [`MetricsSink`] is notified of how the connections end. It can be installed globally with
+//! [`set_global_sink`] or, on native, per connection with `ConnectOptions::metrics_sink`.
+//! [`MetricsHistogram`] aggregates the events in memory.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+#[cfg(not(target_arch = "wasm32"))]
+use tokio_tungstenite::tungstenite::Error as WsError;
+
+use crate::Error;
+
+static GLOBAL_SINK: Mutex<Option<Arc<dyn MetricsSink>>> = Mutex::new(None);
+
+/// Error kind
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// I/O or network error
+    Io,
+    /// TLS error
+    Tls,
+    /// The upgrade handshake failed or was rejected
+    Handshake,
+    /// WebSocket protocol violation
+    Protocol,
+    /// Deadline exceeded
+    Timeout,
+    /// Size limit exceeded
+    Capacity,
+    /// The connection is closed
+    Closed,
+    /// Any other error
+    Other,
+}
+
+impl ErrorKind {
+    /// Classify an [`Error`]
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn of(e: &Error) -> Self {
+        match e {
+            Error::IO(..)
+            | Error::NoAddressFamily { .. }
+            | Error::NoRoute
+            | Error::InvalidDNSName => Self::Io,
+            Error::TlsVersionUnsupported | Error::TlsHandshakeFailed { .. } => Self::Tls,
+            Error::HandshakeRejected { .. }
+            | Error::TooManyRedirects { .. }
+            | Error::RedirectLoop(..)
+            | Error::RedirectDowngrade(..)
+            | Error::AuthRedirectCrossOrigin(..)
+            | Error::InvalidToken
+            | Error::HostNotAllowed(..)
+            | Error::TlsRequired
+            | Error::ProtocolNotNegotiated
+            | Error::CompressionNotRequested => Self::Handshake,
+            Error::Timeout
+            | Error::DnsTimeout
+            | Error::ReadTimeout
+            | Error::RecvTimeout
+            | Error::WriteTimeout => Self::Timeout,
+            Error::SendBufferFull | Error::SizeLimitExceeded { .. } => Self::Capacity,
+            Error::Closed(..) => Self::Closed,
+            Error::Ws(e) => match e.as_ref() {
+                WsError::Io(..) => Self::Io,
+                WsError::Tls(..) => Self::Tls,
+                WsError::Http(..) | WsError::HttpFormat(..) | WsError::Url(..) => Self::Handshake,
+                WsError::Protocol(..) | WsError::Utf8 | WsError::AttackAttempt => Self::Protocol,
+                WsError::Capacity(..) | WsError::WriteBufferFull(..) => Self::Capacity,
+                WsError::ConnectionClosed | WsError::AlreadyClosed => Self::Closed,
+            },
+            #[cfg(feature = "socks")]
+            Error::Socks(..) | Error::ProxyResolve { .. } => Self::Io,
+            #[cfg(feature = "tor")]
+            Error::Tor(..) => Self::Io,
+            _ => Self::Other,
+        }
+    }
+
+    /// Classify an [`Error`]
+    #[cfg(target_arch = "wasm32")]
+    pub fn of(e: &Error) -> Self {
+        match e {
+            Error::Timeout => Self::Timeout,
+            Error::Closed => Self::Closed,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// Receiver of the connection metrics
+///
+/// The methods are called from the connection paths: they must be fast and must not block.
+pub trait MetricsSink: Send + Sync {
+    /// The connection terminated, with the close code received (if any)
+    /// and whether the close handshake completed.
+    fn on_close(&self, code: Option<u16>, clean: bool);
+
+    /// An error occurred, while connecting or on an established connection
+    fn on_error(&self, kind: ErrorKind);
+
+    /// A reconnection attempt started
+    ///
+    /// The crate doesn't reconnect on its own: call it from the reconnect logic.
+    fn on_reconnect(&self, attempt: u32);
+}
+
+/// Install the sink used by the connections without their own
+pub fn set_global_sink(sink: Arc<dyn MetricsSink>) {
+    *lock(&GLOBAL_SINK) = Some(sink);
+}
+
+/// Remove the global sink
+pub fn clear_global_sink() {
+    *lock(&GLOBAL_SINK) = None;
+}
+
+#[inline]
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Per-connection sink, falling back to the global one
+#[derive(Clone, Default)]
+pub(crate) struct Metrics {
+    sink: Option<Arc<dyn MetricsSink>>,
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Metrics")
+            .field("sink", &self.sink.as_ref().map(|_| ".."))
+            .finish()
+    }
+}
+
+impl Metrics {
+    #[inline]
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn new(sink: Arc<dyn MetricsSink>) -> Self {
+        Self { sink: Some(sink) }
+    }
+
+    fn sink(&self) -> Option<Arc<dyn MetricsSink>> {
+        match &self.sink {
+            Some(sink) => Some(sink.clone()),
+            None => lock(&GLOBAL_SINK).clone(),
+        }
+    }
+
+    pub(crate) fn on_close(&self, code: Option<u16>, clean: bool) {
+        if let Some(sink) = self.sink() {
+            sink.on_close(code, clean);
+        }
+    }
+
+    pub(crate) fn on_error(&self, kind: ErrorKind) {
+        if let Some(sink) = self.sink() {
+            sink.on_error(kind);
+        }
+    }
+}
+
+/// Snapshot of a [`MetricsHistogram`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Terminations per close code (`None` if no close frame was received)
+    pub closes: HashMap<Option<u16>, u64>,
+    /// Clean terminations
+    pub clean: u64,
+    /// Unclean terminations
+    pub unclean: u64,
+    /// Errors per kind
+    pub errors: HashMap<ErrorKind, u64>,
+    /// Reconnection attempts
+    pub reconnects: u64,
+}
+
+/// Built-in [`MetricsSink`] aggregating the events in memory
+#[derive(Debug, Default)]
+pub struct MetricsHistogram {
+    inner: Mutex<MetricsSnapshot>,
+}
+
+impl MetricsHistogram {
+    /// New empty histogram
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the current counts
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        lock(&self.inner).clone()
+    }
+
+    /// Reset all the counts
+    pub fn reset(&self) {
+        *lock(&self.inner) = MetricsSnapshot::default();
+    }
+}
+
+impl MetricsSink for MetricsHistogram {
+    fn on_close(&self, code: Option<u16>, clean: bool) {
+        let mut inner = lock(&self.inner);
+        *inner.closes.entry(code).or_default() += 1;
+        if clean {
+            inner.clean += 1;
+        } else {
+            inner.unclean += 1;
+        }
+    }
+
+    fn on_error(&self, kind: ErrorKind) {
+        *lock(&self.inner).errors.entry(kind).or_default() += 1;
+    }
+
+    fn on_reconnect(&self, _attempt: u32) {
+        lock(&self.inner).reconnects += 1;
+    }
+}
+
+#[cfg(all(test, feature = "test-util", not(target_arch = "wasm32")))]
+mod tests {
+    use std::time::Duration;
+
+    use futures_util::StreamExt;
+
+    use super::*;
+    use crate::native::{self, ConnectOptions};
+    use crate::test_util::MockServer;
+    use crate::ConnectionMode;
+
+    #[tokio::test]
+    async fn test_metrics_histogram() {
+        let histogram = Arc::new(MetricsHistogram::new());
+        let opts = ConnectOptions::new().metrics_sink(histogram.clone());
+        let timeout = Duration::from_secs(10);
+
+        let server = MockServer::builder()
+            .close(4001, "bye")
+            .start()
+            .await
+            .unwrap();
+        let (_tx, mut rx) =
+            native::connect_with_options(server.url(), ConnectionMode::Direct, timeout, &opts)
+                .await
+                .unwrap();
+        while rx.next().await.is_some() {}
+
+        let server = MockServer::builder()
+            .reject(503, [("Retry-After", "1")])
+            .start()
+            .await
+            .unwrap();
+        let res =
+            native::connect_with_options(server.url(), ConnectionMode::Direct, timeout, &opts)
+                .await;
+        assert!(res.is_err());
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.closes, HashMap::from([(Some(4001), 1)]));
+        assert_eq!((snapshot.clean, snapshot.unclean), (1, 0));
+        assert_eq!(snapshot.errors, HashMap::from([(ErrorKind::Handshake, 1)]));
+    }
+}
@@ -0,0 +1,211 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Fixed channel multiplexing over a single connection
+//!
+//! A lighter alternative to the [`mux`](crate::mux), for up to 256 channels known upfront.
+//! Both ends must use it, with the same number of channels. Every channel message is sent
+//! as a binary frame prefixed with the 1-byte channel ID.
+//!
+//! Text frames, empty binary frames and frames for channels out of range are ignored.
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex as SyncMutex};
+use std::task::{Context, Poll};
+
+use futures_channel::mpsc;
+use futures_core::Stream as StreamTrait;
+use futures_sink::Sink as SinkTrait;
+use futures_util::lock::Mutex;
+use futures_util::{SinkExt, StreamExt};
+
+use crate::route::{self, Codec, Kind, Router, Routes};
+use crate::{Error, WsMessage};
+
+/// Max number of channels
+pub const MAX_CHANNELS: usize = 256;
+
+/// Default number of messages queued for each channel
+const DEFAULT_CHANNEL_CAPACITY: usize = 64;
+
+/// 1-byte channel ID: the channels can't be closed
+#[derive(Debug, Clone, Copy)]
+struct ByteCodec;
+
+impl Codec for ByteCodec {
+    fn encode(&self, id: u32, _kind: Kind, payload: &[u8]) -> Vec<u8> {
+        let mut frame: Vec<u8> = Vec::with_capacity(1 + payload.len());
+        frame.push(id as u8);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn decode<'a>(&self, frame: &'a [u8]) -> Option<(u32, Kind, &'a [u8])> {
+        let (id, payload) = frame.split_first()?;
+        Some((u32::from(*id), Kind::Data, payload))
+    }
+}
+
+/// Split the connection into `n` channels
+///
+/// The [`MultiplexDriver`] reads the connection and routes the incoming frames:
+/// it must be polled (i.e. spawned) for the streams to receive anything.
+/// The stream of channel `i` is at index `i`.
+///
+/// # Panics
+///
+/// Panics if `n` is `0` or greater than [`MAX_CHANNELS`].
+pub fn multiplex<Si, St>(
+    sink: Si,
+    stream: St,
+    n: usize,
+) -> (
+    MultiplexedSink<Si>,
+    Vec<MultiplexedStream>,
+    MultiplexDriver<St>,
+) {
+    assert!(
+        n > 0 && n <= MAX_CHANNELS,
+        "the number of channels must be between 1 and {MAX_CHANNELS}"
+    );
+
+    let (senders, streams): (HashMap<_, _>, Vec<_>) = (0..n as u32)
+        .map(|id| {
+            let (tx, rx) = mpsc::channel(DEFAULT_CHANNEL_CAPACITY);
+            ((id, tx), MultiplexedStream { rx })
+        })
+        .unzip();
+    let routes: Routes = Arc::new(SyncMutex::new(senders));
+
+    let sink = MultiplexedSink {
+        sink: Arc::new(Mutex::new(sink)),
+        channels: n,
+    };
+    let driver = MultiplexDriver {
+        router: Router::new(stream, routes, ByteCodec),
+    };
+    (sink, streams, driver)
+}
+
+/// Sending half of all the channels
+///
+/// Cheap to clone: the clones share the connection.
+#[derive(Debug)]
+pub struct MultiplexedSink<Si> {
+    sink: Arc<Mutex<Si>>,
+    channels: usize,
+}
+
+impl<Si> Clone for MultiplexedSink<Si> {
+    fn clone(&self) -> Self {
+        Self {
+            sink: self.sink.clone(),
+            channels: self.channels,
+        }
+    }
+}
+
+impl<Si, E> MultiplexedSink<Si>
+where
+    Si: SinkTrait<WsMessage, Error = E> + Unpin,
+    E: Into<Error>,
+{
+    /// Number of channels
+    #[inline]
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// Send a message on a channel
+    ///
+    /// # Panics
+    ///
+    /// Panics if `channel` is out of range.
+    pub async fn send(&self, channel: u8, payload: &[u8]) -> Result<(), Error> {
+        assert!(
+            usize::from(channel) < self.channels,
+            "channel {channel} out of range"
+        );
+
+        route::send(
+            &self.sink,
+            &ByteCodec,
+            u32::from(channel),
+            Kind::Data,
+            payload,
+        )
+        .await
+    }
+
+    /// Close the connection: all the channel streams of both ends end
+    pub async fn close(&self) -> Result<(), Error> {
+        let mut sink = self.sink.lock().await;
+        sink.close().await.map_err(Into::into)
+    }
+}
+
+/// Receiving half of a channel
+///
+/// Ends when the connection terminates.
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct MultiplexedStream {
+    rx: mpsc::Receiver<Vec<u8>>,
+}
+
+impl StreamTrait for MultiplexedStream {
+    type Item = Vec<u8>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_next_unpin(cx)
+    }
+}
+
+/// Read the connection and route the incoming frames to the channels
+#[derive(Debug)]
+#[must_use = "the driver must be run for the channels to receive messages"]
+pub struct MultiplexDriver<St> {
+    router: Router<St, ByteCodec>,
+}
+
+impl<St, E> MultiplexDriver<St>
+where
+    St: StreamTrait<Item = Result<WsMessage, E>> + Unpin,
+    E: Into<Error>,
+{
+    /// Run until the connection terminates
+    ///
+    /// All the channel streams end when this returns.
+    #[inline]
+    pub async fn run(self) -> Result<(), Error> {
+        self.router.run().await
+    }
+}
+
+#[cfg(all(test, feature = "test-util", not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    #[tokio::test]
+    async fn test_multiplex() {
+        let ((client_tx, client_rx), (server_tx, server_rx)) = test_util::pair().await.unwrap();
+
+        let (client, _, client_driver) = multiplex(client_tx, client_rx, 2);
+        let (_server, mut server_streams, server_driver) = multiplex(server_tx, server_rx, 2);
+        tokio::spawn(client_driver.run());
+        let server_driver = tokio::spawn(server_driver.run());
+
+        client.send(1, b"to 1").await.unwrap();
+        client.send(0, b"to 0").await.unwrap();
+        assert_eq!(server_streams[0].next().await.unwrap(), b"to 0");
+        assert_eq!(server_streams[1].next().await.unwrap(), b"to 1");
+
+        // The streams end with the connection
+        client.close().await.unwrap();
+        server_driver.await.unwrap().unwrap();
+        assert!(server_streams[0].next().await.is_none());
+        assert!(server_streams[1].next().await.is_none());
+    }
+}
@@ -0,0 +1,233 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Lightweight channel multiplexing over a single connection
+//!
+//! Both ends must use the mux. Every channel message is sent as a binary frame:
+//!
+//! | Bytes  | Field                               |
+//! |--------|-------------------------------------|
+//! | 0..4   | Channel ID (big-endian `u32`)       |
+//! | 4      | Kind: `0` data, `1` close channel   |
+//! | 5..    | Payload (empty for close)           |
+//!
+//! Text frames and malformed binary frames are ignored, as well as frames for channels not opened locally.
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex as SyncMutex};
+use std::task::{Context, Poll};
+
+use futures_channel::mpsc;
+use futures_core::Stream as StreamTrait;
+use futures_sink::Sink as SinkTrait;
+use futures_util::lock::Mutex;
+use futures_util::StreamExt;
+
+use crate::route::{self, Codec, Kind, Router, Routes};
+use crate::{Error, WsMessage};
+
+const HEADER_LEN: usize = 5;
+const KIND_DATA: u8 = 0;
+const KIND_CLOSE: u8 = 1;
+
+/// Default number of messages queued for each channel
+const DEFAULT_CHANNEL_CAPACITY: usize = 64;
+
+/// 4-byte channel ID, then 1-byte kind
+#[derive(Debug, Clone, Copy)]
+struct MuxCodec;
+
+impl Codec for MuxCodec {
+    fn encode(&self, id: u32, kind: Kind, payload: &[u8]) -> Vec<u8> {
+        let kind: u8 = match kind {
+            Kind::Data => KIND_DATA,
+            Kind::Close => KIND_CLOSE,
+        };
+
+        let mut frame: Vec<u8> = Vec::with_capacity(HEADER_LEN + payload.len());
+        frame.extend_from_slice(&id.to_be_bytes());
+        frame.push(kind);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn decode<'a>(&self, frame: &'a [u8]) -> Option<(u32, Kind, &'a [u8])> {
+        if frame.len() < HEADER_LEN {
+            return None;
+        }
+
+        let id: u32 = u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]);
+        let kind: Kind = match frame[4] {
+            KIND_DATA => Kind::Data,
+            KIND_CLOSE => Kind::Close,
+            _ => return None,
+        };
+        Some((id, kind, &frame[HEADER_LEN..]))
+    }
+}
+
+/// Channel multiplexer
+#[derive(Debug)]
+pub struct WsMux<Si> {
+    sink: Arc<Mutex<Si>>,
+    routes: Routes,
+    capacity: usize,
+}
+
+impl<Si> WsMux<Si> {
+    /// Multiplex the connection
+    ///
+    /// The [`MuxDriver`] reads the connection and routes the incoming frames:
+    /// it must be polled (i.e. spawned) for the channels to receive anything.
+    pub fn new<St>(sink: Si, stream: St) -> (Self, MuxDriver<St>) {
+        let routes: Routes = Arc::new(SyncMutex::new(HashMap::new()));
+        let mux = Self {
+            sink: Arc::new(Mutex::new(sink)),
+            routes: routes.clone(),
+            capacity: DEFAULT_CHANNEL_CAPACITY,
+        };
+        (
+            mux,
+            MuxDriver {
+                router: Router::new(stream, routes, MuxCodec),
+            },
+        )
+    }
+
+    /// Set how many incoming messages are queued for each channel (default: 64)
+    ///
+    /// When a queue is full, the driver waits: a slow channel holds up the others.
+    #[inline]
+    pub fn channel_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Open a channel
+    ///
+    /// Return `None` if a channel with the same `id` is already open.
+    pub fn open_channel(&self, id: u32) -> Option<(ChannelSink<Si>, ChannelStream)> {
+        let mut routes = self.routes.lock().expect("routes mutex poisoned");
+
+        if let Some(tx) = routes.get(&id) {
+            // The ID can be reused once the previous stream is dropped
+            if !tx.is_closed() {
+                return None;
+            }
+        }
+
+        let (tx, rx) = mpsc::channel(self.capacity);
+        routes.insert(id, tx);
+
+        Some((
+            ChannelSink {
+                id,
+                sink: self.sink.clone(),
+            },
+            ChannelStream { rx },
+        ))
+    }
+}
+
+/// Sending half of a channel
+#[derive(Debug)]
+pub struct ChannelSink<Si> {
+    id: u32,
+    sink: Arc<Mutex<Si>>,
+}
+
+impl<Si, E> ChannelSink<Si>
+where
+    Si: SinkTrait<WsMessage, Error = E> + Unpin,
+    E: Into<Error>,
+{
+    /// Channel ID
+    #[inline]
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Send a message on the channel
+    pub async fn send(&self, payload: &[u8]) -> Result<(), Error> {
+        route::send(&self.sink, &MuxCodec, self.id, Kind::Data, payload).await
+    }
+
+    /// Close the channel: the peer's [`ChannelStream`] ends
+    pub async fn close(self) -> Result<(), Error> {
+        route::send(&self.sink, &MuxCodec, self.id, Kind::Close, &[]).await
+    }
+}
+
+/// Receiving half of a channel
+///
+/// Ends when the peer closes the channel or the connection terminates.
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct ChannelStream {
+    rx: mpsc::Receiver<Vec<u8>>,
+}
+
+impl StreamTrait for ChannelStream {
+    type Item = Vec<u8>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_next_unpin(cx)
+    }
+}
+
+/// Read the connection and route the incoming frames to the channels
+#[derive(Debug)]
+#[must_use = "the driver must be run for the channels to receive messages"]
+pub struct MuxDriver<St> {
+    router: Router<St, MuxCodec>,
+}
+
+impl<St, E> MuxDriver<St>
+where
+    St: StreamTrait<Item = Result<WsMessage, E>> + Unpin,
+    E: Into<Error>,
+{
+    /// Run until the connection terminates
+    ///
+    /// All the channel streams end when this returns.
+    #[inline]
+    pub async fn run(self) -> Result<(), Error> {
+        self.router.run().await
+    }
+}
+
+#[cfg(all(test, feature = "test-util", not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    #[tokio::test]
+    async fn test_mux() {
+        let ((client_tx, client_rx), (server_tx, server_rx)) = test_util::pair().await.unwrap();
+
+        let (client, client_driver) = WsMux::new(client_tx, client_rx);
+        let (server, server_driver) = WsMux::new(server_tx, server_rx);
+        tokio::spawn(client_driver.run());
+        tokio::spawn(server_driver.run());
+
+        let (client_a, _client_a_rx) = client.open_channel(1).unwrap();
+        let (client_b, _client_b_rx) = client.open_channel(2).unwrap();
+        assert!(client.open_channel(1).is_none());
+
+        let (_server_a, mut server_a_rx) = server.open_channel(1).unwrap();
+        let (_server_b, mut server_b_rx) = server.open_channel(2).unwrap();
+
+        client_b.send(b"to b").await.unwrap();
+        client_a.send(b"to a").await.unwrap();
+        assert_eq!(server_a_rx.next().await.unwrap(), b"to a");
+        assert_eq!(server_b_rx.next().await.unwrap(), b"to b");
+
+        // Per-channel close
+        client_a.close().await.unwrap();
+        assert!(server_a_rx.next().await.is_none());
+
+        client_b.send(b"still open").await.unwrap();
+        assert_eq!(server_b_rx.next().await.unwrap(), b"still open");
+    }
+}
@@ -0,0 +1,68 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Authentication
+
+use std::fmt;
+
+/// Credentials sent in the `Authorization` header of the handshake
+#[derive(Clone, PartialEq, Eq)]
+pub enum Credentials {
+    /// Basic auth
+    Basic {
+        /// Username
+        username: String,
+        /// Password
+        password: String,
+    },
+    /// Bearer token
+    Bearer(String),
+}
+
+// Never print the secrets
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Basic { username, .. } => f
+                .debug_struct("Basic")
+                .field("username", username)
+                .field("password", &"***")
+                .finish(),
+            Self::Bearer(..) => f.debug_tuple("Bearer").field(&"***").finish(),
+        }
+    }
+}
+
+impl Credentials {
+    /// Value of the `Authorization` header
+    pub(super) fn header_value(&self) -> String {
+        match self {
+            Self::Basic { username, password } => {
+                let userpass: String = format!("{username}:{password}");
+                format!(
+                    "Basic {}",
+                    data_encoding::BASE64.encode(userpass.as_bytes())
+                )
+            }
+            Self::Bearer(token) => format!("Bearer {token}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_value() {
+        let basic = Credentials::Basic {
+            username: String::from("Aladdin"),
+            password: String::from("open sesame"),
+        };
+        assert_eq!(basic.header_value(), "Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ==");
+
+        let bearer = Credentials::Bearer(String::from("abc"));
+        assert_eq!(bearer.header_value(), "Bearer abc");
+        assert_eq!(format!("{bearer:?}"), "Bearer(\"***\")");
+    }
+}
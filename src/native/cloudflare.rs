@@ -0,0 +1,133 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Cloudflare Tunnel
+
+use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use url::Url;
+
+use super::{request, Error};
+
+/// Domain of the quick tunnels
+const TRYCLOUDFLARE_DOMAIN: &str = "trycloudflare.com";
+
+const CLIENT_ID_HEADER: &str = "CF-Access-Client-Id";
+const CLIENT_SECRET_HEADER: &str = "CF-Access-Client-Secret";
+
+/// Split a `<client_id>:<client_secret>` service token
+fn parse_token(token: &str) -> Result<(&str, &str), Error> {
+    let (id, secret) = token.split_once(':').ok_or(Error::InvalidToken)?;
+
+    let valid =
+        |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_graphic() && b != b':');
+
+    if valid(id) && valid(secret) {
+        Ok((id, secret))
+    } else {
+        Err(Error::InvalidToken)
+    }
+}
+
+/// Check if the host is a subdomain of `trycloudflare.com` or of one of the `allowed_domains`
+fn check_host(url: &Url, allowed_domains: &[&str]) -> Result<(), Error> {
+    let host: &str = url.host_str().ok_or_else(Error::empty_host)?;
+
+    let allowed: bool = allowed_domains
+        .iter()
+        .copied()
+        .chain(std::iter::once(TRYCLOUDFLARE_DOMAIN))
+        .any(|domain| {
+            let domain: &str = domain.trim_start_matches('.');
+            host.len() > domain.len()
+                && host.ends_with(domain)
+                && host[..host.len() - domain.len()].ends_with('.')
+        });
+
+    if allowed {
+        Ok(())
+    } else {
+        Err(Error::HostNotAllowed(host.to_string()))
+    }
+}
+
+/// Build the handshake request, authenticated with the service `token`
+pub(super) fn request(url: &Url, token: &str, allowed_domains: &[&str]) -> Result<Request, Error> {
+    let (id, secret) = parse_token(token)?;
+
+    if url.scheme() != "wss" {
+        return Err(Error::TlsRequired);
+    }
+
+    check_host(url, allowed_domains)?;
+
+    let mut request: Request = request::from_url(url)?;
+    let headers = request.headers_mut();
+    headers.insert(CLIENT_ID_HEADER, header_value(id)?);
+    let mut secret: HeaderValue = header_value(secret)?;
+    secret.set_sensitive(true);
+    headers.insert(CLIENT_SECRET_HEADER, secret);
+    Ok(request)
+}
+
+#[inline]
+fn header_value(value: &str) -> Result<HeaderValue, Error> {
+    // The token parts are validated as visible ASCII
+    HeaderValue::from_str(value).map_err(|_| Error::InvalidToken)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_token() {
+        assert_eq!(
+            parse_token("abc.access:s3cr3t").unwrap(),
+            ("abc.access", "s3cr3t")
+        );
+        assert!(matches!(parse_token("abc"), Err(Error::InvalidToken)));
+        assert!(matches!(parse_token(":s3cr3t"), Err(Error::InvalidToken)));
+        assert!(matches!(
+            parse_token("abc:s3 cr3t"),
+            Err(Error::InvalidToken)
+        ));
+        assert!(matches!(parse_token("a:b:c"), Err(Error::InvalidToken)));
+    }
+
+    #[test]
+    fn test_check_host() {
+        let url = Url::parse("wss://random-words.trycloudflare.com/ws").unwrap();
+        assert!(check_host(&url, &[]).is_ok());
+
+        let url = Url::parse("wss://relay.example.com/ws").unwrap();
+        assert!(check_host(&url, &["example.com"]).is_ok());
+        assert!(matches!(
+            check_host(&url, &[]),
+            Err(Error::HostNotAllowed(host)) if host == "relay.example.com"
+        ));
+
+        // Not a subdomain
+        let url = Url::parse("wss://eviltrycloudflare.com/ws").unwrap();
+        assert!(check_host(&url, &[]).is_err());
+        let url = Url::parse("wss://example.com/ws").unwrap();
+        assert!(check_host(&url, &["example.com"]).is_err());
+    }
+
+    #[test]
+    fn test_request() {
+        let url = Url::parse("wss://tunnel.trycloudflare.com/ws").unwrap();
+        let request = request(&url, "abc.access:s3cr3t", &[]).unwrap();
+        let headers = request.headers();
+        assert_eq!(headers[CLIENT_ID_HEADER], "abc.access");
+        assert_eq!(headers[CLIENT_SECRET_HEADER], "s3cr3t");
+        assert!(headers[CLIENT_SECRET_HEADER].is_sensitive());
+    }
+
+    #[test]
+    fn test_request_requires_tls() {
+        let url = Url::parse("ws://tunnel.trycloudflare.com/ws").unwrap();
+        let res = request(&url, "abc.access:s3cr3t", &[]);
+        assert!(matches!(res, Err(Error::TlsRequired)));
+    }
+}
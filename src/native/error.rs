@@ -1,12 +1,21 @@
 // Copyright (c) 2022-2024 Yuki Kishimoto
 // Distributed under the MIT software license
 
+use std::time::Duration;
+
 use thiserror::Error;
+use tokio_tungstenite::tungstenite::error::ProtocolError;
+use tokio_tungstenite::tungstenite::http::{Response, StatusCode};
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
 use tokio_tungstenite::tungstenite::Error as WsError;
-use url::ParseError;
+use url::{ParseError, Url};
 
+use super::options::AddressFamily;
+use super::timeout::IoTimeout;
+use super::tls;
 #[cfg(feature = "tor")]
 use super::tor;
+use super::upgrade;
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -14,8 +23,10 @@ pub enum Error {
     #[error(transparent)]
     IO(#[from] std::io::Error),
     /// Ws error
+    ///
+    /// Boxed: it's way larger than the other variants.
     #[error(transparent)]
-    Ws(#[from] WsError),
+    Ws(Box<WsError>),
     /// Socks error
     #[cfg(feature = "socks")]
     #[error(transparent)]
@@ -30,20 +41,210 @@ pub enum Error {
     /// Timeout
     #[error("timeout")]
     Timeout,
+    /// No resolved address of the requested family
+    #[error("no address of the requested family: {family:?}")]
+    NoAddressFamily {
+        /// The requested family
+        family: AddressFamily,
+    },
+    /// The proxy hostname couldn't be resolved
+    #[cfg(feature = "socks")]
+    #[error("can't resolve proxy {proxy}: {source}")]
+    ProxyResolve {
+        /// Proxy address
+        proxy: String,
+        /// Resolution error
+        source: Box<Error>,
+    },
+    /// All the connection attempts failed
+    #[error("no route to host: all the connection attempts failed")]
+    NoRoute,
+    /// DNS lookup deadline exceeded
+    #[error("DNS lookup timeout")]
+    DnsTimeout,
+    /// Read deadline exceeded on the underlying transport
+    #[error("read timeout")]
+    ReadTimeout,
+    /// Read deadline set with [`Stream::set_read_deadline`](super::Stream::set_read_deadline) exceeded
+    #[error("receive deadline exceeded")]
+    RecvTimeout,
+    /// Write deadline exceeded on the underlying transport
+    #[error("write timeout")]
+    WriteTimeout,
+    /// The outgoing buffer is full
+    #[error("send buffer full")]
+    SendBufferFull,
+    /// Size limit exceeded
+    #[error("size limit exceeded: {limit} bytes")]
+    SizeLimitExceeded {
+        /// The configured limit
+        limit: usize,
+    },
+    /// No TLS protocol version satisfies the configured min/max versions
+    #[error("TLS protocol version unsupported")]
+    TlsVersionUnsupported,
+    /// TLS handshake failure (i.e. expired or untrusted certificate, name mismatch)
+    #[error("TLS handshake with {peer} failed: {details}")]
+    TlsHandshakeFailed {
+        /// Peer hostname
+        peer: String,
+        /// Failure description, including the certificate verification error
+        details: String,
+    },
+    /// JSON error
+    #[cfg(feature = "serde-json")]
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    /// Received a binary frame where a text frame was expected
+    #[error("unexpected binary frame")]
+    UnexpectedBinaryFrame,
+    /// Received a text frame where a binary frame was expected
+    #[error("unexpected text frame")]
+    UnexpectedTextFrame,
+    /// The peer closed the connection
+    #[error("connection closed")]
+    Closed(Option<CloseFrame<'static>>),
+    /// Feature not supported by the backend
+    #[error("{0} is not supported by the backend")]
+    Unsupported(&'static str),
+    /// The server rejected the upgrade with a non-redirect HTTP response
+    #[error("handshake rejected: {status}")]
+    HandshakeRejected {
+        /// Response status
+        status: StatusCode,
+        /// Wait requested by the `Retry-After` header
+        retry_after: Option<Duration>,
+        /// The whole response (i.e. to read the other headers or the body)
+        response: Box<Response<Option<Vec<u8>>>>,
+    },
+    /// Too many redirects on the upgrade path
+    #[error("too many redirects: limit is {limit}")]
+    TooManyRedirects {
+        /// The configured limit
+        limit: u8,
+    },
+    /// Redirect to another origin while sending credentials (the URL password is redacted)
+    #[error("redirect to another origin with credentials: {0}")]
+    AuthRedirectCrossOrigin(Url),
+    /// Redirect to an already visited URL (the URL password is redacted)
+    #[error("redirect loop: {0}")]
+    RedirectLoop(Url),
+    /// Redirect from `wss` to `ws`, that would send the request in clear (the URL password is redacted)
+    #[error("redirect downgrading to plain text: {0}")]
+    RedirectDowngrade(Url),
+    /// A ping with the same payload is already waiting for its pong
+    #[error("duplicate ping payload")]
+    DuplicatePing,
+    /// Too many pings waiting for their pong
+    #[error("too many outstanding pings: limit is {limit}")]
+    TooManyOutstandingPings {
+        /// The configured limit
+        limit: usize,
+    },
     /// Invalid DNS name
     #[error("invalid DNS name")]
     InvalidDNSName,
+    /// The server didn't select one of the requested sub-protocols
+    #[error("sub-protocol not negotiated")]
+    ProtocolNotNegotiated,
+    /// The server enabled `permessage-deflate` without being asked
+    #[error("compression not requested")]
+    CompressionNotRequested,
+    /// Invalid Cloudflare service token
+    #[error("invalid service token: expected <client_id>:<client_secret>")]
+    InvalidToken,
+    /// The host isn't in the allowed domains
+    #[error("host not allowed: {0}")]
+    HostNotAllowed(String),
+    /// The credentials can only be sent over TLS: the URL scheme must be `wss`
+    #[error("TLS required to send the credentials")]
+    TlsRequired,
+    /// The close code can't be sent (check [`close_code::is_sendable`](crate::close_code::is_sendable))
+    #[error("invalid close code: {supplied}")]
+    InvalidCloseCode {
+        /// The close code
+        supplied: u16,
+    },
+    /// The close reason is longer than [`MAX_REASON_LEN`](crate::close_code::MAX_REASON_LEN) bytes
+    #[error("close reason too long")]
+    CloseReasonTooLong,
+    /// Invalid connection parameter
+    #[error("invalid config: {reason}")]
+    InvalidConfig {
+        /// What's wrong
+        reason: &'static str,
+    },
+}
+
+impl From<WsError> for Error {
+    fn from(e: WsError) -> Self {
+        if let WsError::Io(io) = &e {
+            match IoTimeout::from_io_error(io) {
+                Some(IoTimeout::Read) => return Self::ReadTimeout,
+                Some(IoTimeout::Write) => return Self::WriteTimeout,
+                None => {}
+            }
+
+            // rustls errors are wrapped in I/O errors by the TLS stream
+            if let Some(tls) = io.get_ref().and_then(|e| e.downcast_ref()) {
+                if tls::is_version_mismatch(tls) {
+                    return Self::TlsVersionUnsupported;
+                }
+            }
+        }
+
+        // Redirects are kept as they are, to be followed
+        let e = match e {
+            WsError::Http(res) if !res.status().is_redirection() => {
+                return Self::HandshakeRejected {
+                    status: res.status(),
+                    retry_after: upgrade::retry_after(res.headers()),
+                    response: Box::new(res),
+                };
+            }
+            e => e,
+        };
+
+        if let WsError::WriteBufferFull(..) = e {
+            return Self::SendBufferFull;
+        }
+
+        if let WsError::Protocol(ProtocolError::SecWebSocketSubProtocolError(..)) = e {
+            return Self::ProtocolNotNegotiated;
+        }
+
+        Self::Ws(Box::new(e))
+    }
 }
 
 impl Error {
+    /// The tungstenite error, if any
+    #[inline]
+    pub(crate) fn as_ws(&self) -> Option<&WsError> {
+        match self {
+            Self::Ws(e) => Some(e),
+            _ => None,
+        }
+    }
+
+    /// The I/O error, either returned directly or by tungstenite
+    pub(crate) fn as_io(&self) -> Option<&std::io::Error> {
+        match self {
+            Self::IO(e) => Some(e),
+            Self::Ws(e) => match e.as_ref() {
+                WsError::Io(e) => Some(e),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
     #[inline]
-    #[cfg(any(feature = "socks", feature = "tor"))]
     pub(super) fn empty_host() -> Self {
         Self::Url(ParseError::EmptyHost)
     }
 
     #[inline]
-    #[cfg(any(feature = "socks", feature = "tor"))]
     pub(super) fn invalid_port() -> Self {
         Self::Url(ParseError::InvalidPort)
     }
@@ -0,0 +1,73 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! TCP Fast Open (RFC 7413)
+//!
+//! Linux only: the client side relies on `TCP_FASTOPEN_CONNECT` (Linux 4.11+). Once the server gave
+//! a cookie, the SYN is deferred to the first write, so the data goes out with it without changing
+//! how the socket is used. Until then, the connect sends a regular SYN, requesting the cookie.
+//! The other platforms either lack the option or require a dedicated send call (i.e. `sendto` with
+//! `MSG_FASTOPEN`, `connectx` on macOS), that tokio doesn't expose.
+
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::AsFd;
+
+use tokio::net::{TcpSocket, TcpStream};
+
+use super::sys;
+
+/// Open a connection to `addr`, enabling TCP Fast Open
+///
+/// With a cookie, the connection is established lazily: the SYN carries the first write (the HTTP
+/// upgrade request), so an unreachable address only fails there. If the kernel doesn't support it,
+/// a regular connection is made.
+pub(super) async fn connect(addr: SocketAddr) -> io::Result<TcpStream> {
+    let socket: TcpSocket = match addr {
+        SocketAddr::V4(..) => TcpSocket::new_v4()?,
+        SocketAddr::V6(..) => TcpSocket::new_v6()?,
+    };
+
+    if let Err(_e) = sys::set_tcp_fastopen_connect(socket.as_fd(), true) {
+        #[cfg(feature = "tracing")]
+        tracing::debug!("TCP Fast Open not available: {_e}");
+    }
+
+    socket.connect(addr).await
+}
+
+/// Check if the error is the deferred failure of the connection, rather than of the handshake
+pub(super) fn is_connect_error(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted => true,
+        _ => matches!(
+            e.raw_os_error(),
+            Some(libc::ENETUNREACH | libc::EHOSTUNREACH | libc::ETIMEDOUT)
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn fastopen_connect(conn: &TcpStream) -> bool {
+        sys::tcp_fastopen_connect(conn.as_fd()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_fastopen_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let conn = connect(listener.local_addr().unwrap()).await.unwrap();
+        assert!(fastopen_connect(&conn));
+
+        let conn = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        assert!(!fastopen_connect(&conn));
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Graceful transport shutdown
+
+use std::fmt;
+use std::io::{self, IoSlice};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_core::ready;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::runtime::Handle;
+
+/// Max time to deliver the TLS `close_notify` (or the TCP FIN) when the stream is dropped
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Shut down the transport when dropped, if not done yet
+///
+/// tungstenite never shuts down the transport: without this, the TLS session is torn down
+/// without a `close_notify`. The shutdown runs in a background task, bounded by [`SHUTDOWN_TIMEOUT`],
+/// so dropping never blocks.
+pub struct GracefulShutdown<S> {
+    // Taken on drop only
+    inner: Option<S>,
+    shutdown: bool,
+    // Set by `new`, where the bounds are known: the struct has none, to keep the futures holding it `Send`
+    spawn_shutdown: fn(S),
+}
+
+impl<S> GracefulShutdown<S>
+where
+    S: AsyncWrite + Send + Unpin + 'static,
+{
+    #[inline]
+    pub(crate) fn new(inner: S) -> Self {
+        Self {
+            inner: Some(inner),
+            shutdown: false,
+            spawn_shutdown,
+        }
+    }
+}
+
+impl<S> GracefulShutdown<S> {
+    #[inline]
+    pub(crate) fn get_ref(&self) -> &S {
+        self.inner.as_ref().expect("stream taken on drop only")
+    }
+
+    #[inline]
+    fn inner(&mut self) -> Pin<&mut S>
+    where
+        S: Unpin,
+    {
+        Pin::new(self.inner.as_mut().expect("stream taken on drop only"))
+    }
+}
+
+impl<S> fmt::Debug for GracefulShutdown<S>
+where
+    S: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GracefulShutdown")
+            .field("inner", &self.inner)
+            .field("shutdown", &self.shutdown)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S> Drop for GracefulShutdown<S> {
+    fn drop(&mut self) {
+        if self.shutdown {
+            return;
+        }
+
+        if let Some(inner) = self.inner.take() {
+            (self.spawn_shutdown)(inner);
+        }
+    }
+}
+
+fn spawn_shutdown<S>(mut inner: S)
+where
+    S: AsyncWrite + Send + Unpin + 'static,
+{
+    // Outside of a runtime the transport is just closed
+    if let Ok(handle) = Handle::try_current() {
+        handle.spawn(async move {
+            let _ = tokio::time::timeout(SHUTDOWN_TIMEOUT, inner.shutdown()).await;
+        });
+    }
+}
+
+impl<S> AsyncRead for GracefulShutdown<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.inner().poll_read(cx, buf)
+    }
+}
+
+impl<S> AsyncWrite for GracefulShutdown<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.inner().poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        self.inner().poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        matches!(&self.inner, Some(inner) if inner.is_write_vectored())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner().poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let res = ready!(self.inner().poll_shutdown(cx));
+        self.shutdown = true;
+        Poll::Ready(res)
+    }
+}
@@ -0,0 +1,305 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Deterministic masking, for the byte-exact tests
+
+use std::io::{self, IoSlice};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_core::ready;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+const HTTP_END: &[u8; 4] = b"\r\n\r\n";
+
+/// Stream re-masking the outgoing frames with keys drawn from a seed (if any)
+///
+/// tungstenite always masks with a secure random key: this sits right beneath it and replaces
+/// each key (and re-masks the payload accordingly), so the same frames give the same bytes
+/// on the wire. The handshake request is left untouched.
+#[derive(Debug)]
+pub struct SeededMask<S> {
+    inner: S,
+    framing: Option<Framing>,
+}
+
+impl<S> SeededMask<S> {
+    #[inline]
+    pub(crate) fn new(inner: S, seed: Option<u64>) -> Self {
+        Self {
+            inner,
+            framing: seed.map(Framing::new),
+        }
+    }
+
+    /// Same as [`SeededMask::new`], for a transport already past the handshake
+    /// (i.e. upgraded by an HTTP client): the first byte starts a frame.
+    #[inline]
+    pub(crate) fn upgraded(inner: S, seed: Option<u64>) -> Self {
+        Self {
+            inner,
+            framing: seed.map(|seed| Framing {
+                http_end: HTTP_END.len(),
+                ..Framing::new(seed)
+            }),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S> AsyncRead for SeededMask<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S> AsyncWrite for SeededMask<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let framing: &mut Framing = match &mut this.framing {
+            Some(framing) => framing,
+            None => return Pin::new(&mut this.inner).poll_write(cx, buf),
+        };
+
+        // The length is preserved: rewrite a copy, then advance only over what was written
+        let mut ahead: Framing = *framing;
+        let out: Vec<u8> = buf.iter().map(|b| ahead.rewrite(*b)).collect();
+        let n: usize = ready!(Pin::new(&mut this.inner).poll_write(cx, &out))?;
+        for b in &buf[..n] {
+            framing.rewrite(*b);
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        if self.framing.is_some() {
+            let buf: &[u8] = bufs
+                .iter()
+                .find(|b| !b.is_empty())
+                .map_or(&[][..], |b| &**b);
+            return self.poll_write(cx, buf);
+        }
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.framing.is_none() && self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Position in the outgoing byte stream
+#[derive(Debug, Clone, Copy)]
+struct Framing {
+    /// Bytes of the end of the handshake request matched so far
+    http_end: usize,
+    /// Bytes of the current frame header seen so far
+    header_pos: usize,
+    /// Bytes of the extended payload length
+    ext_len: usize,
+    masked: bool,
+    payload_len: u64,
+    payload_pos: u64,
+    key: [u8; 4],
+    new_key: [u8; 4],
+    /// xorshift64 state
+    rng: u64,
+}
+
+impl Framing {
+    fn new(seed: u64) -> Self {
+        Self {
+            http_end: 0,
+            header_pos: 0,
+            ext_len: 0,
+            masked: false,
+            payload_len: 0,
+            payload_pos: 0,
+            key: [0; 4],
+            new_key: [0; 4],
+            // xorshift gets stuck on zero
+            rng: seed.max(1),
+        }
+    }
+
+    fn next_key(&mut self) -> [u8; 4] {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng as u32).to_be_bytes()
+    }
+
+    #[inline]
+    fn header_len(&self) -> usize {
+        2 + self.ext_len + if self.masked { 4 } else { 0 }
+    }
+
+    /// Rewrite the next outgoing byte
+    fn rewrite(&mut self, b: u8) -> u8 {
+        if self.http_end < HTTP_END.len() {
+            self.http_end = if b == HTTP_END[self.http_end] {
+                self.http_end + 1
+            } else {
+                usize::from(b == HTTP_END[0])
+            };
+            return b;
+        }
+
+        let out: u8 = if self.header_pos < 2 || self.header_pos < self.header_len() {
+            let out: u8 = self.rewrite_header(b);
+            self.header_pos += 1;
+            out
+        } else {
+            let i: usize = (self.payload_pos % 4) as usize;
+            self.payload_pos += 1;
+            if self.masked {
+                b ^ self.key[i] ^ self.new_key[i]
+            } else {
+                b
+            }
+        };
+
+        // Frame complete: the next byte starts a new header
+        if self.header_pos >= 2
+            && self.header_pos == self.header_len()
+            && self.payload_pos == self.payload_len
+        {
+            self.header_pos = 0;
+            self.ext_len = 0;
+            self.masked = false;
+            self.payload_len = 0;
+            self.payload_pos = 0;
+        }
+
+        out
+    }
+
+    fn rewrite_header(&mut self, b: u8) -> u8 {
+        let mask_start: usize = 2 + self.ext_len;
+        match self.header_pos {
+            0 => b,
+            1 => {
+                self.masked = b & 0x80 != 0;
+                match b & 0x7f {
+                    126 => self.ext_len = 2,
+                    127 => self.ext_len = 8,
+                    len => self.payload_len = u64::from(len),
+                }
+                b
+            }
+            pos if pos < mask_start => {
+                self.payload_len = (self.payload_len << 8) | u64::from(b);
+                b
+            }
+            pos => {
+                if pos == mask_start {
+                    self.new_key = self.next_key();
+                }
+                self.key[pos - mask_start] = b;
+                self.new_key[pos - mask_start]
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use tokio::io::AsyncWriteExt;
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::{Data, OpCode};
+    use tokio_tungstenite::tungstenite::protocol::frame::{Frame, FrameHeader};
+
+    use super::*;
+
+    const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+    /// The handshake request, then two masked frames (the second with an extended length)
+    fn wire(keys: [[u8; 4]; 2]) -> Vec<u8> {
+        let mut wire: Vec<u8> = REQUEST.to_vec();
+        for (key, len) in keys.into_iter().zip([5, 300]) {
+            let mut frame = Frame::message(vec![7; len], OpCode::Data(Data::Binary), true);
+            frame.header_mut().mask = Some(key);
+            frame.format(&mut wire).unwrap();
+        }
+        wire
+    }
+
+    #[tokio::test]
+    async fn test_seeded_mask() {
+        // Written at once
+        let mut a = SeededMask::new(Vec::new(), Some(42));
+        a.write_all(&wire([[1, 2, 3, 4], [5, 6, 7, 8]]))
+            .await
+            .unwrap();
+
+        // Other keys, written a byte at a time
+        let mut b = SeededMask::new(Vec::new(), Some(42));
+        for byte in wire([[9, 9, 9, 9], [0, 1, 0, 1]]) {
+            b.write_all(&[byte]).await.unwrap();
+        }
+
+        assert_eq!(a.inner, b.inner);
+        assert!(a.inner.starts_with(REQUEST));
+
+        // Still valid frames, with the same payload
+        let mut cursor = Cursor::new(&a.inner[REQUEST.len()..]);
+        for len in [5, 300] {
+            let (header, payload_len) = FrameHeader::parse(&mut cursor).unwrap().unwrap();
+            assert_eq!(payload_len, len);
+
+            let key: [u8; 4] = header.mask.unwrap();
+            let start: usize = cursor.position() as usize;
+            let payload: Vec<u8> = cursor.get_ref()[start..start + len as usize]
+                .iter()
+                .enumerate()
+                .map(|(i, b)| b ^ key[i % 4])
+                .collect();
+            assert_eq!(payload, vec![7; len as usize]);
+            cursor.set_position((start + len as usize) as u64);
+        }
+
+        // Another seed, other keys
+        let mut c = SeededMask::new(Vec::new(), Some(43));
+        c.write_all(&wire([[1, 2, 3, 4], [5, 6, 7, 8]]))
+            .await
+            .unwrap();
+        assert_ne!(a.inner, c.inner);
+
+        // Already upgraded: the same frames, without the handshake request
+        let mut d = SeededMask::upgraded(Vec::new(), Some(42));
+        d.write_all(&wire([[1, 2, 3, 4], [5, 6, 7, 8]])[REQUEST.len()..])
+            .await
+            .unwrap();
+        assert_eq!(d.inner, &a.inner[REQUEST.len()..]);
+    }
+}
@@ -2,105 +2,1447 @@
 // Distributed under the MIT software license
 
 //! Native
+//!
+//! Every timer (connect timeout, I/O deadlines, shutdown deadline) is driven by [`tokio::time`],
+//! so it honors a paused clock ([`tokio::time::pause`] and [`tokio::time::advance`]) in tests.
 
-#[cfg(feature = "socks")]
 use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::time::Duration;
 
 #[cfg(feature = "tor")]
 use arti_client::DataStream;
 use async_utility::time;
+use futures_util::future::{self, Either};
 use futures_util::StreamExt;
-#[cfg(feature = "socks")]
-use tokio::net::TcpStream;
-pub use tokio_tungstenite::tungstenite::Message;
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::net::{self, TcpStream};
+use tokio::time::Instant;
+pub use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_tungstenite::tungstenite::client::uri_mode;
+use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::stream::Mode;
+pub use tokio_tungstenite::tungstenite::{http, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 use url::Url;
 
+mod auth;
+mod cloudflare;
 mod error;
+#[cfg(target_os = "linux")]
+mod fastopen;
+mod graceful;
+#[cfg(feature = "test-util")]
+mod mask;
+mod observe;
+mod options;
+mod ping;
+mod pool;
+mod priority;
+mod redirect;
+mod request;
+#[cfg(target_os = "linux")]
+mod sendq;
 #[cfg(feature = "socks")]
 mod socks;
 mod stream;
+#[cfg(target_os = "linux")]
+mod sys;
+mod timeout;
+mod tls;
 #[cfg(feature = "tor")]
 mod tor;
+#[cfg(feature = "tracing")]
+mod traffic;
+mod tunnel;
+mod upgrade;
 
+pub use self::auth::Credentials;
 pub use self::error::Error;
+use self::observe::ObservedStream;
+pub use self::options::{
+    AddressFamily, ConnectOptions, ReadHalfDropPolicy, ResolvedOptions, SizeLimits, UrlRewriter,
+};
+pub use self::ping::PingTicket;
+use self::ping::PingTracker;
+pub use self::pool::{PoolOptions, PoolSender, PooledConnection, WsPool};
+use self::priority::{CloseRequest, PrioritySink, SharedSink};
+#[cfg(target_os = "linux")]
+use self::sendq::SendQueue;
 #[cfg(feature = "socks")]
 use self::socks::TcpSocks5Stream;
-use self::stream::WebSocket;
-pub use self::stream::{Sink, Stream};
-use crate::ConnectionMode;
+use self::stream::{BoxedTransport, WebSocket, WsStream};
+pub use self::stream::{Sink, Stream, Transport};
+use self::timeout::TimeoutStream;
+pub use self::tls::TlsVersion;
+use self::tunnel::Tunnel;
+use self::upgrade::Negotiated;
+use crate::metrics::ErrorKind;
+#[cfg(feature = "socks")]
+use crate::ProxyAddr;
+use crate::{redact, ConnectionMode};
 
+/// Connect with the default [`ConnectOptions`]
+///
+/// With [`ConnectionMode::Direct`], the IPv6 and IPv4 addresses of the host are raced following
+/// Happy Eyeballs (RFC 8305), with a 250 ms delay: check [`ConnectOptions::happy_eyeballs`]
+/// to tune it or to try the addresses in order instead.
 pub async fn connect(
     url: &Url,
     mode: ConnectionMode,
     timeout: Duration,
 ) -> Result<(Sink, Stream), Error> {
-    let stream: WebSocket = match mode {
-        ConnectionMode::Direct => connect_direct(url, timeout).await?,
+    connect_with_options(url, mode, timeout, &ConnectOptions::default()).await
+}
+
+/// Connect with per-read and per-write deadlines on the underlying transport
+///
+/// These are **TCP-level** timeouts, not WebSocket-level ones.
+/// Check [`ConnectOptions::read_timeout`] and [`ConnectOptions::write_timeout`] for more details.
+pub async fn connect_with_io_timeout(
+    url: &Url,
+    mode: ConnectionMode,
+    timeout: Duration,
+    read_timeout: Duration,
+    write_timeout: Duration,
+) -> Result<(Sink, Stream), Error> {
+    let opts = ConnectOptions::new()
+        .read_timeout(Some(read_timeout))
+        .write_timeout(Some(write_timeout));
+    connect_with_options(url, mode, timeout, &opts).await
+}
+
+/// Connect with a max size for the single frames of a (fragmented) message
+///
+/// A larger incoming frame is rejected before being buffered.
+/// Returns [`Error::InvalidConfig`] if `max_frame` is zero.
+/// Check [`ConnectOptions::max_frame_size`] for more details.
+pub async fn connect_with_max_frame_size(
+    url: &Url,
+    mode: ConnectionMode,
+    timeout: Duration,
+    max_frame: usize,
+) -> Result<(Sink, Stream), Error> {
+    let opts = ConnectOptions::new().max_frame_size(Some(max_frame));
+    connect_with_options(url, mode, timeout, &opts).await
+}
+
+/// Connect with a deadline for the DNS lookup of the host
+///
+/// Check [`ConnectOptions::dns_timeout`] for more details.
+pub async fn connect_with_resolver_timeout(
+    url: &Url,
+    mode: ConnectionMode,
+    timeout: Duration,
+    dns_timeout: Duration,
+) -> Result<(Sink, Stream), Error> {
+    let opts = ConnectOptions::new().dns_timeout(Some(dns_timeout));
+    connect_with_options(url, mode, timeout, &opts).await
+}
+
+/// Connect to the IPv4 addresses of the host only
+///
+/// Unlike [`connect`], which races the IPv6 and IPv4 addresses (Happy Eyeballs), the IPv6 ones are discarded.
+/// Check [`ConnectOptions::address_family`] for more details.
+pub async fn connect_ipv4_only(
+    url: &Url,
+    mode: ConnectionMode,
+    timeout: Duration,
+) -> Result<(Sink, Stream), Error> {
+    let opts = ConnectOptions::new().address_family(AddressFamily::Ipv4);
+    connect_with_options(url, mode, timeout, &opts).await
+}
+
+/// Connect to the IPv6 addresses of the host only
+///
+/// Unlike [`connect`], which races the IPv6 and IPv4 addresses (Happy Eyeballs), the IPv4 ones are discarded.
+/// Check [`ConnectOptions::address_family`] for more details.
+pub async fn connect_ipv6_only(
+    url: &Url,
+    mode: ConnectionMode,
+    timeout: Duration,
+) -> Result<(Sink, Stream), Error> {
+    let opts = ConnectOptions::new().address_family(AddressFamily::Ipv6);
+    connect_with_options(url, mode, timeout, &opts).await
+}
+
+/// Connect racing the IPv6 and IPv4 addresses of the host (Happy Eyeballs) with a custom `delay`
+///
+/// [`connect`] already does it, with a 250 ms delay. Check [`ConnectOptions::happy_eyeballs`] for more details.
+pub async fn connect_with_happy_eyeballs(
+    url: &Url,
+    mode: ConnectionMode,
+    timeout: Duration,
+    delay: Duration,
+) -> Result<(Sink, Stream), Error> {
+    let opts = ConnectOptions::new().happy_eyeballs(Some(delay));
+    connect_with_options(url, mode, timeout, &opts).await
+}
+
+/// Connect with TCP Fast Open, sending the HTTP upgrade request with the SYN
+///
+/// Linux only. Check [`ConnectOptions::tcp_fastopen`] for more details.
+#[cfg(target_os = "linux")]
+pub async fn connect_with_tcp_fastopen(
+    url: &Url,
+    mode: ConnectionMode,
+    timeout: Duration,
+) -> Result<(Sink, Stream), Error> {
+    let opts = ConnectOptions::new().tcp_fastopen(true);
+    connect_with_options(url, mode, timeout, &opts).await
+}
+
+/// Connect with custom [`ConnectOptions`]
+pub async fn connect_with_options(
+    url: &Url,
+    mode: ConnectionMode,
+    timeout: Duration,
+    opts: &ConnectOptions,
+) -> Result<(Sink, Stream), Error> {
+    let url: Url = rewrite(url, opts);
+    let request: Request = request::from_url(&url)?;
+    connect_request(&url, request, mode, timeout, opts).await
+}
+
+/// Connect with a full handshake [`http::Request`] (i.e. custom headers or auth schemes)
+///
+/// The mandatory WebSocket headers (`Host`, `Connection`, `Upgrade`, `Sec-WebSocket-Version`
+/// and `Sec-WebSocket-Key`) are filled in, overriding the provided ones.
+/// The request URI must have a `ws` or `wss` scheme and a host.
+pub async fn connect_with_request(
+    request: http::Request<()>,
+    mode: ConnectionMode,
+    timeout: Duration,
+    opts: &ConnectOptions,
+) -> Result<(Sink, Stream), Error> {
+    let (url, request) = request::prepare(request)?;
+    let (url, request): (Url, Request) = match opts.url_rewriter.rewrite(&url) {
+        Some(rewritten) => {
+            let request: Request = request::retarget(request, &rewritten)?;
+            (rewritten, request)
+        }
+        None => (url, request),
+    };
+    connect_request(&url, request, mode, timeout, opts).await
+}
+
+/// Apply the [`ConnectOptions::url_rewriter`], if any
+///
+/// Done at the entry points, so the validations (i.e. the allowed hosts) see the URL actually
+/// connected to.
+fn rewrite(url: &Url, opts: &ConnectOptions) -> Url {
+    opts.url_rewriter
+        .rewrite(url)
+        .unwrap_or_else(|| url.clone())
+}
+
+async fn connect_request(
+    url: &Url,
+    request: Request,
+    mode: ConnectionMode,
+    timeout: Duration,
+    opts: &ConnectOptions,
+) -> Result<(Sink, Stream), Error> {
+    opts.validate()?;
+
+    if opts.deflate_dictionary.is_some() {
+        return Err(Error::Unsupported("permessage-deflate preset dictionary"));
+    }
+
+    let res: Result<(WebSocket, Negotiated), Error> = match mode {
+        ConnectionMode::Direct => connect_direct(url, request, timeout, opts).await,
         #[cfg(feature = "socks")]
-        ConnectionMode::Proxy(proxy) => connect_proxy(url, proxy, timeout).await?,
+        ConnectionMode::Proxy(proxy) => connect_proxy(url, request, proxy, timeout, opts).await,
         #[cfg(feature = "tor")]
-        ConnectionMode::Tor => connect_tor(url, timeout).await?,
+        ConnectionMode::Tor => connect_tor(url, request, timeout, opts).await,
     };
 
+    match res {
+        Ok((stream, negotiated)) => Ok(split(stream, Some(url.clone()), negotiated, opts)),
+        Err(e) => {
+            opts.metrics.on_error(ErrorKind::of(&e));
+            Err(e)
+        }
+    }
+}
+
+/// Connect through a Cloudflare Tunnel, authenticated with a service `token`
+///
+/// The `token` has the `<client_id>:<client_secret>` format: the parts are sent in the
+/// `CF-Access-Client-Id` and `CF-Access-Client-Secret` headers ([`Error::InvalidToken`] otherwise).
+///
+/// To not send the token to third parties, the host must be a subdomain of `trycloudflare.com`
+/// or of one of the `allowed_domains` ([`Error::HostNotAllowed`] otherwise), and the scheme must
+/// be `wss` ([`Error::TlsRequired`] otherwise).
+pub async fn connect_via_cloudflare_tunnel(
+    url: &Url,
+    mode: ConnectionMode,
+    timeout: Duration,
+    token: &str,
+    allowed_domains: &[&str],
+    opts: &ConnectOptions,
+) -> Result<(Sink, Stream), Error> {
+    let url: Url = rewrite(url, opts);
+    let request: Request = cloudflare::request(&url, token, allowed_domains)?;
+    connect_request(&url, request, mode, timeout, opts).await
+}
+
+/// Connect following the HTTP redirects (`301`, `302` and `307`) returned on the upgrade path
+///
+/// Up to `max_redirects` hops are followed, each one with its own `timeout`.
+/// A redirect to an already visited URL is rejected with [`Error::RedirectLoop`],
+/// and one from `wss` to `ws` with [`Error::RedirectDowngrade`].
+///
+/// The last item is the final URL, after the redirects.
+pub async fn connect_with_redirect_follow(
+    url: &Url,
+    mode: ConnectionMode,
+    timeout: Duration,
+    max_redirects: u8,
+    opts: &ConnectOptions,
+) -> Result<(Sink, Stream, Url), Error> {
+    follow_redirects(url, mode, timeout, max_redirects, None, opts).await
+}
+
+/// Connect with `credentials`, following the HTTP redirects like [`connect_with_redirect_follow`]
+///
+/// The `Authorization` header is re-sent on every hop. To not leak the credentials to third
+/// parties, a redirect to another origin (scheme, host and port) is rejected with
+/// [`Error::AuthRedirectCrossOrigin`].
+pub async fn connect_with_redirects_and_auth(
+    url: &Url,
+    mode: ConnectionMode,
+    timeout: Duration,
+    max_redirects: u8,
+    credentials: &Credentials,
+    opts: &ConnectOptions,
+) -> Result<(Sink, Stream, Url), Error> {
+    follow_redirects(url, mode, timeout, max_redirects, Some(credentials), opts).await
+}
+
+async fn follow_redirects(
+    url: &Url,
+    mode: ConnectionMode,
+    timeout: Duration,
+    max_redirects: u8,
+    credentials: Option<&Credentials>,
+    opts: &ConnectOptions,
+) -> Result<(Sink, Stream, Url), Error> {
+    let url: Url = rewrite(url, opts);
+    let mut visited: Vec<Url> = Vec::new();
+    let mut current: Url = url.clone();
+
+    loop {
+        let mut request: Request = request::from_url(&current)?;
+
+        if let Some(credentials) = credentials {
+            request = request::with_credentials(request, credentials)?;
+        }
+
+        let e: Error = match connect_request(&current, request, mode.clone(), timeout, opts).await {
+            Ok((tx, rx)) => return Ok((tx, rx, current)),
+            Err(e) => e,
+        };
+
+        let next: Url = match redirect::location(&current, &e) {
+            Some(next) => next?,
+            None => return Err(e),
+        };
+
+        if credentials.is_some() && next.origin() != url.origin() {
+            return Err(Error::AuthRedirectCrossOrigin(redact::url(&next)));
+        }
+
+        if visited.len() >= max_redirects as usize {
+            return Err(Error::TooManyRedirects {
+                limit: max_redirects,
+            });
+        }
+
+        visited.push(current);
+
+        if visited.contains(&next) {
+            return Err(Error::RedirectLoop(redact::url(&next)));
+        }
+
+        current = next;
+    }
+}
+
+/// Connect with a fixed `Sec-WebSocket-Key`, for reproducible handshakes
+///
+/// The `key` bytes are base64-encoded as per spec.
+///
+/// **Only for testing!** A fixed key defeats the purpose of the header.
+#[cfg(feature = "test-util")]
+pub async fn connect_with_websocket_key(
+    url: &Url,
+    mode: ConnectionMode,
+    timeout: Duration,
+    key: [u8; 16],
+    opts: &ConnectOptions,
+) -> Result<(Sink, Stream), Error> {
+    let url: Url = rewrite(url, opts);
+    let request: Request = request::with_key(request::from_url(&url)?, key)?;
+    connect_request(&url, request, mode, timeout, opts).await
+}
+
+/// Connect within a shared `deadline`, i.e. the total time budget of a failover loop
+///
+/// The attempt timeout is the **minimum** between `timeout` and the time left until the `deadline`,
+/// so successive attempts sharing the same deadline never exceed the overall budget.
+/// If the deadline has already passed, [`Error::Timeout`] is returned without trying to connect.
+///
+/// The deadline is a [`tokio::time::Instant`], so it honors a paused clock in tests.
+pub async fn connect_with_deadline(
+    url: &Url,
+    mode: ConnectionMode,
+    timeout: Duration,
+    deadline: Instant,
+    opts: &ConnectOptions,
+) -> Result<(Sink, Stream), Error> {
+    let remaining: Duration = deadline.saturating_duration_since(Instant::now());
+
+    if remaining.is_zero() {
+        return Err(Error::Timeout);
+    }
+
+    connect_with_options(url, mode, timeout.min(remaining), opts).await
+}
+
+/// Connect over a provided transport (i.e. an already connected TCP stream)
+///
+/// The transport is upgraded to TLS if the URL scheme is `wss`.
+pub async fn connect_with_stream<S>(
+    url: &Url,
+    conn: S,
+    timeout: Duration,
+    opts: &ConnectOptions,
+) -> Result<(Sink, Stream), Error>
+where
+    S: Transport + 'static,
+{
+    let request: Request = request::from_url(url)?;
+    let conn: BoxedTransport = Box::new(conn);
+    let (stream, negotiated) = time::timeout(Some(timeout), handshake(request, conn, opts))
+        .await
+        .ok_or(Error::Timeout)??;
+    Ok(split(
+        WebSocket::Custom(stream),
+        Some(url.clone()),
+        negotiated,
+        opts,
+    ))
+}
+
+/// Connect to `url` through an established connection (WebSocket-in-WebSocket tunneling)
+///
+/// The upgrade request is sent as text messages over the `outer` connection and the
+/// response is read from the text (or binary) messages received; then the inner frames
+/// flow as binary messages. The peer must relay them to the target as raw bytes.
+/// The inner connection ends when the outer one is closed.
+pub async fn connect_through_websocket_tunnel(
+    outer: (Sink, Stream),
+    url: &Url,
+    timeout: Duration,
+    opts: &ConnectOptions,
+) -> Result<(Sink, Stream), Error> {
+    let request: Request = request::from_url(url)?;
+    let (tunnel, handshake_done) = Tunnel::new(outer.0, outer.1);
+    let conn: BoxedTransport = Box::new(tunnel);
+    let (stream, negotiated) = time::timeout(Some(timeout), handshake(request, conn, opts))
+        .await
+        .ok_or(Error::Timeout)??;
+    handshake_done.store(true, Ordering::SeqCst);
+    Ok(split(
+        WebSocket::Custom(stream),
+        Some(url.clone()),
+        negotiated,
+        opts,
+    ))
+}
+
+/// Connect over a Unix domain socket at `path`, for local IPC
+///
+/// The `url` is only used for the handshake request (i.e. the `Host` header and the path):
+/// `ws://localhost/` is fine in most cases.
+///
+/// **Only available on Unix!** On other platforms, [`Error::Unsupported`] is returned.
+pub async fn connect_with_unix_socket<P>(
+    path: P,
+    url: &Url,
+    timeout: Duration,
+    opts: &ConnectOptions,
+) -> Result<(Sink, Stream), Error>
+where
+    P: AsRef<Path>,
+{
+    #[cfg(unix)]
+    {
+        let request: Request = request::from_url(url)?;
+        let (stream, negotiated) = time::timeout(Some(timeout), async {
+            let conn: BoxedTransport = Box::new(UnixStream::connect(path).await?);
+            handshake(request, conn, opts).await
+        })
+        .await
+        .ok_or(Error::Timeout)??;
+        Ok(split(
+            WebSocket::Custom(stream),
+            Some(url.clone()),
+            negotiated,
+            opts,
+        ))
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = (path, url, timeout, opts);
+        Err(Error::Unsupported("unix domain socket"))
+    }
+}
+
+/// Wrap a connection already upgraded to WebSocket by an HTTP client (i.e. `hyper`)
+///
+/// The `key` is the `Sec-WebSocket-Key` sent with the upgrade request: the `101` `response`
+/// is validated against it. No further handshake is performed on the transport.
+///
+/// With `hyper` 1.x, send a `GET` request with the WebSocket headers, then pass
+/// `hyper_util::rt::TokioIo::new(hyper::upgrade::on(response).await?)` as the transport.
+pub async fn connect_upgraded<S, B>(
+    upgraded: S,
+    key: &str,
+    response: &http::Response<B>,
+    opts: &ConnectOptions,
+) -> Result<(Sink, Stream), Error>
+where
+    S: Transport + 'static,
+{
+    opts.validate()?;
+    upgrade::verify_response(key, response)?;
+    let negotiated = Negotiated::from_headers(response.headers(), &opts.protocols)?;
+
+    let conn: BoxedTransport = Box::new(upgraded);
+    let conn = TimeoutStream::new(conn, opts.read_timeout, opts.write_timeout);
+    let stream = WebSocketStream::from_raw_socket(
+        stream::wrap_upgraded_conn(MaybeTlsStream::Plain(conn), opts),
+        Role::Client,
+        Some(opts.ws_config()),
+    )
+    .await;
+    Ok(split(WebSocket::Custom(stream), None, negotiated, opts))
+}
+
+/// Accept a client connection over a provided transport, performing the server-side handshake
+#[cfg(feature = "test-util")]
+pub(crate) async fn accept<S>(conn: S) -> Result<(Sink, Stream), Error>
+where
+    S: Transport + 'static,
+{
+    let conn: BoxedTransport = Box::new(conn);
+    let opts = ConnectOptions::default();
+    let conn = stream::wrap_conn(
+        MaybeTlsStream::Plain(TimeoutStream::new(conn, None, None)),
+        &opts,
+    );
+    let stream = tokio_tungstenite::accept_async(conn).await?;
+    Ok(split(
+        WebSocket::Custom(stream),
+        None,
+        Negotiated::default(),
+        &opts,
+    ))
+}
+
+fn split(
+    stream: WebSocket,
+    url: Option<Url>,
+    negotiated: Negotiated,
+    opts: &ConnectOptions,
+) -> (Sink, Stream) {
+    let pings = Arc::new(PingTracker::new(
+        opts.max_outstanding_pings,
+        opts.ping_timeout,
+    ));
+
     match stream {
         WebSocket::Std(stream) => {
+            #[cfg(target_os = "linux")]
+            let send_queue: Option<SendQueue> =
+                stream::tcp_stream(&stream).and_then(|s| SendQueue::new(s).ok());
             let (tx, rx) = stream.split();
-            Ok((Sink::Std(tx), Stream::Std(rx)))
+            let tx = SharedSink::new(tx);
+            let close = Arc::new(CloseRequest::new(&tx));
+            let sink = PrioritySink::new(tx, pings.clone(), close.clone());
+            #[cfg(target_os = "linux")]
+            let sink = sink.with_send_queue(send_queue);
+            (
+                Sink::Std(sink),
+                Stream::Std(ObservedStream::new(rx, pings, url, negotiated, opts, close)),
+            )
         }
         #[cfg(feature = "tor")]
         WebSocket::Tor(stream) => {
             let (tx, rx) = stream.split();
-            Ok((Sink::Tor(tx), Stream::Tor(rx)))
+            let tx = SharedSink::new(tx);
+            let close = Arc::new(CloseRequest::new(&tx));
+            (
+                Sink::Tor(PrioritySink::new(tx, pings.clone(), close.clone())),
+                Stream::Tor(ObservedStream::new(rx, pings, url, negotiated, opts, close)),
+            )
+        }
+        WebSocket::Custom(stream) => {
+            let (tx, rx) = stream.split();
+            let tx = SharedSink::new(tx);
+            let close = Arc::new(CloseRequest::new(&tx));
+            (
+                Sink::Custom(PrioritySink::new(tx, pings.clone(), close.clone())),
+                Stream::Custom(ObservedStream::new(rx, pings, url, negotiated, opts, close)),
+            )
         }
     }
 }
 
-async fn connect_direct(url: &Url, timeout: Duration) -> Result<WebSocket, Error> {
-    let (stream, _) = time::timeout(
-        Some(timeout),
-        tokio_tungstenite::connect_async(url.as_str()),
-    )
+async fn connect_direct(
+    url: &Url,
+    request: Request,
+    timeout: Duration,
+    opts: &ConnectOptions,
+) -> Result<(WebSocket, Negotiated), Error> {
+    let host: &str = url.host_str().ok_or_else(Error::empty_host)?;
+    let port: u16 = url
+        .port_or_known_default()
+        .ok_or_else(Error::invalid_port)?;
+    let addr: String = format!("{host}:{port}");
+
+    let (stream, negotiated) = time::timeout(Some(timeout), async {
+        let addrs: Vec<SocketAddr> = resolve(&addr, opts).await?;
+
+        #[cfg(target_os = "linux")]
+        if opts.tcp_fastopen {
+            return handshake_fastopen(request, &addrs, opts).await;
+        }
+
+        let conn: TcpStream = match opts.happy_eyeballs {
+            Some(delay) => happy_eyeballs(addrs, delay).await?,
+            None => TcpStream::connect(addrs.as_slice()).await?,
+        };
+        handshake(request, conn, opts).await
+    })
     .await
     .ok_or(Error::Timeout)??;
-    Ok(WebSocket::Std(stream))
+    Ok((WebSocket::Std(stream), negotiated))
+}
+
+/// Resolve the host, within the DNS timeout (if any), keeping the addresses of the requested family
+async fn resolve(addr: &str, opts: &ConnectOptions) -> Result<Vec<SocketAddr>, Error> {
+    let lookup = net::lookup_host(addr);
+    let addrs = match opts.dns_timeout {
+        Some(dns_timeout) => tokio::time::timeout(dns_timeout, lookup)
+            .await
+            .map_err(|_| Error::DnsTimeout)??,
+        None => lookup.await?,
+    };
+
+    let family: AddressFamily = opts.address_family;
+    let addrs: Vec<SocketAddr> = addrs.filter(|addr| family.matches(addr)).collect();
+
+    if addrs.is_empty() && family != AddressFamily::Any {
+        return Err(Error::NoAddressFamily { family });
+    }
+
+    Ok(addrs)
+}
+
+/// Try the addresses in order with TCP Fast Open, up to the handshake
+///
+/// With a cookie, the connection is only established on the first write: an unreachable address
+/// fails the handshake, not the connect, so the next address is tried from there.
+#[cfg(target_os = "linux")]
+async fn handshake_fastopen(
+    request: Request,
+    addrs: &[SocketAddr],
+    opts: &ConnectOptions,
+) -> Result<(WsStream<TcpStream>, Negotiated), Error> {
+    let mut last_err: Option<Error> = None;
+
+    for addr in addrs {
+        #[cfg(feature = "tracing")]
+        tracing::debug!("Connecting to {addr} with TCP Fast Open");
+        let res = match fastopen::connect(*addr).await {
+            Ok(conn) => handshake(request.clone(), conn, opts).await,
+            Err(e) => Err(Error::IO(e)),
+        };
+
+        match res {
+            Ok(res) => return Ok(res),
+            Err(e) if matches!(e.as_io(), Some(io) if fastopen::is_connect_error(io)) => {
+                #[cfg(feature = "tracing")]
+                tracing::debug!("Can't connect to {addr}: {e}");
+                last_err = Some(e);
+            }
+            // Any other address would fail the same way
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(last_err.unwrap_or(Error::NoRoute))
+}
+
+/// Try the IPv6 addresses, then after `delay` the IPv4 ones in parallel: the first connection wins
+async fn happy_eyeballs(addrs: Vec<SocketAddr>, delay: Duration) -> Result<TcpStream, Error> {
+    let (ipv6, ipv4): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.into_iter().partition(SocketAddr::is_ipv6);
+
+    let primary = attempt(ipv6);
+    let delayed = tokio::time::sleep(delay);
+    futures_util::pin_mut!(primary, delayed);
+
+    let res: Option<TcpStream> = match future::select(primary, delayed).await {
+        Either::Left((Some(conn), _)) => Some(conn),
+        // IPv6 failed before the delay: don't wait
+        Either::Left((None, _)) => attempt(ipv4).await,
+        Either::Right(((), primary)) => {
+            let secondary = attempt(ipv4);
+            futures_util::pin_mut!(secondary);
+
+            // Dropping the other attempt cancels it
+            match future::select(primary, secondary).await {
+                Either::Left((Some(conn), _)) | Either::Right((Some(conn), _)) => Some(conn),
+                Either::Left((None, secondary)) => secondary.await,
+                Either::Right((None, primary)) => primary.await,
+            }
+        }
+    };
+
+    res.ok_or(Error::NoRoute)
+}
+
+/// Try the addresses in order
+async fn attempt(addrs: Vec<SocketAddr>) -> Option<TcpStream> {
+    for addr in addrs {
+        #[cfg(feature = "tracing")]
+        tracing::debug!("Connecting to {addr}");
+        match TcpStream::connect(addr).await {
+            Ok(conn) => {
+                #[cfg(feature = "tracing")]
+                tracing::debug!("Connected to {addr}");
+                return Some(conn);
+            }
+            Err(_e) => {
+                #[cfg(feature = "tracing")]
+                tracing::debug!("Can't connect to {addr}: {_e}");
+            }
+        }
+    }
+    None
 }
 
 #[cfg(feature = "socks")]
 async fn connect_proxy(
     url: &Url,
-    proxy: SocketAddr,
+    request: Request,
+    proxy: ProxyAddr,
     timeout: Duration,
-) -> Result<WebSocket, Error> {
+    opts: &ConnectOptions,
+) -> Result<(WebSocket, Negotiated), Error> {
     let host: &str = url.host_str().ok_or_else(Error::empty_host)?;
     let port: u16 = url
         .port_or_known_default()
         .ok_or_else(Error::invalid_port)?;
     let addr: String = format!("{host}:{port}");
 
-    let conn: TcpStream = TcpSocks5Stream::connect(proxy, addr).await?;
-    let (stream, _) = time::timeout(
-        Some(timeout),
-        tokio_tungstenite::client_async_tls(url.as_str(), conn),
-    )
+    let (stream, negotiated) = time::timeout(Some(timeout), async {
+        let proxies: Vec<SocketAddr> = match &proxy {
+            ProxyAddr::Socket(addr) => vec![*addr],
+            ProxyAddr::Host { host, port } => resolve(&format!("{host}:{port}"), opts)
+                .await
+                .map_err(|e| Error::ProxyResolve {
+                    proxy: proxy.to_string(),
+                    source: Box::new(e),
+                })?,
+        };
+        let conn: TcpStream = TcpSocks5Stream::connect(proxies.as_slice(), addr).await?;
+        handshake(request, conn, opts).await
+    })
     .await
     .ok_or(Error::Timeout)??;
-    Ok(WebSocket::Std(stream))
+    Ok((WebSocket::Std(stream), negotiated))
 }
 
 #[cfg(feature = "tor")]
-async fn connect_tor(url: &Url, timeout: Duration) -> Result<WebSocket, Error> {
+async fn connect_tor(
+    url: &Url,
+    request: Request,
+    timeout: Duration,
+    opts: &ConnectOptions,
+) -> Result<(WebSocket, Negotiated), Error> {
     let host: &str = url.host_str().ok_or_else(Error::empty_host)?;
     let port: u16 = url
         .port_or_known_default()
         .ok_or_else(Error::invalid_port)?;
 
     let conn: DataStream = tor::connect(host, port).await?;
-    let (stream, _) = time::timeout(
-        Some(timeout),
-        tokio_tungstenite::client_async_tls(url.as_str(), conn),
+    let (stream, negotiated) = time::timeout(Some(timeout), handshake(request, conn, opts))
+        .await
+        .ok_or(Error::Timeout)??;
+    Ok((WebSocket::Tor(stream), negotiated))
+}
+
+/// Upgrade the transport to TLS (if required) and perform the WebSocket handshake
+///
+/// Return the sub-protocol selected by the server, if any.
+async fn handshake<S>(
+    request: Request,
+    conn: S,
+    opts: &ConnectOptions,
+) -> Result<(WsStream<S>, Negotiated), Error>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    opts.validate()?;
+
+    let request: Request = request::with_user_agent(request, opts.user_agent.as_deref())?;
+    let request: Request = request::with_protocols(request, &opts.protocols)?;
+    let requested: Vec<String> = request::protocols(&request);
+    let peer: String = request.uri().host().unwrap_or_default().to_string();
+    let conn = TimeoutStream::new(conn, opts.read_timeout, opts.write_timeout);
+
+    // The TLS stream is kept beneath tungstenite, to send the `close_notify` on shutdown
+    let conn = match uri_mode(request.uri())? {
+        Mode::Plain => MaybeTlsStream::Plain(conn),
+        Mode::Tls => {
+            let server_name: ServerName<'static> = match &opts.server_name {
+                Some(server_name) => server_name.clone(),
+                None => ServerName::try_from(
+                    peer.trim_start_matches('[')
+                        .trim_end_matches(']')
+                        .to_string(),
+                )
+                .map_err(|_| Error::InvalidDNSName)?,
+            };
+            tls::connect(conn, server_name, opts).await?
+        }
+    };
+
+    let (stream, response) = tokio_tungstenite::client_async_with_config(
+        request,
+        stream::wrap_conn(conn, opts),
+        Some(opts.ws_config()),
     )
-    .await
-    .ok_or(Error::Timeout)??;
-    Ok(WebSocket::Tor(stream))
+    .await?;
+    let negotiated = Negotiated::from_headers(response.headers(), &requested)?;
+    Ok((stream, negotiated))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_connect_timeout_virtual_time() {
+        let (client, _server) = io::duplex(1024);
+        let url = Url::parse("ws://localhost").unwrap();
+
+        // The runtime auto-advances the paused clock: one hour elapses instantly
+        let res = connect_with_stream(
+            &url,
+            client,
+            Duration::from_secs(3600),
+            &ConnectOptions::default(),
+        )
+        .await;
+        assert!(matches!(res, Err(Error::Timeout)));
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "test-util")]
+    async fn test_max_write_buffer_size() {
+        use futures_util::SinkExt;
+
+        let (client, server) = io::duplex(1024);
+        let url = Url::parse("ws://localhost").unwrap();
+        let opts = ConnectOptions::new().max_write_buffer_size(16 * 1024);
+
+        // The server never reads
+        let ((mut tx, _rx), _server) = futures_util::future::try_join(
+            connect_with_stream(&url, client, Duration::from_secs(10), &opts),
+            accept(server),
+        )
+        .await
+        .unwrap();
+
+        let res = tx.send(Message::Binary(vec![0; 64 * 1024])).await;
+        assert!(matches!(res, Err(Error::SendBufferFull)));
+    }
+
+    #[tokio::test]
+    #[cfg(all(unix, feature = "test-util"))]
+    async fn test_connect_with_unix_socket() {
+        use futures_util::{SinkExt, StreamExt};
+        use tokio::net::UnixListener;
+
+        let path = std::env::temp_dir().join(format!("async-wsocket-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        tokio::spawn(async move {
+            let (conn, _) = listener.accept().await.unwrap();
+            let (tx, rx) = accept(conn).await.unwrap();
+            crate::test_util::echo(tx, rx).await.unwrap();
+        });
+
+        let url = Url::parse("ws://localhost").unwrap();
+        let (mut tx, mut rx) = connect_with_unix_socket(
+            &path,
+            &url,
+            Duration::from_secs(10),
+            &ConnectOptions::default(),
+        )
+        .await
+        .unwrap();
+        tx.send(Message::text("hello")).await.unwrap();
+        assert_eq!(rx.next().await.unwrap().unwrap(), Message::text("hello"));
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "test-util")]
+    async fn test_size_limits() {
+        let (client, server) = io::duplex(1024);
+        let url = Url::parse("ws://localhost").unwrap();
+        let opts = ConnectOptions::new()
+            .max_message_size(Some(1024))
+            .max_frame_size(None);
+
+        let ((_tx, rx), (_server_tx, server_rx)) = futures_util::future::try_join(
+            connect_with_stream(&url, client, Duration::from_secs(10), &opts),
+            accept(server),
+        )
+        .await
+        .unwrap();
+
+        let limits = rx.size_limits();
+        assert_eq!(limits.max_message_size, Some(1024));
+        assert_eq!(limits.max_frame_size, None);
+
+        // Backend defaults
+        let limits = server_rx.size_limits();
+        assert_eq!(limits.max_message_size, Some(64 << 20));
+        assert_eq!(limits.max_frame_size, Some(16 << 20));
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "test-util")]
+    async fn test_resolved_options() {
+        let (client, server) = io::duplex(1024);
+        let url = Url::parse("ws://localhost").unwrap();
+        let opts = ConnectOptions::new()
+            .read_timeout(Some(Duration::from_secs(5)))
+            .min_tls_version(TlsVersion::Tls13)
+            .max_write_buffer_size(1024);
+
+        let ((_tx, rx), _) = futures_util::future::try_join(
+            connect_with_stream(&url, client, Duration::from_secs(10), &opts),
+            accept(server),
+        )
+        .await
+        .unwrap();
+
+        let options = rx.options();
+        assert_eq!(options.read_timeout, Some(Duration::from_secs(5)));
+        assert_eq!(options.tls_versions, vec![TlsVersion::Tls13]);
+        assert_eq!(options.max_write_buffer_size, 1024);
+        assert!(options.write_buffer_size < 1024);
+        assert_eq!(options.ping_timeout, Duration::from_secs(30));
+    }
+
+    #[tokio::test]
+    async fn test_protocol_negotiation() {
+        use tokio_tungstenite::tungstenite::handshake::server::{
+            ErrorResponse, Request as ServerRequest, Response,
+        };
+
+        // Server selecting the given sub-protocol
+        async fn serve(conn: io::DuplexStream, selected: &'static str) {
+            // The signature is set by tungstenite
+            #[allow(clippy::result_large_err)]
+            let callback = move |req: &ServerRequest, mut res: Response| {
+                assert_eq!(req.headers()["sec-websocket-protocol"], "v2.chat,v1.chat");
+                res.headers_mut()
+                    .insert("sec-websocket-protocol", selected.parse().unwrap());
+                Ok::<_, ErrorResponse>(res)
+            };
+            let _ = tokio_tungstenite::accept_hdr_async(conn, callback).await;
+        }
+
+        let url = Url::parse("ws://localhost").unwrap();
+        let opts = ConnectOptions::new().protocols(["v2.chat", "v1.chat"]);
+
+        let (client, server) = io::duplex(1024);
+        tokio::spawn(serve(server, "v1.chat"));
+        let (_tx, rx) = connect_with_stream(&url, client, Duration::from_secs(10), &opts)
+            .await
+            .unwrap();
+        assert_eq!(rx.protocol(), Some("v1.chat"));
+
+        let (client, server) = io::duplex(1024);
+        tokio::spawn(serve(server, "v3.chat"));
+        let res = connect_with_stream(&url, client, Duration::from_secs(10), &opts).await;
+        assert!(matches!(res, Err(Error::ProtocolNotNegotiated)));
+    }
+
+    #[tokio::test]
+    async fn test_unsolicited_compression() {
+        use tokio_tungstenite::tungstenite::handshake::server::{
+            ErrorResponse, Request as ServerRequest, Response,
+        };
+
+        // The signature is set by tungstenite
+        #[allow(clippy::result_large_err)]
+        let callback = |_: &ServerRequest, mut res: Response| {
+            res.headers_mut().insert(
+                "sec-websocket-extensions",
+                "permessage-deflate; client_max_window_bits=15"
+                    .parse()
+                    .unwrap(),
+            );
+            Ok::<_, ErrorResponse>(res)
+        };
+
+        let url = Url::parse("ws://localhost").unwrap();
+        let (client, server) = io::duplex(1024);
+        tokio::spawn(async move {
+            let _ = tokio_tungstenite::accept_hdr_async(server, callback).await;
+        });
+        let res = connect_with_stream(
+            &url,
+            client,
+            Duration::from_secs(10),
+            &ConnectOptions::default(),
+        )
+        .await;
+        assert!(matches!(res, Err(Error::CompressionNotRequested)));
+    }
+
+    #[tokio::test]
+    async fn test_close_with_sink() {
+        use futures_util::SinkExt;
+        use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+        use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+
+        let url = Url::parse("ws://localhost").unwrap();
+        let (client, server) = io::duplex(1024);
+        let server = tokio::spawn(async move {
+            let mut ws = tokio_tungstenite::accept_async(server).await.unwrap();
+            let mut received = Vec::new();
+            while let Some(Ok(msg)) = ws.next().await {
+                received.push(msg);
+            }
+            received
+        });
+        let (mut tx, _rx) = connect_with_stream(
+            &url,
+            client,
+            Duration::from_secs(10),
+            &ConnectOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        // Invalid code
+        let invalid = Message::Close(Some(CloseFrame {
+            code: CloseCode::from(1005),
+            reason: "".into(),
+        }));
+        assert!(matches!(
+            tx.send(invalid).await,
+            Err(Error::InvalidCloseCode { supplied: 1005 })
+        ));
+
+        // Flushed before the close
+        tx.send(Message::Text(String::from("last"))).await.unwrap();
+
+        // Queued, not flushed: discarded by the close, which jumps ahead
+        tx.feed(Message::Text(String::from("dropped")))
+            .await
+            .unwrap();
+        let close = Message::Close(Some(CloseFrame {
+            code: CloseCode::Away,
+            reason: "bye".into(),
+        }));
+        tx.send(close.clone()).await.unwrap();
+
+        assert!(tx.send(Message::Text(String::from("late"))).await.is_err());
+        tx.send(close.clone()).await.unwrap();
+        tx.close().await.unwrap();
+
+        let received = server.await.unwrap();
+        assert_eq!(received, vec![Message::Text(String::from("last")), close]);
+    }
+
+    #[tokio::test]
+    async fn test_close_before_queued_data() {
+        use futures_util::SinkExt;
+
+        let url = Url::parse("ws://localhost").unwrap();
+        let (client, server) = io::duplex(1024);
+        let server = tokio::spawn(async move {
+            let mut ws = tokio_tungstenite::accept_async(server).await.unwrap();
+            ws.next().await.unwrap().unwrap()
+        });
+        let (mut tx, _rx) = connect_with_stream(
+            &url,
+            client,
+            Duration::from_secs(10),
+            &ConnectOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        // Stuff the send buffer, then close
+        for _ in 0..16 {
+            tx.feed(Message::Binary(vec![0; 16 * 1024])).await.unwrap();
+        }
+        tx.send(Message::Close(None)).await.unwrap();
+
+        assert_eq!(server.await.unwrap(), Message::Close(None));
+    }
+
+    #[tokio::test]
+    async fn test_try_send() {
+        use tokio_tungstenite::tungstenite::error::ProtocolError;
+        use tokio_tungstenite::tungstenite::Error as WsError;
+
+        let url = Url::parse("ws://localhost").unwrap();
+        let (client, server) = io::duplex(1024);
+        let server = tokio::spawn(async move {
+            let mut ws = tokio_tungstenite::accept_async(server).await.unwrap();
+            let mut received = Vec::new();
+            while let Some(Ok(msg)) = ws.next().await {
+                received.push(msg);
+            }
+            received
+        });
+        let (mut tx, _rx) = connect_with_stream(
+            &url,
+            client,
+            Duration::from_secs(10),
+            &ConnectOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        tx.try_send(Message::Text(String::from("first")))
+            .await
+            .unwrap();
+        tx.try_send(Message::Close(None)).await.unwrap();
+
+        // Never handed to the connection: given back
+        let (msg, e) = tx.try_send(Message::Binary(vec![1; 64])).await.unwrap_err();
+        assert_eq!(msg, Some(Message::Binary(vec![1; 64])));
+        assert!(matches!(
+            e.as_ws(),
+            Some(WsError::Protocol(ProtocolError::SendAfterClosing))
+        ));
+
+        let received = server.await.unwrap();
+        assert_eq!(received[0], Message::Text(String::from("first")));
+    }
+
+    #[tokio::test]
+    async fn test_close_send() {
+        use futures_util::StreamExt;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let url = Url::parse("ws://localhost").unwrap();
+        let (client, server) = io::duplex(1024);
+        tokio::spawn(async move {
+            let mut ws = tokio_tungstenite::accept_async(server).await.unwrap();
+            let conn = ws.get_mut();
+
+            // The masked close frame of the client (short payload)
+            let mut header = [0; 2];
+            conn.read_exact(&mut header).await.unwrap();
+            assert_eq!(header[0], 0x88);
+            let mut rest = vec![0; 4 + usize::from(header[1] & 0x7f)];
+            conn.read_exact(&mut rest).await.unwrap();
+
+            // Still delivering, then the close reply (1000)
+            conn.write_all(&[0x81, 1, b'a', 0x81, 1, b'b', 0x88, 2, 0x03, 0xe8])
+                .await
+                .unwrap();
+        });
+        let (mut tx, mut rx) = connect_with_stream(
+            &url,
+            client,
+            Duration::from_secs(10),
+            &ConnectOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        tx.close_send(1000, "bye").await.unwrap();
+
+        let mut received = Vec::new();
+        while let Some(Ok(msg)) = rx.next().await {
+            received.push(msg);
+        }
+        assert_eq!(received[..2], [Message::text("a"), Message::text("b")]);
+
+        let frame = rx.closed().await.unwrap();
+        assert_eq!(u16::from(frame.code), 1000);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "test-util")]
+    async fn test_auth_redirect_cross_origin() {
+        use crate::test_util::MockServer;
+
+        let server = MockServer::builder()
+            .reject(302, [("Location", "wss://other.example.com/ws")])
+            .start()
+            .await
+            .unwrap();
+
+        let res = connect_with_redirects_and_auth(
+            server.url(),
+            ConnectionMode::Direct,
+            Duration::from_secs(10),
+            5,
+            &Credentials::Bearer(String::from("secret")),
+            &ConnectOptions::default(),
+        )
+        .await;
+        match res {
+            Err(Error::AuthRedirectCrossOrigin(url)) => {
+                assert_eq!(url.as_str(), "wss://other.example.com/ws")
+            }
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_zero_max_frame_size() {
+        let url = Url::parse("ws://localhost").unwrap();
+        let res =
+            connect_with_max_frame_size(&url, ConnectionMode::Direct, Duration::from_secs(10), 0)
+                .await;
+        assert!(matches!(res, Err(Error::InvalidConfig { .. })));
+
+        let opts = ConnectOptions::new().max_frame_size(Some(0));
+        let res =
+            connect_with_options(&url, ConnectionMode::Direct, Duration::from_secs(10), &opts)
+                .await;
+        assert!(matches!(res, Err(Error::InvalidConfig { .. })));
+
+        // Over a provided transport too
+        let (client, _server) = io::duplex(1024);
+        let res = connect_with_stream(&url, client, Duration::from_secs(10), &opts).await;
+        assert!(matches!(res, Err(Error::InvalidConfig { .. })));
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "test-util")]
+    async fn test_url_rewriter() {
+        use crate::test_util::MockServer;
+
+        let server = MockServer::builder().start().await.unwrap();
+        let target = server.url().clone();
+        let opts = ConnectOptions::new().url_rewriter(Arc::new(move |url: &Url| {
+            if url.host_str() == Some("relay.alias") {
+                target.clone()
+            } else {
+                url.clone()
+            }
+        }));
+
+        let url = Url::parse("ws://relay.alias/ws").unwrap();
+        let (_tx, rx) =
+            connect_with_options(&url, ConnectionMode::Direct, Duration::from_secs(10), &opts)
+                .await
+                .unwrap();
+        assert_eq!(rx.url(), Some(server.url()));
+    }
+
+    #[tokio::test]
+    async fn test_url_rewriter_before_validation() {
+        let opts = ConnectOptions::new().url_rewriter(Arc::new(|_: &Url| {
+            Url::parse("wss://relay.example.com/ws").unwrap()
+        }));
+
+        // The token must not be sent to the rewritten host
+        let url = Url::parse("wss://random-words.trycloudflare.com/ws").unwrap();
+        let res = connect_via_cloudflare_tunnel(
+            &url,
+            ConnectionMode::Direct,
+            Duration::from_secs(10),
+            "id:secret",
+            &[],
+            &opts,
+        )
+        .await;
+        assert!(matches!(res, Err(Error::HostNotAllowed(host)) if host == "relay.example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_address_family() {
+        let opts = ConnectOptions::new().address_family(AddressFamily::Ipv4);
+        let addrs = resolve("127.0.0.1:80", &opts).await.unwrap();
+        assert_eq!(addrs, vec![SocketAddr::from(([127, 0, 0, 1], 80))]);
+
+        let opts = ConnectOptions::new().address_family(AddressFamily::Ipv6);
+        let res = resolve("127.0.0.1:80", &opts).await;
+        assert!(matches!(
+            res,
+            Err(Error::NoAddressFamily {
+                family: AddressFamily::Ipv6
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_happy_eyeballs() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ipv4: SocketAddr = listener.local_addr().unwrap();
+
+        // Nothing listens on the IPv6 address: the IPv4 one wins
+        let unused: SocketAddr = SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], ipv4.port()));
+        let delay = Duration::from_millis(50);
+        let conn = happy_eyeballs(vec![unused, ipv4], delay).await.unwrap();
+        assert_eq!(conn.peer_addr().unwrap(), ipv4);
+
+        drop(listener);
+        let res = happy_eyeballs(vec![unused, ipv4], delay).await;
+        assert!(matches!(res, Err(Error::NoRoute)));
+    }
+
+    #[tokio::test]
+    #[cfg(all(target_os = "linux", feature = "test-util"))]
+    async fn test_connect_with_tcp_fastopen() {
+        use futures_util::{SinkExt, StreamExt};
+
+        let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (conn, _) = listener.accept().await.unwrap();
+            let (tx, rx) = accept(conn).await.unwrap();
+            crate::test_util::echo(tx, rx).await.unwrap();
+        });
+
+        let url = Url::parse(&format!("ws://{addr}")).unwrap();
+        let (mut tx, mut rx) =
+            connect_with_tcp_fastopen(&url, ConnectionMode::Direct, Duration::from_secs(10))
+                .await
+                .unwrap();
+        assert!(rx.options().tcp_fastopen);
+        tx.send(Message::text("hello")).await.unwrap();
+        assert_eq!(rx.next().await.unwrap().unwrap(), Message::text("hello"));
+    }
+
+    #[tokio::test]
+    #[cfg(all(target_os = "linux", feature = "test-util"))]
+    async fn test_tcp_fastopen_fallback() {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+        let refused: SocketAddr = {
+            let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+
+        tokio::spawn(async move {
+            let (conn, _) = listener.accept().await.unwrap();
+            let _ws = accept(conn).await.unwrap();
+        });
+
+        // The refused address only fails at the handshake: the next one is tried
+        let request: Request = format!("ws://{addr}").into_client_request().unwrap();
+        let opts = ConnectOptions::default().tcp_fastopen(true);
+        let res = handshake_fastopen(request, &[refused, addr], &opts).await;
+        assert!(res.is_ok());
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "socks")]
+    async fn test_proxy_resolve_error() {
+        let url = Url::parse("ws://127.0.0.1:1").unwrap();
+        let opts = ConnectOptions::new().address_family(AddressFamily::Ipv6);
+        let mode = ConnectionMode::proxy(("127.0.0.1", 9050));
+        let res = connect_with_options(&url, mode, Duration::from_secs(5), &opts).await;
+        assert!(matches!(res, Err(Error::ProxyResolve { .. })));
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "test-util")]
+    async fn test_full_duplex_with_blocked_write() {
+        use futures_util::{SinkExt, StreamExt};
+
+        let (client, server) = io::duplex(1024);
+        let url = Url::parse("ws://localhost").unwrap();
+
+        let ((mut tx, mut rx), (mut server_tx, mut server_rx)) = futures_util::future::try_join(
+            connect_with_stream(
+                &url,
+                client,
+                Duration::from_secs(10),
+                &ConnectOptions::default(),
+            ),
+            accept(server),
+        )
+        .await
+        .unwrap();
+
+        // The server doesn't read: the client write blocks
+        let writer = tokio::spawn(async move {
+            tx.send(Message::Binary(vec![0; 64 * 1024])).await.unwrap();
+            tx
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!writer.is_finished());
+
+        // Incoming messages still flow while the write is blocked
+        server_tx.send(Message::text("hello")).await.unwrap();
+        server_tx.send(Message::Ping(vec![1])).await.unwrap();
+        assert_eq!(rx.next().await.unwrap().unwrap(), Message::text("hello"));
+        assert_eq!(rx.next().await.unwrap().unwrap(), Message::Ping(vec![1]));
+
+        // Once the server reads again, the write completes and the pong is delivered
+        let mut got_pong = false;
+        let mut got_data = false;
+        while !(got_pong && got_data) {
+            match server_rx.next().await.unwrap().unwrap() {
+                Message::Pong(payload) => got_pong = payload == vec![1],
+                Message::Binary(data) => got_data = data.len() == 64 * 1024,
+                _ => {}
+            }
+        }
+        writer.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_connect_with_expired_deadline() {
+        let url = Url::parse("ws://localhost").unwrap();
+        let deadline = Instant::now();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let res = connect_with_deadline(
+            &url,
+            ConnectionMode::Direct,
+            Duration::from_secs(60),
+            deadline,
+            &ConnectOptions::default(),
+        )
+        .await;
+        assert!(matches!(res, Err(Error::Timeout)));
+    }
 }
@@ -0,0 +1,469 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Observed stream
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures_core::{ready, Stream as StreamTrait};
+use futures_util::{future, StreamExt};
+use tokio::runtime::Handle;
+use tokio::time::{self, Instant, Sleep};
+use tokio_tungstenite::tungstenite::Message;
+#[cfg(feature = "tracing")]
+use tracing::Level;
+use url::Url;
+
+use super::options::{ConnectOptions, ReadHalfDropPolicy, ResolvedOptions};
+use super::ping::PingTracker;
+use super::priority::CloseRequest;
+#[cfg(feature = "tracing")]
+use super::traffic::{self, Direction};
+use super::upgrade::Negotiated;
+use crate::close_event::{CloseEvent, Initiator};
+use crate::close_notifier::CloseNotifier;
+use crate::metrics::Metrics;
+use crate::DeflateParams;
+
+/// Stream observing the incoming control frames: pongs resolve the ping tickets and
+/// the connection termination fires the close callbacks.
+pub struct ObservedStream<S> {
+    /// `None` once handed to the background reader
+    inner: Option<S>,
+    pings: Arc<PingTracker>,
+    close: Arc<CloseNotifier<CloseEvent>>,
+    options: Arc<ResolvedOptions>,
+    url: Option<Url>,
+    negotiated: Negotiated,
+    drop_policy: ReadHalfDropPolicy,
+    close_request: Arc<CloseRequest>,
+    metrics: Metrics,
+    deadline: Option<Pin<Box<Sleep>>>,
+    #[cfg(feature = "tracing")]
+    traffic_log: Option<Level>,
+}
+
+impl<S> ObservedStream<S> {
+    #[inline]
+    pub(crate) fn new(
+        inner: S,
+        pings: Arc<PingTracker>,
+        url: Option<Url>,
+        negotiated: Negotiated,
+        opts: &ConnectOptions,
+        close_request: Arc<CloseRequest>,
+    ) -> Self {
+        Self {
+            inner: Some(inner),
+            pings,
+            close: Arc::new(CloseNotifier::default()),
+            options: Arc::new(opts.resolve()),
+            url,
+            negotiated,
+            drop_policy: opts.read_half_drop_policy,
+            close_request,
+            metrics: opts.metrics.clone(),
+            deadline: None,
+            #[cfg(feature = "tracing")]
+            traffic_log: None,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn options(&self) -> &ResolvedOptions {
+        &self.options
+    }
+
+    #[inline]
+    pub(crate) fn url(&self) -> Option<&Url> {
+        self.url.as_ref()
+    }
+
+    #[inline]
+    pub(crate) fn protocol(&self) -> Option<&str> {
+        self.negotiated.protocol.as_deref()
+    }
+
+    #[inline]
+    pub(crate) fn negotiated_compression(&self) -> Option<DeflateParams> {
+        self.negotiated.compression
+    }
+
+    #[inline]
+    pub(crate) fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    #[inline]
+    pub(crate) fn close_notifier(&self) -> &CloseNotifier<CloseEvent> {
+        &self.close
+    }
+
+    pub(crate) fn set_read_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline.map(|deadline| Box::pin(time::sleep_until(deadline)));
+    }
+
+    #[cfg(feature = "tracing")]
+    pub(crate) fn set_traffic_log(&mut self, level: Option<Level>) {
+        self.traffic_log = level;
+    }
+
+    /// Ready once the read deadline (if any) has passed
+    pub(crate) fn poll_deadline(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        match &mut self.deadline {
+            Some(sleep) => sleep.as_mut().poll(cx),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<S, E> ObservedStream<S>
+where
+    S: StreamTrait<Item = Result<Message, E>> + Send + Unpin + 'static,
+    E: Send + 'static,
+{
+    /// Apply the [`ReadHalfDropPolicy`]: called when the stream is dropped
+    pub(crate) fn apply_drop_policy(&mut self) {
+        let code: Option<u16> = match self.drop_policy {
+            ReadHalfDropPolicy::StopReading => return,
+            ReadHalfDropPolicy::DiscardIncoming => None,
+            ReadHalfDropPolicy::CloseConnection(code) => Some(code),
+        };
+
+        // Dropped outside of a runtime: nothing can read in the background
+        let handle: Handle = match Handle::try_current() {
+            Ok(handle) => handle,
+            Err(..) => return,
+        };
+
+        let mut inner: S = match self.inner.take() {
+            Some(inner) => inner,
+            None => return,
+        };
+
+        let close_request: Arc<CloseRequest> = self.close_request.clone();
+        let pings: Arc<PingTracker> = self.pings.clone();
+        let notifier: Arc<CloseNotifier<CloseEvent>> = self.close.clone();
+        let metrics: Metrics = self.metrics.clone();
+
+        // Reading answers the pings and completes the close handshake, firing the close callbacks
+        handle.spawn(async move {
+            let close = async {
+                if let Some(code) = code {
+                    close_request.send(code).await;
+                }
+            };
+            let read = async {
+                loop {
+                    let item = inner.next().await;
+                    observe(item.as_ref(), &pings, &notifier, &close_request, &metrics);
+                    if !matches!(item, Some(Ok(..))) {
+                        break;
+                    }
+                }
+            };
+            future::join(close, read).await;
+        });
+    }
+}
+
+impl<S, E> StreamTrait for ObservedStream<S>
+where
+    S: StreamTrait<Item = Result<Message, E>> + Unpin,
+{
+    type Item = Result<Message, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let item = match &mut self.inner {
+            Some(inner) => ready!(Pin::new(inner).poll_next(cx)),
+            None => None,
+        };
+
+        #[cfg(feature = "tracing")]
+        if let (Some(level), Some(Ok(msg))) = (self.traffic_log, &item) {
+            traffic::log(level, Direction::Received, msg);
+        }
+
+        observe(
+            item.as_ref(),
+            &self.pings,
+            &self.close,
+            &self.close_request,
+            &self.metrics,
+        );
+
+        Poll::Ready(item)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match &self.inner {
+            Some(inner) => inner.size_hint(),
+            None => (0, Some(0)),
+        }
+    }
+}
+
+impl<S> Drop for ObservedStream<S> {
+    fn drop(&mut self) {
+        // Unless read in the background, the connection can't be observed anymore
+        if self.inner.is_some() {
+            self.close
+                .notify(Some(CloseEvent::abnormal(Initiator::Client)));
+        }
+    }
+}
+
+/// Resolve the ping tickets with the pongs and fire the close callbacks on termination
+fn observe<E>(
+    item: Option<&Result<Message, E>>,
+    pings: &PingTracker,
+    close: &CloseNotifier<CloseEvent>,
+    close_request: &CloseRequest,
+    metrics: &Metrics,
+) {
+    let initiator = || {
+        if close_request.is_sent() {
+            Initiator::Client
+        } else {
+            Initiator::Server
+        }
+    };
+
+    match item {
+        Some(Ok(Message::Pong(payload))) => pings.resolve(payload),
+        Some(Ok(Message::Close(frame))) => {
+            pings.clear();
+            let event = CloseEvent::from_close_frame(frame.as_ref(), initiator());
+            let clean: bool = event.was_clean;
+            if close.notify(Some(event)) {
+                let code: Option<u16> = frame.as_ref().map(|f| u16::from(f.code));
+                metrics.on_close(code, clean);
+            }
+        }
+        Some(Err(..)) | None => {
+            pings.clear();
+            let event = CloseEvent::abnormal(initiator());
+            let clean: bool = event.was_clean;
+            if close.notify(Some(event)) {
+                metrics.on_close(None, clean);
+            }
+        }
+        Some(Ok(..)) => {}
+    }
+}
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::io;
+    use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+    use url::Url;
+
+    use super::*;
+
+    use crate::native::{self, Message};
+    use crate::test_util;
+    use crate::{shutdown_all, ConnectionMode, ShutdownReport};
+
+    #[tokio::test]
+    async fn test_read_deadline() {
+        let ((_tx, mut rx), (mut server_tx, _server_rx)) = test_util::pair().await.unwrap();
+
+        rx.set_read_deadline(Some(Instant::now() + Duration::from_millis(50)));
+        assert!(matches!(
+            rx.next().await,
+            Some(Err(native::Error::RecvTimeout))
+        ));
+
+        // Not terminated: a received message still wins over the passed deadline
+        server_tx.send(Message::text("hello")).await.unwrap();
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(rx.next().await.unwrap().unwrap(), Message::text("hello"));
+        assert!(matches!(
+            rx.next().await,
+            Some(Err(native::Error::RecvTimeout))
+        ));
+
+        rx.set_read_deadline(None);
+        server_tx.send(Message::text("world")).await.unwrap();
+        assert_eq!(rx.next().await.unwrap().unwrap(), Message::text("world"));
+    }
+
+    #[tokio::test]
+    async fn test_on_close() {
+        let ((_tx, mut rx), server) = test_util::pair().await.unwrap();
+
+        let codes = Arc::new(Mutex::new(Vec::new()));
+        let c = codes.clone();
+        rx.on_close(move |frame| c.lock().unwrap().push(frame.map(|f| u16::from(f.code))));
+        let c = codes.clone();
+        rx.on_close(move |frame| c.lock().unwrap().push(frame.map(|f| u16::from(f.code))));
+
+        let (reports, _) = tokio::join!(
+            shutdown_all([server], 4001, "bye", Duration::from_secs(10)),
+            async { while rx.next().await.is_some() {} }
+        );
+        assert_eq!(reports, vec![ShutdownReport::Clean]);
+
+        // Registered after the closure
+        let c = codes.clone();
+        rx.on_close(move |frame| c.lock().unwrap().push(frame.map(|f| u16::from(f.code))));
+
+        assert_eq!(*codes.lock().unwrap(), vec![Some(4001); 3]);
+    }
+
+    #[tokio::test]
+    async fn test_closed() {
+        let ((_tx, mut rx), server) = test_util::pair().await.unwrap();
+
+        let code = |frame: Option<CloseFrame<'static>>| frame.map(|f| u16::from(f.code));
+        let first = rx.closed();
+        let second = rx.closed();
+
+        let (_, _, first, second) = tokio::join!(
+            shutdown_all([server], 4001, "bye", Duration::from_secs(10)),
+            async { while rx.next().await.is_some() {} },
+            first,
+            second,
+        );
+        assert_eq!(code(first), Some(4001));
+        assert_eq!(code(second), Some(4001));
+
+        // Already closed
+        assert_eq!(code(rx.closed().await), Some(4001));
+    }
+
+    #[tokio::test]
+    async fn test_close_event() {
+        let ((_tx, mut rx), server) = test_util::pair().await.unwrap();
+        let closed = rx.close_event();
+        let (_, _, event) = tokio::join!(
+            shutdown_all([server], 4001, "bye", Duration::from_secs(10)),
+            async { while rx.next().await.is_some() {} },
+            closed,
+        );
+        assert_eq!(event.to_string(), "4001 Private Use: bye");
+        assert!(event.was_clean);
+        assert_eq!(event.initiated_by, Initiator::Server);
+
+        // Dropped without a close frame: synthesized as the browser does
+        let ((_tx, mut rx), server) = test_util::pair().await.unwrap();
+        drop(server);
+        while rx.next().await.is_some() {}
+        let event = rx.close_event().await;
+        assert!(event.is_abnormal());
+        assert!(!event.was_clean);
+        assert!(rx.closed().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_read_half_drop_policy_discard() {
+        let (client, server) = io::duplex(1024);
+        let url = Url::parse("ws://localhost").unwrap();
+        let opts = ConnectOptions::new().read_half_drop_policy(ReadHalfDropPolicy::DiscardIncoming);
+        let ((_tx, rx), (mut server_tx, mut server_rx)) = futures_util::future::try_join(
+            native::connect_with_stream(&url, client, Duration::from_secs(10), &opts),
+            native::accept(server),
+        )
+        .await
+        .unwrap();
+        drop(rx);
+
+        // Pings are still answered
+        server_tx.send(Message::text("discarded")).await.unwrap();
+        server_tx.send(Message::Ping(vec![7])).await.unwrap();
+        assert_eq!(
+            server_rx.next().await.unwrap().unwrap(),
+            Message::Pong(vec![7])
+        );
+
+        // The close handshake is completed
+        server_tx.send(Message::Close(None)).await.unwrap();
+        assert!(matches!(
+            server_rx.next().await.unwrap().unwrap(),
+            Message::Close(..)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_on_close_after_drop() {
+        let (client, server) = io::duplex(1024);
+        let url = Url::parse("ws://localhost").unwrap();
+        let opts = ConnectOptions::new().read_half_drop_policy(ReadHalfDropPolicy::DiscardIncoming);
+        let ((_tx, rx), (mut server_tx, _server_rx)) = futures_util::future::try_join(
+            native::connect_with_stream(&url, client, Duration::from_secs(10), &opts),
+            native::accept(server),
+        )
+        .await
+        .unwrap();
+
+        let closed = rx.closed();
+        drop(rx);
+
+        // Fired by the background reader, with the actual close frame
+        server_tx
+            .send(Message::Close(Some(CloseFrame {
+                code: 4001.into(),
+                reason: "bye".into(),
+            })))
+            .await
+            .unwrap();
+        let frame = time::timeout(Duration::from_secs(5), closed).await.unwrap();
+        assert_eq!(frame.map(|f| u16::from(f.code)), Some(4001));
+    }
+
+    #[tokio::test]
+    async fn test_read_half_drop_policy_close() {
+        let (client, server) = io::duplex(1024);
+        let url = Url::parse("ws://localhost").unwrap();
+        let opts =
+            ConnectOptions::new().read_half_drop_policy(ReadHalfDropPolicy::CloseConnection(4000));
+        let ((mut tx, rx), (_server_tx, mut server_rx)) = futures_util::future::try_join(
+            native::connect_with_stream(&url, client, Duration::from_secs(10), &opts),
+            native::accept(server),
+        )
+        .await
+        .unwrap();
+        drop(rx);
+
+        // Sent without using the sink
+        let msg = time::timeout(Duration::from_secs(5), server_rx.next())
+            .await
+            .unwrap();
+        assert!(matches!(
+            msg,
+            Some(Ok(Message::Close(Some(frame)))) if u16::from(frame.code) == 4000
+        ));
+
+        // The message can't be sent anymore
+        assert!(tx.send(Message::text("too late")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_half_drop_policy_stop() {
+        let server = test_util::MockServer::builder()
+            .send(Message::text("never read"))
+            .expect(|msg| msg == &Message::text("still writing"))
+            .start()
+            .await
+            .unwrap();
+
+        let (mut tx, rx) = native::connect(
+            server.url(),
+            ConnectionMode::Direct,
+            Duration::from_secs(10),
+        )
+        .await
+        .unwrap();
+        drop(rx);
+
+        tx.send(Message::text("still writing")).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
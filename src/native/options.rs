@@ -0,0 +1,556 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Connect options
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use url::Url;
+
+use super::error::Error;
+use super::tls::TlsVersion;
+use crate::metrics::{Metrics, MetricsSink};
+use crate::redact;
+
+/// Address family used to connect
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum AddressFamily {
+    /// Any family: the resolved addresses are tried in order
+    #[default]
+    Any,
+    /// IPv4 only
+    Ipv4,
+    /// IPv6 only
+    Ipv6,
+}
+
+impl AddressFamily {
+    #[inline]
+    pub(super) fn matches(&self, addr: &SocketAddr) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Ipv4 => addr.is_ipv4(),
+            Self::Ipv6 => addr.is_ipv6(),
+        }
+    }
+}
+
+/// What happens to the incoming data once the [`Stream`](super::Stream) is dropped
+/// while the [`Sink`](super::Sink) is kept
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum ReadHalfDropPolicy {
+    /// Keep reading in the background, discarding the messages
+    ///
+    /// Pings are still answered and the close handshake completed, so the connection stays
+    /// healthy for the writer.
+    DiscardIncoming,
+    /// Close the connection with a close code
+    ///
+    /// The close frame is sent right away by the background reader, ahead of the messages still
+    /// queued in the sink (that are discarded), and the following sends fail. The incoming messages
+    /// are discarded until the peer replies.
+    CloseConnection(u16),
+    /// Stop reading: the incoming data fills the transport buffers and the pings are unanswered
+    #[default]
+    StopReading,
+}
+
+/// Size limits of the incoming messages in force on a connection
+///
+/// `None` means no limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeLimits {
+    /// Max size of a message, in bytes
+    pub max_message_size: Option<usize>,
+    /// Max size of a single frame, in bytes
+    pub max_frame_size: Option<usize>,
+}
+
+/// Rewrite the URL before connecting (i.e. to resolve relay aliases)
+pub type UrlRewriter = Arc<dyn Fn(&Url) -> Url + Send + Sync>;
+
+#[derive(Clone, Default)]
+pub(super) struct Rewriter {
+    inner: Option<UrlRewriter>,
+}
+
+impl fmt::Debug for Rewriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Rewriter")
+            .field("inner", &self.inner.as_ref().map(|_| ".."))
+            .finish()
+    }
+}
+
+impl Rewriter {
+    /// The rewritten URL, `None` if there is no rewriter
+    #[inline]
+    pub(super) fn rewrite(&self, url: &Url) -> Option<Url> {
+        self.inner.as_ref().map(|f| f(url))
+    }
+}
+
+/// Effective options of a connection, after the defaults are applied
+///
+/// A read-only reflection of the [`ConnectOptions`], i.e. for logging.
+/// The sub-protocols are redacted from the `Debug` output.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ResolvedOptions {
+    /// Deadline for every read on the transport
+    pub read_timeout: Option<Duration>,
+    /// Deadline for every write on the transport
+    pub write_timeout: Option<Duration>,
+    /// Deadline for the DNS lookup
+    pub dns_timeout: Option<Duration>,
+    /// How long a ping waits for its pong
+    pub ping_timeout: Duration,
+    /// Max number of pings waiting for a pong
+    pub max_outstanding_pings: usize,
+    /// Size limits of the incoming messages
+    pub size_limits: SizeLimits,
+    /// Size of the coalescing write buffer, in bytes
+    pub write_buffer_size: usize,
+    /// Max size of the outgoing buffer, in bytes
+    pub max_write_buffer_size: usize,
+    /// TLS backend
+    pub tls_backend: &'static str,
+    /// TLS protocol versions allowed
+    pub tls_versions: Vec<TlsVersion>,
+    /// TLS server name override
+    pub server_name: Option<ServerName<'static>>,
+    /// Whether `permessage-deflate` compression is enabled (never, currently)
+    pub compression: bool,
+    /// Address family used to connect
+    pub address_family: AddressFamily,
+    /// Happy Eyeballs delay
+    pub happy_eyeballs: Option<Duration>,
+    /// Whether TCP Fast Open is enabled
+    #[cfg(target_os = "linux")]
+    pub tcp_fastopen: bool,
+    /// `User-Agent` handshake header
+    pub user_agent: Option<String>,
+    /// Sub-protocols requested, in order of preference
+    pub protocols: Vec<String>,
+    /// What happens to the incoming data once the stream is dropped
+    pub read_half_drop_policy: ReadHalfDropPolicy,
+}
+
+impl fmt::Debug for ResolvedOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = f.debug_struct("ResolvedOptions");
+        s.field("read_timeout", &self.read_timeout)
+            .field("write_timeout", &self.write_timeout)
+            .field("dns_timeout", &self.dns_timeout)
+            .field("ping_timeout", &self.ping_timeout)
+            .field("max_outstanding_pings", &self.max_outstanding_pings)
+            .field("size_limits", &self.size_limits)
+            .field("write_buffer_size", &self.write_buffer_size)
+            .field("max_write_buffer_size", &self.max_write_buffer_size)
+            .field("tls_backend", &self.tls_backend)
+            .field("tls_versions", &self.tls_versions)
+            .field("server_name", &self.server_name)
+            .field("compression", &self.compression)
+            .field("address_family", &self.address_family)
+            .field("happy_eyeballs", &self.happy_eyeballs);
+        #[cfg(target_os = "linux")]
+        s.field("tcp_fastopen", &self.tcp_fastopen);
+        s.field("user_agent", &self.user_agent)
+            .field("protocols", &redact::Protocols(&self.protocols))
+            .field("read_half_drop_policy", &self.read_half_drop_policy)
+            .finish()
+    }
+}
+
+/// Native connect options
+///
+/// The sub-protocols are redacted from the `Debug` output.
+#[derive(Clone)]
+pub struct ConnectOptions {
+    pub(super) read_timeout: Option<Duration>,
+    pub(super) write_timeout: Option<Duration>,
+    pub(super) deflate_dictionary: Option<Vec<u8>>,
+    pub(super) min_tls_version: Option<TlsVersion>,
+    pub(super) max_tls_version: Option<TlsVersion>,
+    pub(super) user_agent: Option<String>,
+    pub(super) protocols: Vec<String>,
+    pub(super) max_outstanding_pings: usize,
+    pub(super) ping_timeout: Duration,
+    pub(super) max_write_buffer_size: Option<usize>,
+    pub(super) max_message_size: Option<usize>,
+    pub(super) max_frame_size: Option<usize>,
+    pub(super) dns_timeout: Option<Duration>,
+    pub(super) address_family: AddressFamily,
+    pub(super) happy_eyeballs: Option<Duration>,
+    #[cfg(target_os = "linux")]
+    pub(super) tcp_fastopen: bool,
+    pub(super) read_half_drop_policy: ReadHalfDropPolicy,
+    pub(super) server_name: Option<ServerName<'static>>,
+    pub(super) metrics: Metrics,
+    pub(super) url_rewriter: Rewriter,
+    #[cfg(feature = "test-util")]
+    pub(super) masking_seed: Option<u64>,
+}
+
+impl fmt::Debug for ConnectOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = f.debug_struct("ConnectOptions");
+        s.field("read_timeout", &self.read_timeout)
+            .field("write_timeout", &self.write_timeout)
+            .field("deflate_dictionary", &self.deflate_dictionary)
+            .field("min_tls_version", &self.min_tls_version)
+            .field("max_tls_version", &self.max_tls_version)
+            .field("user_agent", &self.user_agent)
+            .field("protocols", &redact::Protocols(&self.protocols))
+            .field("max_outstanding_pings", &self.max_outstanding_pings)
+            .field("ping_timeout", &self.ping_timeout)
+            .field("max_write_buffer_size", &self.max_write_buffer_size)
+            .field("max_message_size", &self.max_message_size)
+            .field("max_frame_size", &self.max_frame_size)
+            .field("dns_timeout", &self.dns_timeout)
+            .field("address_family", &self.address_family)
+            .field("happy_eyeballs", &self.happy_eyeballs);
+        #[cfg(target_os = "linux")]
+        s.field("tcp_fastopen", &self.tcp_fastopen);
+        s.field("read_half_drop_policy", &self.read_half_drop_policy)
+            .field("server_name", &self.server_name)
+            .field("metrics", &self.metrics)
+            .field("url_rewriter", &self.url_rewriter);
+        #[cfg(feature = "test-util")]
+        s.field("masking_seed", &self.masking_seed);
+        s.finish()
+    }
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        let config = WebSocketConfig::default();
+        Self {
+            read_timeout: None,
+            write_timeout: None,
+            deflate_dictionary: None,
+            min_tls_version: None,
+            max_tls_version: None,
+            user_agent: None,
+            protocols: Vec::new(),
+            max_outstanding_pings: 16,
+            ping_timeout: Duration::from_secs(30),
+            max_write_buffer_size: None,
+            max_message_size: config.max_message_size,
+            max_frame_size: config.max_frame_size,
+            dns_timeout: None,
+            address_family: AddressFamily::Any,
+            happy_eyeballs: Some(Duration::from_millis(250)),
+            #[cfg(target_os = "linux")]
+            tcp_fastopen: false,
+            read_half_drop_policy: ReadHalfDropPolicy::StopReading,
+            server_name: None,
+            metrics: Metrics::default(),
+            url_rewriter: Rewriter::default(),
+            #[cfg(feature = "test-util")]
+            masking_seed: None,
+        }
+    }
+}
+
+impl ConnectOptions {
+    /// New default options
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set a deadline for every read on the underlying transport (default: none)
+    ///
+    /// This is a **TCP-level** timeout, not a WebSocket-level one: if the peer doesn't send
+    /// any byte within the deadline, the stream yields [`Error::ReadTimeout`](super::Error::ReadTimeout).
+    /// An idle connection without pings will hit this timeout too.
+    #[inline]
+    pub fn read_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    /// Set a deadline for every write on the underlying transport (default: none)
+    ///
+    /// This is a **TCP-level** timeout, not a WebSocket-level one: if the transport doesn't
+    /// accept any byte within the deadline, the sink returns [`Error::WriteTimeout`](super::Error::WriteTimeout).
+    #[inline]
+    pub fn write_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.write_timeout = timeout;
+        self
+    }
+
+    /// Set a preset dictionary for the `permessage-deflate` context (default: none)
+    ///
+    /// **The current backend doesn't support `permessage-deflate`:** connecting with a
+    /// dictionary set returns [`Error::Unsupported`](super::Error::Unsupported).
+    #[inline]
+    pub fn deflate_dictionary(mut self, dictionary: Vec<u8>) -> Self {
+        self.deflate_dictionary = Some(dictionary);
+        self
+    }
+
+    /// Set the minimum TLS protocol version (default: backend default)
+    ///
+    /// If the server can't satisfy it, connect returns [`Error::TlsVersionUnsupported`](super::Error::TlsVersionUnsupported).
+    #[inline]
+    pub fn min_tls_version(mut self, version: TlsVersion) -> Self {
+        self.min_tls_version = Some(version);
+        self
+    }
+
+    /// Set the maximum TLS protocol version (default: backend default)
+    ///
+    /// If the server can't satisfy it, connect returns [`Error::TlsVersionUnsupported`](super::Error::TlsVersionUnsupported).
+    #[inline]
+    pub fn max_tls_version(mut self, version: TlsVersion) -> Self {
+        self.max_tls_version = Some(version);
+        self
+    }
+
+    /// Set the TLS server name, instead of deriving it from the URL host (default: none)
+    ///
+    /// Useful when the URL-derived name is wrong: i.e. an IP address SNI or an internationalized domain.
+    /// The certificate is verified against this name.
+    #[inline]
+    pub fn server_name_override(mut self, name: Option<ServerName<'static>>) -> Self {
+        self.server_name = name;
+        self
+    }
+
+    /// Set the `User-Agent` handshake header (default: none)
+    #[inline]
+    pub fn user_agent<S>(mut self, user_agent: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.user_agent = Some(user_agent.into());
+        self
+    }
+
+    /// Request the sub-protocols, in order of preference (default: none)
+    ///
+    /// They are sent in the `Sec-WebSocket-Protocol` handshake header in the given order.
+    /// The server must select one of them ([`Error::ProtocolNotNegotiated`](super::Error::ProtocolNotNegotiated)
+    /// otherwise): check [`Stream::protocol`](super::Stream::protocol).
+    #[inline]
+    pub fn protocols<I, S>(mut self, protocols: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.protocols = protocols.into_iter().map(|p| p.into()).collect();
+        self
+    }
+
+    /// Set the max number of pings sent with [`Sink::send_ping`](super::Sink::send_ping)
+    /// waiting for a pong (default: 16)
+    #[inline]
+    pub fn max_outstanding_pings(mut self, limit: usize) -> Self {
+        self.max_outstanding_pings = limit;
+        self
+    }
+
+    /// Set the max size of the outgoing buffer, in bytes (default: unlimited)
+    ///
+    /// A slow or stuck peer can't make the outgoing data buffer grow past this limit:
+    /// a send that would exceed it fails with [`Error::SendBufferFull`](super::Error::SendBufferFull).
+    /// The coalescing write buffer is shrunk below the limit if needed.
+    #[inline]
+    pub fn max_write_buffer_size(mut self, size: usize) -> Self {
+        self.max_write_buffer_size = Some(size);
+        self
+    }
+
+    /// Set the max size of an incoming message, in bytes (default: 64 MiB)
+    ///
+    /// `None` means no limit.
+    #[inline]
+    pub fn max_message_size(mut self, size: Option<usize>) -> Self {
+        self.max_message_size = size;
+        self
+    }
+
+    /// Set the max size of a single incoming frame, in bytes (default: 16 MiB)
+    ///
+    /// A larger incoming frame is rejected before being buffered. `None` means no limit.
+    /// Connecting with a zero size returns [`Error::InvalidConfig`](super::Error::InvalidConfig).
+    #[inline]
+    pub fn max_frame_size(mut self, size: Option<usize>) -> Self {
+        self.max_frame_size = size;
+        self
+    }
+
+    /// Set a deadline for the DNS lookup of the host (default: none)
+    ///
+    /// If the lookup takes longer, connect returns [`Error::DnsTimeout`](super::Error::DnsTimeout).
+    /// Also bounds the lookup of a SOCKS5 proxy given by hostname (`ProxyAddr::Host`): the error
+    /// is then wrapped in `Error::ProxyResolve`.
+    /// The target host is resolved remotely with a proxy or Tor, so it isn't affected there.
+    #[inline]
+    pub fn dns_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.dns_timeout = timeout;
+        self
+    }
+
+    /// Only connect to the addresses of an [`AddressFamily`] (default: any)
+    ///
+    /// The resolved addresses of the other family are discarded: if none is left,
+    /// connect returns [`Error::NoAddressFamily`](super::Error::NoAddressFamily).
+    /// Also filters the addresses of a SOCKS5 proxy given by hostname (`ProxyAddr::Host`), the error
+    /// being then wrapped in `Error::ProxyResolve`. The target host is resolved remotely with a proxy
+    /// or Tor, so it isn't affected there.
+    #[inline]
+    pub fn address_family(mut self, family: AddressFamily) -> Self {
+        self.address_family = family;
+        self
+    }
+
+    /// Race the IPv6 and IPv4 addresses of the host, following Happy Eyeballs (RFC 8305) (default: 250 ms)
+    ///
+    /// The IPv6 addresses are tried first; if none succeeds within `delay` (RFC 8305 suggests 250 ms),
+    /// the IPv4 ones are tried in parallel. The first established connection wins and the other attempt
+    /// is cancelled. If all the attempts fail, connect returns [`Error::NoRoute`](super::Error::NoRoute).
+    /// With `None`, the addresses are tried one after the other, in the resolved order.
+    /// Only applies to [`ConnectionMode::Direct`](crate::ConnectionMode::Direct).
+    #[inline]
+    pub fn happy_eyeballs(mut self, delay: Option<Duration>) -> Self {
+        self.happy_eyeballs = delay;
+        self
+    }
+
+    /// Enable TCP Fast Open (RFC 7413) (default: disabled)
+    ///
+    /// The HTTP upgrade request is sent with the SYN, saving a round trip to the servers that
+    /// support it (after a first connection, to get the cookie). The kernel falls back to
+    /// a regular handshake otherwise. Takes precedence over [`ConnectOptions::happy_eyeballs`]:
+    /// the connection is only established on the first write, so there is nothing to race.
+    /// Only applies to [`ConnectionMode::Direct`](crate::ConnectionMode::Direct).
+    ///
+    /// Linux only (4.11+): the other platforms require a dedicated send call, that tokio doesn't expose.
+    #[inline]
+    #[cfg(target_os = "linux")]
+    pub fn tcp_fastopen(mut self, enable: bool) -> Self {
+        self.tcp_fastopen = enable;
+        self
+    }
+
+    /// Set what happens to the incoming data once the [`Stream`](super::Stream) is dropped
+    /// while the [`Sink`](super::Sink) is kept (default: stop reading)
+    ///
+    /// Check [`ReadHalfDropPolicy`].
+    #[inline]
+    pub fn read_half_drop_policy(mut self, policy: ReadHalfDropPolicy) -> Self {
+        self.read_half_drop_policy = policy;
+        self
+    }
+
+    /// Report the metrics of the connection to a [`MetricsSink`] (default: the global sink, if any)
+    ///
+    /// Check the [`metrics`](crate::metrics) module.
+    #[inline]
+    pub fn metrics_sink(mut self, sink: Arc<dyn MetricsSink>) -> Self {
+        self.metrics = Metrics::new(sink);
+        self
+    }
+
+    /// Rewrite the URL before connecting (default: none)
+    ///
+    /// The handshake is sent to the rewritten URL, which is the one reported by [`Stream::url`](super::Stream::url).
+    /// The checks on the URL (i.e. the allowed hosts of a Cloudflare Tunnel, the origin of the redirects)
+    /// apply to the rewritten one.
+    /// Applied to the connections opened by the crate, not to the provided transports
+    /// (i.e. [`connect_with_stream`](super::connect_with_stream)). The redirect targets aren't rewritten.
+    #[inline]
+    pub fn url_rewriter(mut self, rewriter: UrlRewriter) -> Self {
+        self.url_rewriter = Rewriter {
+            inner: Some(rewriter),
+        };
+        self
+    }
+
+    /// Draw the masking keys of the sent frames from a deterministic `seed` (default: none)
+    ///
+    /// The same messages then give the same bytes on the wire, i.e. for byte-exact assertions
+    /// against a captured expectation. Otherwise each frame is masked with a key from a secure RNG,
+    /// as required by RFC 6455: predictable keys defeat the masking, so never use this outside tests.
+    #[inline]
+    #[cfg(feature = "test-util")]
+    pub fn masking_seed(mut self, seed: Option<u64>) -> Self {
+        self.masking_seed = seed;
+        self
+    }
+
+    /// Set how long a [`Sink::send_ping`](super::Sink::send_ping) waits for its pong (default: 30 secs)
+    #[inline]
+    pub fn ping_timeout(mut self, timeout: Duration) -> Self {
+        self.ping_timeout = timeout;
+        self
+    }
+
+    /// Reject the invalid combinations of options
+    pub(super) fn validate(&self) -> Result<(), Error> {
+        if self.max_frame_size == Some(0) {
+            return Err(Error::InvalidConfig {
+                reason: "max_frame_size cannot be zero",
+            });
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn resolve(&self) -> ResolvedOptions {
+        let config: WebSocketConfig = self.ws_config();
+        ResolvedOptions {
+            read_timeout: self.read_timeout,
+            write_timeout: self.write_timeout,
+            dns_timeout: self.dns_timeout,
+            ping_timeout: self.ping_timeout,
+            max_outstanding_pings: self.max_outstanding_pings,
+            size_limits: self.size_limits(),
+            write_buffer_size: config.write_buffer_size,
+            max_write_buffer_size: config.max_write_buffer_size,
+            tls_backend: "rustls",
+            tls_versions: TlsVersion::allowed(self.min_tls_version, self.max_tls_version),
+            server_name: self.server_name.clone(),
+            compression: false,
+            address_family: self.address_family,
+            happy_eyeballs: self.happy_eyeballs,
+            #[cfg(target_os = "linux")]
+            tcp_fastopen: self.tcp_fastopen,
+            user_agent: self.user_agent.clone(),
+            protocols: self.protocols.clone(),
+            read_half_drop_policy: self.read_half_drop_policy,
+        }
+    }
+
+    #[inline]
+    pub(super) fn size_limits(&self) -> SizeLimits {
+        SizeLimits {
+            max_message_size: self.max_message_size,
+            max_frame_size: self.max_frame_size,
+        }
+    }
+
+    pub(super) fn ws_config(&self) -> WebSocketConfig {
+        let mut config = WebSocketConfig {
+            max_message_size: self.max_message_size,
+            max_frame_size: self.max_frame_size,
+            ..Default::default()
+        };
+
+        if let Some(max) = self.max_write_buffer_size {
+            // The max must be greater than the coalescing buffer size
+            let max: usize = max.max(1);
+            config.max_write_buffer_size = max;
+            config.write_buffer_size = config.write_buffer_size.min(max - 1);
+        }
+
+        config
+    }
+}
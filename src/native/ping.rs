@@ -0,0 +1,244 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Correlated pings
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::sync::oneshot;
+use tokio::time::{self, Instant, Sleep};
+use tokio_tungstenite::tungstenite::Error as WsError;
+
+use super::Error;
+
+#[derive(Debug)]
+struct Outstanding {
+    /// Id of the ticket: a payload can be reused once resolved
+    id: u64,
+    sent_at: Instant,
+    tx: oneshot::Sender<Duration>,
+}
+
+/// Outstanding pings, keyed by payload
+#[derive(Debug)]
+pub(crate) struct PingTracker {
+    outstanding: Mutex<HashMap<Vec<u8>, Outstanding>>,
+    next_id: AtomicU64,
+    limit: usize,
+    timeout: Duration,
+}
+
+impl PingTracker {
+    pub(crate) fn new(limit: usize, timeout: Duration) -> Self {
+        Self {
+            outstanding: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            limit,
+            timeout,
+        }
+    }
+
+    #[inline]
+    fn lock(&self) -> MutexGuard<'_, HashMap<Vec<u8>, Outstanding>> {
+        self.outstanding.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Register an outgoing ping
+    pub(crate) fn register(self: &Arc<Self>, payload: Vec<u8>) -> Result<PingTicket, Error> {
+        let mut outstanding = self.lock();
+
+        if outstanding.contains_key(&payload) {
+            return Err(Error::DuplicatePing);
+        }
+
+        if outstanding.len() >= self.limit {
+            return Err(Error::TooManyOutstandingPings { limit: self.limit });
+        }
+
+        let id: u64 = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        outstanding.insert(
+            payload.clone(),
+            Outstanding {
+                id,
+                sent_at: Instant::now(),
+                tx,
+            },
+        );
+
+        Ok(PingTicket {
+            id,
+            payload,
+            rx,
+            sleep: Box::pin(time::sleep(self.timeout)),
+            tracker: self.clone(),
+        })
+    }
+
+    /// Resolve the ping matching a received pong. Unsolicited pongs are ignored.
+    pub(super) fn resolve(&self, payload: &[u8]) {
+        if let Some(ping) = self.lock().remove(payload) {
+            let _ = ping.tx.send(ping.sent_at.elapsed());
+        }
+    }
+
+    /// Remove the ping of a ticket, unless already replaced by a newer one with the same payload
+    fn remove(&self, payload: &[u8], id: u64) {
+        let mut outstanding = self.lock();
+        if outstanding.get(payload).map(|ping| ping.id) == Some(id) {
+            outstanding.remove(payload);
+        }
+    }
+
+    /// Fail all the outstanding pings
+    pub(super) fn clear(&self) {
+        self.lock().clear();
+    }
+}
+
+/// Future resolving with the round-trip time of a ping, created with
+/// [`Sink::send_ping`](super::Sink::send_ping).
+///
+/// Fail with [`Error::Timeout`] if the pong doesn't arrive in time, or with
+/// [`WsError::ConnectionClosed`] if the connection closes first.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct PingTicket {
+    id: u64,
+    payload: Vec<u8>,
+    rx: oneshot::Receiver<Duration>,
+    sleep: Pin<Box<Sleep>>,
+    tracker: Arc<PingTracker>,
+}
+
+impl PingTicket {
+    /// Ping payload
+    #[inline]
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+}
+
+impl Future for PingTicket {
+    type Output = Result<Duration, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match Pin::new(&mut self.rx).poll(cx) {
+            Poll::Ready(Ok(rtt)) => return Poll::Ready(Ok(rtt)),
+            Poll::Ready(Err(..)) => return Poll::Ready(Err(WsError::ConnectionClosed.into())),
+            Poll::Pending => {}
+        }
+
+        if self.sleep.as_mut().poll(cx).is_ready() {
+            self.tracker.remove(&self.payload, self.id);
+            return Poll::Ready(Err(Error::Timeout));
+        }
+
+        Poll::Pending
+    }
+}
+
+impl Drop for PingTicket {
+    fn drop(&mut self) {
+        // Free the slot if still outstanding
+        self.tracker.remove(&self.payload, self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "test-util")]
+    use futures_util::{SinkExt, StreamExt};
+
+    use super::*;
+    #[cfg(feature = "test-util")]
+    use crate::native::Message;
+    #[cfg(feature = "test-util")]
+    use crate::test_util;
+
+    #[tokio::test]
+    async fn test_reused_payload() {
+        let tracker = Arc::new(PingTracker::new(8, Duration::from_secs(30)));
+        let old = tracker.register(vec![1]).unwrap();
+        tracker.resolve(&[1]);
+
+        // The payload is free again: the old ticket must not remove the new ping
+        let new = tracker.register(vec![1]).unwrap();
+        drop(old);
+        tracker.resolve(&[1]);
+        assert!(new.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_too_many_outstanding_pings() {
+        let tracker = Arc::new(PingTracker::new(2, Duration::from_secs(30)));
+        let first = tracker.register(vec![1]).unwrap();
+        let _second = tracker.register(vec![2]).unwrap();
+        assert!(matches!(
+            tracker.register(vec![3]),
+            Err(Error::TooManyOutstandingPings { limit: 2 })
+        ));
+
+        // Dropping a ticket frees its slot
+        drop(first);
+        assert!(tracker.register(vec![3]).is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ping_timeout() {
+        let tracker = Arc::new(PingTracker::new(8, Duration::from_secs(5)));
+        let ticket = tracker.register(vec![1]).unwrap();
+        assert!(matches!(ticket.await, Err(Error::Timeout)));
+
+        // The expired ping is no longer outstanding
+        assert!(tracker.register(vec![1]).is_ok());
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "test-util")]
+    async fn test_correlated_pings() {
+        let ((mut tx, mut rx), (mut server_tx, server_rx)) = test_util::pair().await.unwrap();
+
+        let first = tx.send_ping(vec![1]).await.unwrap();
+        let second = tx.send_ping(vec![2]).await.unwrap();
+        assert!(matches!(
+            tx.send_ping(vec![1]).await,
+            Err(Error::DuplicatePing)
+        ));
+
+        // Unsolicited pong, received before the replies
+        server_tx.send(Message::Pong(vec![3])).await.unwrap();
+        tokio::spawn(test_util::echo(server_tx, server_rx));
+
+        // Drive the stream to observe the pongs
+        let reader = tokio::spawn(async move { while rx.next().await.is_some() {} });
+
+        assert!(first.await.is_ok());
+        assert!(second.await.is_ok());
+        reader.abort();
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "test-util")]
+    async fn test_ping_connection_closed() {
+        let ((mut tx, mut rx), server) = test_util::pair().await.unwrap();
+
+        let ticket = tx.send_ping(vec![1]).await.unwrap();
+
+        // The server goes away without answering
+        drop(server);
+        while rx.next().await.is_some() {}
+
+        let res = time::timeout(Duration::from_secs(5), ticket).await.unwrap();
+        assert!(matches!(
+            res.unwrap_err().as_ws(),
+            Some(WsError::ConnectionClosed)
+        ));
+    }
+}
@@ -0,0 +1,542 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Connection pool
+//!
+//! Components connecting to the same URL through the same [`ConnectionMode`] share the same
+//! connection. Nobody owns the raw stream: a background task reads it and broadcasts every message
+//! to all the handles, while sends go through a cloneable [`PoolSender`].
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use async_utility::thread;
+use futures_util::{SinkExt, StreamExt};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::time::Instant;
+use url::Url;
+
+use super::{ConnectOptions, Error, Message, Sink, Stream};
+use crate::ConnectionMode;
+
+type Key = (Url, ConnectionMode);
+
+/// Pool options
+#[derive(Debug, Clone)]
+pub struct PoolOptions {
+    max_connections_per_key: usize,
+    idle_timeout: Option<Duration>,
+    ping_before_reuse: bool,
+    capacity: usize,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        Self {
+            max_connections_per_key: 1,
+            idle_timeout: Some(Duration::from_secs(60)),
+            ping_before_reuse: false,
+            capacity: 1024,
+        }
+    }
+}
+
+impl PoolOptions {
+    /// New default options
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the max number of connections for the same URL and mode (default: 1)
+    ///
+    /// An unused connection is always reused first. When all are in use, a new one is dialed
+    /// until the limit is reached, then the least used one is shared.
+    #[inline]
+    pub fn max_connections_per_key(mut self, max: usize) -> Self {
+        self.max_connections_per_key = max.max(1);
+        self
+    }
+
+    /// Set after how long an unused connection is closed (default: 60 secs)
+    ///
+    /// Idle connections are evicted on [`WsPool::get`] and [`WsPool::evict_idle`].
+    #[inline]
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Ping a connection and wait for the pong before reusing it (default: false)
+    ///
+    /// The pong timeout is the [`ConnectOptions::ping_timeout`] of the connection.
+    #[inline]
+    pub fn ping_before_reuse(mut self, ping: bool) -> Self {
+        self.ping_before_reuse = ping;
+        self
+    }
+
+    /// Set how many incoming messages are buffered for each handle (default: 1024)
+    ///
+    /// A handle falling behind by more than this skips the oldest messages
+    /// (check [`PooledConnection::recv`]).
+    #[inline]
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+}
+
+/// Connection shared by the handles
+///
+/// Closed once dropped, i.e. when it's neither in the pool nor used by a handle.
+struct Pooled {
+    sink: Arc<AsyncMutex<Sink>>,
+    /// Kept to subscribe new handles
+    messages: broadcast::Receiver<Message>,
+    /// Set when the read task ends
+    closed: Arc<AtomicBool>,
+    handles: AtomicUsize,
+    idle_since: Mutex<Instant>,
+    ping_counter: AtomicU64,
+}
+
+impl Pooled {
+    fn spawn(tx: Sink, mut rx: Stream, capacity: usize) -> Self {
+        let (sender, messages) = broadcast::channel(capacity);
+        let closed = Arc::new(AtomicBool::new(false));
+
+        let task_closed = closed.clone();
+        let _ = thread::spawn(async move {
+            while let Some(Ok(msg)) = rx.next().await {
+                // No handle is listening right now
+                let _ = sender.send(msg);
+            }
+
+            task_closed.store(true, Ordering::SeqCst);
+        });
+
+        Self {
+            sink: Arc::new(AsyncMutex::new(tx)),
+            messages,
+            closed,
+            handles: AtomicUsize::new(0),
+            idle_since: Mutex::new(Instant::now()),
+            ping_counter: AtomicU64::new(0),
+        }
+    }
+
+    #[inline]
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    #[inline]
+    fn handles(&self) -> usize {
+        self.handles.load(Ordering::SeqCst)
+    }
+
+    fn is_expired(&self, idle_timeout: Duration) -> bool {
+        let idle_since = self.idle_since.lock().expect("idle mutex poisoned");
+        self.handles() == 0 && idle_since.elapsed() >= idle_timeout
+    }
+
+    /// Check if the peer answers a ping
+    async fn ping(&self) -> bool {
+        let payload = self
+            .ping_counter
+            .fetch_add(1, Ordering::SeqCst)
+            .to_be_bytes();
+        let ticket = self.sink.lock().await.send_ping(payload.to_vec()).await;
+        match ticket {
+            Ok(ticket) => ticket.await.is_ok(),
+            Err(..) => false,
+        }
+    }
+
+    async fn close(&self) {
+        // The read task ends when the peer replies
+        let _ = self.sink.lock().await.close().await;
+    }
+}
+
+impl Drop for Pooled {
+    fn drop(&mut self) {
+        if self.is_closed() {
+            return;
+        }
+
+        let sink = self.sink.clone();
+        let _ = thread::spawn(async move {
+            let _ = sink.lock().await.close().await;
+        });
+    }
+}
+
+/// Keep a connection in use until dropped
+struct Lease {
+    pooled: Arc<Pooled>,
+}
+
+impl Lease {
+    fn new(pooled: Arc<Pooled>) -> Self {
+        pooled.handles.fetch_add(1, Ordering::SeqCst);
+        Self { pooled }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        let mut idle_since = self.pooled.idle_since.lock().expect("idle mutex poisoned");
+        if self.pooled.handles.fetch_sub(1, Ordering::SeqCst) == 1 {
+            *idle_since = Instant::now();
+        }
+    }
+}
+
+/// Connections to the same key
+#[derive(Default)]
+struct Slot {
+    list: Mutex<Vec<Arc<Pooled>>>,
+    /// Held while dialing, so the same connection is never dialed twice
+    dial: AsyncMutex<()>,
+}
+
+impl Slot {
+    #[inline]
+    fn list(&self) -> MutexGuard<'_, Vec<Arc<Pooled>>> {
+        self.list.lock().expect("pool mutex poisoned")
+    }
+}
+
+/// Connection pool keyed by URL and [`ConnectionMode`]
+///
+/// Cheap to clone: clones share the same connections. Once all the clones are dropped, the
+/// unused connections are closed, and the others as soon as their last handle is dropped.
+#[derive(Clone)]
+pub struct WsPool {
+    connections: Arc<Mutex<HashMap<Key, Arc<Slot>>>>,
+    opts: PoolOptions,
+}
+
+impl fmt::Debug for WsPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WsPool").field("opts", &self.opts).finish()
+    }
+}
+
+impl Default for WsPool {
+    #[inline]
+    fn default() -> Self {
+        Self::new(PoolOptions::default())
+    }
+}
+
+impl WsPool {
+    /// New empty pool
+    pub fn new(opts: PoolOptions) -> Self {
+        Self {
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            opts,
+        }
+    }
+
+    /// Get a handle to a healthy connection to `url`, dialing a new one if needed
+    ///
+    /// `timeout` and `opts` are only used to dial a new connection. Concurrent calls for the same
+    /// URL and mode wait for each other's dial, so the same connection is never dialed twice:
+    /// the other keys aren't affected.
+    pub async fn get(
+        &self,
+        url: &Url,
+        mode: ConnectionMode,
+        timeout: Duration,
+        opts: &ConnectOptions,
+    ) -> Result<PooledConnection, Error> {
+        self.evict_idle().await;
+
+        let slot: Arc<Slot> = self
+            .connections()
+            .entry((url.clone(), mode.clone()))
+            .or_default()
+            .clone();
+
+        if let Some(pooled) = self.reuse(&slot).await {
+            return Ok(PooledConnection::new(pooled));
+        }
+
+        let _dialing = slot.dial.lock().await;
+
+        // Dialed by a concurrent call in the meantime
+        if let Some(pooled) = self.reuse(&slot).await {
+            return Ok(PooledConnection::new(pooled));
+        }
+
+        let (tx, rx) = super::connect_with_options(url, mode, timeout, opts).await?;
+        let pooled = Arc::new(Pooled::spawn(tx, rx, self.opts.capacity));
+        slot.list().push(pooled.clone());
+        Ok(PooledConnection::new(pooled))
+    }
+
+    /// Close the connections unused for longer than [`PoolOptions::idle_timeout`]
+    pub async fn evict_idle(&self) {
+        let idle_timeout: Duration = match self.opts.idle_timeout {
+            Some(timeout) => timeout,
+            None => return,
+        };
+
+        let mut expired: Vec<Arc<Pooled>> = Vec::new();
+
+        self.connections().retain(|_, slot| {
+            let mut list = slot.list();
+            list.retain(|pooled| {
+                if pooled.is_expired(idle_timeout) {
+                    expired.push(pooled.clone());
+                    false
+                } else {
+                    true
+                }
+            });
+
+            // Unless a concurrent call is using it
+            !list.is_empty() || Arc::strong_count(slot) > 1
+        });
+
+        for pooled in expired {
+            pooled.close().await;
+        }
+    }
+
+    #[inline]
+    fn connections(&self) -> MutexGuard<'_, HashMap<Key, Arc<Slot>>> {
+        self.connections.lock().expect("pool mutex poisoned")
+    }
+
+    /// Find a healthy connection to reuse, without holding any lock across the ping
+    async fn reuse(&self, slot: &Slot) -> Option<Arc<Pooled>> {
+        loop {
+            let pooled: Arc<Pooled> = {
+                let mut list = slot.list();
+
+                // Drop the connections closed by the peer
+                list.retain(|pooled| !pooled.is_closed());
+
+                self.candidate(&list)?
+            };
+
+            if !self.opts.ping_before_reuse || pooled.ping().await {
+                return Some(pooled);
+            }
+
+            slot.list().retain(|p| !Arc::ptr_eq(p, &pooled));
+            pooled.close().await;
+        }
+    }
+
+    /// Pick the connection to reuse, if any
+    fn candidate(&self, list: &[Arc<Pooled>]) -> Option<Arc<Pooled>> {
+        if let Some(unused) = list.iter().find(|pooled| pooled.handles() == 0) {
+            return Some(unused.clone());
+        }
+
+        if list.len() < self.opts.max_connections_per_key {
+            return None;
+        }
+
+        list.iter().min_by_key(|pooled| pooled.handles()).cloned()
+    }
+}
+
+/// Cloneable sender of a pooled connection
+///
+/// The connection is kept in use while a sender or the [`PooledConnection`] is alive.
+#[derive(Clone)]
+pub struct PoolSender {
+    lease: Arc<Lease>,
+}
+
+impl fmt::Debug for PoolSender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PoolSender").finish()
+    }
+}
+
+impl PoolSender {
+    /// Send a message
+    ///
+    /// The connection is shared: closing it affects all the handles.
+    pub async fn send(&self, msg: Message) -> Result<(), Error> {
+        self.lease.pooled.sink.lock().await.send(msg).await
+    }
+
+    /// Check if the connection has been closed
+    #[inline]
+    pub fn is_closed(&self) -> bool {
+        self.lease.pooled.is_closed()
+    }
+}
+
+/// Handle to a pooled connection
+///
+/// Every handle receives all the messages read after it was handed out,
+/// control frames included.
+pub struct PooledConnection {
+    sender: PoolSender,
+    receiver: broadcast::Receiver<Message>,
+}
+
+impl fmt::Debug for PooledConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PooledConnection").finish()
+    }
+}
+
+impl PooledConnection {
+    fn new(pooled: Arc<Pooled>) -> Self {
+        let receiver = pooled.messages.resubscribe();
+        Self {
+            sender: PoolSender {
+                lease: Arc::new(Lease::new(pooled)),
+            },
+            receiver,
+        }
+    }
+
+    /// Get a sender for this connection
+    #[inline]
+    pub fn sender(&self) -> PoolSender {
+        self.sender.clone()
+    }
+
+    /// Send a message
+    #[inline]
+    pub async fn send(&self, msg: Message) -> Result<(), Error> {
+        self.sender.send(msg).await
+    }
+
+    /// Receive the next message
+    ///
+    /// Return [`RecvError::Lagged`] if this handle fell behind by more than
+    /// [`PoolOptions::capacity`] messages (the oldest ones are skipped), and
+    /// [`RecvError::Closed`] once the connection is closed and all the messages are read.
+    pub async fn recv(&mut self) -> Result<Message, RecvError> {
+        self.receiver.recv().await
+    }
+}
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::native;
+    use crate::test_util;
+
+    #[tokio::test]
+    async fn test_shared_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("ws://{}", listener.local_addr().unwrap())).unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            while let Ok((conn, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let (tx, rx) = native::accept(conn).await.unwrap();
+                tokio::spawn(test_util::echo(tx, rx));
+            }
+        });
+
+        let pool = WsPool::default();
+        let opts = ConnectOptions::default();
+        let timeout = Duration::from_secs(10);
+        let mut first = pool
+            .get(&url, ConnectionMode::Direct, timeout, &opts)
+            .await
+            .unwrap();
+        let mut second = pool
+            .get(&url, ConnectionMode::Direct, timeout, &opts)
+            .await
+            .unwrap();
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+        first.send(Message::text("hello")).await.unwrap();
+        assert_eq!(first.recv().await.unwrap(), Message::text("hello"));
+        assert_eq!(second.recv().await.unwrap(), Message::text("hello"));
+    }
+
+    #[tokio::test]
+    async fn test_dial_doesnt_block_other_keys() {
+        // Accepts the TCP connection, but never answers the handshake
+        let stalled = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stalled_url = Url::parse(&format!("ws://{}", stalled.local_addr().unwrap())).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("ws://{}", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            let (conn, _) = listener.accept().await.unwrap();
+            let (tx, rx) = native::accept(conn).await.unwrap();
+            test_util::echo(tx, rx).await.unwrap();
+        });
+
+        let pool = WsPool::default();
+        let opts = ConnectOptions::default();
+
+        let stalled_pool = pool.clone();
+        let stalled_opts = opts.clone();
+        tokio::spawn(async move {
+            let _ = stalled_pool
+                .get(
+                    &stalled_url,
+                    ConnectionMode::Direct,
+                    Duration::from_secs(60),
+                    &stalled_opts,
+                )
+                .await;
+        });
+        let _accepted = stalled.accept().await.unwrap();
+
+        let get = pool.get(&url, ConnectionMode::Direct, Duration::from_secs(10), &opts);
+        let conn = tokio::time::timeout(Duration::from_secs(5), get).await;
+        assert!(conn.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_close_on_drop() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("ws://{}", listener.local_addr().unwrap())).unwrap();
+
+        let server = tokio::spawn(async move {
+            let (conn, _) = listener.accept().await.unwrap();
+            let (_tx, mut rx) = native::accept(conn).await.unwrap();
+            rx.next().await
+        });
+
+        let pool = WsPool::default();
+        let conn = pool
+            .get(
+                &url,
+                ConnectionMode::Direct,
+                Duration::from_secs(10),
+                &ConnectOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        // Still in use by the handle
+        drop(pool);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!server.is_finished());
+
+        drop(conn);
+        let msg = tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(msg, Some(Ok(Message::Close(..)))));
+    }
+}
@@ -0,0 +1,405 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Priority sink
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+
+use futures_core::ready;
+use futures_sink::Sink as SinkTrait;
+use futures_util::future;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::OpCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::Message;
+#[cfg(feature = "tracing")]
+use tracing::Level;
+
+use super::ping::PingTracker;
+#[cfg(target_os = "linux")]
+use super::sendq::SendQueue;
+#[cfg(feature = "tracing")]
+use super::traffic::{self, Direction};
+
+/// Max number of messages queued before `poll_ready` starts pushing them to the inner sink
+const MAX_QUEUED_MESSAGES: usize = 32;
+
+/// Close state shared by the halves of the connection
+///
+/// Also lets the read half send a close frame on its own, once dropped.
+#[derive(Default)]
+pub(crate) struct CloseRequest {
+    /// A close frame was sent: we initiated the close (unless replying to the peer)
+    sent: AtomicBool,
+    sink: Option<Arc<dyn SendClose>>,
+}
+
+impl fmt::Debug for CloseRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CloseRequest")
+            .field("sent", &self.sent)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CloseRequest {
+    #[inline]
+    pub(crate) fn new<S>(sink: &SharedSink<S>) -> Self
+    where
+        S: SinkTrait<Message> + Send + Unpin + 'static,
+    {
+        Self {
+            sent: AtomicBool::new(false),
+            sink: Some(sink.inner.clone()),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn is_sent(&self) -> bool {
+        self.sent.load(Ordering::SeqCst)
+    }
+
+    /// Send a close frame with `code` through the shared sink, ahead of the frames still queued
+    /// in the [`PrioritySink`] (that can't be sent anymore)
+    pub(crate) async fn send(&self, code: u16) {
+        let sink: &Arc<dyn SendClose> = match &self.sink {
+            Some(sink) => sink,
+            None => return,
+        };
+
+        self.sent.store(true, Ordering::SeqCst);
+        let mut frame: Option<Message> = Some(Message::Close(Some(CloseFrame {
+            code: code.into(),
+            reason: "".into(),
+        })));
+        future::poll_fn(|cx| sink.poll_send_close(cx, &mut frame)).await
+    }
+}
+
+/// Send a close frame, whatever the sink type
+pub(crate) trait SendClose: Send + Sync {
+    /// Errors are ignored: the connection is gone anyway
+    fn poll_send_close(&self, cx: &mut Context<'_>, frame: &mut Option<Message>) -> Poll<()>;
+}
+
+impl<S> SendClose for Mutex<S>
+where
+    S: SinkTrait<Message> + Send + Unpin,
+{
+    fn poll_send_close(&self, cx: &mut Context<'_>, frame: &mut Option<Message>) -> Poll<()> {
+        let mut sink = lock(self);
+
+        if frame.is_some() {
+            if ready!(Pin::new(&mut *sink).poll_ready(cx)).is_err() {
+                return Poll::Ready(());
+            }
+
+            if let Some(frame) = frame.take() {
+                if Pin::new(&mut *sink).start_send(frame).is_err() {
+                    return Poll::Ready(());
+                }
+            }
+        }
+
+        let _ = ready!(Pin::new(&mut *sink).poll_flush(cx));
+        Poll::Ready(())
+    }
+}
+
+#[inline]
+fn lock<S>(sink: &Mutex<S>) -> MutexGuard<'_, S> {
+    sink.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Sink shared by the write half and the read half of the connection, to send a close frame once
+/// the read half is dropped ([`ReadHalfDropPolicy::CloseConnection`](super::ReadHalfDropPolicy::CloseConnection))
+///
+/// Only locked within each poll, never across an await.
+pub struct SharedSink<S> {
+    inner: Arc<Mutex<S>>,
+}
+
+impl<S> fmt::Debug for SharedSink<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedSink").finish_non_exhaustive()
+    }
+}
+
+impl<S> SharedSink<S> {
+    #[inline]
+    pub(crate) fn new(inner: S) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(inner)),
+        }
+    }
+}
+
+impl<S> SinkTrait<Message> for SharedSink<S>
+where
+    S: SinkTrait<Message> + Unpin,
+{
+    type Error = S::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut *lock(&self.inner)).poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        Pin::new(&mut *lock(&self.inner)).start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut *lock(&self.inner)).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut *lock(&self.inner)).poll_close(cx)
+    }
+}
+
+/// Sink that lets control frames (ping and pong) jump ahead of queued data frames.
+///
+/// Messages are queued until the sink is flushed (or the queue is full). On drain, all the
+/// queued control frames are handed to the inner sink before the queued data frames.
+/// Data frames are never reordered between themselves, so a fragmented message is never split.
+/// A close frame jumps ahead too: the data frames still queued are discarded, as nothing
+/// can be sent after it.
+#[derive(Debug)]
+pub struct PrioritySink<S> {
+    inner: S,
+    control: VecDeque<Message>,
+    data: VecDeque<Message>,
+    /// The inner sink returned `Poll::Pending` on the last ready/flush/close attempt
+    backpressured: bool,
+    pings: Arc<PingTracker>,
+    close: Arc<CloseRequest>,
+    #[cfg(target_os = "linux")]
+    send_queue: Option<SendQueue>,
+    #[cfg(feature = "tracing")]
+    traffic_log: Option<Level>,
+}
+
+impl<S> PrioritySink<S> {
+    #[inline]
+    pub(crate) fn new(inner: S, pings: Arc<PingTracker>, close: Arc<CloseRequest>) -> Self {
+        Self {
+            inner,
+            control: VecDeque::new(),
+            data: VecDeque::new(),
+            backpressured: false,
+            pings,
+            close,
+            #[cfg(target_os = "linux")]
+            send_queue: None,
+            #[cfg(feature = "tracing")]
+            traffic_log: None,
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[inline]
+    pub(crate) fn with_send_queue(mut self, send_queue: Option<SendQueue>) -> Self {
+        self.send_queue = send_queue;
+        self
+    }
+
+    /// Kernel send queue of the TCP socket, if known
+    #[cfg(target_os = "linux")]
+    #[inline]
+    pub(crate) fn send_queue(&self) -> Option<&SendQueue> {
+        self.send_queue.as_ref()
+    }
+
+    #[inline]
+    pub(crate) fn pings(&self) -> &Arc<PingTracker> {
+        &self.pings
+    }
+
+    /// Check if a close frame was sent (or queued): the close handshake is in progress
+    #[inline]
+    pub(crate) fn is_closing(&self) -> bool {
+        self.close.is_sent()
+    }
+
+    #[cfg(feature = "tracing")]
+    pub(crate) fn set_traffic_log(&mut self, level: Option<Level>) {
+        self.traffic_log = level;
+    }
+
+    /// Check if the inner sink is currently not ready (i.e. the socket write would block)
+    #[inline]
+    pub fn is_backpressured(&self) -> bool {
+        self.backpressured
+    }
+
+    #[inline]
+    fn queued(&self) -> usize {
+        self.control.len() + self.data.len()
+    }
+
+    /// Queue a close frame ahead of the data frames, discarding them
+    fn queue_close(&mut self, close: Message) {
+        self.close.sent.store(true, Ordering::SeqCst);
+        self.data.clear();
+        self.control.push_back(close);
+    }
+}
+
+impl<S> PrioritySink<S>
+where
+    S: SinkTrait<Message> + Unpin,
+{
+    /// Push a single queued message (control first) to the inner sink.
+    ///
+    /// Return `Poll::Ready(Ok(false))` if nothing was queued.
+    fn poll_push_one(&mut self, cx: &mut Context<'_>) -> Poll<Result<bool, S::Error>> {
+        if self.queued() == 0 {
+            return Poll::Ready(Ok(false));
+        }
+
+        ready!(self.track(|inner| Pin::new(inner).poll_ready(cx)))?;
+
+        let msg: Message = match self.control.pop_front() {
+            Some(msg) => msg,
+            None => match self.data.pop_front() {
+                Some(msg) => msg,
+                None => return Poll::Ready(Ok(false)),
+            },
+        };
+
+        Pin::new(&mut self.inner).start_send(msg)?;
+
+        Poll::Ready(Ok(true))
+    }
+
+    /// Poll the inner sink, keeping track of the backpressure
+    fn track<F>(&mut self, f: F) -> Poll<Result<(), S::Error>>
+    where
+        F: FnOnce(&mut S) -> Poll<Result<(), S::Error>>,
+    {
+        let poll = f(&mut self.inner);
+        self.backpressured = poll.is_pending();
+        poll
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        while ready!(self.poll_push_one(cx))? {}
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> SinkTrait<Message> for PrioritySink<S>
+where
+    S: SinkTrait<Message> + Unpin,
+{
+    type Error = S::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        while self.queued() >= MAX_QUEUED_MESSAGES {
+            ready!(self.poll_push_one(cx))?;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        #[cfg(feature = "tracing")]
+        if let Some(level) = self.traffic_log {
+            traffic::log(level, Direction::Sent, &item);
+        }
+
+        if let Message::Close(..) = item {
+            self.queue_close(item);
+            return Ok(());
+        }
+
+        if is_control(&item) {
+            self.control.push_back(item);
+        } else {
+            self.data.push_back(item);
+        }
+
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.poll_drain(cx))?;
+        self.track(|inner| Pin::new(inner).poll_flush(cx))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // The inner sink sends the close frame, unless one was already sent
+        self.close.sent.store(true, Ordering::SeqCst);
+        ready!(self.poll_drain(cx))?;
+        self.track(|inner| Pin::new(inner).poll_close(cx))
+    }
+}
+
+fn is_control(msg: &Message) -> bool {
+    match msg {
+        Message::Ping(..) | Message::Pong(..) | Message::Close(..) => true,
+        Message::Frame(frame) => matches!(frame.header().opcode, OpCode::Control(..)),
+        Message::Text(..) | Message::Binary(..) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures_util::SinkExt;
+
+    use super::*;
+
+    fn sink() -> PrioritySink<Vec<Message>> {
+        let pings = Arc::new(PingTracker::new(8, Duration::from_secs(30)));
+        PrioritySink::new(Vec::new(), pings, Arc::default())
+    }
+
+    #[tokio::test]
+    async fn test_control_first() {
+        let mut tx = sink();
+        for _ in 0..4 {
+            tx.feed(Message::Binary(vec![0; 1024])).await.unwrap();
+        }
+        tx.feed(Message::Pong(vec![1])).await.unwrap();
+        tx.flush().await.unwrap();
+
+        assert_eq!(tx.inner[0], Message::Pong(vec![1]));
+        assert_eq!(tx.inner.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_close_first() {
+        // Sent by the read half, without using the sink
+        let shared = SharedSink::new(Vec::new());
+        let close = Arc::new(CloseRequest::new(&shared));
+        let pings = Arc::new(PingTracker::new(8, Duration::from_secs(30)));
+        let mut tx = PrioritySink::new(shared, pings, close.clone());
+        for _ in 0..4 {
+            tx.feed(Message::Binary(vec![0; 1024])).await.unwrap();
+        }
+        close.send(1001).await;
+        assert_eq!(
+            *lock(&tx.inner.inner),
+            vec![Message::Close(Some(CloseFrame {
+                code: 1001.into(),
+                reason: "".into(),
+            }))]
+        );
+        assert!(tx.is_closing());
+
+        // Sent with the sink
+        let mut tx = sink();
+        for _ in 0..4 {
+            tx.feed(Message::Binary(vec![0; 1024])).await.unwrap();
+        }
+        tx.send(Message::Close(None)).await.unwrap();
+        assert_eq!(tx.inner, vec![Message::Close(None)]);
+        assert!(tx.is_closing());
+    }
+}
@@ -0,0 +1,104 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Redirects on the upgrade path
+
+use tokio_tungstenite::tungstenite::http::{header, StatusCode};
+use tokio_tungstenite::tungstenite::Error as WsError;
+use url::Url;
+
+use super::Error;
+use crate::redact;
+
+/// Extract the redirect target from a failed handshake.
+///
+/// Return `None` if the error isn't a `301`, `302` or `307` response with a `Location` header.
+pub(super) fn location(current: &Url, e: &Error) -> Option<Result<Url, Error>> {
+    let res = match e {
+        Error::Ws(e) => match e.as_ref() {
+            WsError::Http(res) => res,
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    if !matches!(
+        res.status(),
+        StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND | StatusCode::TEMPORARY_REDIRECT
+    ) {
+        return None;
+    }
+
+    let location: &str = res.headers().get(header::LOCATION)?.to_str().ok()?;
+    Some(resolve(current, location))
+}
+
+/// Resolve a (possibly relative) `Location` against the current URL,
+/// mapping the HTTP schemes to the WebSocket ones.
+///
+/// A redirect from `wss` to `ws` is rejected with [`Error::RedirectDowngrade`]: the request,
+/// with its credentials and headers, would be sent in clear.
+fn resolve(current: &Url, location: &str) -> Result<Url, Error> {
+    let mut url: Url = current.join(location)?;
+
+    let scheme: Option<&str> = match url.scheme() {
+        "http" => Some("ws"),
+        "https" => Some("wss"),
+        _ => None,
+    };
+
+    if let Some(scheme) = scheme {
+        // Can't fail: switching between special schemes
+        let _ = url.set_scheme(scheme);
+    }
+
+    if current.scheme() == "wss" && url.scheme() != "wss" {
+        return Err(Error::RedirectDowngrade(redact::url(&url)));
+    }
+
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_location() {
+        let current = Url::parse("wss://relay.example.com/ws").unwrap();
+
+        assert_eq!(
+            resolve(&current, "/v2").unwrap().as_str(),
+            "wss://relay.example.com/v2"
+        );
+        assert_eq!(
+            resolve(&current, "https://other.example.com/ws")
+                .unwrap()
+                .as_str(),
+            "wss://other.example.com/ws"
+        );
+        assert_eq!(
+            resolve(
+                &Url::parse("ws://relay.example.com").unwrap(),
+                "http://other.example.com"
+            )
+            .unwrap()
+            .as_str(),
+            "ws://other.example.com/"
+        );
+    }
+
+    #[test]
+    fn test_reject_downgrade() {
+        let current = Url::parse("wss://relay.example.com/ws").unwrap();
+
+        for location in ["ws://relay.example.com/ws", "http://relay.example.com/ws"] {
+            match resolve(&current, location) {
+                Err(Error::RedirectDowngrade(url)) => {
+                    assert_eq!(url.as_str(), "ws://relay.example.com/ws")
+                }
+                res => panic!("unexpected result: {res:?}"),
+            }
+        }
+    }
+}
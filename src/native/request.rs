@@ -0,0 +1,191 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Handshake request
+
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::error::UrlError;
+use tokio_tungstenite::tungstenite::handshake::client::{generate_key, Request};
+use tokio_tungstenite::tungstenite::http::header::{
+    AUTHORIZATION, CONNECTION, HOST, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_PROTOCOL,
+    SEC_WEBSOCKET_VERSION, UPGRADE, USER_AGENT,
+};
+use tokio_tungstenite::tungstenite::http::{self, HeaderValue};
+use tokio_tungstenite::tungstenite::Error as WsError;
+use url::Url;
+
+use super::{Credentials, Error};
+
+/// Build the default handshake request for a URL
+#[inline]
+pub(super) fn from_url(url: &Url) -> Result<Request, Error> {
+    Ok(url.as_str().into_client_request()?)
+}
+
+/// Validate a user-provided request and fill in (or override) the mandatory WebSocket headers.
+///
+/// Return the request URL, used to open the transport.
+pub(super) fn prepare(mut request: Request) -> Result<(Url, Request), Error> {
+    let url: Url = Url::parse(&request.uri().to_string())?;
+
+    if !matches!(url.scheme(), "ws" | "wss") {
+        return Err(WsError::Url(UrlError::UnsupportedUrlScheme).into());
+    }
+
+    let headers = request.headers_mut();
+    headers.insert(HOST, host_header(&url)?);
+    headers.insert(CONNECTION, HeaderValue::from_static("Upgrade"));
+    headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+    headers.insert(SEC_WEBSOCKET_VERSION, HeaderValue::from_static("13"));
+    headers.insert(SEC_WEBSOCKET_KEY, to_header_value(&generate_key())?);
+
+    Ok((url, request))
+}
+
+/// Send the request to another URL, updating the `Host` header
+pub(super) fn retarget(mut request: Request, url: &Url) -> Result<Request, Error> {
+    let uri = url
+        .as_str()
+        .parse()
+        .map_err(|e: http::uri::InvalidUri| WsError::HttpFormat(e.into()))?;
+    *request.uri_mut() = uri;
+    let host: HeaderValue = host_header(url)?;
+    request.headers_mut().insert(HOST, host);
+    Ok(request)
+}
+
+/// Value of the `Host` header: the host, followed by the port if not the default one
+fn host_header(url: &Url) -> Result<HeaderValue, Error> {
+    let host: &str = url.host_str().ok_or_else(Error::empty_host)?;
+    match url.port() {
+        Some(port) => to_header_value(&format!("{host}:{port}")),
+        None => to_header_value(host),
+    }
+}
+
+/// Set the `User-Agent` header, if any
+pub(super) fn with_user_agent(
+    mut request: Request,
+    user_agent: Option<&str>,
+) -> Result<Request, Error> {
+    if let Some(user_agent) = user_agent {
+        request
+            .headers_mut()
+            .insert(USER_AGENT, to_header_value(user_agent)?);
+    }
+
+    Ok(request)
+}
+
+/// Set the `Sec-WebSocket-Protocol` header, keeping the order of preference, if any protocol is requested
+pub(super) fn with_protocols(mut request: Request, protocols: &[String]) -> Result<Request, Error> {
+    if !protocols.is_empty() {
+        // Without spaces: the backend doesn't trim them when checking the response
+        request.headers_mut().insert(
+            SEC_WEBSOCKET_PROTOCOL,
+            to_header_value(&protocols.join(","))?,
+        );
+    }
+    Ok(request)
+}
+
+/// Get the sub-protocols requested in the `Sec-WebSocket-Protocol` header, in order
+pub(super) fn protocols(request: &Request) -> Vec<String> {
+    request
+        .headers()
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|p| p.trim().to_string())
+        .filter(|p| !p.is_empty())
+        .collect()
+}
+
+/// Set the `Authorization` header
+pub(super) fn with_credentials(
+    mut request: Request,
+    credentials: &Credentials,
+) -> Result<Request, Error> {
+    request
+        .headers_mut()
+        .insert(AUTHORIZATION, to_header_value(&credentials.header_value())?);
+    Ok(request)
+}
+
+/// Replace the `Sec-WebSocket-Key` header
+#[cfg(feature = "test-util")]
+pub(super) fn with_key(mut request: Request, key: [u8; 16]) -> Result<Request, Error> {
+    let key: String = data_encoding::BASE64.encode(&key);
+    request
+        .headers_mut()
+        .insert(SEC_WEBSOCKET_KEY, to_header_value(&key)?);
+    Ok(request)
+}
+
+#[inline]
+fn to_header_value(value: &str) -> Result<HeaderValue, Error> {
+    HeaderValue::from_str(value).map_err(|e| WsError::HttpFormat(e.into()).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prepare_request() {
+        let request = http::Request::builder()
+            .uri("wss://relay.example.com:4443/ws?token=abc")
+            .header("Authorization", "Custom abc")
+            .header(UPGRADE, "h2c")
+            .body(())
+            .unwrap();
+
+        let (url, request) = prepare(request).unwrap();
+        assert_eq!(url.as_str(), "wss://relay.example.com:4443/ws?token=abc");
+
+        let headers = request.headers();
+        assert_eq!(headers[HOST], "relay.example.com:4443");
+        assert_eq!(headers[UPGRADE], "websocket");
+        assert_eq!(headers["authorization"], "Custom abc");
+        assert!(headers.contains_key(SEC_WEBSOCKET_KEY));
+
+        let request = http::Request::builder()
+            .uri("https://relay.example.com")
+            .body(())
+            .unwrap();
+        assert!(prepare(request).is_err());
+    }
+
+    #[test]
+    fn test_retarget() {
+        let url = Url::parse("wss://alias/ws").unwrap();
+        let request = from_url(&url).unwrap();
+
+        let url = Url::parse("wss://relay.example.com:4443/ws").unwrap();
+        let request = retarget(request, &url).unwrap();
+        assert_eq!(request.uri(), "wss://relay.example.com:4443/ws");
+        assert_eq!(request.headers()[HOST], "relay.example.com:4443");
+    }
+
+    #[test]
+    fn test_protocols_order() {
+        let url = Url::parse("ws://localhost").unwrap();
+        let protocols = vec![String::from("v2.chat"), String::from("v1.chat")];
+        let request = with_protocols(from_url(&url).unwrap(), &protocols).unwrap();
+        assert_eq!(request.headers()[SEC_WEBSOCKET_PROTOCOL], "v2.chat,v1.chat");
+        assert_eq!(super::protocols(&request), protocols);
+    }
+
+    #[test]
+    #[cfg(feature = "test-util")]
+    fn test_fixed_key() {
+        let url = Url::parse("ws://localhost").unwrap();
+        let key: [u8; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+        let request = with_key(from_url(&url).unwrap(), key).unwrap();
+        assert_eq!(
+            request.headers()[SEC_WEBSOCKET_KEY],
+            "AAECAwQFBgcICQoLDA0ODw=="
+        );
+    }
+}
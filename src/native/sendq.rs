@@ -0,0 +1,83 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Kernel send queue of a TCP socket
+//!
+//! Linux only: relies on the `SIOCOUTQ` ioctl, that reports the bytes not yet acknowledged
+//! by the peer (both unsent and in flight).
+
+use std::io;
+use std::os::fd::{AsFd, OwnedFd};
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+use tokio::time;
+
+use super::sys;
+
+/// Interval between the checks of the send queue
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Send queue of a TCP socket
+///
+/// Holds a duplicate of the descriptor: it can't be reused by another socket while this is alive.
+#[derive(Debug)]
+pub(crate) struct SendQueue {
+    fd: OwnedFd,
+}
+
+impl SendQueue {
+    pub(crate) fn new(stream: &TcpStream) -> io::Result<Self> {
+        Ok(Self {
+            fd: stream.as_fd().try_clone_to_owned()?,
+        })
+    }
+
+    /// Bytes not yet acknowledged by the peer
+    pub(crate) fn len(&self) -> io::Result<usize> {
+        sys::outq(self.fd.as_fd())
+    }
+
+    /// Wait until the send queue is empty
+    ///
+    /// The kernel also empties it when the connection fails (i.e. reset or retransmission timeout).
+    /// A peer that stops reading, but keeps acknowledging the window probes, holds it forever:
+    /// there is no deadline, the caller has to bound the wait.
+    pub(crate) async fn drained(&self) -> io::Result<()> {
+        while self.len()? > 0 {
+            time::sleep(POLL_INTERVAL).await;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_send_queue_drained() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+
+        let queue = SendQueue::new(&client).unwrap();
+        assert_eq!(queue.len().unwrap(), 0);
+
+        // Acknowledged by the peer kernel, even if not read yet
+        client.write_all(&[1; 1024]).await.unwrap();
+        time::timeout(Duration::from_secs(5), queue.drained())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let mut buf = [0; 1024];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [1; 1024]);
+    }
+}
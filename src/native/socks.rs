@@ -14,7 +14,7 @@ pub(crate) struct TcpSocks5Stream;
 impl TcpSocks5Stream {
     #[inline]
     pub async fn connect<'a>(
-        proxy: SocketAddr,
+        proxy: &[SocketAddr],
         dest: impl IntoTargetAddr<'a>,
     ) -> Result<TcpStream, tokio_socks::Error> {
         Ok(Socks5Stream::connect(proxy, dest).await?.into_inner())
@@ -1,32 +1,269 @@
 // Copyright (c) 2022-2024 Yuki Kishimoto
 // Distributed under the MIT software license
 
+use std::future::Future;
 use std::ops::DerefMut;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
 #[cfg(feature = "tor")]
 use arti_client::DataStream;
+use futures_core::{ready, Stream as StreamTrait};
+use futures_sink::Sink as SinkTrait;
 use futures_util::stream::{SplitSink, SplitStream};
-use futures_util::{Sink as SinkTrait, Stream as StreamTrait};
+use futures_util::SinkExt;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
-use tokio_tungstenite::tungstenite::Message;
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::error::ProtocolError;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+#[cfg(feature = "tracing")]
+use tracing::Level;
+use url::Url;
 
 use super::error::Error;
+use super::graceful::GracefulShutdown;
+#[cfg(feature = "test-util")]
+use super::mask::SeededMask;
+use super::observe::ObservedStream;
+use super::options::{ConnectOptions, ResolvedOptions, SizeLimits};
+use super::ping::PingTicket;
+use super::priority::{PrioritySink, SharedSink};
+use super::timeout::TimeoutStream;
+use crate::close_code;
+use crate::close_event::{CloseEvent, Initiator};
+use crate::close_notifier::CloseNotifier;
+use crate::metrics::ErrorKind;
+use crate::DeflateParams;
 
-type WsStream<T> = WebSocketStream<MaybeTlsStream<T>>;
+#[cfg(not(feature = "test-util"))]
+type Conn<T> = MaybeTlsStream<TimeoutStream<T>>;
+#[cfg(feature = "test-util")]
+type Conn<T> = SeededMask<MaybeTlsStream<TimeoutStream<T>>>;
+
+/// TCP socket of the connection, beneath the TLS session (if any)
+#[cfg(target_os = "linux")]
+pub(super) fn tcp_stream(stream: &WsStream<TcpStream>) -> Option<&TcpStream> {
+    let conn = stream.get_ref().get_ref();
+    #[cfg(feature = "test-util")]
+    let conn = conn.get_ref();
+    match conn {
+        MaybeTlsStream::Plain(s) => Some(s.get_ref()),
+        MaybeTlsStream::Rustls(s) => Some(s.get_ref().0.get_ref()),
+        _ => None,
+    }
+}
+
+pub(super) type WsStream<T> = WebSocketStream<GracefulShutdown<Conn<T>>>;
+
+/// Any async I/O transport
+pub trait Transport: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T> Transport for T where T: AsyncRead + AsyncWrite + Send + Unpin {}
+
+pub(super) type BoxedTransport = Box<dyn Transport>;
+
+/// Wrap the transport (TLS included) handed to tungstenite
+pub(super) fn wrap_conn<T>(
+    conn: MaybeTlsStream<TimeoutStream<T>>,
+    _opts: &ConnectOptions,
+) -> GracefulShutdown<Conn<T>>
+where
+    T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    #[cfg(feature = "test-util")]
+    let conn = SeededMask::new(conn, _opts.masking_seed);
+    GracefulShutdown::new(conn)
+}
+
+/// Same as [`wrap_conn`], for a transport already upgraded to WebSocket
+pub(super) fn wrap_upgraded_conn<T>(
+    conn: MaybeTlsStream<TimeoutStream<T>>,
+    _opts: &ConnectOptions,
+) -> GracefulShutdown<Conn<T>>
+where
+    T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    #[cfg(feature = "test-util")]
+    let conn = SeededMask::upgraded(conn, _opts.masking_seed);
+    GracefulShutdown::new(conn)
+}
 
 pub enum WebSocket {
     Std(WsStream<TcpStream>),
     #[cfg(feature = "tor")]
     Tor(WsStream<DataStream>),
+    Custom(WsStream<BoxedTransport>),
 }
 
+/// Sending half of a connection
+///
+/// The halves are independent: the lock shared with the [`Stream`] is only held while polling,
+/// never across a blocked write. A [`Stream`] polled from another task keeps reading (and
+/// answering pings) while a send is waiting for a peer that doesn't read.
+///
+/// ## Closing the connection
+///
+/// Sending a [`Message::Close`] starts the close handshake right away: the close frame jumps ahead of the messages
+/// queued but not flushed yet (i.e. with [`SinkExt::feed`]), which are discarded. Flush them first to deliver them.
+/// The code and the reason are validated first ([`Error::InvalidCloseCode`] and [`Error::CloseReasonTooLong`]).
+/// Closing the sink (i.e. [`SinkExt::close`]) sends a close frame without code, unless one was already sent,
+/// and waits for the transport to shut down. Once the handshake started, either way, any other close is a no-op
+/// and the other messages are rejected when flushed. Meanwhile, the [`Stream`] keeps yielding the messages
+/// received until the peer's close frame (check [`Sink::close_send`]).
 pub enum Sink {
-    Std(SplitSink<WsStream<TcpStream>, Message>),
+    Std(PrioritySink<SharedSink<SplitSink<WsStream<TcpStream>, Message>>>),
     #[cfg(feature = "tor")]
-    Tor(SplitSink<WsStream<DataStream>, Message>),
+    Tor(PrioritySink<SharedSink<SplitSink<WsStream<DataStream>, Message>>>),
+    Custom(PrioritySink<SharedSink<SplitSink<WsStream<BoxedTransport>, Message>>>),
+}
+
+impl Sink {
+    /// Check if the sink is currently applying backpressure (i.e. the socket write would block)
+    ///
+    /// Reflects the outcome of the last ready, flush or close attempt.
+    pub fn is_backpressured(&self) -> bool {
+        match self {
+            Self::Std(s) => s.is_backpressured(),
+            #[cfg(feature = "tor")]
+            Self::Tor(s) => s.is_backpressured(),
+            Self::Custom(s) => s.is_backpressured(),
+        }
+    }
+
+    /// Wait until everything sent has left the local buffers
+    ///
+    /// Stronger than [`flush`](SinkExt::flush): the queued messages are written, the transport
+    /// (including TLS) is flushed, then, on Linux, the kernel send queue of the TCP socket is
+    /// waited to be empty (i.e. all the data was acknowledged by the peer). The kernel also empties
+    /// it when the connection fails.
+    ///
+    /// There is no deadline: if the peer stops reading (i.e. its receive window stays closed),
+    /// it can wait indefinitely, so wrap it in a timeout (see [`tokio::time::timeout`]).
+    ///
+    /// Elsewhere, or over a transport without a known TCP socket (i.e. tor or custom streams),
+    /// it returns once the data has been handed to the OS.
+    pub async fn flush_completely(&mut self) -> Result<(), Error> {
+        self.flush().await?;
+
+        #[cfg(target_os = "linux")]
+        if let Self::Std(s) = self {
+            if let Some(send_queue) = s.send_queue() {
+                send_queue.drained().await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Send a message, giving it back if it couldn't be handed to the connection
+    ///
+    /// The messages sent before are flushed first. On failure, the message is returned if it never
+    /// left the sink (i.e. an earlier message failed, the close handshake started or the close frame
+    /// is invalid): it can be resent, on a new connection, without a copy and without duplicates.
+    /// If the transport fails while writing it, `None` is returned instead: the message may or may not
+    /// have reached the peer and, being already encoded, can't be given back.
+    // The message is given back as it is: boxing it would cost an allocation on every send
+    #[allow(clippy::result_large_err)]
+    pub async fn try_send(&mut self, msg: Message) -> Result<(), (Option<Message>, Error)> {
+        if let Err(e) = self.flush().await {
+            return Err((Some(msg), e));
+        }
+
+        match &msg {
+            // The close handshake is already in progress: a no-op, as with `send`
+            Message::Close(..) if self.is_closing() => return Ok(()),
+            Message::Close(Some(frame)) => {
+                if let Err(e) = check_close_frame(frame) {
+                    return Err((Some(msg), e));
+                }
+            }
+            _ if self.is_closing() => {
+                let e = WsError::Protocol(ProtocolError::SendAfterClosing);
+                return Err((Some(msg), e.into()));
+            }
+            _ => {}
+        }
+
+        // Nothing is queued anymore: the message is handed to the connection right away
+        self.feed(msg).await.map_err(|e| (None, e))?;
+        self.flush().await.map_err(|e| (None, e))
+    }
+
+    fn is_closing(&self) -> bool {
+        match self {
+            Self::Std(s) => s.is_closing(),
+            #[cfg(feature = "tor")]
+            Self::Tor(s) => s.is_closing(),
+            Self::Custom(s) => s.is_closing(),
+        }
+    }
+
+    /// Send a ping, returning a ticket that resolves with the round-trip time when the matching pong arrives
+    ///
+    /// Pongs are matched by payload, so outstanding pings must have distinct payloads
+    /// ([`Error::DuplicatePing`] otherwise). Pongs are observed while reading the [`Stream`].
+    /// Check [`ConnectOptions::max_outstanding_pings`](super::ConnectOptions::max_outstanding_pings)
+    /// and [`ConnectOptions::ping_timeout`](super::ConnectOptions::ping_timeout).
+    pub async fn send_ping(&mut self, payload: Vec<u8>) -> Result<PingTicket, Error> {
+        let pings = match self {
+            Self::Std(s) => s.pings(),
+            #[cfg(feature = "tor")]
+            Self::Tor(s) => s.pings(),
+            Self::Custom(s) => s.pings(),
+        };
+        let ticket: PingTicket = pings.register(payload.clone())?;
+        self.send(Message::Ping(payload)).await?;
+        Ok(ticket)
+    }
+
+    /// Half-close: send our close frame, then stop sending
+    ///
+    /// The [`Stream`] keeps yielding the messages the peer sent before echoing the close
+    /// (RFC 6455, section 5.5.1), then ends: the peer's close frame is returned by [`Stream::closed`].
+    /// Unlike closing the sink (i.e. [`SinkExt::close`]), this returns once the frame is flushed,
+    /// without waiting for the transport to shut down. Same rules as sending a [`Message::Close`].
+    pub async fn close_send(&mut self, code: u16, reason: &str) -> Result<(), Error> {
+        let frame = CloseFrame {
+            code: code.into(),
+            reason: reason.to_string().into(),
+        };
+        self.send(Message::Close(Some(frame))).await
+    }
+
+    /// Log every sent message at the `level`
+    ///
+    /// Each message is logged with its direction, type, length and the hex dump of its first 64 bytes.
+    /// The messages are logged when queued, so a message may be logged before it's sent on the wire.
+    #[cfg(feature = "tracing")]
+    pub fn log_traffic(mut self, level: Level) -> Self {
+        match &mut self {
+            Self::Std(s) => s.set_traffic_log(Some(level)),
+            #[cfg(feature = "tor")]
+            Self::Tor(s) => s.set_traffic_log(Some(level)),
+            Self::Custom(s) => s.set_traffic_log(Some(level)),
+        }
+        self
+    }
+
+    /// Toggle the logging of the sent messages at the `DEBUG` level, without reconnecting
+    ///
+    /// Same as [`Sink::log_traffic`], but in place: i.e. to capture the traffic only while investigating
+    /// an issue. Disabling it also stops the logging enabled with [`Sink::log_traffic`].
+    /// The halves are independent: check [`Stream::set_debug`] for the received messages.
+    /// When disabled, the cost is a branch per message.
+    #[cfg(feature = "tracing")]
+    pub fn set_debug(&mut self, enabled: bool) {
+        let level: Option<Level> = enabled.then_some(Level::DEBUG);
+        match self {
+            Self::Std(s) => s.set_traffic_log(level),
+            #[cfg(feature = "tor")]
+            Self::Tor(s) => s.set_traffic_log(level),
+            Self::Custom(s) => s.set_traffic_log(level),
+        }
+    }
 }
 
 impl SinkTrait<Message> for Sink {
@@ -37,14 +274,27 @@ impl SinkTrait<Message> for Sink {
             Self::Std(s) => Pin::new(s).poll_ready(cx).map_err(Into::into),
             #[cfg(feature = "tor")]
             Self::Tor(s) => Pin::new(s).poll_ready(cx).map_err(Into::into),
+            Self::Custom(s) => Pin::new(s).poll_ready(cx).map_err(Into::into),
         }
     }
 
     fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        if let Message::Close(frame) = &item {
+            // The close handshake is already in progress
+            if self.is_closing() {
+                return Ok(());
+            }
+
+            if let Some(frame) = frame {
+                check_close_frame(frame)?;
+            }
+        }
+
         match self.deref_mut() {
             Self::Std(s) => Pin::new(s).start_send(item).map_err(Into::into),
             #[cfg(feature = "tor")]
             Self::Tor(s) => Pin::new(s).start_send(item).map_err(Into::into),
+            Self::Custom(s) => Pin::new(s).start_send(item).map_err(Into::into),
         }
     }
 
@@ -53,6 +303,7 @@ impl SinkTrait<Message> for Sink {
             Self::Std(s) => Pin::new(s).poll_flush(cx).map_err(Into::into),
             #[cfg(feature = "tor")]
             Self::Tor(s) => Pin::new(s).poll_flush(cx).map_err(Into::into),
+            Self::Custom(s) => Pin::new(s).poll_flush(cx).map_err(Into::into),
         }
     }
 
@@ -61,14 +312,226 @@ impl SinkTrait<Message> for Sink {
             Self::Std(s) => Pin::new(s).poll_close(cx).map_err(Into::into),
             #[cfg(feature = "tor")]
             Self::Tor(s) => Pin::new(s).poll_close(cx).map_err(Into::into),
+            Self::Custom(s) => Pin::new(s).poll_close(cx).map_err(Into::into),
         }
     }
 }
 
+fn check_close_frame(frame: &CloseFrame<'_>) -> Result<(), Error> {
+    let code: u16 = frame.code.into();
+    if !close_code::is_sendable(code) {
+        return Err(Error::InvalidCloseCode { supplied: code });
+    }
+
+    if frame.reason.len() > close_code::MAX_REASON_LEN {
+        return Err(Error::CloseReasonTooLong);
+    }
+
+    Ok(())
+}
+
 pub enum Stream {
-    Std(SplitStream<WsStream<TcpStream>>),
+    Std(ObservedStream<SplitStream<WsStream<TcpStream>>>),
     #[cfg(feature = "tor")]
-    Tor(SplitStream<WsStream<DataStream>>),
+    Tor(ObservedStream<SplitStream<WsStream<DataStream>>>),
+    Custom(ObservedStream<SplitStream<WsStream<BoxedTransport>>>),
+}
+
+impl Stream {
+    /// Get the size limits of the incoming messages in force on this connection
+    ///
+    /// Either the ones set in [`ConnectOptions`](super::ConnectOptions) or the backend defaults.
+    #[inline]
+    pub fn size_limits(&self) -> SizeLimits {
+        self.options().size_limits
+    }
+
+    /// Get the effective options of this connection, after the defaults are applied
+    pub fn options(&self) -> &ResolvedOptions {
+        match self {
+            Self::Std(s) => s.options(),
+            #[cfg(feature = "tor")]
+            Self::Tor(s) => s.options(),
+            Self::Custom(s) => s.options(),
+        }
+    }
+
+    /// Get the URL of the connection, after the [`ConnectOptions::url_rewriter`](super::ConnectOptions::url_rewriter)
+    ///
+    /// `None` for the connections upgraded by an HTTP client ([`connect_upgraded`](super::connect_upgraded)).
+    pub fn url(&self) -> Option<&Url> {
+        match self {
+            Self::Std(s) => s.url(),
+            #[cfg(feature = "tor")]
+            Self::Tor(s) => s.url(),
+            Self::Custom(s) => s.url(),
+        }
+    }
+
+    /// Get the sub-protocol selected by the server, if any
+    ///
+    /// It's one of the [`ConnectOptions::protocols`](super::ConnectOptions::protocols) requested.
+    pub fn protocol(&self) -> Option<&str> {
+        match self {
+            Self::Std(s) => s.protocol(),
+            #[cfg(feature = "tor")]
+            Self::Tor(s) => s.protocol(),
+            Self::Custom(s) => s.protocol(),
+        }
+    }
+
+    /// Get the `permessage-deflate` parameters selected by the server, if any
+    ///
+    /// Parsed from the `Sec-WebSocket-Extensions` header of the handshake response.
+    /// The current backend doesn't request compression: it's always `None`, since the handshake
+    /// fails with [`Error::CompressionNotRequested`] if the server enables it anyway.
+    pub fn negotiated_compression(&self) -> Option<DeflateParams> {
+        match self {
+            Self::Std(s) => s.negotiated_compression(),
+            #[cfg(feature = "tor")]
+            Self::Tor(s) => s.negotiated_compression(),
+            Self::Custom(s) => s.negotiated_compression(),
+        }
+    }
+
+    /// Register a callback invoked once when the connection terminates
+    ///
+    /// The callback receives the close frame sent by the peer, or `None` if the connection
+    /// failed abruptly (or the stream was dropped). All the registered callbacks are invoked.
+    /// If the connection is already terminated, the callback is invoked immediately.
+    ///
+    /// **The termination is detected while reading:** the callbacks don't fire until the stream
+    /// is polled, even if the connection is closed with the [`Sink`]. Once the stream is dropped,
+    /// they fire with the actual termination if the [`ReadHalfDropPolicy`](super::ReadHalfDropPolicy)
+    /// keeps reading in the background, and right away with `None` otherwise.
+    pub fn on_close<F>(&self, callback: F)
+    where
+        F: FnOnce(Option<CloseFrame<'static>>) + Send + 'static,
+    {
+        self.close_notifier().register(Box::new(move |event| {
+            callback(event.and_then(|e| e.to_close_frame()))
+        }));
+    }
+
+    /// Same as [`Stream::on_close`], but the callback receives a [`CloseEvent`]
+    ///
+    /// If the connection dropped without a close frame (or the stream was dropped),
+    /// the event is synthesized as the browser does: `1006`, not clean.
+    pub fn on_close_event<F>(&self, callback: F)
+    where
+        F: FnOnce(CloseEvent) + Send + 'static,
+    {
+        self.close_notifier().register(Box::new(move |event| {
+            callback(event.unwrap_or_else(|| CloseEvent::abnormal(Initiator::Client)))
+        }));
+    }
+
+    /// Wait for the connection termination, without consuming the stream (i.e. in a `select!` branch)
+    ///
+    /// Resolve with the close frame sent by the peer, or `None` as for [`Stream::on_close`].
+    /// **The termination is observed while reading:** the stream must be read meanwhile.
+    /// If the connection is already terminated, resolve immediately.
+    pub fn closed(&self) -> impl Future<Output = Option<CloseFrame<'static>>> {
+        let closed = self.close_notifier().closed();
+        async move { closed.await.and_then(|e| e.to_close_frame()) }
+    }
+
+    /// Same as [`Stream::closed`], but resolve with a [`CloseEvent`], as [`Stream::on_close_event`]
+    pub fn close_event(&self) -> impl Future<Output = CloseEvent> {
+        let closed = self.close_notifier().closed();
+        async move {
+            closed
+                .await
+                .unwrap_or_else(|| CloseEvent::abnormal(Initiator::Client))
+        }
+    }
+
+    /// Set a deadline for the next reads, or clear it with `None`
+    ///
+    /// Once the deadline has passed, every read yields [`Error::RecvTimeout`] instead of waiting,
+    /// until a new deadline is set or it's cleared. The stream isn't terminated: the messages
+    /// already received are still yielded first. Unlike [`ConnectOptions::read_timeout`](super::ConnectOptions::read_timeout),
+    /// the deadline is absolute and doesn't reset when data arrives.
+    ///
+    /// The deadline is a [`tokio::time::Instant`], so it honors a paused clock in tests.
+    pub fn set_read_deadline(&mut self, deadline: Option<Instant>) {
+        match self {
+            Self::Std(s) => s.set_read_deadline(deadline),
+            #[cfg(feature = "tor")]
+            Self::Tor(s) => s.set_read_deadline(deadline),
+            Self::Custom(s) => s.set_read_deadline(deadline),
+        }
+    }
+
+    /// Log every received message at the `level`
+    ///
+    /// Each message is logged with its direction, type, length and the hex dump of its first 64 bytes.
+    #[cfg(feature = "tracing")]
+    pub fn log_traffic(mut self, level: Level) -> Self {
+        match &mut self {
+            Self::Std(s) => s.set_traffic_log(Some(level)),
+            #[cfg(feature = "tor")]
+            Self::Tor(s) => s.set_traffic_log(Some(level)),
+            Self::Custom(s) => s.set_traffic_log(Some(level)),
+        }
+        self
+    }
+
+    /// Toggle the logging of the received messages at the `DEBUG` level, without reconnecting
+    ///
+    /// Same as [`Stream::log_traffic`], but in place. Check [`Sink::set_debug`].
+    #[cfg(feature = "tracing")]
+    pub fn set_debug(&mut self, enabled: bool) {
+        let level: Option<Level> = enabled.then_some(Level::DEBUG);
+        match self {
+            Self::Std(s) => s.set_traffic_log(level),
+            #[cfg(feature = "tor")]
+            Self::Tor(s) => s.set_traffic_log(level),
+            Self::Custom(s) => s.set_traffic_log(level),
+        }
+    }
+
+    #[inline]
+    fn close_notifier(&self) -> &CloseNotifier<CloseEvent> {
+        match self {
+            Self::Std(s) => s.close_notifier(),
+            #[cfg(feature = "tor")]
+            Self::Tor(s) => s.close_notifier(),
+            Self::Custom(s) => s.close_notifier(),
+        }
+    }
+}
+
+fn poll_observed<S, E>(
+    s: &mut ObservedStream<S>,
+    cx: &mut Context<'_>,
+) -> Poll<Option<Result<Message, Error>>>
+where
+    S: StreamTrait<Item = Result<Message, E>> + Unpin,
+    Error: From<E>,
+{
+    // An available message wins over the deadline
+    if let Poll::Ready(item) = Pin::new(&mut *s).poll_next(cx) {
+        let item: Option<Result<Message, Error>> = item.map(|res| res.map_err(Error::from));
+        if let Some(Err(e)) = &item {
+            s.metrics().on_error(ErrorKind::of(e));
+        }
+        return Poll::Ready(item);
+    }
+
+    ready!(s.poll_deadline(cx));
+    Poll::Ready(Some(Err(Error::RecvTimeout)))
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        match self {
+            Self::Std(s) => s.apply_drop_policy(),
+            #[cfg(feature = "tor")]
+            Self::Tor(s) => s.apply_drop_policy(),
+            Self::Custom(s) => s.apply_drop_policy(),
+        }
+    }
 }
 
 impl StreamTrait for Stream {
@@ -76,9 +539,10 @@ impl StreamTrait for Stream {
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         match self.deref_mut() {
-            Self::Std(s) => Pin::new(s).poll_next(cx).map_err(Into::into),
+            Self::Std(s) => poll_observed(s, cx),
             #[cfg(feature = "tor")]
-            Self::Tor(s) => Pin::new(s).poll_next(cx).map_err(Into::into),
+            Self::Tor(s) => poll_observed(s, cx),
+            Self::Custom(s) => poll_observed(s, cx),
         }
     }
 
@@ -87,6 +551,7 @@ impl StreamTrait for Stream {
             Self::Std(s) => s.size_hint(),
             #[cfg(feature = "tor")]
             Self::Tor(s) => s.size_hint(),
+            Self::Custom(s) => s.size_hint(),
         }
     }
 }
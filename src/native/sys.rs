@@ -0,0 +1,64 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Linux socket calls without a safe binding
+//!
+//! The only place of the crate allowed to use `unsafe`: each call takes a borrowed descriptor,
+//! so it can't outlive the socket.
+
+#![allow(unsafe_code)]
+
+use std::io;
+use std::os::fd::{AsRawFd, BorrowedFd};
+
+/// Set `TCP_FASTOPEN_CONNECT`
+pub(super) fn set_tcp_fastopen_connect(fd: BorrowedFd<'_>, enabled: bool) -> io::Result<()> {
+    let value: libc::c_int = enabled.into();
+    // SAFETY: the descriptor is borrowed for the call and the value outlives it
+    let res = unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN_CONNECT,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    cvt(res)
+}
+
+/// Get `TCP_FASTOPEN_CONNECT`
+#[cfg(test)]
+pub(super) fn tcp_fastopen_connect(fd: BorrowedFd<'_>) -> io::Result<bool> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: the descriptor is borrowed for the call and the value outlives it
+    let res = unsafe {
+        libc::getsockopt(
+            fd.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN_CONNECT,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    cvt(res)?;
+    Ok(value != 0)
+}
+
+/// Bytes in the send queue not yet acknowledged by the peer (`SIOCOUTQ`)
+pub(super) fn outq(fd: BorrowedFd<'_>) -> io::Result<usize> {
+    let mut len: libc::c_int = 0;
+    // SAFETY: the descriptor is borrowed for the call and `len` outlives it
+    let res = unsafe { libc::ioctl(fd.as_raw_fd(), libc::TIOCOUTQ, &mut len) };
+    cvt(res)?;
+    Ok(len.max(0) as usize)
+}
+
+fn cvt(res: libc::c_int) -> io::Result<()> {
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
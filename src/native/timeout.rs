@@ -0,0 +1,270 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! I/O timeout
+
+use std::fmt;
+use std::future::Future;
+use std::io::{self, ErrorKind, IoSlice};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{self, Sleep};
+
+/// Which I/O operation exceeded its deadline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum IoTimeout {
+    Read,
+    Write,
+}
+
+impl fmt::Display for IoTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Read => write!(f, "read timeout"),
+            Self::Write => write!(f, "write timeout"),
+        }
+    }
+}
+
+impl std::error::Error for IoTimeout {}
+
+impl IoTimeout {
+    /// Extract the timeout kind from an I/O error produced by [`TimeoutStream`]
+    pub(crate) fn from_io_error(e: &io::Error) -> Option<Self> {
+        if e.kind() != ErrorKind::TimedOut {
+            return None;
+        }
+
+        e.get_ref()?.downcast_ref::<Self>().copied()
+    }
+}
+
+impl From<IoTimeout> for io::Error {
+    fn from(timeout: IoTimeout) -> Self {
+        io::Error::new(ErrorKind::TimedOut, timeout)
+    }
+}
+
+#[derive(Debug)]
+struct Deadline {
+    timeout: Option<Duration>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Deadline {
+    #[inline]
+    fn new(timeout: Option<Duration>) -> Self {
+        Self {
+            timeout,
+            sleep: None,
+        }
+    }
+
+    /// Arm the timer (if not already armed) and check if it's expired.
+    fn poll_expired(&mut self, cx: &mut Context<'_>) -> bool {
+        match self.timeout {
+            Some(timeout) => {
+                let sleep = self
+                    .sleep
+                    .get_or_insert_with(|| Box::pin(time::sleep(timeout)));
+                sleep.as_mut().poll(cx).is_ready()
+            }
+            None => false,
+        }
+    }
+
+    #[inline]
+    fn reset(&mut self) {
+        self.sleep = None;
+    }
+}
+
+/// Stream with per-read and per-write deadlines.
+///
+/// The deadline starts when an operation can't complete immediately
+/// and is reset each time an operation makes progress.
+#[derive(Debug)]
+pub struct TimeoutStream<S> {
+    inner: S,
+    read: Deadline,
+    write: Deadline,
+}
+
+impl<S> TimeoutStream<S> {
+    #[inline]
+    pub(crate) fn new(
+        inner: S,
+        read_timeout: Option<Duration>,
+        write_timeout: Option<Duration>,
+    ) -> Self {
+        Self {
+            inner,
+            read: Deadline::new(read_timeout),
+            write: Deadline::new(write_timeout),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S> TimeoutStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write_op<T, F>(&mut self, cx: &mut Context<'_>, f: F) -> Poll<io::Result<T>>
+    where
+        F: FnOnce(Pin<&mut S>, &mut Context<'_>) -> Poll<io::Result<T>>,
+    {
+        match f(Pin::new(&mut self.inner), cx) {
+            Poll::Ready(res) => {
+                self.write.reset();
+                Poll::Ready(res)
+            }
+            Poll::Pending => {
+                if self.write.poll_expired(cx) {
+                    self.write.reset();
+                    return Poll::Ready(Err(IoTimeout::Write.into()));
+                }
+
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<S> AsyncRead for TimeoutStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(res) => {
+                this.read.reset();
+                Poll::Ready(res)
+            }
+            Poll::Pending => {
+                if this.read.poll_expired(cx) {
+                    this.read.reset();
+                    return Poll::Ready(Err(IoTimeout::Read.into()));
+                }
+
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for TimeoutStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_write_op(cx, |s, cx| s.poll_write(cx, buf))
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_write_op(cx, |s, cx| s.poll_write_vectored(cx, bufs))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_write_op(cx, |s, cx| s.poll_flush(cx))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_write_op(cx, |s, cx| s.poll_shutdown(cx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "test-util")]
+    use futures_util::{future, SinkExt, StreamExt};
+    use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+    use tokio::time::Instant;
+    #[cfg(feature = "test-util")]
+    use url::Url;
+
+    use super::*;
+    #[cfg(feature = "test-util")]
+    use crate::native::{self, ConnectOptions, Error, Message, Sink, Stream};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_read_timeout_virtual_time() {
+        let (client, _server) = io::duplex(1024);
+        let mut stream = TimeoutStream::new(client, Some(Duration::from_secs(30)), None);
+
+        let start = Instant::now();
+        let err = stream.read(&mut [0; 8]).await.unwrap_err();
+        assert_eq!(IoTimeout::from_io_error(&err), Some(IoTimeout::Read));
+        assert_eq!(start.elapsed(), Duration::from_secs(30));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_write_timeout_virtual_time() {
+        let (client, _server) = io::duplex(4);
+        let mut stream = TimeoutStream::new(client, None, Some(Duration::from_secs(30)));
+
+        let start = Instant::now();
+        let err = stream.write_all(&[0; 8]).await.unwrap_err();
+        assert_eq!(IoTimeout::from_io_error(&err), Some(IoTimeout::Write));
+        assert_eq!(start.elapsed(), Duration::from_secs(30));
+    }
+
+    /// Connect to a peer that never sends nor reads anything after the handshake
+    #[cfg(feature = "test-util")]
+    async fn connect_stalled(opts: ConnectOptions) -> ((Sink, Stream), (Sink, Stream)) {
+        let (client, server) = io::duplex(16 * 1024);
+        let url = Url::parse("ws://localhost").unwrap();
+
+        future::try_join(
+            native::connect_with_stream(&url, client, Duration::from_secs(10), &opts),
+            native::accept(server),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[cfg(feature = "test-util")]
+    #[tokio::test]
+    async fn test_read_timeout_stalled_peer() {
+        let opts = ConnectOptions::new().read_timeout(Some(Duration::from_millis(100)));
+        let ((_client_tx, mut client_rx), _server) = connect_stalled(opts).await;
+
+        let res = client_rx.next().await.unwrap();
+        assert!(matches!(res, Err(Error::ReadTimeout)));
+    }
+
+    #[cfg(feature = "test-util")]
+    #[tokio::test]
+    async fn test_write_timeout_stalled_peer() {
+        let opts = ConnectOptions::new().write_timeout(Some(Duration::from_millis(100)));
+        let ((mut client_tx, _client_rx), _server) = connect_stalled(opts).await;
+
+        // Way larger than the transport buffer
+        let res = client_tx.send(Message::Binary(vec![0; 1024 * 1024])).await;
+        assert!(matches!(res, Err(Error::WriteTimeout)));
+    }
+}
@@ -0,0 +1,261 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! TLS
+
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{self, ClientConfig, RootCertStore, SupportedProtocolVersion};
+use tokio_rustls::TlsConnector;
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tokio_tungstenite::{Connector, MaybeTlsStream};
+
+use super::options::ConnectOptions;
+use super::Error;
+
+/// TLS protocol version
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TlsVersion {
+    /// TLS 1.2
+    Tls12,
+    /// TLS 1.3
+    Tls13,
+}
+
+impl TlsVersion {
+    const ALL: [Self; 2] = [Self::Tls12, Self::Tls13];
+
+    /// The versions within the min and max (if any)
+    pub(super) fn allowed(min: Option<Self>, max: Option<Self>) -> Vec<Self> {
+        Self::ALL
+            .into_iter()
+            .filter(|v| !matches!(min, Some(min) if *v < min))
+            .filter(|v| !matches!(max, Some(max) if *v > max))
+            .collect()
+    }
+
+    fn as_rustls(&self) -> &'static SupportedProtocolVersion {
+        match self {
+            Self::Tls12 => &rustls::version::TLS12,
+            Self::Tls13 => &rustls::version::TLS13,
+        }
+    }
+}
+
+/// Build a custom TLS connector, if the options require it.
+pub(super) fn connector(opts: &ConnectOptions) -> Result<Option<Connector>, Error> {
+    if opts.min_tls_version.is_none() && opts.max_tls_version.is_none() {
+        return Ok(None);
+    }
+
+    let versions: Vec<&'static SupportedProtocolVersion> =
+        TlsVersion::allowed(opts.min_tls_version, opts.max_tls_version)
+            .iter()
+            .map(TlsVersion::as_rustls)
+            .collect();
+
+    if versions.is_empty() {
+        return Err(Error::TlsVersionUnsupported);
+    }
+
+    let config = ClientConfig::builder_with_protocol_versions(&versions)
+        .with_root_certificates(root_store())
+        .with_no_client_auth();
+
+    Ok(Some(Connector::Rustls(Arc::new(config))))
+}
+
+/// Perform the TLS handshake with an explicit server name
+pub(super) async fn connect<S>(
+    conn: S,
+    server_name: ServerName<'static>,
+    opts: &ConnectOptions,
+) -> Result<MaybeTlsStream<S>, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let config: Arc<ClientConfig> = match connector(opts)? {
+        Some(Connector::Rustls(config)) => config,
+        _ => Arc::new(
+            ClientConfig::builder()
+                .with_root_certificates(root_store())
+                .with_no_client_auth(),
+        ),
+    };
+
+    let peer: String = server_name.to_str().into_owned();
+    let stream = TlsConnector::from(config)
+        .connect(server_name, conn)
+        .await
+        .map_err(|e| handshake_error(WsError::Io(e).into(), &peer))?;
+
+    Ok(MaybeTlsStream::Rustls(stream))
+}
+
+fn root_store() -> RootCertStore {
+    let mut root_store = RootCertStore::empty();
+    root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    root_store
+}
+
+/// Turn a TLS failure of the handshake with `peer` into [`Error::TlsHandshakeFailed`].
+///
+/// The other errors are returned as they are.
+pub(super) fn handshake_error(e: Error, peer: &str) -> Error {
+    if let Some(WsError::Io(io)) = e.as_ws() {
+        // rustls errors are wrapped in I/O errors by the TLS stream
+        if let Some(tls) = io.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>()) {
+            return Error::TlsHandshakeFailed {
+                peer: peer.to_string(),
+                details: tls.to_string(),
+            };
+        }
+    }
+
+    e
+}
+
+/// Check if a TLS error is caused by a protocol version mismatch
+pub(super) fn is_version_mismatch(e: &rustls::Error) -> bool {
+    use tokio_rustls::rustls::{AlertDescription, PeerIncompatible};
+
+    matches!(
+        e,
+        rustls::Error::AlertReceived(AlertDescription::ProtocolVersion)
+            | rustls::Error::PeerIncompatible(
+                PeerIncompatible::ServerDoesNotSupportTls12Or13
+                    | PeerIncompatible::ServerTlsVersionIsDisabledByOurConfig
+                    | PeerIncompatible::SupportedVersionsExtensionRequired
+                    | PeerIncompatible::Tls12NotOffered
+                    | PeerIncompatible::Tls12NotOfferedOrEnabled
+            )
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use data_encoding::BASE64;
+    use tokio::io::{self, AsyncReadExt};
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+    use tokio_rustls::rustls::ServerConfig;
+    use tokio_rustls::TlsAcceptor;
+    use url::Url;
+
+    use super::*;
+    use crate::native::graceful::GracefulShutdown;
+
+    /// Self-signed certificate for `localhost`
+    const CERT: &str = "MIIBkjCCATigAwIBAgIUVXPwTTXtFDyZCtExJjSkCJb4xgIwCgYIKoZIzj0EAwIwFDESMBAGA1UEAwwJbG9jYWxob3N0MCAXDTI2MTAxNTE0MDMwN1oYDzIxMjYwOTIxMTQwMzA3WjAUMRIwEAYDVQQDDAlsb2NhbGhvc3QwWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAAS4rlh9G5Pu3OLjWQiK4Zo8K7lzQY6vNFgc+IUrPXkYuPNX/seHFwVI0WMWqOI/c6/C97PZ+eU2bHHLkEeyEOgWo2YwZDAdBgNVHQ4EFgQU2zKQ3EzrJXImowqXdmogLBkl6yUwHwYDVR0jBBgwFoAU2zKQ3EzrJXImowqXdmogLBkl6yUwFAYDVR0RBA0wC4IJbG9jYWxob3N0MAwGA1UdEwEB/wQCMAAwCgYIKoZIzj0EAwIDSAAwRQIgEL/EcoUhMjftfVsRLINSR2uDs15o1FduN0HOIZ3yodQCIQDLrclEt3/g5spXbP8MfpDb8BfivjKlVlTcR+2+pzP87g==";
+    /// PKCS#8 key of [`CERT`]
+    const KEY: &str = "MIGHAgEAMBMGByqGSM49AgEGCCqGSM49AwEHBG0wawIBAQQgL13qVYSC0n1hfywkwdtIxA/y5KNGLjvhWT/l++LmItehRANCAAS4rlh9G5Pu3OLjWQiK4Zo8K7lzQY6vNFgc+IUrPXkYuPNX/seHFwVI0WMWqOI/c6/C97PZ+eU2bHHLkEeyEOgW";
+
+    fn cert() -> CertificateDer<'static> {
+        CertificateDer::from(BASE64.decode(CERT.as_bytes()).unwrap())
+    }
+
+    fn acceptor() -> TlsAcceptor {
+        acceptor_with_versions(rustls::DEFAULT_VERSIONS)
+    }
+
+    fn acceptor_with_versions(versions: &[&'static SupportedProtocolVersion]) -> TlsAcceptor {
+        let key = PrivatePkcs8KeyDer::from(BASE64.decode(KEY.as_bytes()).unwrap());
+        let config = ServerConfig::builder_with_protocol_versions(versions)
+            .with_no_client_auth()
+            .with_single_cert(vec![cert()], PrivateKeyDer::Pkcs8(key))
+            .unwrap();
+        TlsAcceptor::from(Arc::new(config))
+    }
+
+    /// TLS server with the self-signed certificate
+    fn serve(server: io::DuplexStream) {
+        serve_with_acceptor(server, acceptor());
+    }
+
+    fn serve_with_acceptor(server: io::DuplexStream, acceptor: TlsAcceptor) {
+        tokio::spawn(async move {
+            let _ = acceptor.accept(server).await;
+        });
+    }
+
+    #[tokio::test]
+    async fn test_self_signed_certificate() {
+        let (client, server) = io::duplex(16 * 1024);
+        serve(server);
+
+        let url = Url::parse("wss://localhost").unwrap();
+        let res = super::super::connect_with_stream(
+            &url,
+            client,
+            Duration::from_secs(10),
+            &ConnectOptions::default(),
+        )
+        .await;
+        match res {
+            Err(Error::TlsHandshakeFailed { peer, details }) => {
+                assert_eq!(peer, "localhost");
+                assert!(details.contains("invalid peer certificate"), "{details}");
+            }
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tls_version_unsupported() {
+        let (client, server) = io::duplex(16 * 1024);
+        serve_with_acceptor(server, acceptor_with_versions(&[&rustls::version::TLS12]));
+
+        let url = Url::parse("wss://localhost").unwrap();
+        let opts = ConnectOptions::new().min_tls_version(TlsVersion::Tls13);
+        let res =
+            super::super::connect_with_stream(&url, client, Duration::from_secs(10), &opts).await;
+        match res {
+            Err(Error::TlsVersionUnsupported) => {}
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_name_override() {
+        let (client, server) = io::duplex(16 * 1024);
+        serve(server);
+
+        let url = Url::parse("wss://127.0.0.1").unwrap();
+        let name = ServerName::try_from("example.org").unwrap();
+        let opts = ConnectOptions::new().server_name_override(Some(name));
+        let res =
+            super::super::connect_with_stream(&url, client, Duration::from_secs(10), &opts).await;
+        match res {
+            Err(Error::TlsHandshakeFailed { peer, .. }) => assert_eq!(peer, "example.org"),
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_close_notify_on_drop() {
+        let (client, server) = io::duplex(16 * 1024);
+        let server = tokio::spawn(async move {
+            let mut stream = acceptor().accept(server).await.unwrap();
+            // Without the `close_notify`, the EOF is reported as an error
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).await
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert()).unwrap();
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let name = ServerName::try_from("localhost").unwrap();
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(name, client)
+            .await
+            .unwrap();
+        drop(GracefulShutdown::new(MaybeTlsStream::Rustls(stream)));
+
+        assert_eq!(server.await.unwrap().unwrap(), 0);
+    }
+}
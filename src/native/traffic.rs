@@ -0,0 +1,76 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Traffic logging
+
+use tokio_tungstenite::tungstenite::Message;
+use tracing::Level;
+
+/// Max number of bytes in the hex dump
+const MAX_DUMP_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Direction {
+    Sent,
+    Received,
+}
+
+impl Direction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Sent => "sent",
+            Self::Received => "received",
+        }
+    }
+}
+
+fn kind(msg: &Message) -> &'static str {
+    match msg {
+        Message::Text(..) => "text",
+        Message::Binary(..) => "binary",
+        Message::Ping(..) => "ping",
+        Message::Pong(..) => "pong",
+        Message::Close(..) => "close",
+        Message::Frame(..) => "frame",
+    }
+}
+
+fn payload(msg: &Message) -> &[u8] {
+    match msg {
+        Message::Text(text) => text.as_bytes(),
+        Message::Binary(data) | Message::Ping(data) | Message::Pong(data) => data,
+        Message::Close(Some(frame)) => frame.reason.as_bytes(),
+        Message::Close(None) => &[],
+        Message::Frame(frame) => frame.payload(),
+    }
+}
+
+/// Log a message: direction, type, length and hex dump of the first bytes
+pub(super) fn log(level: Level, direction: Direction, msg: &Message) {
+    let payload: &[u8] = payload(msg);
+    let len: usize = payload.len();
+    let dump: String = data_encoding::HEXLOWER.encode(&payload[..len.min(MAX_DUMP_LEN)]);
+    let direction: &str = direction.as_str();
+    let kind: &str = kind(msg);
+
+    // The level of the `tracing` macros must be a constant
+    match level {
+        Level::ERROR => tracing::error!(direction, kind, len, dump, "WebSocket message"),
+        Level::WARN => tracing::warn!(direction, kind, len, dump, "WebSocket message"),
+        Level::INFO => tracing::info!(direction, kind, len, dump, "WebSocket message"),
+        Level::DEBUG => tracing::debug!(direction, kind, len, dump, "WebSocket message"),
+        _ => tracing::trace!(direction, kind, len, dump, "WebSocket message"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payload() {
+        assert_eq!(payload(&Message::text("hi")), b"hi");
+        assert_eq!(payload(&Message::Close(None)), b"");
+        assert_eq!(kind(&Message::Ping(vec![1])), "ping");
+    }
+}
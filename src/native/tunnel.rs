@@ -0,0 +1,168 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! WebSocket-in-WebSocket tunnel
+
+use std::io::{self, ErrorKind};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures_core::{ready, Stream as StreamTrait};
+use futures_sink::Sink as SinkTrait;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::Message;
+
+use super::{Sink, Stream};
+
+/// Byte transport over an outer connection
+///
+/// Until the inner handshake completes, the bytes are written as text messages (the upgrade
+/// request is plain HTTP); then as binary messages. Both are accepted when reading.
+/// The transport ends when the outer connection is closed.
+/// The writes go through immediately: the upgrade request isn't followed by an explicit flush.
+pub(super) struct Tunnel {
+    sink: Sink,
+    stream: Stream,
+    /// Incoming bytes not read yet
+    buffer: Vec<u8>,
+    handshake_done: Arc<AtomicBool>,
+}
+
+impl Tunnel {
+    pub(super) fn new(sink: Sink, stream: Stream) -> (Self, Arc<AtomicBool>) {
+        let handshake_done = Arc::new(AtomicBool::new(false));
+        let tunnel = Self {
+            sink,
+            stream,
+            buffer: Vec::new(),
+            handshake_done: handshake_done.clone(),
+        };
+        (tunnel, handshake_done)
+    }
+}
+
+// `io::Error::other` requires a newer MSRV
+#[inline]
+#[allow(clippy::io_other_error)]
+fn io_error(e: super::Error) -> io::Error {
+    io::Error::new(ErrorKind::Other, e)
+}
+
+impl AsyncRead for Tunnel {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        // Keep flushing the written bytes, since the handshake doesn't
+        if let Poll::Ready(Err(e)) = Pin::new(&mut self.sink).poll_flush(cx) {
+            return Poll::Ready(Err(io_error(e)));
+        }
+
+        while self.buffer.is_empty() {
+            match ready!(Pin::new(&mut self.stream).poll_next(cx)) {
+                Some(Ok(Message::Text(text))) => self.buffer = text.into_bytes(),
+                Some(Ok(Message::Binary(data))) => self.buffer = data,
+                // EOF
+                Some(Ok(Message::Close(..))) | None => return Poll::Ready(Ok(())),
+                Some(Ok(..)) => continue,
+                Some(Err(e)) => return Poll::Ready(Err(io_error(e))),
+            }
+        }
+
+        let len: usize = self.buffer.len().min(buf.remaining());
+        buf.put_slice(&self.buffer[..len]);
+        self.buffer.drain(..len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Tunnel {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(Pin::new(&mut self.sink).poll_ready(cx)).map_err(io_error)?;
+
+        let msg: Message = if self.handshake_done.load(Ordering::SeqCst) {
+            Message::Binary(buf.to_vec())
+        } else {
+            match String::from_utf8(buf.to_vec()) {
+                Ok(text) => Message::Text(text),
+                Err(e) => Message::Binary(e.into_bytes()),
+            }
+        };
+
+        Pin::new(&mut self.sink).start_send(msg).map_err(io_error)?;
+
+        // Write through: a pending flush is resumed on the next read or write
+        if let Poll::Ready(Err(e)) = Pin::new(&mut self.sink).poll_flush(cx) {
+            return Poll::Ready(Err(io_error(e)));
+        }
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.sink).poll_flush(cx).map_err(io_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.sink).poll_close(cx).map_err(io_error)
+    }
+}
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use std::time::Duration;
+
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::native::{self, ConnectOptions};
+    use crate::test_util::{self, MockServer};
+
+    #[tokio::test]
+    async fn test_websocket_tunnel() {
+        let server = MockServer::builder()
+            .expect(|msg| msg.to_text().ok() == Some("ping"))
+            .send("pong")
+            .start()
+            .await
+            .unwrap();
+
+        // Relay: pipe the outer messages to the inner server, as raw bytes
+        let (outer, (mut relay_tx, mut relay_rx)) = test_util::pair().await.unwrap();
+        let addr = format!("127.0.0.1:{}", server.url().port().unwrap());
+        let (mut tcp_rx, mut tcp_tx) = TcpStream::connect(addr).await.unwrap().into_split();
+        tokio::spawn(async move {
+            while let Some(Ok(msg)) = relay_rx.next().await {
+                tcp_tx.write_all(&msg.into_data()).await.unwrap();
+            }
+        });
+        tokio::spawn(async move {
+            let mut buf = [0u8; 4096];
+            while let Ok(n @ 1..) = tcp_rx.read(&mut buf).await {
+                let msg = Message::Binary(buf[..n].to_vec());
+                if relay_tx.send(msg).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let opts = ConnectOptions::default();
+        let timeout = Duration::from_secs(5);
+        let (mut tx, mut rx) =
+            native::connect_through_websocket_tunnel(outer, server.url(), timeout, &opts)
+                .await
+                .unwrap();
+
+        tx.send(Message::text("ping")).await.unwrap();
+        assert_eq!(rx.next().await.unwrap().unwrap(), Message::text("pong"));
+    }
+}
@@ -0,0 +1,167 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Upgraded HTTP connections
+
+use std::time::{Duration, SystemTime};
+
+use tokio_tungstenite::tungstenite::error::ProtocolError;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::http::header::{
+    CONNECTION, RETRY_AFTER, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_EXTENSIONS,
+    SEC_WEBSOCKET_PROTOCOL, UPGRADE,
+};
+use tokio_tungstenite::tungstenite::http::{HeaderMap, Response, StatusCode};
+use tokio_tungstenite::tungstenite::Error as WsError;
+
+use super::Error;
+use crate::DeflateParams;
+
+/// What the server selected in the handshake response
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Negotiated {
+    pub(super) protocol: Option<String>,
+    pub(super) compression: Option<DeflateParams>,
+}
+
+impl Negotiated {
+    /// Check the selected sub-protocol (as [`negotiated_protocol`]) and parse the extensions
+    ///
+    /// The backend never requests `permessage-deflate`: if the server enables it anyway, the
+    /// handshake fails with [`Error::CompressionNotRequested`] (RFC 6455, section 9.1).
+    pub(super) fn from_headers(headers: &HeaderMap, requested: &[String]) -> Result<Self, Error> {
+        let compression: Option<DeflateParams> = headers
+            .get(SEC_WEBSOCKET_EXTENSIONS)
+            .and_then(|value| value.to_str().ok())
+            .and_then(DeflateParams::from_extensions);
+
+        if compression.is_some() {
+            return Err(Error::CompressionNotRequested);
+        }
+
+        Ok(Self {
+            protocol: negotiated_protocol(headers, requested)?,
+            compression,
+        })
+    }
+}
+
+/// Validate the `101 Switching Protocols` response of an upgrade request sent with `key`
+/// as `Sec-WebSocket-Key` (RFC 6455, section 4.1).
+pub(super) fn verify_response<B>(key: &str, response: &Response<B>) -> Result<(), Error> {
+    if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        let mut res = Response::new(None);
+        *res.status_mut() = response.status();
+        *res.headers_mut() = response.headers().clone();
+        return Err(WsError::Http(res).into());
+    }
+
+    let headers: &HeaderMap = response.headers();
+
+    if !header_eq(headers, UPGRADE.as_str(), "websocket") {
+        return Err(WsError::Protocol(ProtocolError::MissingUpgradeWebSocketHeader).into());
+    }
+
+    if !header_eq(headers, CONNECTION.as_str(), "upgrade") {
+        return Err(WsError::Protocol(ProtocolError::MissingConnectionUpgradeHeader).into());
+    }
+
+    let accept: String = derive_accept_key(key.as_bytes());
+    if !headers
+        .get(SEC_WEBSOCKET_ACCEPT)
+        .map(|h| h == accept.as_str())
+        .unwrap_or(false)
+    {
+        return Err(WsError::Protocol(ProtocolError::SecWebSocketAcceptKeyMismatch).into());
+    }
+
+    Ok(())
+}
+
+/// Get the sub-protocol selected by the server, checking it's one of the `requested` (if any)
+pub(super) fn negotiated_protocol(
+    headers: &HeaderMap,
+    requested: &[String],
+) -> Result<Option<String>, Error> {
+    let selected: Option<&str> = match headers.get(SEC_WEBSOCKET_PROTOCOL) {
+        Some(value) => Some(value.to_str().map_err(|_| Error::ProtocolNotNegotiated)?),
+        None => None,
+    };
+
+    match selected {
+        Some(selected) if requested.iter().any(|p| p == selected) => Ok(Some(selected.to_string())),
+        None if requested.is_empty() => Ok(None),
+        _ => Err(Error::ProtocolNotNegotiated),
+    }
+}
+
+/// Parse the `Retry-After` header of a rejected handshake (RFC 9110, section 10.2.3)
+///
+/// Both the delta-seconds and the HTTP-date forms are supported. A date in the past means no wait.
+pub(super) fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value: &str = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let date: SystemTime = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+fn header_eq(headers: &HeaderMap, name: &str, value: &str) -> bool {
+    headers
+        .get(name)
+        .and_then(|h| h.to_str().ok())
+        .map(|h| h.eq_ignore_ascii_case(value))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_response() {
+        // RFC 6455 sample
+        let key = "dGhlIHNhbXBsZSBub25jZQ==";
+        let response = Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(UPGRADE, "websocket")
+            .header(CONNECTION, "Upgrade")
+            .header(SEC_WEBSOCKET_ACCEPT, "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+            .body(())
+            .unwrap();
+        assert!(verify_response(key, &response).is_ok());
+        assert!(verify_response("AAAAAAAAAAAAAAAAAAAAAA==", &response).is_err());
+
+        let response = Response::builder().status(StatusCode::OK).body(()).unwrap();
+        assert!(verify_response(key, &response).is_err());
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+
+        headers.insert(RETRY_AFTER, "120".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(120)));
+
+        headers.insert(
+            RETRY_AFTER,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(retry_after(&headers), Some(Duration::ZERO));
+
+        let date = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(3600));
+        headers.insert(RETRY_AFTER, date.parse().unwrap());
+        let wait = retry_after(&headers).unwrap();
+        assert!(wait > Duration::from_secs(3500) && wait <= Duration::from_secs(3600));
+
+        headers.insert(RETRY_AFTER, "soon".parse().unwrap());
+        assert_eq!(retry_after(&headers), None);
+    }
+}
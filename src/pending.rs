@@ -0,0 +1,86 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Pending connection
+
+use std::time::Duration;
+
+use async_utility::thread;
+use futures_channel::oneshot;
+use url::Url;
+
+use crate::{ConnectionMode, Error, Sink, Stream};
+
+type Outcome = Result<(Sink, Stream), Error>;
+
+/// Connection being established in the background
+///
+/// Returned by [`connect_pending`]. The connection proceeds in a background task,
+/// whether [`PendingConnection::opened`] is awaited or not.
+/// Dropping the handle doesn't stop the attempt, but the connection is closed once established.
+#[derive(Debug)]
+pub struct PendingConnection {
+    /// `None` once the outcome has been taken
+    outcome: Option<oneshot::Receiver<Outcome>>,
+}
+
+impl PendingConnection {
+    /// Wait for the connection to be open
+    ///
+    /// Cancellation safe: it can be wrapped in a (shorter) timeout and awaited again later.
+    /// Once the outcome has been returned, the next calls fail with a closed error.
+    pub async fn opened(&mut self) -> Result<(Sink, Stream), Error> {
+        let outcome = match &mut self.outcome {
+            Some(outcome) => outcome.await,
+            None => return Err(closed()),
+        };
+        self.outcome = None;
+
+        // The background task stopped without reporting (i.e. the runtime shut down)
+        outcome.unwrap_or_else(|_| Err(closed()))
+    }
+}
+
+/// Start connecting in the background and return immediately, without waiting for the connection to be open
+///
+/// Same as [`connect`](crate::connect), but the handshake is driven by a spawned task:
+/// await [`PendingConnection::opened`] to get the connection.
+///
+/// **Proxy is ignored for WASM targets!**
+pub fn connect_pending(url: &Url, mode: ConnectionMode, timeout: Duration) -> PendingConnection {
+    let (tx, rx) = oneshot::channel();
+    let url: Url = url.clone();
+
+    // If spawning fails, the sender is dropped and the outcome is a closed error
+    let _ = thread::spawn(async move {
+        let outcome: Outcome = crate::connect(&url, mode, timeout).await;
+        let _ = tx.send(outcome);
+    });
+
+    PendingConnection { outcome: Some(rx) }
+}
+
+#[inline]
+fn closed() -> Error {
+    #[cfg(not(target_arch = "wasm32"))]
+    return Error::Closed(None);
+
+    #[cfg(target_arch = "wasm32")]
+    Error::Closed
+}
+
+#[cfg(all(test, feature = "test-util", not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::test_util::MockServer;
+
+    #[tokio::test]
+    async fn test_connect_pending() {
+        let server = MockServer::builder().start().await.unwrap();
+
+        let mut pending =
+            connect_pending(server.url(), ConnectionMode::Direct, Duration::from_secs(5));
+        assert!(pending.opened().await.is_ok());
+        assert!(matches!(pending.opened().await, Err(Error::Closed(None))));
+    }
+}
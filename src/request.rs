@@ -0,0 +1,141 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! One-shot request/response
+
+use std::time::Duration;
+
+use async_utility::time;
+use futures_util::{SinkExt, StreamExt};
+use url::Url;
+
+use crate::{ConnectionMode, Error, Sink, Stream, WsMessage};
+
+/// Connect, send `msg` and wait for the first incoming message for which `matches` returns `true`.
+///
+/// The non-matching messages are discarded. The connection is closed once the response is received
+/// (check [`request_keep_alive`] to reuse it).
+/// The `timeout` covers the whole exchange (connect included): if it expires, [`Error::Timeout`]
+/// is returned. If the connection terminates before a match, the closed error is returned.
+pub async fn request<F>(
+    url: &Url,
+    mode: ConnectionMode,
+    msg: WsMessage,
+    matches: F,
+    timeout: Duration,
+) -> Result<WsMessage, Error>
+where
+    F: FnMut(&WsMessage) -> bool,
+{
+    time::timeout(Some(timeout), async {
+        let (msg, (mut tx, _rx)) = exchange(url, mode, msg, matches, timeout).await?;
+        let _ = tx.close().await;
+        Ok(msg)
+    })
+    .await
+    .ok_or(Error::Timeout)?
+}
+
+/// Like [`request`], but keep the connection open: return the response with the connection halves.
+///
+/// The messages received after the response are left in the [`Stream`].
+pub async fn request_keep_alive<F>(
+    url: &Url,
+    mode: ConnectionMode,
+    msg: WsMessage,
+    matches: F,
+    timeout: Duration,
+) -> Result<(WsMessage, (Sink, Stream)), Error>
+where
+    F: FnMut(&WsMessage) -> bool,
+{
+    time::timeout(Some(timeout), exchange(url, mode, msg, matches, timeout))
+        .await
+        .ok_or(Error::Timeout)?
+}
+
+async fn exchange<F>(
+    url: &Url,
+    mode: ConnectionMode,
+    msg: WsMessage,
+    mut matches: F,
+    timeout: Duration,
+) -> Result<(WsMessage, (Sink, Stream)), Error>
+where
+    F: FnMut(&WsMessage) -> bool,
+{
+    let (mut tx, mut rx): (Sink, Stream) = crate::connect(url, mode, timeout).await?;
+    tx.send(msg).await?;
+
+    while let Some(res) = rx.next().await {
+        let msg: WsMessage = res?;
+
+        #[cfg(not(target_arch = "wasm32"))]
+        if let WsMessage::Close(frame) = msg {
+            return Err(Error::Closed(frame));
+        }
+
+        if matches(&msg) {
+            return Ok((msg, (tx, rx)));
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    return Err(Error::Closed(None));
+
+    #[cfg(target_arch = "wasm32")]
+    return Err(Error::Closed);
+}
+
+#[cfg(all(test, feature = "test-util", not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::test_util::MockServer;
+
+    #[tokio::test]
+    async fn test_request() {
+        let server = MockServer::builder()
+            .expect(|msg| msg.to_text().map(|t| t == "ping 7").unwrap_or(false))
+            .send("pong 6")
+            .send("pong 7")
+            .start()
+            .await
+            .unwrap();
+
+        let res = request(
+            server.url(),
+            ConnectionMode::Direct,
+            WsMessage::text("ping 7"),
+            |msg| msg.to_text().map(|t| t == "pong 7").unwrap_or(false),
+            Duration::from_secs(10),
+        )
+        .await
+        .unwrap();
+        assert_eq!(res, WsMessage::text("pong 7"));
+    }
+
+    #[tokio::test]
+    async fn test_request_keep_alive() {
+        let server = MockServer::builder()
+            .expect(|msg| msg.to_text().map(|t| t == "ping").unwrap_or(false))
+            .send("pong")
+            .send("after")
+            .start()
+            .await
+            .unwrap();
+
+        let (res, (_tx, mut rx)) = request_keep_alive(
+            server.url(),
+            ConnectionMode::Direct,
+            WsMessage::text("ping"),
+            |msg| msg.to_text().map(|t| t == "pong").unwrap_or(false),
+            Duration::from_secs(10),
+        )
+        .await
+        .unwrap();
+        assert_eq!(res, WsMessage::text("pong"));
+
+        // Still open: the next message is received
+        assert_eq!(rx.next().await.unwrap().unwrap(), WsMessage::text("after"));
+    }
+}
@@ -0,0 +1,128 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Channel routing, shared by the [`mux`](crate::mux) and the [`multiplex`](crate::multiplex)
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex as SyncMutex};
+
+use futures_channel::mpsc;
+use futures_core::Stream as StreamTrait;
+use futures_sink::Sink as SinkTrait;
+use futures_util::lock::Mutex;
+use futures_util::{SinkExt, StreamExt};
+
+use crate::{Error, WsMessage};
+
+/// Kind of a channel frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Data,
+    /// The peer closed the channel
+    Close,
+}
+
+/// Header of the channel frames
+pub(crate) trait Codec {
+    fn encode(&self, id: u32, kind: Kind, payload: &[u8]) -> Vec<u8>;
+
+    /// Return `None` if the frame is malformed
+    fn decode<'a>(&self, frame: &'a [u8]) -> Option<(u32, Kind, &'a [u8])>;
+}
+
+/// Senders of the open channels
+pub(crate) type Routes = Arc<SyncMutex<HashMap<u32, mpsc::Sender<Vec<u8>>>>>;
+
+/// Send a channel frame on the shared sink
+pub(crate) async fn send<Si, E, C>(
+    sink: &Mutex<Si>,
+    codec: &C,
+    id: u32,
+    kind: Kind,
+    payload: &[u8],
+) -> Result<(), Error>
+where
+    Si: SinkTrait<WsMessage, Error = E> + Unpin,
+    E: Into<Error>,
+    C: Codec,
+{
+    let frame: Vec<u8> = codec.encode(id, kind, payload);
+    let mut sink = sink.lock().await;
+    sink.send(WsMessage::Binary(frame))
+        .await
+        .map_err(Into::into)
+}
+
+/// Read the connection and route the incoming frames to the channels
+///
+/// Text frames, malformed binary frames and frames for unknown channels are ignored.
+#[derive(Debug)]
+pub(crate) struct Router<St, C> {
+    stream: St,
+    routes: Routes,
+    codec: C,
+}
+
+impl<St, C> Router<St, C> {
+    #[inline]
+    pub(crate) fn new(stream: St, routes: Routes, codec: C) -> Self {
+        Self {
+            stream,
+            routes,
+            codec,
+        }
+    }
+}
+
+impl<St, E, C> Router<St, C>
+where
+    St: StreamTrait<Item = Result<WsMessage, E>> + Unpin,
+    E: Into<Error>,
+    C: Codec,
+{
+    /// Run until the connection terminates, then end all the channel streams
+    pub(crate) async fn run(mut self) -> Result<(), Error> {
+        let res = self.route().await;
+
+        // Drop the senders: every channel stream ends
+        self.routes.lock().expect("routes mutex poisoned").clear();
+
+        res
+    }
+
+    async fn route(&mut self) -> Result<(), Error> {
+        while let Some(msg) = self.stream.next().await {
+            let data: Vec<u8> = match msg.map_err(Into::into)? {
+                WsMessage::Binary(data) => data,
+                #[cfg(not(target_arch = "wasm32"))]
+                WsMessage::Close(..) => break,
+                // Text, ping, pong or raw frame
+                #[allow(unreachable_patterns)]
+                _ => continue,
+            };
+
+            let (id, kind, payload) = match self.codec.decode(&data) {
+                Some(frame) => frame,
+                None => continue,
+            };
+
+            let tx: Option<mpsc::Sender<Vec<u8>>> = {
+                let mut routes = self.routes.lock().expect("routes mutex poisoned");
+                match kind {
+                    Kind::Data => routes.get(&id).cloned(),
+                    Kind::Close => {
+                        routes.remove(&id);
+                        None
+                    }
+                }
+            };
+
+            if let Some(mut tx) = tx {
+                // The stream may have been dropped
+                let _ = tx.send(payload.to_vec()).await;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,388 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Request/response correlation
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex as SyncMutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_utility::time;
+use futures_channel::{mpsc, oneshot};
+use futures_core::Stream as StreamTrait;
+use futures_sink::Sink as SinkTrait;
+use futures_util::lock::Mutex;
+use futures_util::{SinkExt, StreamExt};
+use thiserror::Error;
+
+use crate::{Error, WsMessage};
+
+/// Default number of unmatched messages queued
+const DEFAULT_UNMATCHED_CAPACITY: usize = 64;
+
+/// Extract the correlation IDs of the messages
+pub trait Correlator {
+    /// Correlation ID
+    type Id: Eq + Hash + Clone;
+
+    /// Get the ID of an outgoing request
+    fn request_id(&self, msg: &WsMessage) -> Option<Self::Id>;
+
+    /// Get the ID of an incoming message, if it's a response
+    fn response_id(&self, msg: &WsMessage) -> Option<Self::Id>;
+}
+
+/// [`RpcClient`] error
+#[derive(Debug, Error)]
+pub enum RpcError {
+    /// Connection error
+    #[error(transparent)]
+    Ws(#[from] Error),
+    /// The correlator returned no ID for the request
+    #[error("request has no ID")]
+    MissingId,
+    /// A call with the same ID is already pending
+    #[error("a call with the same ID is already pending")]
+    DuplicateId,
+    /// No response received within the timeout
+    #[error("timeout")]
+    Timeout,
+    /// The connection terminated before the response
+    #[error("connection closed")]
+    Closed,
+}
+
+type Responder = oneshot::Sender<WsMessage>;
+
+#[derive(Debug)]
+struct Pending<Id> {
+    calls: HashMap<Id, Responder>,
+    closed: bool,
+}
+
+type SharedPending<Id> = Arc<SyncMutex<Pending<Id>>>;
+
+/// Pending call, removed from the [`Pending`] calls when dropped (i.e. on timeout or cancellation)
+struct PendingCall<'a, Id>
+where
+    Id: Eq + Hash,
+{
+    pending: &'a SharedPending<Id>,
+    id: Id,
+    rx: Option<oneshot::Receiver<WsMessage>>,
+}
+
+impl<Id> Drop for PendingCall<'_, Id>
+where
+    Id: Eq + Hash,
+{
+    fn drop(&mut self) {
+        // Cancel the responder first: the ID may have been reused by a following call meanwhile
+        drop(self.rx.take());
+
+        let mut pending = self.pending.lock().expect("pending mutex poisoned");
+        if matches!(pending.calls.get(&self.id), Some(tx) if tx.is_canceled()) {
+            pending.calls.remove(&self.id);
+        }
+    }
+}
+
+/// Request/response client
+///
+/// Every [`RpcClient::call`] waits for the incoming message with the same correlation ID.
+/// If many responses share an ID, the first one resolves the call and the others are
+/// yielded by the [`UnmatchedStream`], as well as all the other messages (i.e. server push).
+#[derive(Debug)]
+pub struct RpcClient<Si, C>
+where
+    C: Correlator,
+{
+    sink: Arc<Mutex<Si>>,
+    correlator: Arc<C>,
+    pending: SharedPending<C::Id>,
+}
+
+impl<Si, C> RpcClient<Si, C>
+where
+    C: Correlator,
+{
+    /// Wrap the two halves of a connection
+    ///
+    /// The [`RpcDriver`] reads the connection and dispatches the responses:
+    /// it must be polled (i.e. spawned) for the calls to complete.
+    pub fn new<St>(
+        sink: Si,
+        stream: St,
+        correlator: C,
+    ) -> (Self, RpcDriver<St, C>, UnmatchedStream) {
+        let correlator = Arc::new(correlator);
+        let pending = Arc::new(SyncMutex::new(Pending {
+            calls: HashMap::new(),
+            closed: false,
+        }));
+        let (tx, rx) = mpsc::channel(DEFAULT_UNMATCHED_CAPACITY);
+
+        let client = Self {
+            sink: Arc::new(Mutex::new(sink)),
+            correlator: correlator.clone(),
+            pending: pending.clone(),
+        };
+        let driver = RpcDriver {
+            stream,
+            correlator,
+            pending,
+            unmatched: tx,
+        };
+
+        (client, driver, UnmatchedStream { rx })
+    }
+}
+
+impl<Si, C, E> RpcClient<Si, C>
+where
+    Si: SinkTrait<WsMessage, Error = E> + Unpin,
+    E: Into<Error>,
+    C: Correlator,
+{
+    /// Send a request and wait for its response
+    ///
+    /// Fail with [`RpcError::Closed`] if the connection terminates before the response
+    /// (pending calls included) and with [`RpcError::Timeout`] if `timeout` expires.
+    pub async fn call(&self, msg: WsMessage, timeout: Duration) -> Result<WsMessage, RpcError> {
+        let id: C::Id = self
+            .correlator
+            .request_id(&msg)
+            .ok_or(RpcError::MissingId)?;
+
+        let mut call: PendingCall<'_, C::Id> = {
+            let mut pending = self.pending.lock().expect("pending mutex poisoned");
+
+            if pending.closed {
+                return Err(RpcError::Closed);
+            }
+
+            if pending.calls.contains_key(&id) {
+                return Err(RpcError::DuplicateId);
+            }
+
+            let (tx, rx) = oneshot::channel();
+            pending.calls.insert(id.clone(), tx);
+            PendingCall {
+                pending: &self.pending,
+                id,
+                rx: Some(rx),
+            }
+        };
+
+        time::timeout(Some(timeout), async {
+            let mut sink = self.sink.lock().await;
+            sink.send(msg).await.map_err(|e| RpcError::Ws(e.into()))?;
+            drop(sink);
+
+            let rx = call.rx.as_mut().expect("taken on drop only");
+            rx.await.map_err(|_| RpcError::Closed)
+        })
+        .await
+        .unwrap_or(Err(RpcError::Timeout))
+    }
+}
+
+/// Messages not matching any pending call
+///
+/// Up to 64 messages are queued: when full, the following ones are dropped until it's read,
+/// so keeping it unread never stalls the calls. Ends when the connection terminates.
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct UnmatchedStream {
+    rx: mpsc::Receiver<WsMessage>,
+}
+
+impl StreamTrait for UnmatchedStream {
+    type Item = WsMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_next_unpin(cx)
+    }
+}
+
+/// Read the connection and dispatch the responses to the pending calls
+#[derive(Debug)]
+#[must_use = "the driver must be run for the calls to complete"]
+pub struct RpcDriver<St, C>
+where
+    C: Correlator,
+{
+    stream: St,
+    correlator: Arc<C>,
+    pending: SharedPending<C::Id>,
+    unmatched: mpsc::Sender<WsMessage>,
+}
+
+impl<St, C, E> RpcDriver<St, C>
+where
+    St: StreamTrait<Item = Result<WsMessage, E>> + Unpin,
+    E: Into<Error>,
+    C: Correlator,
+{
+    /// Run until the connection terminates
+    ///
+    /// All the pending calls fail with [`RpcError::Closed`] and the [`UnmatchedStream`] ends when this returns.
+    pub async fn run(mut self) -> Result<(), Error> {
+        let res = self.dispatch().await;
+
+        // Drop the responders: every pending call fails
+        let mut pending = self.pending.lock().expect("pending mutex poisoned");
+        pending.closed = true;
+        pending.calls.clear();
+
+        res
+    }
+
+    async fn dispatch(&mut self) -> Result<(), Error> {
+        while let Some(msg) = self.stream.next().await {
+            let msg: WsMessage = msg.map_err(Into::into)?;
+
+            #[cfg(not(target_arch = "wasm32"))]
+            if msg.is_close() {
+                break;
+            }
+
+            let msg: WsMessage = match self.correlator.response_id(&msg) {
+                Some(id) => {
+                    let responder: Option<Responder> = self
+                        .pending
+                        .lock()
+                        .expect("pending mutex poisoned")
+                        .calls
+                        .remove(&id);
+                    match responder {
+                        // The call may have timed out meanwhile
+                        Some(responder) => match responder.send(msg) {
+                            Ok(()) => continue,
+                            Err(msg) => msg,
+                        },
+                        None => msg,
+                    }
+                }
+                None => msg,
+            };
+
+            // The unmatched stream may have been dropped, or be full
+            if let Err(_e) = self.unmatched.try_send(msg) {
+                #[cfg(feature = "tracing")]
+                if _e.is_full() {
+                    tracing::debug!("Unmatched stream full: message dropped");
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "test-util", not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    /// ID is the text before the first `:`
+    struct Prefix;
+
+    impl Correlator for Prefix {
+        type Id = String;
+
+        fn request_id(&self, msg: &WsMessage) -> Option<String> {
+            self.response_id(msg)
+        }
+
+        fn response_id(&self, msg: &WsMessage) -> Option<String> {
+            let text = msg.to_text().ok()?;
+            text.split_once(':').map(|(id, _)| id.to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rpc_client() {
+        let ((client_tx, client_rx), (mut server_tx, mut server_rx)) =
+            test_util::pair().await.unwrap();
+
+        let (client, driver, mut unmatched) = RpcClient::new(client_tx, client_rx, Prefix);
+        let driver = tokio::spawn(driver.run());
+
+        // Server: push a message, then reply twice to every request
+        tokio::spawn(async move {
+            server_tx.send(WsMessage::text("push")).await.unwrap();
+            while let Some(Ok(msg)) = server_rx.next().await {
+                if msg.is_text() {
+                    server_tx.send(msg.clone()).await.unwrap();
+                    server_tx.send(msg).await.unwrap();
+                }
+            }
+        });
+
+        let timeout = Duration::from_secs(5);
+        let res = client.call(WsMessage::text("1:a"), timeout).await.unwrap();
+        assert_eq!(res, WsMessage::text("1:a"));
+
+        assert!(matches!(
+            client.call(WsMessage::text("no id"), timeout).await,
+            Err(RpcError::MissingId)
+        ));
+
+        // The server push and the duplicate response are unmatched
+        assert_eq!(unmatched.next().await.unwrap(), WsMessage::text("push"));
+        assert_eq!(unmatched.next().await.unwrap(), WsMessage::text("1:a"));
+
+        // Pending calls fail when the connection closes
+        client.sink.lock().await.close().await.unwrap();
+        driver.await.unwrap().unwrap();
+        assert!(matches!(
+            client.call(WsMessage::text("2:b"), timeout).await,
+            Err(RpcError::Closed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_call() {
+        let ((client_tx, client_rx), (_server_tx, _server_rx)) = test_util::pair().await.unwrap();
+
+        let (client, driver, _unmatched) = RpcClient::new(client_tx, client_rx, Prefix);
+        tokio::spawn(driver.run());
+
+        // Never answered: cancelled by the select
+        let call = client.call(WsMessage::text("1:a"), Duration::from_secs(60));
+        let cancelled = tokio::time::timeout(Duration::from_millis(50), call).await;
+        assert!(cancelled.is_err());
+        assert!(client.pending.lock().unwrap().calls.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unread_unmatched() {
+        let ((client_tx, client_rx), (mut server_tx, mut server_rx)) =
+            test_util::pair().await.unwrap();
+
+        // Kept, but never read
+        let (client, driver, _unmatched) = RpcClient::new(client_tx, client_rx, Prefix);
+        tokio::spawn(driver.run());
+
+        tokio::spawn(async move {
+            for _ in 0..DEFAULT_UNMATCHED_CAPACITY * 2 {
+                server_tx.send(WsMessage::text("push")).await.unwrap();
+            }
+            while let Some(Ok(msg)) = server_rx.next().await {
+                if msg.is_text() {
+                    server_tx.send(msg).await.unwrap();
+                }
+            }
+        });
+
+        let res = client
+            .call(WsMessage::text("1:a"), Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(res, WsMessage::text("1:a"));
+    }
+}
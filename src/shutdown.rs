@@ -0,0 +1,146 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Graceful shutdown
+
+use std::time::Duration;
+
+use async_utility::time;
+#[cfg(not(target_arch = "wasm32"))]
+use futures_util::future;
+#[cfg(not(target_arch = "wasm32"))]
+use futures_util::SinkExt;
+use futures_util::StreamExt;
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::native::Message;
+use crate::{Sink, Stream};
+
+/// How a connection was shut down
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ShutdownReport {
+    /// The close handshake completed before the deadline
+    Clean,
+    /// The close handshake failed or didn't complete before the deadline: the connection was dropped
+    Forced,
+}
+
+/// Close many connections politely within a `deadline`.
+///
+/// The close handshake is initiated on each connection and the peer close frames are awaited
+/// until the `deadline`, then the remaining connections are dropped.
+///
+/// On native the connections are closed concurrently. On WASM they are closed sequentially and
+/// the `deadline` applies to the whole sequence.
+///
+/// The returned reports are in the same order of the input connections.
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn shutdown_all<I>(
+    connections: I,
+    code: u16,
+    reason: &str,
+    deadline: Duration,
+) -> Vec<ShutdownReport>
+where
+    I: IntoIterator<Item = (Sink, Stream)>,
+{
+    let futures = connections
+        .into_iter()
+        .map(|(tx, rx)| shutdown(tx, rx, code, reason, deadline));
+    future::join_all(futures).await
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+async fn shutdown(
+    mut tx: Sink,
+    mut rx: Stream,
+    code: u16,
+    reason: &str,
+    deadline: Duration,
+) -> ShutdownReport {
+    use std::borrow::Cow;
+
+    use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+
+    let handshake = async {
+        let frame = CloseFrame {
+            code: code.into(),
+            reason: Cow::Owned(reason.to_string()),
+        };
+        tx.send(Message::Close(Some(frame))).await?;
+
+        // Wait for the peer close frame
+        while let Some(msg) = rx.next().await {
+            if msg?.is_close() {
+                return Ok(ShutdownReport::Clean);
+            }
+        }
+
+        Ok::<_, crate::Error>(ShutdownReport::Forced)
+    };
+
+    match time::timeout(Some(deadline), handshake).await {
+        Some(Ok(report)) => report,
+        Some(Err(..)) | None => ShutdownReport::Forced,
+    }
+}
+
+/// Close many connections politely within a `deadline`.
+///
+/// The close handshake is initiated on each connection and the peer close frames are awaited
+/// until the `deadline`, then the remaining connections are dropped.
+///
+/// On native the connections are closed concurrently. On WASM they are closed sequentially and
+/// the `deadline` applies to the whole sequence.
+///
+/// The returned reports are in the same order of the input connections.
+#[cfg(target_arch = "wasm32")]
+pub async fn shutdown_all<I>(
+    connections: I,
+    code: u16,
+    reason: &str,
+    deadline: Duration,
+) -> Vec<ShutdownReport>
+where
+    I: IntoIterator<Item = (Sink, Stream)>,
+{
+    let connections: Vec<(Sink, Stream)> = connections.into_iter().collect();
+    let mut reports: Vec<ShutdownReport> = vec![ShutdownReport::Forced; connections.len()];
+
+    // Not completed connections are dropped (and so closed) on timeout
+    let _ = time::timeout(Some(deadline), async {
+        for ((tx, rx), report) in connections.into_iter().zip(reports.iter_mut()) {
+            let mut ws = match tx.reunite(rx) {
+                Ok(ws) => ws,
+                Err(..) => continue,
+            };
+
+            if ws.initiate_close(code, reason).is_err() {
+                continue;
+            }
+
+            // Wait for the stream to end
+            while ws.next().await.is_some() {}
+
+            *report = ShutdownReport::Clean;
+        }
+    })
+    .await;
+
+    reports
+}
+
+#[cfg(all(test, feature = "test-util", not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shutdown_deadline_virtual_time() {
+        // The server side is never polled, so the close handshake can't complete
+        let (client, _server) = test_util::pair().await.unwrap();
+
+        let reports = shutdown_all([client], 1000, "bye", Duration::from_secs(3600)).await;
+        assert_eq!(reports, vec![ShutdownReport::Forced]);
+    }
+}
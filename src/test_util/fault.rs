@@ -0,0 +1,442 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Fault injection
+
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{self, Sleep};
+
+/// Latency applied to every read or write
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Latency {
+    /// No latency
+    #[default]
+    None,
+    /// Fixed latency
+    Fixed(Duration),
+    /// Random latency in the `min..=max` range
+    ///
+    /// The random generator is seeded with [`FaultPlan::seed`], so runs are reproducible.
+    Random {
+        /// Min latency
+        min: Duration,
+        /// Max latency
+        max: Duration,
+    },
+}
+
+/// Scripted misbehaviors of a [`FaultyTransport`]
+///
+/// Byte offsets refer to the incoming (read) or outgoing (write) direction as documented.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FaultPlan {
+    read_latency: Latency,
+    write_latency: Latency,
+    stall_after: Option<usize>,
+    reset_after: Option<usize>,
+    truncate_writes_after: Option<usize>,
+    flip_bit_at: Option<usize>,
+    seed: u64,
+}
+
+impl Default for FaultPlan {
+    fn default() -> Self {
+        Self {
+            read_latency: Latency::None,
+            write_latency: Latency::None,
+            stall_after: None,
+            reset_after: None,
+            truncate_writes_after: None,
+            flip_bit_at: None,
+            seed: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+}
+
+impl FaultPlan {
+    /// New plan without faults
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Latency applied to every read
+    #[inline]
+    pub fn read_latency(mut self, latency: Latency) -> Self {
+        self.read_latency = latency;
+        self
+    }
+
+    /// Latency applied to every write
+    #[inline]
+    pub fn write_latency(mut self, latency: Latency) -> Self {
+        self.write_latency = latency;
+        self
+    }
+
+    /// Stop delivering incoming data after `bytes` have been read: reads never complete.
+    #[inline]
+    pub fn stall_after(mut self, bytes: usize) -> Self {
+        self.stall_after = Some(bytes);
+        self
+    }
+
+    /// Abruptly close the transport after `bytes` have been read:
+    /// reads and writes fail with [`ErrorKind::ConnectionReset`].
+    #[inline]
+    pub fn reset_after(mut self, bytes: usize) -> Self {
+        self.reset_after = Some(bytes);
+        self
+    }
+
+    /// Silently drop outgoing data after `bytes` have been written.
+    #[inline]
+    pub fn truncate_writes_after(mut self, bytes: usize) -> Self {
+        self.truncate_writes_after = Some(bytes);
+        self
+    }
+
+    /// Flip the lowest bit of the incoming byte at `offset`.
+    #[inline]
+    pub fn flip_bit_at(mut self, offset: usize) -> Self {
+        self.flip_bit_at = Some(offset);
+        self
+    }
+
+    /// Seed of the random generator used for [`Latency::Random`]
+    #[inline]
+    pub fn seed(mut self, seed: u64) -> Self {
+        // Xorshift state can't be zero
+        self.seed = seed.max(1);
+        self
+    }
+}
+
+#[derive(Debug, Default)]
+struct Delay {
+    sleep: Option<Pin<Box<Sleep>>>,
+    /// The delay of the current operation elapsed
+    elapsed: bool,
+}
+
+impl Delay {
+    fn poll(&mut self, cx: &mut Context<'_>, duration: Option<Duration>) -> Poll<()> {
+        if self.elapsed {
+            return Poll::Ready(());
+        }
+
+        if let Some(duration) = duration {
+            let sleep = self
+                .sleep
+                .get_or_insert_with(|| Box::pin(time::sleep(duration)));
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+
+        self.elapsed = true;
+        Poll::Ready(())
+    }
+
+    /// The delay of the current operation was already drawn
+    #[inline]
+    fn is_armed(&self) -> bool {
+        self.elapsed || self.sleep.is_some()
+    }
+
+    #[inline]
+    fn reset(&mut self) {
+        self.sleep = None;
+        self.elapsed = false;
+    }
+}
+
+/// Transport wrapper injecting scripted faults, for resilience testing.
+///
+/// Use it with [`connect_with_stream`](crate::native::connect_with_stream).
+#[derive(Debug)]
+pub struct FaultyTransport<S> {
+    inner: S,
+    plan: FaultPlan,
+    rng: u64,
+    read_pos: usize,
+    write_pos: usize,
+    read_delay: Delay,
+    write_delay: Delay,
+}
+
+impl<S> FaultyTransport<S> {
+    /// Wrap a transport
+    pub fn wrap(inner: S, plan: FaultPlan) -> Self {
+        Self {
+            inner,
+            rng: plan.seed,
+            plan,
+            read_pos: 0,
+            write_pos: 0,
+            read_delay: Delay::default(),
+            write_delay: Delay::default(),
+        }
+    }
+
+    /// Bytes read so far
+    #[inline]
+    pub fn bytes_read(&self) -> usize {
+        self.read_pos
+    }
+
+    /// Bytes written so far (including the dropped ones)
+    #[inline]
+    pub fn bytes_written(&self) -> usize {
+        self.write_pos
+    }
+
+    /// Xorshift64
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    fn latency(&mut self, latency: Latency) -> Option<Duration> {
+        match latency {
+            Latency::None => None,
+            Latency::Fixed(duration) => Some(duration),
+            Latency::Random { min, max } => {
+                let range: u64 = max.saturating_sub(min).as_nanos() as u64;
+                let offset: u64 = match range.checked_add(1) {
+                    Some(modulo) => self.next_random() % modulo,
+                    None => self.next_random(),
+                };
+                Some(min + Duration::from_nanos(offset))
+            }
+        }
+    }
+
+    #[inline]
+    fn is_reset(&self) -> bool {
+        matches!(self.plan.reset_after, Some(limit) if self.read_pos >= limit)
+    }
+}
+
+impl<S> AsyncRead for FaultyTransport<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+
+        if this.is_reset() {
+            return Poll::Ready(Err(ErrorKind::ConnectionReset.into()));
+        }
+
+        if matches!(this.plan.stall_after, Some(limit) if this.read_pos >= limit) {
+            // Never wake up
+            return Poll::Pending;
+        }
+
+        // Draw once per operation, not on every poll
+        let latency = if this.read_delay.is_armed() {
+            None
+        } else {
+            this.latency(this.plan.read_latency)
+        };
+        if this.read_delay.poll(cx, latency).is_pending() {
+            return Poll::Pending;
+        }
+
+        // Don't read past the next fault
+        let mut len: usize = buf.remaining();
+        for limit in [this.plan.stall_after, this.plan.reset_after]
+            .into_iter()
+            .flatten()
+        {
+            len = len.min(limit.saturating_sub(this.read_pos));
+        }
+
+        let mut tmp: Vec<u8> = vec![0; len];
+        let mut tmp_buf = ReadBuf::new(&mut tmp);
+        match Pin::new(&mut this.inner).poll_read(cx, &mut tmp_buf) {
+            Poll::Ready(Ok(())) => {
+                let filled: &mut [u8] = tmp_buf.filled_mut();
+
+                if let Some(offset) = this.plan.flip_bit_at {
+                    if offset >= this.read_pos && offset < this.read_pos + filled.len() {
+                        filled[offset - this.read_pos] ^= 0x01;
+                    }
+                }
+
+                this.read_pos += filled.len();
+                buf.put_slice(filled);
+                this.read_delay.reset();
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(e)) => {
+                this.read_delay.reset();
+                Poll::Ready(Err(e))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<S> AsyncWrite for FaultyTransport<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+
+        if this.is_reset() {
+            return Poll::Ready(Err(ErrorKind::ConnectionReset.into()));
+        }
+
+        // Draw once per operation, not on every poll
+        let latency = if this.write_delay.is_armed() {
+            None
+        } else {
+            this.latency(this.plan.write_latency)
+        };
+        if this.write_delay.poll(cx, latency).is_pending() {
+            return Poll::Pending;
+        }
+
+        let len: usize = match this.plan.truncate_writes_after {
+            Some(limit) => {
+                let remaining: usize = limit.saturating_sub(this.write_pos);
+                if remaining == 0 {
+                    // Pretend the data was written
+                    this.write_pos += buf.len();
+                    this.write_delay.reset();
+                    return Poll::Ready(Ok(buf.len()));
+                }
+                remaining.min(buf.len())
+            }
+            None => buf.len(),
+        };
+
+        match Pin::new(&mut this.inner).poll_write(cx, &buf[..len]) {
+            Poll::Ready(res) => {
+                if let Ok(written) = res {
+                    this.write_pos += written;
+                }
+                this.write_delay.reset();
+                Poll::Ready(res)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.is_reset() {
+            return Poll::Ready(Err(ErrorKind::ConnectionReset.into()));
+        }
+
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{future, SinkExt, StreamExt};
+    use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+    use url::Url;
+
+    use super::*;
+    use crate::native::{self, ConnectOptions, Error, Message};
+
+    #[tokio::test]
+    async fn test_handshake_timeout() {
+        let (client, _server) = io::duplex(1024);
+        let client = FaultyTransport::wrap(client, FaultPlan::new().stall_after(0));
+        let url = Url::parse("ws://localhost").unwrap();
+
+        let res = native::connect_with_stream(
+            &url,
+            client,
+            Duration::from_millis(100),
+            &ConnectOptions::default(),
+        )
+        .await;
+        assert!(matches!(res, Err(Error::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn test_mid_frame_reset() {
+        let (client, server) = io::duplex(64 * 1024);
+        // The handshake response is way smaller than 1 KiB
+        let client = FaultyTransport::wrap(client, FaultPlan::new().reset_after(1024));
+        let url = Url::parse("ws://localhost").unwrap();
+
+        let ((_client_tx, mut client_rx), (mut server_tx, _server_rx)) = future::try_join(
+            native::connect_with_stream(
+                &url,
+                client,
+                Duration::from_secs(10),
+                &ConnectOptions::default(),
+            ),
+            native::accept(server),
+        )
+        .await
+        .unwrap();
+
+        server_tx
+            .send(Message::Binary(vec![0; 16 * 1024]))
+            .await
+            .unwrap();
+
+        let res = client_rx.next().await.unwrap();
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_random_latency_drawn_once() {
+        let (client, mut server) = io::duplex(1024);
+        let plan = FaultPlan::new()
+            .read_latency(Latency::Random {
+                min: Duration::from_millis(10),
+                max: Duration::from_millis(50),
+            })
+            .seed(7);
+        let mut client = FaultyTransport::wrap(client, plan.clone());
+
+        // Poll the pending read many times
+        let mut buf = [0; 4];
+        for _ in 0..10 {
+            let res = future::poll_fn(|cx| {
+                let mut read_buf = ReadBuf::new(&mut buf);
+                Poll::Ready(Pin::new(&mut client).poll_read(cx, &mut read_buf))
+            })
+            .await;
+            assert!(res.is_pending());
+        }
+
+        // A single draw
+        let mut rng = FaultyTransport::wrap((), plan);
+        rng.next_random();
+        assert_eq!(client.rng, rng.rng);
+
+        server.write_all(b"ping").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+}
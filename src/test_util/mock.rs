@@ -0,0 +1,365 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Scripted mock server
+
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{HeaderName, HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::WebSocketStream;
+use url::Url;
+
+use crate::native::{Error, Message};
+
+type Predicate = Box<dyn Fn(&Message) -> bool + Send + Sync>;
+
+enum Step {
+    Expect(Predicate),
+    Send(Message),
+    Wait(Duration),
+    Close { code: u16, reason: String },
+}
+
+impl fmt::Debug for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Expect(..) => write!(f, "Expect"),
+            Self::Send(msg) => f.debug_tuple("Send").field(msg).finish(),
+            Self::Wait(duration) => f.debug_tuple("Wait").field(duration).finish(),
+            Self::Close { code, reason } => f
+                .debug_struct("Close")
+                .field("code", code)
+                .field("reason", reason)
+                .finish(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Rejection {
+    status: u16,
+    headers: Vec<(String, String)>,
+}
+
+/// [`MockServer`] builder
+///
+/// The steps are run in order on the first accepted connection.
+#[derive(Debug, Default)]
+pub struct MockServerBuilder {
+    steps: Vec<Step>,
+    rejection: Option<Rejection>,
+}
+
+impl MockServerBuilder {
+    /// Expect the next data (text, binary or close) message to match the `predicate`
+    pub fn expect<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&Message) -> bool + Send + Sync + 'static,
+    {
+        self.steps.push(Step::Expect(Box::new(predicate)));
+        self
+    }
+
+    /// Send a message
+    pub fn send<M>(mut self, msg: M) -> Self
+    where
+        M: Into<Message>,
+    {
+        self.steps.push(Step::Send(msg.into()));
+        self
+    }
+
+    /// Wait before running the next step
+    pub fn wait(mut self, duration: Duration) -> Self {
+        self.steps.push(Step::Wait(duration));
+        self
+    }
+
+    /// Close the connection with a code and a reason
+    pub fn close<S>(mut self, code: u16, reason: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.steps.push(Step::Close {
+            code,
+            reason: reason.into(),
+        });
+        self
+    }
+
+    /// Reject the handshake with an HTTP `status` and `headers` (i.e. `429` and `Retry-After: 3`)
+    ///
+    /// The script steps aren't run.
+    pub fn reject<I, K, V>(mut self, status: u16, headers: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.rejection = Some(Rejection {
+            status,
+            headers: headers
+                .into_iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect(),
+        });
+        self
+    }
+
+    /// Bind an ephemeral local port and start serving the script
+    pub async fn start(self) -> Result<MockServer, Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let url = Url::parse(&format!("ws://{addr}"))?;
+
+        let total: usize = self.steps.len();
+        let state = Arc::new(State::default());
+
+        let handle = tokio::spawn(serve(listener, self, state.clone()));
+
+        Ok(MockServer {
+            url,
+            total,
+            state,
+            handle,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    done: AtomicUsize,
+    failure: Mutex<Option<String>>,
+}
+
+impl State {
+    fn fail(&self, failure: String) {
+        let mut f = self.failure.lock().unwrap_or_else(|e| e.into_inner());
+        f.get_or_insert(failure);
+    }
+}
+
+/// Local WebSocket server running a script, for tests
+///
+/// **Panics on drop** if a step failed or wasn't consumed.
+#[derive(Debug)]
+pub struct MockServer {
+    url: Url,
+    total: usize,
+    state: Arc<State>,
+    handle: JoinHandle<()>,
+}
+
+impl MockServer {
+    /// Script builder
+    #[inline]
+    pub fn builder() -> MockServerBuilder {
+        MockServerBuilder::default()
+    }
+
+    /// Server URL (`ws://127.0.0.1:PORT`)
+    #[inline]
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+
+        // Don't panic while panicking
+        if std::thread::panicking() {
+            return;
+        }
+
+        let failure = self
+            .state
+            .failure
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+        if let Some(failure) = failure {
+            panic!("mock server: {failure}");
+        }
+
+        let done: usize = self.state.done.load(Ordering::SeqCst);
+        if done < self.total {
+            panic!(
+                "mock server: {} of {} script steps not consumed",
+                self.total - done,
+                self.total
+            );
+        }
+    }
+}
+
+async fn serve(listener: TcpListener, builder: MockServerBuilder, state: Arc<State>) {
+    let stream: TcpStream = match listener.accept().await {
+        Ok((stream, _)) => stream,
+        Err(e) => return state.fail(format!("accept failed: {e}")),
+    };
+
+    let rejection = builder.rejection;
+    // The signature is set by tungstenite
+    #[allow(clippy::result_large_err)]
+    let callback = |_: &Request, res: Response| match rejection {
+        Some(rejection) => Err(error_response(rejection)),
+        None => Ok(res),
+    };
+
+    let mut ws: WebSocketStream<TcpStream> =
+        match tokio_tungstenite::accept_hdr_async(stream, callback).await {
+            Ok(ws) => ws,
+            // Rejected or failed: nothing else to do
+            Err(..) => return,
+        };
+
+    for (index, step) in builder.steps.into_iter().enumerate() {
+        // Outgoing steps are consumed as soon as they are started: the client may observe
+        // them (and drop the server) before the write returns.
+        let outgoing: bool = matches!(step, Step::Send(..) | Step::Close { .. });
+        if outgoing {
+            state.done.fetch_add(1, Ordering::SeqCst);
+        }
+
+        let res: Result<(), String> = match step {
+            Step::Expect(predicate) => match next_data(&mut ws).await {
+                Some(msg) if predicate(&msg) => Ok(()),
+                Some(msg) => Err(format!("step {index}: unexpected message {msg:?}")),
+                None => Err(format!("step {index}: connection closed")),
+            },
+            Step::Send(msg) => ws
+                .send(msg)
+                .await
+                .map_err(|e| format!("step {index}: send failed: {e}")),
+            Step::Wait(duration) => {
+                tokio::time::sleep(duration).await;
+                Ok(())
+            }
+            Step::Close { code, reason } => {
+                let frame = CloseFrame {
+                    code: code.into(),
+                    reason: Cow::Owned(reason),
+                };
+                ws.close(Some(frame))
+                    .await
+                    .map_err(|e| format!("step {index}: close failed: {e}"))
+            }
+        };
+
+        match res {
+            Ok(()) => {
+                if !outgoing {
+                    state.done.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+            Err(failure) => return state.fail(failure),
+        }
+    }
+
+    // Keep answering pings and the close handshake
+    while let Some(Ok(..)) = ws.next().await {}
+}
+
+/// Next text, binary or close message
+async fn next_data(ws: &mut WebSocketStream<TcpStream>) -> Option<Message> {
+    while let Some(Ok(msg)) = ws.next().await {
+        match msg {
+            Message::Text(..) | Message::Binary(..) | Message::Close(..) => return Some(msg),
+            Message::Ping(..) | Message::Pong(..) | Message::Frame(..) => {}
+        }
+    }
+
+    None
+}
+
+fn error_response(rejection: Rejection) -> ErrorResponse {
+    let mut res = ErrorResponse::new(None);
+    *res.status_mut() =
+        StatusCode::from_u16(rejection.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+
+    for (name, value) in rejection.headers {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(name), HeaderValue::try_from(value)) {
+            res.headers_mut().append(name, value);
+        }
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{native, ConnectionMode};
+
+    #[tokio::test]
+    async fn test_scripted_close() {
+        let server = MockServer::builder()
+            .expect(|msg| msg.to_text().map(|t| t == "hello").unwrap_or(false))
+            .send("one")
+            .send("two")
+            .close(4001, "bye")
+            .start()
+            .await
+            .unwrap();
+
+        let (mut tx, mut rx) = native::connect(
+            server.url(),
+            ConnectionMode::Direct,
+            Duration::from_secs(10),
+        )
+        .await
+        .unwrap();
+        tx.send(Message::text("hello")).await.unwrap();
+
+        assert_eq!(rx.next().await.unwrap().unwrap(), Message::text("one"));
+        assert_eq!(rx.next().await.unwrap().unwrap(), Message::text("two"));
+        match rx.next().await.unwrap().unwrap() {
+            Message::Close(frame) => {
+                assert_eq!(crate::close_code::from_close_frame(frame.as_ref()), 4001)
+            }
+            msg => panic!("unexpected message: {msg:?}"),
+        }
+        tx.flush().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rejected_handshake() {
+        let server = MockServer::builder()
+            .reject(429, [("Retry-After", "3")])
+            .start()
+            .await
+            .unwrap();
+
+        let res = native::connect(
+            server.url(),
+            ConnectionMode::Direct,
+            Duration::from_secs(10),
+        )
+        .await;
+        match res {
+            Err(Error::HandshakeRejected {
+                status,
+                retry_after,
+                response,
+            }) => {
+                assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+                assert_eq!(retry_after, Some(Duration::from_secs(3)));
+                assert_eq!(response.status(), status);
+                assert_eq!(response.headers()["retry-after"], "3");
+            }
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+    }
+}
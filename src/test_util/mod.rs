@@ -0,0 +1,54 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Test utilities
+//!
+//! **Only for testing!**
+
+use std::time::Duration;
+
+use futures_util::{future, SinkExt, StreamExt};
+use tokio::io;
+use url::Url;
+
+use crate::native::{self, ConnectOptions, Error, Message, Sink, Stream};
+
+mod fault;
+mod mock;
+
+pub use self::fault::{FaultPlan, FaultyTransport, Latency};
+pub use self::mock::{MockServer, MockServerBuilder};
+
+/// Size of the in-memory pipe buffer
+const DUPLEX_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Create two connections linked back-to-back over an in-memory pipe.
+///
+/// A real client/server handshake is performed, so framing, close handshakes and pings
+/// behave like in production. The first item is the client side, the second the server side.
+pub async fn pair() -> Result<((Sink, Stream), (Sink, Stream)), Error> {
+    let (client, server) = io::duplex(DUPLEX_BUFFER_SIZE);
+    let url = Url::parse("ws://localhost").expect("valid url");
+    let opts = ConnectOptions::default();
+    future::try_join(
+        native::connect_with_stream(&url, client, Duration::from_secs(60), &opts),
+        native::accept(server),
+    )
+    .await
+}
+
+/// Drive a connection as an echo peer: every text and binary message is sent back.
+///
+/// Return when the connection is closed.
+pub async fn echo(mut tx: Sink, mut rx: Stream) -> Result<(), Error> {
+    while let Some(msg) = rx.next().await {
+        match msg? {
+            msg @ Message::Text(..) | msg @ Message::Binary(..) => tx.send(msg).await?,
+            // Flush the close frame reply queued by the backend
+            Message::Close(..) => tx.flush().await?,
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,175 @@
+// Copyright (c) 2023-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Message delivery flow control
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Arc;
+use std::task::Waker;
+
+use crate::wasm::heartbeat::Replies;
+use crate::wasm::{WsError, WsMessage};
+
+/// Max number of messages buffered while the delivery is paused
+pub(crate) const MAX_PAUSED_MESSAGES: usize = 1024;
+
+/// Incoming messages shared between the `onmessage` callback, [`WsStream`](crate::wasm::WsStream)
+/// and [`WebSocket`](crate::wasm::WebSocket).
+#[derive(Default)]
+pub(crate) struct Delivery {
+    queue: RefCell<VecDeque<Result<WsMessage, WsError>>>,
+    /// Number of active pauses: the delivery resumes when the last one is released
+    pauses: Cell<usize>,
+    // Last waker of task that wants to read incoming messages
+    waker: RefCell<Option<Waker>>,
+    replies: Replies,
+}
+
+impl fmt::Debug for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Delivery")
+            .field("queued", &self.queue.borrow().len())
+            .field("pauses", &self.pauses.get())
+            .finish()
+    }
+}
+
+impl Delivery {
+    /// Queue an incoming message and wake up the reader.
+    ///
+    /// While paused, at most [`MAX_PAUSED_MESSAGES`] are buffered: the following ones are dropped
+    /// and a single [`WsError::BufferFull`] is queued in their place.
+    pub(crate) fn push(&self, msg: WsMessage) {
+        // Replies count even if dropped: the connection is alive
+        self.replies.observe(&msg);
+
+        {
+            let mut queue = self.queue.borrow_mut();
+
+            if self.is_paused() && queue.len() >= MAX_PAUSED_MESSAGES {
+                if !matches!(queue.back(), Some(Err(WsError::BufferFull))) {
+                    queue.push_back(Err(WsError::BufferFull));
+                }
+            } else {
+                queue.push_back(Ok(msg));
+            }
+        }
+
+        self.wake();
+    }
+
+    /// Pop the next message, unless the delivery is paused.
+    pub(crate) fn pop(&self) -> Option<Result<WsMessage, WsError>> {
+        if self.is_paused() {
+            return None;
+        }
+
+        self.queue.borrow_mut().pop_front()
+    }
+
+    #[inline]
+    pub(crate) fn replies(&self) -> &Replies {
+        &self.replies
+    }
+
+    #[inline]
+    pub(crate) fn is_paused(&self) -> bool {
+        self.pauses.get() > 0
+    }
+
+    #[inline]
+    pub(crate) fn register(&self, waker: &Waker) {
+        *self.waker.borrow_mut() = Some(waker.clone());
+    }
+
+    pub(crate) fn wake(&self) {
+        if let Some(w) = self.waker.borrow_mut().take() {
+            w.wake()
+        }
+    }
+
+    pub(crate) fn pause(&self) {
+        self.pauses.set(self.pauses.get() + 1);
+    }
+
+    /// Release a pause: the delivery resumes only when no other pause is active.
+    fn release(&self) {
+        let pauses: usize = self.pauses.get();
+        if pauses > 0 {
+            self.pauses.set(pauses - 1);
+            if pauses == 1 {
+                self.wake();
+            }
+        }
+    }
+
+    /// Resume the delivery, overriding all the active pauses, and replay the buffered messages.
+    pub(crate) fn resume(&self) {
+        if self.pauses.replace(0) > 0 {
+            self.wake();
+        }
+    }
+}
+
+/// Guard returned by [`WebSocket::pause_delivery`](crate::wasm::WebSocket::pause_delivery).
+///
+/// The delivery is resumed when the last guard is dropped.
+#[must_use = "the delivery is resumed when the guard is dropped"]
+#[derive(Debug)]
+pub struct PauseGuard {
+    delivery: Arc<Delivery>,
+}
+
+impl PauseGuard {
+    #[inline]
+    pub(crate) fn new(delivery: Arc<Delivery>) -> Self {
+        delivery.pause();
+        Self { delivery }
+    }
+}
+
+impl Drop for PauseGuard {
+    fn drop(&mut self) {
+        self.delivery.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nested_pauses() {
+        let delivery = Arc::new(Delivery::default());
+        delivery.push(WsMessage::Text(String::from("a")));
+
+        let first = PauseGuard::new(delivery.clone());
+        let second = PauseGuard::new(delivery.clone());
+        assert!(delivery.pop().is_none());
+
+        // Another guard is still active
+        drop(first);
+        assert!(delivery.is_paused());
+        assert!(delivery.pop().is_none());
+
+        drop(second);
+        assert!(!delivery.is_paused());
+        assert!(matches!(delivery.pop(), Some(Ok(WsMessage::Text(..)))));
+    }
+
+    #[test]
+    fn test_resume_overrides_pauses() {
+        let delivery = Arc::new(Delivery::default());
+        let guard = PauseGuard::new(delivery.clone());
+        let _other = PauseGuard::new(delivery.clone());
+
+        delivery.resume();
+        assert!(!delivery.is_paused());
+
+        // Already resumed: a stale guard doesn't underflow the counter
+        drop(guard);
+        assert!(!delivery.is_paused());
+    }
+}
@@ -44,14 +44,42 @@ pub enum WsError {
 
     /// The reason string given to a close method is longer than 123 bytes, please see:
     /// [MDN Documentation](https://developer.mozilla.org/en-US/docs/Web/API/WebSocket/close).
+    /// Check `WebSocket::close_reason_policy` to truncate it instead.
     #[error("The reason string given to a close method is to long.")]
     ReasonStringToLong,
 
     /// Failed to connect to the server.
-    #[error("Failed to connect to the server. CloseEvent: {event:?}")]
+    #[error("Failed to connect to the server (code: {code}, opened before close: {opened_before_close}). CloseEvent: {event:?}")]
     ConnectionFailed {
         /// The close event that might hold extra code and reason information.
         event: CloseEvent,
+        /// The close code (normalized, see [`close_code`](crate::close_code))
+        code: u16,
+        /// Whether the socket was open before closing: `false` means the upgrade was rejected,
+        /// `true` an immediate close after a successful upgrade.
+        opened_before_close: bool,
+    },
+
+    /// The connection was closed cleanly, with a normal (`1000`) or going away (`1001`) code.
+    #[error("The connection was closed cleanly. CloseEvent: {event:?}")]
+    PeerClosedCleanly {
+        /// The close event.
+        event: CloseEvent,
+    },
+
+    /// The connection was closed with an error code.
+    #[error("The connection was closed. CloseEvent: {event:?}")]
+    PeerClosed {
+        /// The close event.
+        event: CloseEvent,
+    },
+
+    /// The connection was lost without a close handshake (`1006`).
+    /// The browser doesn't expose the underlying I/O error.
+    #[error("The connection was lost. CloseEvent: {event:?}")]
+    ConnectionLost {
+        /// The close event.
+        event: CloseEvent,
     },
 
     /// When converting the JavaScript Message into a WsMessage, it's possible that
@@ -78,6 +106,14 @@ pub enum WsError {
     #[error("Received a message that is neither ArrayBuffer, String or Blob.")]
     UnknownDataType,
 
+    /// Too many messages were received while the delivery was paused: the exceeding ones were dropped.
+    #[error("The buffer of paused messages is full.")]
+    BufferFull,
+
+    /// The operation didn't complete within its deadline.
+    #[error("The operation timed out.")]
+    Timeout,
+
     #[error("DOM Exception: {0}")]
     Dom(u16),
 
@@ -2,11 +2,28 @@
 // Copyright (c) 2023-2024 Yuki Kishimoto
 // Distributed under the MIT software license
 
-use web_sys::CloseEvent as JsCloseEvt;
+use std::fmt;
 
+use wasm_bindgen::JsCast;
+use web_sys::{CloseEvent as JsCloseEvt, ErrorEvent as JsErrorEvt, Event as JsEvt};
+
+use crate::close_code;
+use crate::close_event::{CloseEvent, Initiator};
+use crate::wasm::pharos::{Channel, Filter, ObserveConfig};
 use crate::wasm::WsError;
 
+/// Events related to the WebSocket connection
+///
+/// # Serialization
+///
+/// With the `serde-json` feature, the events are serialized as `{"event": "<Variant>", "data": <data>}`
+/// (`data` is omitted for the variants without it): [`WsEvent::Error`] holds the [`ErrorDetail`] or `null`,
+/// [`WsEvent::Closed`] the [`CloseEvent`] and [`WsEvent::WsErr`] the error message.
+/// This representation is semi-stable: new variants may be added, but the existing ones won't change
+/// in a patch release.
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde-json", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde-json", serde(tag = "event", content = "data"))]
 pub enum WsEvent {
     /// The connection is now Open and ready for use.
     Open,
@@ -14,8 +31,8 @@ pub enum WsEvent {
     /// occurs, see the [HTML Living Standard](https://html.spec.whatwg.org/multipage/web-sockets.html).
     /// Since the browser is not allowed to convey any information to the client code as to why an error
     /// happened (for security reasons), as described in the HTML specification, there usually is no extra
-    /// information available. That's why this event has no data attached to it.
-    Error,
+    /// information available. The [`ErrorDetail`] is only set if the browser fires an `ErrorEvent`.
+    Error(Option<ErrorDetail>),
     /// The connection has started closing, but is not closed yet. You shouldn't try to send messages over
     /// it anymore. Trying to do so will result in an error.
     Closing,
@@ -24,10 +41,25 @@ pub enum WsEvent {
     /// An error happened, not on the connection, but inside _ws_stream_wasm_. This currently happens
     /// when an incoming message can not be converted to Rust types, eg. a String message with invalid
     /// encoding.
+    #[cfg_attr(feature = "serde-json", serde(serialize_with = "serialize_display"))]
     WsErr(WsError),
+    /// No reply to the [`Heartbeat`](crate::wasm::Heartbeat) within its timeout: the connection is being closed.
+    HeartbeatTimeout,
 }
 
 impl WsEvent {
+    /// Observe all the events, without filtering.
+    pub const ALL: ObserveConfig<WsEvent> = ObserveConfig {
+        filter: Some(Filter::Pointer(|_| true)),
+        channel: Channel::Unbounded,
+    };
+
+    /// Reject all the events. Useful as a no-op observer placeholder.
+    pub const NONE: ObserveConfig<WsEvent> = ObserveConfig {
+        filter: Some(Filter::Pointer(|_| false)),
+        channel: Channel::Unbounded,
+    };
+
     /// Predicate indicating whether this is a [WsEvent::Open] event. Can be used as a filter for the
     /// event stream obtained with [`pharos::Observable::observe`] on [`WsMeta`](crate::WsMeta).
     #[inline]
@@ -41,29 +73,167 @@ impl WsEvent {
     pub fn is_closed(&self) -> bool {
         matches!(self, Self::Closed(_))
     }
+
+    /// Predicate indicating whether this is a [WsEvent::HeartbeatTimeout] event.
+    #[inline]
+    pub fn is_heartbeat_timeout(&self) -> bool {
+        matches!(self, Self::HeartbeatTimeout)
+    }
 }
 
-/// An event holding information about how/why the connection was closed.
-///
-/// See: [MDN Documentation](https://developer.mozilla.org/en-US/docs/Web/API/WebSocket/close).
-// We use this wrapper because the web_sys version isn't Send and pharos requires events
-// to be Send.
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub struct CloseEvent {
-    /// The close code.
-    pub code: u16,
-    /// The reason why the connection was closed.
-    pub reason: String,
-    /// Whether the connection was closed cleanly.
-    pub was_clean: bool,
+/// Lowercase name, followed by the data if any, i.e. `closed: 1000 Normal Closure: bye`
+impl fmt::Display for WsEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Open => write!(f, "open"),
+            Self::Error(None) => write!(f, "error"),
+            Self::Error(Some(detail)) => write!(f, "error: {detail}"),
+            Self::Closing => write!(f, "closing"),
+            Self::Closed(event) => write!(f, "closed: {event}"),
+            Self::WsErr(e) => write!(f, "ws error: {e}"),
+            Self::HeartbeatTimeout => write!(f, "heartbeat timeout"),
+        }
+    }
+}
+
+#[cfg(feature = "serde-json")]
+fn serialize_display<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: fmt::Display,
+    S: serde::Serializer,
+{
+    serializer.collect_str(value)
+}
+
+/// Detail of an `ErrorEvent`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde-json", derive(serde::Serialize, serde::Deserialize))]
+pub struct ErrorDetail {
+    /// Error message
+    pub message: String,
+    /// Script where the error occurred
+    pub filename: String,
+    /// Line where the error occurred
+    pub lineno: u32,
+}
+
+impl ErrorDetail {
+    /// Get the detail of an `ErrorEvent`: `None` for a plain `Event`
+    pub(crate) fn from_event(evt: &JsEvt) -> Option<Self> {
+        let evt: &JsErrorEvt = evt.dyn_ref()?;
+        Some(Self {
+            message: evt.message(),
+            filename: evt.filename(),
+            lineno: evt.lineno(),
+        })
+    }
 }
 
+/// The message, followed by the location if known, i.e. `boom (app.js:12)`
+impl fmt::Display for ErrorDetail {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        if !self.filename.is_empty() {
+            write!(f, " ({}:{})", self.filename, self.lineno)?;
+        }
+        Ok(())
+    }
+}
+
+impl CloseEvent {
+    /// Convert into the most appropriate [`WsError`], based on the close code:
+    ///
+    /// * clean `1000` or `1001`: [`WsError::PeerClosedCleanly`]
+    /// * `1006` or not clean: [`WsError::ConnectionLost`]
+    /// * anything else: [`WsError::PeerClosed`]
+    pub fn to_ws_error(&self) -> WsError {
+        let event: CloseEvent = self.clone();
+        match self.code {
+            close_code::ABNORMAL_CLOSURE => WsError::ConnectionLost { event },
+            _ if !self.was_clean => WsError::ConnectionLost { event },
+            close_code::NORMAL_CLOSURE | close_code::GOING_AWAY => {
+                WsError::PeerClosedCleanly { event }
+            }
+            _ => WsError::PeerClosed { event },
+        }
+    }
+}
+
+/// The close is considered as initiated by the [Initiator::Server], since
+/// the JavaScript event doesn't carry this information.
 impl From<JsCloseEvt> for CloseEvent {
     fn from(js_evt: JsCloseEvt) -> Self {
         Self {
-            code: js_evt.code(),
+            code: close_code::normalize(js_evt.code()),
             reason: js_evt.reason(),
             was_clean: js_evt.was_clean(),
+            initiated_by: Initiator::Server,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "serde-json")]
+    use crate::wasm::WsState;
+
+    fn closed() -> WsEvent {
+        WsEvent::Closed(CloseEvent {
+            code: close_code::NORMAL_CLOSURE,
+            reason: String::from("bye"),
+            was_clean: true,
+            initiated_by: Initiator::Client,
+        })
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(WsEvent::Open.to_string(), "open");
+        assert_eq!(WsEvent::Error(None).to_string(), "error");
+        let detail = ErrorDetail {
+            message: String::from("boom"),
+            filename: String::from("app.js"),
+            lineno: 12,
+        };
+        assert_eq!(
+            WsEvent::Error(Some(detail)).to_string(),
+            "error: boom (app.js:12)"
+        );
+        assert_eq!(closed().to_string(), "closed: 1000 Normal Closure: bye");
+        assert_eq!(WsEvent::HeartbeatTimeout.to_string(), "heartbeat timeout");
+    }
+
+    #[test]
+    #[cfg(feature = "serde-json")]
+    fn test_serialize() {
+        use serde_json::json;
+
+        assert_eq!(
+            serde_json::to_value(WsEvent::Open).unwrap(),
+            json!({"event": "Open"})
+        );
+        assert_eq!(
+            serde_json::to_value(WsEvent::Error(None)).unwrap(),
+            json!({"event": "Error", "data": null})
+        );
+        assert_eq!(
+            serde_json::to_value(closed()).unwrap(),
+            json!({
+                "event": "Closed",
+                "data": {"code": 1000, "reason": "bye", "was_clean": true, "initiated_by": "Client"}
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(WsEvent::WsErr(WsError::ConnectionNotOpen)).unwrap(),
+            json!({"event": "WsErr", "data": WsError::ConnectionNotOpen.to_string()})
+        );
+
+        let state: String = serde_json::to_string(&WsState::Closing).unwrap();
+        assert_eq!(state, "\"Closing\"");
+        assert_eq!(
+            serde_json::from_str::<WsState>(&state).unwrap(),
+            WsState::Closing
+        );
+    }
+}
@@ -0,0 +1,152 @@
+// Copyright (c) 2023-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Application-level heartbeat
+
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::wasm::WsMessage;
+
+/// Predicate recognizing the reply to a heartbeat
+pub type ReplyPredicate = Rc<dyn Fn(&WsMessage) -> bool>;
+
+/// Application-level keepalive, for the servers answering a specific message
+///
+/// Browsers can't send protocol pings: the payload is sent as a regular message and
+/// the first message matching the predicate counts as the pong. Check [`WebSocket::heartbeat`](crate::wasm::WebSocket::heartbeat).
+#[derive(Clone)]
+pub struct Heartbeat {
+    pub(crate) payload: WsMessage,
+    pub(crate) interval: Duration,
+    pub(crate) timeout: Duration,
+    pub(crate) is_reply: ReplyPredicate,
+}
+
+impl fmt::Debug for Heartbeat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Heartbeat")
+            .field("payload", &self.payload)
+            .field("interval", &self.interval)
+            .field("timeout", &self.timeout)
+            .field("is_reply", &"..")
+            .finish()
+    }
+}
+
+impl Heartbeat {
+    /// Close code sent when the reply doesn't arrive in time
+    ///
+    /// From the private-use range: browsers only allow sending `1000` and `3000..=4999`.
+    pub const TIMEOUT_CLOSE_CODE: u16 = 4000;
+
+    /// Send the `payload` every `interval`, expecting a reply matching `is_reply`
+    ///
+    /// # Panics
+    ///
+    /// Panics if the `payload` is a [`WsMessage::Close`].
+    pub fn new<F>(payload: WsMessage, interval: Duration, is_reply: F) -> Self
+    where
+        F: Fn(&WsMessage) -> bool + 'static,
+    {
+        assert!(
+            !matches!(payload, WsMessage::Close(..)),
+            "the heartbeat payload can't be a close message"
+        );
+
+        Self {
+            payload,
+            interval,
+            timeout: interval,
+            is_reply: Rc::new(is_reply),
+        }
+    }
+
+    /// Set how long to wait for the reply, after each send (default: the interval)
+    ///
+    /// A timeout longer than the interval delays the next send.
+    #[inline]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+/// Heartbeat replies seen by the `onmessage` callback
+#[derive(Default)]
+pub(crate) struct Replies {
+    is_reply: RefCell<Option<ReplyPredicate>>,
+    received: Cell<u64>,
+    // Bumped when a heartbeat is started or stopped: the previous one stops on its next tick
+    generation: Cell<u64>,
+}
+
+impl Replies {
+    /// Install the predicate of a new heartbeat, replacing the previous one. Return its generation.
+    pub(crate) fn start(&self, is_reply: ReplyPredicate) -> u64 {
+        *self.is_reply.borrow_mut() = Some(is_reply);
+        self.bump()
+    }
+
+    pub(crate) fn stop(&self) {
+        *self.is_reply.borrow_mut() = None;
+        self.bump();
+    }
+
+    fn bump(&self) -> u64 {
+        let generation: u64 = self.generation.get().wrapping_add(1);
+        self.generation.set(generation);
+        generation
+    }
+
+    #[inline]
+    pub(crate) fn is_current(&self, generation: u64) -> bool {
+        self.generation.get() == generation
+    }
+
+    /// Number of replies received so far
+    #[inline]
+    pub(crate) fn received(&self) -> u64 {
+        self.received.get()
+    }
+
+    /// Count the message if it's a reply
+    pub(crate) fn observe(&self, msg: &WsMessage) {
+        if let Some(is_reply) = &*self.is_reply.borrow() {
+            if is_reply(msg) {
+                self.received.set(self.received.get().wrapping_add(1));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replies() {
+        let replies = Replies::default();
+        let pong = WsMessage::Text(String::from("pong"));
+
+        // No heartbeat
+        replies.observe(&pong);
+        assert_eq!(replies.received(), 0);
+
+        let first = replies.start(Rc::new(|msg| msg == &WsMessage::Text(String::from("pong"))));
+        replies.observe(&WsMessage::Text(String::from("other")));
+        replies.observe(&pong);
+        assert_eq!(replies.received(), 1);
+        assert!(replies.is_current(first));
+
+        // Replaced
+        let second = replies.start(Rc::new(|_| false));
+        assert!(!replies.is_current(first));
+        assert!(replies.is_current(second));
+
+        replies.stop();
+        assert!(!replies.is_current(second));
+    }
+}
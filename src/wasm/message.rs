@@ -17,6 +17,19 @@ pub enum WsMessage {
     Text(String),
     /// The message contains binary data.
     Binary(Vec<u8>),
+    /// Close the connection, with an optional code and reason.
+    ///
+    /// Only sent: the closes are reported by the [`CloseEvent`](crate::CloseEvent), never received as a message.
+    Close(Option<CloseFrame>),
+}
+
+/// The code and the reason of a [`WsMessage::Close`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CloseFrame {
+    /// The close code. Browsers only accept `1000` and `3000..=4999`.
+    pub code: u16,
+    /// The close reason, at most 123 bytes.
+    pub reason: String,
 }
 
 impl WsMessage {
@@ -26,6 +39,7 @@ impl WsMessage {
         match self {
             Self::Text(string) => string.len(),
             Self::Binary(data) => data.len(),
+            Self::Close(frame) => frame.as_ref().map_or(0, |f| f.reason.len()),
         }
     }
 
@@ -41,6 +55,7 @@ impl WsMessage {
         match self {
             Self::Text(string) => string.into_bytes(),
             Self::Binary(data) => data,
+            Self::Close(frame) => frame.map(|f| f.reason.into_bytes()).unwrap_or_default(),
         }
     }
 
@@ -50,6 +65,7 @@ impl WsMessage {
         match self {
             Self::Text(string) => Ok(string),
             Self::Binary(data) => Ok(str::from_utf8(data)?),
+            Self::Close(frame) => Ok(frame.as_ref().map_or("", |f| f.reason.as_str())),
         }
     }
 }
@@ -105,6 +121,7 @@ impl AsRef<[u8]> for WsMessage {
         match self {
             Self::Text(string) => string.as_ref(),
             Self::Binary(data) => data.as_ref(),
+            Self::Close(frame) => frame.as_ref().map_or(&[], |f| f.reason.as_bytes()),
         }
     }
 }
@@ -15,21 +15,26 @@ use futures_util::StreamExt;
 use thiserror::Error;
 use url::Url;
 
+mod delivery;
 mod error;
 mod event;
+mod heartbeat;
 mod message;
 mod pharos;
 mod socket;
 mod state;
 mod stream;
 
-use self::error::WsError;
-use self::event::{CloseEvent, WsEvent};
-pub use self::message::WsMessage;
+use self::delivery::{Delivery, PauseGuard};
+pub(crate) use self::error::WsError;
+use self::event::{ErrorDetail, WsEvent};
+pub use self::heartbeat::{Heartbeat, ReplyPredicate};
+pub use self::message::{CloseFrame, WsMessage};
 use self::pharos::SharedPharos;
 use self::socket::WebSocket;
 use self::state::WsState;
 use self::stream::WsStream;
+pub use crate::close_event::{CloseEvent, CloseEventBuilder, Initiator};
 
 pub type Sink = SplitSink<WsStream, WsMessage>;
 pub type Stream = SplitStream<WsStream>;
@@ -42,6 +47,25 @@ pub enum Error {
     /// Timeout
     #[error("timeout")]
     Timeout,
+    /// JSON error
+    #[cfg(feature = "serde-json")]
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    /// Received a binary frame where a text frame was expected
+    #[error("unexpected binary frame")]
+    UnexpectedBinaryFrame,
+    /// Received a text frame where a binary frame was expected
+    #[error("unexpected text frame")]
+    UnexpectedTextFrame,
+    /// Size limit exceeded
+    #[error("size limit exceeded: {limit} bytes")]
+    SizeLimitExceeded {
+        /// The configured limit
+        limit: usize,
+    },
+    /// The connection closed
+    #[error("connection closed")]
+    Closed,
 }
 
 pub async fn connect(url: &Url, timeout: Duration) -> Result<(Sink, Stream), Error> {
@@ -51,7 +75,25 @@ pub async fn connect(url: &Url, timeout: Duration) -> Result<(Sink, Stream), Err
     Ok(stream.split())
 }
 
+/// Connect with an application-level [`Heartbeat`]
+///
+/// If the server stops answering, the connection is closed: the stream ends.
+pub async fn connect_with_heartbeat(
+    url: &Url,
+    timeout: Duration,
+    heartbeat: Heartbeat,
+) -> Result<(Sink, Stream), Error> {
+    let (ws, stream) = time::timeout(Some(timeout), WebSocket::connect(url))
+        .await
+        .ok_or(Error::Timeout)??;
+    ws.heartbeat(heartbeat);
+    Ok(stream.split())
+}
+
 /// Helper function to reduce code bloat
+///
+/// Sending to the observers never waits, so the spawned tasks deliver the events in the order
+/// they are notified.
 pub(crate) fn notify(pharos: SharedPharos<WsEvent>, evt: WsEvent) {
     let _ = thread::spawn(async move {
         pharos
@@ -6,7 +6,7 @@ use std::error::Error;
 use std::fmt;
 use std::ops::Deref;
 
-use futures::channel::mpsc;
+use futures_channel::mpsc;
 
 /// The error type for errors happening in `pharos`.
 ///
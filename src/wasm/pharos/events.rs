@@ -7,12 +7,14 @@ use std::fmt;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
-use futures::channel::mpsc::{
-    self, UnboundedReceiver as FutUnboundedReceiver, UnboundedSender as FutUnboundedSender,
+use futures_channel::mpsc::{
+    self, Receiver as FutReceiver, Sender as FutSender, UnboundedReceiver as FutUnboundedReceiver,
+    UnboundedSender as FutUnboundedSender,
 };
-use futures::{Sink, Stream};
+use futures_core::Stream;
+use futures_sink::Sink;
 
-use super::{ErrorKind, Filter, ObserveConfig, PharErr};
+use super::{Channel, ErrorKind, Filter, ObserveConfig, PharErr};
 
 /// A stream of events. This is returned from [Observable::observe](crate::Observable::observe).
 /// You will only start receiving events from the moment you call this. Any events in the observed
@@ -30,11 +32,20 @@ where
     Event: Clone + 'static + Send,
 {
     pub(crate) fn new(config: ObserveConfig<Event>) -> (Self, Sender<Event>) {
-        let (tx, rx) = mpsc::unbounded();
+        let (tx, rx) = match config.channel {
+            Channel::Bounded(queue_size) => {
+                // The futures channel has one extra slot per sender
+                let (tx, rx) = mpsc::channel(queue_size.saturating_sub(1));
+                (SenderKind::Bounded(tx), Receiver::Bounded(rx))
+            }
+            Channel::Unbounded => {
+                let (tx, rx) = mpsc::unbounded();
+                (SenderKind::Unbounded(tx), Receiver::Unbounded(rx))
+            }
+        };
+
         (
-            Self {
-                rx: Receiver { rx },
-            },
+            Self { rx },
             Sender {
                 tx,
                 filter: config.filter,
@@ -55,12 +66,17 @@ where
     }
 }
 
+enum SenderKind<Event> {
+    Bounded(FutSender<Event>),
+    Unbounded(FutUnboundedSender<Event>),
+}
+
 /// The sender of the channel
 pub(crate) struct Sender<Event>
 where
     Event: Clone + 'static + Send,
 {
-    tx: FutUnboundedSender<Event>,
+    tx: SenderKind<Event>,
     filter: Option<Filter<Event>>,
 }
 
@@ -71,7 +87,10 @@ where
     // Verify whether this observer is still around.
     #[inline]
     pub(crate) fn is_closed(&self) -> bool {
-        self.tx.is_closed()
+        match &self.tx {
+            SenderKind::Bounded(tx) => tx.is_closed(),
+            SenderKind::Unbounded(tx) => tx.is_closed(),
+        }
     }
 
     /// Check whether this sender is interested in this event.
@@ -89,11 +108,12 @@ where
 }
 
 /// The receiver of the channel, abstracting over different channel types.
-struct Receiver<Event>
+enum Receiver<Event>
 where
     Event: Clone + 'static + Send,
 {
-    rx: FutUnboundedReceiver<Event>,
+    Bounded(FutReceiver<Event>),
+    Unbounded(FutUnboundedReceiver<Event>),
 }
 
 impl<Event> fmt::Debug for Receiver<Event>
@@ -101,7 +121,18 @@ where
     Event: 'static + Clone + Send,
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "PharosReceiver<{}>", type_name::<Event>())
+        match self {
+            Self::Bounded(..) => write!(
+                f,
+                "pharos::events::Receiver::<{}>::Bounded(_)",
+                type_name::<Event>()
+            ),
+            Self::Unbounded(..) => write!(
+                f,
+                "pharos::events::Receiver::<{}>::Unbounded(_)",
+                type_name::<Event>()
+            ),
+        }
     }
 }
 
@@ -112,8 +143,10 @@ where
     type Item = Event;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let rx = self.get_mut();
-        Pin::new(&mut rx.rx).poll_next(cx)
+        match self.get_mut() {
+            Self::Bounded(rx) => Pin::new(rx).poll_next(cx),
+            Self::Unbounded(rx) => Pin::new(rx).poll_next(cx),
+        }
     }
 }
 
@@ -123,14 +156,25 @@ where
 {
     type Error = PharErr;
 
+    // Never wait on a bounded channel: a full queue drops the event in `start_send` instead,
+    // so that a slow observer doesn't stall the others.
     fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        let tx = self.get_mut();
-        Pin::new(&mut tx.tx).poll_ready(cx).map_err(Into::into)
+        match &mut self.get_mut().tx {
+            SenderKind::Bounded(tx) if tx.is_closed() => Poll::Ready(Err(ErrorKind::Closed.into())),
+            SenderKind::Bounded(..) => Poll::Ready(Ok(())),
+            SenderKind::Unbounded(tx) => Pin::new(tx).poll_ready(cx).map_err(Into::into),
+        }
     }
 
     fn start_send(self: Pin<&mut Self>, item: Event) -> Result<(), Self::Error> {
-        let tx = self.get_mut();
-        Pin::new(&mut tx.tx).start_send(item).map_err(Into::into)
+        match &mut self.get_mut().tx {
+            SenderKind::Bounded(tx) => match tx.try_send(item) {
+                Ok(()) => Ok(()),
+                Err(e) if e.is_full() => Ok(()),
+                Err(..) => Err(ErrorKind::Closed.into()),
+            },
+            SenderKind::Unbounded(tx) => Pin::new(tx).start_send(item).map_err(Into::into),
+        }
     }
 
     // Note that on futures-rs bounded channels poll_flush has a problematic implementation.
@@ -143,7 +187,7 @@ where
     //
     // We compensate for the error swallowing by checking `is_closed`.
     fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        if self.tx.is_closed() {
+        if self.is_closed() {
             Poll::Ready(Err(ErrorKind::Closed.into()))
         } else {
             Poll::Ready(Ok(()))
@@ -151,8 +195,10 @@ where
     }
 
     fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        let tx = self.get_mut();
-        Pin::new(&mut tx.tx).poll_close(cx).map_err(Into::into)
+        match &mut self.get_mut().tx {
+            SenderKind::Bounded(tx) => Pin::new(tx).poll_close(cx).map_err(Into::into),
+            SenderKind::Unbounded(tx) => Pin::new(tx).poll_close(cx).map_err(Into::into),
+        }
     }
 }
 
@@ -8,8 +8,9 @@ use std::future::Future;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
-use futures::future::FutureExt;
-use futures::{ready, Sink};
+use futures_core::ready;
+use futures_sink::Sink;
+use futures_util::future::FutureExt;
 
 mod error;
 mod events;
@@ -21,7 +22,7 @@ pub use self::error::{ErrorKind, PharErr};
 pub use self::events::Events;
 use self::events::Sender;
 pub use self::filter::Filter;
-pub use self::observable::{Observable, ObserveConfig};
+pub use self::observable::{Channel, Observable, ObserveConfig};
 pub use self::shared::SharedPharos;
 
 /// A pinned boxed future returned by the Observable::observe method.
@@ -53,6 +54,12 @@ pub type Observe<'a, Event, Error> =
 ///
 /// TODO: I will do some benchmarking and see if this can be improved, eg. by keeping a state which tracks which
 /// observers we still have to poll.
+///
+/// ## Ordering.
+///
+/// Every event is handed to all the observers before the next one, so all the observers see the
+/// events in the same order: the order they are sent. Each observer has its own queue and sending
+/// never waits on it (see [Channel]): a slow observer can't stall the delivery to the others.
 pub struct Pharos<Event>
 where
     Event: 'static + Clone + Send,
@@ -252,3 +259,42 @@ where
         Ok(()).into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+
+    use super::*;
+
+    #[test]
+    fn slow_observer() {
+        let mut pharos: Pharos<u8> = Pharos::default();
+
+        let mut fast = pharos
+            .observe(ObserveConfig::default())
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+
+        // Never read
+        let mut slow = pharos
+            .observe(ObserveConfig::default().channel(Channel::Bounded(1)))
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+
+        // The full queue of the slow observer doesn't stall the delivery
+        for evt in 0..3 {
+            pharos.send(evt).now_or_never().unwrap().unwrap();
+        }
+
+        let fast: Vec<u8> = (0..3)
+            .map(|_| fast.next().now_or_never().unwrap().unwrap())
+            .collect();
+        assert_eq!(fast, vec![0, 1, 2]);
+
+        // The events that didn't fit are dropped
+        assert_eq!(slow.next().now_or_never().unwrap(), Some(0));
+        assert!(slow.next().now_or_never().is_none());
+    }
+}
@@ -25,12 +25,36 @@ where
     fn observe(&mut self, options: ObserveConfig<Event>) -> Observe<'_, Event, Self::Error>;
 }
 
+/// The channel delivering the events to an observer.
+///
+/// Every observer has its own queue, so a slow observer never stalls the others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Channel {
+    /// The observer gets every event, but its queue grows as long as it doesn't keep up.
+    Unbounded,
+    /// Queue of `n` events. Once it's full, the new events are dropped for this observer only.
+    Bounded(usize),
+}
+
 #[derive(Debug)]
 pub struct ObserveConfig<Event>
 where
     Event: Clone + 'static + Send,
 {
     pub(crate) filter: Option<Filter<Event>>,
+    pub(crate) channel: Channel,
+}
+
+impl<Event> ObserveConfig<Event>
+where
+    Event: Clone + 'static + Send,
+{
+    /// Set the [Channel] delivering the events.
+    #[inline]
+    pub fn channel(mut self, channel: Channel) -> Self {
+        self.channel = channel;
+        self
+    }
 }
 
 /// Create a default configuration:
@@ -41,7 +65,10 @@ where
     Event: Clone + 'static + Send,
 {
     fn default() -> Self {
-        Self { filter: None }
+        Self {
+            filter: None,
+            channel: Channel::Unbounded,
+        }
     }
 }
 
@@ -53,6 +80,7 @@ where
     fn from(filter: Filter<Event>) -> Self {
         Self {
             filter: Some(filter),
+            channel: Channel::Unbounded,
         }
     }
 }
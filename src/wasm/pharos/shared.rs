@@ -4,9 +4,9 @@
 
 use std::sync::Arc;
 
-use futures::future::FutureExt;
-use futures::lock::Mutex;
-use futures::SinkExt;
+use futures_util::future::FutureExt;
+use futures_util::lock::Mutex;
+use futures_util::SinkExt;
 
 use super::{Events, Observable, Observe, ObserveConfig, PharErr, Pharos};
 
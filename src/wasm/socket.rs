@@ -2,17 +2,34 @@
 // Copyright (c) 2023-2024 Yuki Kishimoto
 // Distributed under the MIT software license
 
+use std::cell::RefCell;
 use std::fmt;
+use std::future::Future;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
-use futures::StreamExt;
+use async_utility::{thread, time};
+use futures_util::StreamExt;
+use js_sys::Array;
 use url::Url;
 use wasm_bindgen::closure::Closure;
-use wasm_bindgen::{JsCast, UnwrapThrowExt};
-use web_sys::{BinaryType, CloseEvent as JsCloseEvt, DomException, WebSocket as WebSysSocket};
-
+use wasm_bindgen::{JsCast, JsValue, UnwrapThrowExt};
+use web_sys::{
+    BinaryType, CloseEvent as JsCloseEvt, DomException, Event as JsEvt, WebSocket as WebSysSocket,
+};
+
+use crate::close_code::{self, CloseReasonPolicy};
+use crate::close_notifier::CloseNotifier;
+use crate::metrics::{ErrorKind, Metrics};
+use crate::redact::Redacted;
 use crate::wasm::pharos::{Filter, Observable, Observe, ObserveConfig, PharErr, SharedPharos};
-use crate::wasm::{notify, CloseEvent, WsError, WsEvent, WsState, WsStream};
+use crate::wasm::{
+    notify, CloseEvent, Delivery, ErrorDetail, Heartbeat, Initiator, PauseGuard, WsError, WsEvent,
+    WsMessage, WsState, WsStream,
+};
+use crate::DeflateParams;
 
 /// The metadata related to a websocket. Allows access to the methods on the WebSocket API.
 /// This is split from the `Stream`/`Sink` so you can pass the latter to a combinator whilst
@@ -20,27 +37,105 @@ use crate::wasm::{notify, CloseEvent, WsError, WsEvent, WsState, WsStream};
 ///
 /// When you drop this, the connection does not get closed, however when you drop [WsStream] it does.
 ///
+/// ## Clones
+///
+/// The clones share the connection and the observers: the events are observable from any clone
+/// and dropping a clone doesn't affect the others. If two clones race to close the connection,
+/// the first close wins and both get the resulting [`CloseEvent`]. The
+/// [`close_reason_policy`](WebSocket::close_reason_policy) is per clone.
+///
 /// Most of the methods on this type directly map to the web API. For more documentation, check the
 /// [MDN WebSocket documentation](https://developer.mozilla.org/en-US/docs/Web/API/WebSocket/WebSocket).
+#[derive(Clone)]
 pub struct WebSocket {
     ws: Arc<WebSysSocket>,
     pharos: SharedPharos<WsEvent>,
+    // Set when we request the close of the connection
+    client_close: Arc<AtomicBool>,
+    delivery: Arc<Delivery>,
+    protocols: Vec<String>,
+    close: Arc<CloseNotifier<CloseEvent>>,
+    close_reason_policy: CloseReasonPolicy,
 }
 
 impl WebSocket {
     const OPEN_CLOSE: Filter<WsEvent> =
         Filter::Pointer(|evt: &WsEvent| evt.is_open() | evt.is_closed());
 
+    /// Buffered bytes above which the connection is considered backpressured
+    pub const BACKPRESSURE_THRESHOLD: u32 = 1024 * 1024;
+
+    /// The browser doesn't notify when the buffer drains, so it's polled
+    const FLUSH_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
     /// Connect to the server. The future will resolve when the connection has been established with a successful WebSocket
     /// handshake.
     pub async fn connect(url: &Url) -> Result<(Self, WsStream), WsError> {
-        let ws: Arc<WebSysSocket> = match WebSysSocket::new(url.as_str()) {
+        Self::connect_inner(url, Vec::new(), None).await
+    }
+
+    /// Connect to the server requesting one of the `protocols` as sub-protocol.
+    ///
+    /// The one selected by the server is returned by [`WebSocket::protocol`].
+    pub async fn connect_with_protocols<I, S>(
+        url: &Url,
+        protocols: I,
+    ) -> Result<(Self, WsStream), WsError>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let protocols: Vec<String> = protocols.into_iter().map(|p| p.into()).collect();
+        Self::connect_inner(url, protocols, None).await
+    }
+
+    /// Connect to the server, like [`WebSocket::connect`], but if the future is dropped (i.e. cancelled
+    /// by a timeout) while the socket is connecting or open, the socket is handed to the `slot` instead
+    /// of being closed. Use [`WebSocket::adopt`] to take it back.
+    pub async fn connect_adoptable(
+        url: &Url,
+        slot: &AdoptSlot,
+    ) -> Result<(Self, WsStream), WsError> {
+        Self::connect_inner(url, Vec::new(), Some(slot.clone())).await
+    }
+
+    /// Adopt the socket left in the `slot` by a cancelled [`WebSocket::connect_adoptable`].
+    ///
+    /// Return `Ok(None)` if the slot is empty. If the socket is still connecting, the future
+    /// resolves when it's open. If it was closed (or is closing) in the meantime, it's dropped
+    /// and [`WsError::ConnectionNotOpen`] is returned.
+    pub async fn adopt(slot: &AdoptSlot) -> Result<Option<(Self, WsStream)>, WsError> {
+        match slot.take() {
+            Some((ws, protocols)) => match ws.ready_state() {
+                WebSysSocket::CONNECTING | WebSysSocket::OPEN => {
+                    Ok(Some(Self::setup(ws, protocols, None).await?))
+                }
+                // No event would ever resolve the setup
+                _ => Err(WsError::ConnectionNotOpen),
+            },
+            None => Ok(None),
+        }
+    }
+
+    async fn connect_inner(
+        url: &Url,
+        protocols: Vec<String>,
+        slot: Option<AdoptSlot>,
+    ) -> Result<(Self, WsStream), WsError> {
+        let ws = if protocols.is_empty() {
+            WebSysSocket::new(url.as_str())
+        } else {
+            let list: Array = protocols.iter().map(JsValue::from).collect();
+            WebSysSocket::new_with_str_sequence(url.as_str(), &list)
+        };
+
+        let ws: Arc<WebSysSocket> = match ws {
             Ok(ws) => Arc::new(ws),
             Err(e) => {
                 let de: &DomException = e.unchecked_ref();
                 return match de.code() {
                     DomException::SYNTAX_ERR => Err(WsError::InvalidUrl {
-                        supplied: url.to_string(),
+                        supplied: Redacted(url).to_string(),
                     }),
                     code => {
                         if code == 0 {
@@ -55,6 +150,15 @@ impl WebSocket {
             }
         };
 
+        Self::setup(ws, protocols, slot).await
+    }
+
+    /// Install the callbacks and wait for the socket to be open
+    async fn setup(
+        ws: Arc<WebSysSocket>,
+        protocols: Vec<String>,
+        slot: Option<AdoptSlot>,
+    ) -> Result<(Self, WsStream), WsError> {
         // Create our pharos.
         let mut pharos = SharedPharos::default();
         let ph1 = pharos.clone();
@@ -62,25 +166,60 @@ impl WebSocket {
         let ph3 = pharos.clone();
         let ph4 = pharos.clone();
 
+        // Listen to the events to figure out whether the connection opens successfully, before any
+        // callback is installed so none is missed. We don't want to deal with the error event. Either
+        // a close event happens, in which case we want to recover the CloseEvent to return it to the
+        // user, or an Open event happens in which case we are happy campers.
+        let mut evts = pharos
+            .observe(Self::OPEN_CLOSE.into())
+            .await
+            .expect("we didn't close pharos");
+
+        // No event would ever come. There is no await point from here to the callbacks installation.
+        if let WebSysSocket::CLOSING | WebSysSocket::CLOSED = ws.ready_state() {
+            return Err(WsError::ConnectionNotOpen);
+        }
+
+        let client_close = Arc::new(AtomicBool::new(false));
+        let cc = client_close.clone();
+
+        let delivery: Arc<Delivery> = Arc::new(Delivery::default());
+
+        // Set synchronously by the browser callback, before any event is notified
+        let opened = Arc::new(AtomicBool::new(ws.ready_state() == WebSysSocket::OPEN));
+        let op = opened.clone();
+
         // Setup our event listeners
         let on_open = Closure::wrap(Box::new(move || {
+            op.store(true, Ordering::SeqCst);
+
             // notify observers
             notify(ph1.clone(), WsEvent::Open)
         }) as Box<dyn FnMut()>);
 
-        // TODO: is there no information at all in an error?
+        // The browser usually fires a plain `Event`, without any information
         #[allow(trivial_casts)]
-        let on_error = Closure::wrap(Box::new(move || {
+        let on_error = Closure::wrap(Box::new(move |evt: JsEvt| {
+            Metrics::default().on_error(ErrorKind::Io);
+
             // notify observers.
-            notify(ph2.clone(), WsEvent::Error)
-        }) as Box<dyn FnMut()>);
+            notify(ph2.clone(), WsEvent::Error(ErrorDetail::from_event(&evt)))
+        }) as Box<dyn FnMut(JsEvt)>);
 
         #[allow(trivial_casts)]
         let on_close = Closure::wrap(Box::new(move |evt: JsCloseEvt| {
+            let initiated_by = if cc.load(Ordering::SeqCst) {
+                Initiator::Client
+            } else {
+                Initiator::Server
+            };
+
+            let code: u16 = close_code::normalize(evt.code());
             let c = WsEvent::Closed(CloseEvent {
-                code: evt.code(),
+                code,
                 reason: evt.reason(),
-                was_clean: evt.was_clean(),
+                was_clean: close_code::is_clean(code, evt.was_clean()),
+                initiated_by,
             });
 
             notify(ph3.clone(), c)
@@ -96,7 +235,10 @@ impl WebSocket {
         // constructed.
         let guard = {
             struct Guard<'lt> {
-                ws: &'lt WebSysSocket,
+                ws: &'lt Arc<WebSysSocket>,
+                client_close: &'lt AtomicBool,
+                protocols: &'lt [String],
+                slot: Option<AdoptSlot>,
             }
 
             impl Drop for Guard<'_> {
@@ -105,33 +247,55 @@ impl WebSocket {
                     self.ws.set_onclose(None);
                     self.ws.set_onerror(None);
 
+                    let state: Result<WsState, WsError> = self.ws.ready_state().try_into();
+
+                    // Hand the socket over instead of closing it
+                    if let Some(slot) = &self.slot {
+                        if let Ok(WsState::Connecting) | Ok(WsState::Open) = state {
+                            slot.put(self.ws.clone(), self.protocols.to_vec());
+                            return;
+                        }
+                    }
+
                     // Check if connection is `OPEN`. Will cause a panic if is not `open`
-                    if let Ok(WsState::Open) = self.ws.ready_state().try_into() {
+                    if let Ok(WsState::Open) = state {
+                        self.client_close.store(true, Ordering::SeqCst);
                         let _ = self.ws.close();
                     }
-
-                    println!(
-                        "WebSocket::connect future was dropped while connecting to: {}.",
-                        self.ws.url()
-                    );
                 }
             }
 
-            Guard { ws: &ws }
+            Guard {
+                ws: &ws,
+                client_close: &client_close,
+                protocols: &protocols,
+                slot,
+            }
         };
 
-        // Listen to the events to figure out whether the connection opens successfully. We don't want to deal with
-        // the error event. Either a close event happens, in which case we want to recover the CloseEvent to return it
-        // to the user, or an Open event happens in which case we are happy campers.
-        let mut evts = pharos
-            .observe(Self::OPEN_CLOSE.into())
-            .await
-            .expect("we didn't close pharos");
-
-        // If the connection is closed, return error
+        // An adopted socket may be already open
+        if ws.ready_state() == WebSysSocket::CONNECTING {
+            // If the connection is closed, return error
+            if let Some(WsEvent::Closed(evt)) = evts.next().await {
+                return Err(WsError::ConnectionFailed {
+                    code: evt.code,
+                    opened_before_close: opened.load(Ordering::SeqCst),
+                    event: evt,
+                });
+            }
+        }
 
-        if let Some(WsEvent::Closed(evt)) = evts.next().await {
-            return Err(WsError::ConnectionFailed { event: evt });
+        // Opened, then closed right away: the close event is on its way
+        if ws.ready_state() != WebSysSocket::OPEN {
+            while let Some(evt) = evts.next().await {
+                if let WsEvent::Closed(evt) = evt {
+                    return Err(WsError::ConnectionFailed {
+                        code: evt.code,
+                        opened_before_close: true,
+                        event: evt,
+                    });
+                }
+            }
         }
 
         // We have now passed all the `await` points in this function and so the `WsStream` construction is guaranteed
@@ -141,32 +305,57 @@ impl WebSocket {
         // We don't handle Blob's
         ws.set_binary_type(BinaryType::Arraybuffer);
 
+        let stream = WsStream::new(
+            ws.clone(),
+            ph4,
+            client_close.clone(),
+            delivery.clone(),
+            Arc::new(on_open),
+            Arc::new(on_error),
+            Arc::new(on_close),
+        );
+
         Ok((
             Self {
                 pharos,
-                ws: ws.clone(),
-            },
-            WsStream::new(
                 ws,
-                ph4,
-                Arc::new(on_open),
-                Arc::new(on_error),
-                Arc::new(on_close),
-            ),
+                client_close,
+                delivery,
+                protocols,
+                close: stream.close_notifier(),
+                close_reason_policy: CloseReasonPolicy::default(),
+            },
+            stream,
         ))
     }
 
+    /// Set what [`WebSocket::close_reason`] does with a reason longer than 123 bytes (default: error)
+    ///
+    /// Check [`CloseReasonPolicy`].
+    #[inline]
+    pub fn close_reason_policy(mut self, policy: CloseReasonPolicy) -> Self {
+        self.close_reason_policy = policy;
+        self
+    }
+
     /// Close the socket. The future will resolve once the socket's state has become `WsState::CLOSED`.
     /// See: [MDN Documentation](https://developer.mozilla.org/en-US/docs/Web/API/WebSocket/close)
+    ///
+    /// If the connection is already closing or closed by this handle or a clone, resolve with the
+    /// resulting [`CloseEvent`]. If it was closed by the server, [`WsError::ConnectionNotOpen`] is returned.
     pub async fn close_code(&self, code: u16) -> Result<CloseEvent, WsError> {
         match self.ready_state() {
-            WsState::Closed => return Err(WsError::ConnectionNotOpen),
+            WsState::Closed => return self.completed_close().await,
+            // Closing, maybe by a clone: wait for the same close event
             WsState::Closing => {}
 
             _ => {
                 match self.ws.close_with_code(code) {
                     // Notify Observers
-                    Ok(_) => notify(self.pharos.clone(), WsEvent::Closing),
+                    Ok(_) => {
+                        self.client_close.store(true, Ordering::SeqCst);
+                        notify(self.pharos.clone(), WsEvent::Closing)
+                    }
 
                     Err(_) => {
                         return Err(WsError::InvalidCloseCode { supplied: code });
@@ -175,49 +364,52 @@ impl WebSocket {
             }
         }
 
-        let mut evts = match self
-            .pharos
-            .observe_shared(Filter::Pointer(WsEvent::is_closed).into())
-            .await
-        {
-            Ok(events) => events,
-            Err(e) => unreachable!("{:?}", e), // only happens if we closed it.
-        };
-
-        let ce = evts.next().await.expect_throw("receive a close event");
-
-        if let WsEvent::Closed(e) = ce {
-            Ok(e)
-        } else {
-            unreachable!()
-        }
+        self.wait_close_event().await
     }
 
     /// Close the socket. The future will resolve once the socket's state has become `WsState::CLOSED`.
     /// See: [MDN Documentation](https://developer.mozilla.org/en-US/docs/Web/API/WebSocket/close)
+    ///
+    /// If the connection is already closing or closed by this handle or a clone, resolve with the
+    /// resulting [`CloseEvent`]. If it was closed by the server, [`WsError::ConnectionNotOpen`] is returned.
     pub async fn close_reason(
         &self,
         code: u16,
         reason: impl AsRef<str>,
     ) -> Result<CloseEvent, WsError> {
         match self.ready_state() {
-            WsState::Closed => return Err(WsError::ConnectionNotOpen),
+            WsState::Closed => return self.completed_close().await,
+            // Closing, maybe by a clone: wait for the same close event
             WsState::Closing => {}
 
             _ => {
-                if reason.as_ref().len() > 123 {
-                    return Err(WsError::ReasonStringToLong);
-                }
+                let reason: &str = match self.close_reason_policy {
+                    CloseReasonPolicy::Error
+                        if reason.as_ref().len() > close_code::MAX_REASON_LEN =>
+                    {
+                        return Err(WsError::ReasonStringToLong);
+                    }
+                    CloseReasonPolicy::Error => reason.as_ref(),
+                    CloseReasonPolicy::Truncate => close_code::truncate_reason(reason.as_ref()),
+                };
 
-                match self.ws.close_with_code_and_reason(code, reason.as_ref()) {
+                match self.ws.close_with_code_and_reason(code, reason) {
                     // Notify Observers
-                    Ok(_) => notify(self.pharos.clone(), WsEvent::Closing),
+                    Ok(_) => {
+                        self.client_close.store(true, Ordering::SeqCst);
+                        notify(self.pharos.clone(), WsEvent::Closing)
+                    }
 
                     Err(_) => return Err(WsError::InvalidCloseCode { supplied: code }),
                 }
             }
         }
 
+        self.wait_close_event().await
+    }
+
+    /// Wait for the close event of the close in progress
+    async fn wait_close_event(&self) -> Result<CloseEvent, WsError> {
         let mut evts = match self
             .pharos
             .observe_shared(Filter::Pointer(WsEvent::is_closed).into())
@@ -236,6 +428,111 @@ impl WebSocket {
         }
     }
 
+    /// Close event of a close already completed by this handle or a clone
+    ///
+    /// A connection closed by the server (or never open) is an error.
+    async fn completed_close(&self) -> Result<CloseEvent, WsError> {
+        if !self.client_close.load(Ordering::SeqCst) {
+            return Err(WsError::ConnectionNotOpen);
+        }
+
+        self.close.closed().await.ok_or(WsError::ConnectionNotOpen)
+    }
+
+    /// Suspend the delivery of incoming messages to the stream, until the returned guard is dropped
+    /// or [`WebSocket::resume_delivery`] is called.
+    ///
+    /// The pauses nest: with several guards, the delivery resumes when the last one is dropped.
+    ///
+    /// Messages keep being received into a bounded buffer and are replayed on resume. If the buffer
+    /// overflows, the exceeding messages are dropped and the stream yields [`WsError::BufferFull`].
+    pub fn pause_delivery(&self) -> PauseGuard {
+        PauseGuard::new(self.delivery.clone())
+    }
+
+    /// Start an application-level [`Heartbeat`], replacing the previous one (if any)
+    ///
+    /// The payload is first sent after one interval. If no reply arrives within the timeout,
+    /// a [`WsEvent::HeartbeatTimeout`] is emitted and the connection is closed with
+    /// [`Heartbeat::TIMEOUT_CLOSE_CODE`].
+    /// The replies are still delivered to the stream. The heartbeat stops when the connection closes.
+    pub fn heartbeat(&self, heartbeat: Heartbeat) {
+        let generation: u64 = self.delivery.replies().start(heartbeat.is_reply.clone());
+        let this: Self = self.clone();
+        let _ = thread::spawn(async move { this.run_heartbeat(heartbeat, generation).await });
+    }
+
+    /// Stop the heartbeat, if any
+    pub fn stop_heartbeat(&self) {
+        self.delivery.replies().stop();
+    }
+
+    async fn run_heartbeat(self, heartbeat: Heartbeat, generation: u64) {
+        let replies = self.delivery.replies();
+        let mut wait: Duration = heartbeat.interval;
+
+        loop {
+            thread::sleep(wait).await;
+
+            if !replies.is_current(generation) || self.ready_state() != WsState::Open {
+                return;
+            }
+
+            let received: u64 = replies.received();
+            let sent = match &heartbeat.payload {
+                WsMessage::Text(text) => self.ws.send_with_str(text),
+                WsMessage::Binary(data) => self.ws.send_with_u8_array(data),
+                // Rejected by `Heartbeat::new`
+                WsMessage::Close(..) => return,
+            };
+
+            if sent.is_err() {
+                return;
+            }
+
+            thread::sleep(heartbeat.timeout).await;
+
+            if !replies.is_current(generation) || self.ready_state() != WsState::Open {
+                return;
+            }
+
+            if replies.received() == received {
+                return self.heartbeat_timeout();
+            }
+
+            wait = heartbeat.interval.saturating_sub(heartbeat.timeout);
+        }
+    }
+
+    fn heartbeat_timeout(&self) {
+        Metrics::default().on_error(ErrorKind::Timeout);
+        notify(self.pharos.clone(), WsEvent::HeartbeatTimeout);
+
+        if self
+            .ws
+            .close_with_code_and_reason(Heartbeat::TIMEOUT_CLOSE_CODE, "heartbeat timeout")
+            .is_ok()
+        {
+            self.client_close.store(true, Ordering::SeqCst);
+            notify(self.pharos.clone(), WsEvent::Closing);
+        }
+    }
+
+    /// Resume the delivery of incoming messages and replay the buffered ones.
+    ///
+    /// Override all the active [`PauseGuard`]s.
+    pub fn resume_delivery(&self) {
+        self.delivery.resume();
+    }
+
+    /// Wait for the connection termination
+    ///
+    /// Resolve with the [`CloseEvent`], or `None` if the [WsStream] is dropped first.
+    /// If the connection is already terminated, resolve immediately.
+    pub fn closed(&self) -> impl Future<Output = Option<CloseEvent>> {
+        self.close.closed()
+    }
+
     /// Verify the [WsState] of the connection.
     pub fn ready_state(&self) -> WsState {
         self.ws
@@ -265,6 +562,56 @@ impl WebSocket {
         self.ws.buffered_amount()
     }
 
+    /// Wait until all the queued data has been transmitted to the network (`buffered_amount` is `0`).
+    ///
+    /// Stronger than flushing the sink, which is a no-op in the browser.
+    /// Return [`WsError::ConnectionNotOpen`] if the connection closes with data still buffered.
+    pub async fn flush_completely(&self) -> Result<(), WsError> {
+        while self.buffered_amount() > 0 {
+            if matches!(self.ready_state(), WsState::Closing | WsState::Closed) {
+                return Err(WsError::ConnectionNotOpen);
+            }
+
+            thread::sleep(Self::FLUSH_POLL_INTERVAL).await;
+        }
+
+        Ok(())
+    }
+
+    /// Flush the pending sends, then close with code `1000` and the `reason`, within `drain_timeout`.
+    ///
+    /// The `drain_timeout` is split in two halves:
+    /// * up to the first half waits for the queued data to be transmitted
+    ///   (like [`WebSocket::flush_completely`]). If it expires, the socket is closed anyway,
+    ///   with the data still buffered;
+    /// * the rest (the second half plus what's left of the first one) waits for the server
+    ///   to acknowledge the close. If it expires, [`WsError::Timeout`] is returned.
+    pub async fn close_graceful(
+        &self,
+        reason: &str,
+        drain_timeout: Duration,
+    ) -> Result<CloseEvent, WsError> {
+        let started: f64 = js_sys::Date::now();
+
+        // Proceed to close anyway on error or timeout
+        let _ = time::timeout(Some(drain_timeout / 2), self.flush_completely()).await;
+
+        let elapsed = Duration::from_millis((js_sys::Date::now() - started).max(0.0) as u64);
+        let remaining: Duration = drain_timeout.saturating_sub(elapsed);
+        time::timeout(
+            Some(remaining),
+            self.close_reason(close_code::NORMAL_CLOSURE, reason),
+        )
+        .await
+        .ok_or(WsError::Timeout)?
+    }
+
+    /// Check if the connection is currently applying backpressure, i.e. more than
+    /// [`BACKPRESSURE_THRESHOLD`](Self::BACKPRESSURE_THRESHOLD) bytes are waiting to be transmitted.
+    pub fn is_backpressured(&self) -> bool {
+        self.buffered_amount() > Self::BACKPRESSURE_THRESHOLD
+    }
+
     /// The extensions selected by the server as negotiated during the connection.
     ///
     /// **NOTE**: This is an untested feature. The back-end server we use for testing (_tungstenite_)
@@ -273,6 +620,13 @@ impl WebSocket {
         self.ws.extensions()
     }
 
+    /// The `permessage-deflate` parameters, parsed from the [`WebSocket::extensions`]
+    ///
+    /// Best-effort: the browser negotiates (and applies) the compression on its own.
+    pub fn negotiated_compression(&self) -> Option<DeflateParams> {
+        DeflateParams::from_extensions(&self.extensions())
+    }
+
     /// The name of the sub-protocol the server selected during the connection.
     ///
     /// This will be one of the strings specified in the protocols parameter when
@@ -281,15 +635,69 @@ impl WebSocket {
         self.ws.protocol()
     }
 
+    /// The sub-protocols requested at connect time
+    #[inline]
+    pub fn protocols_requested(&self) -> &[String] {
+        &self.protocols
+    }
+
+    /// The extensions requested at connect time.
+    ///
+    /// The browser API doesn't let the page request extensions (the browser picks them on its own),
+    /// so this is always empty. Use [`WebSocket::extensions`] to get the negotiated ones.
+    #[inline]
+    pub fn extensions_requested(&self) -> &[String] {
+        &[]
+    }
+
     /// Retrieve the address to which this socket is connected.
     pub fn url(&self) -> String {
         self.ws.url()
     }
 }
 
+/// Socket left by a cancelled connect, with the sub-protocols it requested
+type Adoptable = (Arc<WebSysSocket>, Vec<String>);
+
+/// Slot receiving the socket of a cancelled [`WebSocket::connect_adoptable`]
+#[derive(Debug, Clone, Default)]
+pub struct AdoptSlot {
+    ws: Rc<RefCell<Option<Adoptable>>>,
+}
+
+impl AdoptSlot {
+    /// New empty slot
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check if a socket is waiting to be adopted
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.ws.borrow().is_none()
+    }
+
+    fn put(&self, ws: Arc<WebSysSocket>, protocols: Vec<String>) {
+        // A previous socket that was never adopted is closed
+        if let Some((old, _)) = self.ws.borrow_mut().replace((ws, protocols)) {
+            let _ = old.close();
+        }
+    }
+
+    #[inline]
+    fn take(&self) -> Option<Adoptable> {
+        self.ws.borrow_mut().take()
+    }
+}
+
 impl fmt::Debug for WebSocket {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "WebSocket for connection: {}", self.url())
+        let url: String = self.url();
+        match Url::parse(&url) {
+            Ok(url) => write!(f, "WebSocket for connection: {}", Redacted(url)),
+            Err(..) => write!(f, "WebSocket for connection: {url}"),
+        }
     }
 }
 
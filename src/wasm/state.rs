@@ -2,6 +2,8 @@
 // Copyright (c) 2023-2024 Yuki Kishimoto
 // Distributed under the MIT software license
 
+use std::fmt;
+
 use web_sys::WebSocket;
 
 use crate::wasm::WsError;
@@ -10,7 +12,10 @@ use crate::wasm::WsError;
 /// is [WsState::Open].
 ///
 /// See [MDN](https://developer.mozilla.org/en-US/docs/Web/API/WebSocket/readyState) for the ready state values.
+///
+/// With the `serde-json` feature, the states are serialized as their variant name (i.e. `"Open"`).
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde-json", derive(serde::Serialize, serde::Deserialize))]
 pub enum WsState {
     Connecting,
     Open,
@@ -18,6 +23,18 @@ pub enum WsState {
     Closed,
 }
 
+/// Lowercase name, i.e. `open`
+impl fmt::Display for WsState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Connecting => write!(f, "connecting"),
+            Self::Open => write!(f, "open"),
+            Self::Closing => write!(f, "closing"),
+            Self::Closed => write!(f, "closed"),
+        }
+    }
+}
+
 /// Internally ready state is a u16, so it's possible to create one from a u16. Only 0-3 are valid values.
 ///
 /// See [MDN](https://developer.mozilla.org/en-US/docs/Web/API/WebSocket/readyState) for the ready state values.
@@ -3,24 +3,28 @@
 // Distributed under the MIT software license
 
 use std::cell::RefCell;
-use std::collections::VecDeque;
 use std::fmt;
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll, Waker};
 
 use async_utility::thread;
-use futures::prelude::{Sink, Stream};
-use futures::{ready, FutureExt, StreamExt};
+use futures_core::{ready, Stream};
+use futures_sink::Sink;
+use futures_util::{future, FutureExt, StreamExt};
 use wasm_bindgen::closure::Closure;
 use wasm_bindgen::JsCast;
-use web_sys::{CloseEvent as JsCloseEvt, WebSocket, *};
+use web_sys::{CloseEvent as JsCloseEvt, Event as JsEvt, WebSocket, *};
 
 pub mod io;
 
+use crate::close_code;
+use crate::close_notifier::CloseNotifier;
+use crate::metrics::Metrics;
 use crate::wasm::pharos::{Filter, Observable, SharedPharos};
-use crate::wasm::{notify, WsError, WsEvent, WsMessage, WsState};
+use crate::wasm::{notify, CloseEvent, CloseFrame, Delivery, WsError, WsEvent, WsMessage, WsState};
 
 /// A futures 0.3 Sink/Stream of [WsMessage]. Created with [WsMeta::connect](crate::WsMeta::connect).
 ///
@@ -34,6 +38,11 @@ use crate::wasm::{notify, WsError, WsEvent, WsMessage, WsState};
 /// without parameters. Eg. a default value of `1005` will be assumed for the close code. The
 /// situation is the same when dropping without calling close.
 ///
+/// Sending a [`WsMessage::Close`] starts the close handshake with its code and reason, as on native,
+/// without waiting for it to complete. Once the close was started by this side, either way,
+/// any other close is a no-op (the sink close still waits for the close event) and sending other
+/// messages fails with [`WsError::ConnectionNotOpen`].
+///
 /// **Warning**: This object holds the callbacks needed to receive events from the browser.
 /// If you drop it before the close event was emitted, you will no longer receive events. Thus,
 /// observers will never receive a `Close` event. Drop will issue a `Closing` event and this
@@ -44,11 +53,8 @@ use crate::wasm::{notify, WsError, WsEvent, WsMessage, WsState};
 pub struct WsStream {
     ws: Arc<WebSocket>,
 
-    // The queue of received messages
-    queue: Arc<RefCell<VecDeque<WsMessage>>>,
-
-    // Last waker of task that wants to read incoming messages to be woken up on a new message
-    waker: Arc<RefCell<Option<Waker>>>,
+    // The queue of received messages and the waker of the task that wants to read them
+    delivery: Arc<Delivery>,
 
     // Last waker of task that wants to write to the Sink
     sink_waker: Arc<RefCell<Option<Waker>>>,
@@ -56,9 +62,15 @@ pub struct WsStream {
     // A pointer to the pharos of WsMeta for when we need to listen to events
     pharos: SharedPharos<WsEvent>,
 
+    // Set when we request the close of the connection
+    client_close: Arc<AtomicBool>,
+
+    // Callbacks to invoke when the connection terminates
+    close: Arc<CloseNotifier<CloseEvent>>,
+
     // The callback closures.
     _on_open: Arc<Closure<dyn FnMut()>>,
-    _on_error: Arc<Closure<dyn FnMut()>>,
+    _on_error: Arc<Closure<dyn FnMut(JsEvt)>>,
     _on_close: Arc<Closure<dyn FnMut(JsCloseEvt)>>,
     _on_msg: Arc<Closure<dyn FnMut(MessageEvent)>>,
 
@@ -71,30 +83,29 @@ impl WsStream {
     pub(crate) fn new(
         ws: Arc<WebSocket>,
         pharos: SharedPharos<WsEvent>,
+        client_close: Arc<AtomicBool>,
+        delivery: Arc<Delivery>,
         on_open: Arc<Closure<dyn FnMut()>>,
-        on_error: Arc<Closure<dyn FnMut()>>,
+        on_error: Arc<Closure<dyn FnMut(JsEvt)>>,
         on_close: Arc<Closure<dyn FnMut(JsCloseEvt)>>,
     ) -> Self {
-        let waker: Arc<RefCell<Option<Waker>>> = Arc::new(RefCell::new(None));
         let sink_waker: Arc<RefCell<Option<Waker>>> = Arc::new(RefCell::new(None));
 
-        let queue = Arc::new(RefCell::new(VecDeque::new()));
-        let q2 = queue.clone();
-        let w2 = waker.clone();
+        let d2 = delivery.clone();
         let ph2 = pharos.clone();
 
         // Send the incoming ws messages to the WsMeta object
         #[allow(trivial_casts)]
-        let on_msg = Closure::wrap(Box::new(move |msg_evt: MessageEvent| {
-            match WsMessage::try_from(msg_evt) {
-                Ok(msg) => q2.borrow_mut().push_back(msg),
-                Err(err) => notify(ph2.clone(), WsEvent::WsErr(err)),
-            }
-
-            if let Some(w) = w2.borrow_mut().take() {
-                w.wake()
-            }
-        }) as Box<dyn FnMut(MessageEvent)>);
+        let on_msg =
+            Closure::wrap(Box::new(move |msg_evt: MessageEvent| {
+                match WsMessage::try_from(msg_evt) {
+                    Ok(msg) => d2.push(msg),
+                    Err(err) => {
+                        notify(ph2.clone(), WsEvent::WsErr(err));
+                        d2.wake();
+                    }
+                }
+            }) as Box<dyn FnMut(MessageEvent)>);
 
         // Install callback
         ws.set_onmessage(Some(on_msg.as_ref().unchecked_ref()));
@@ -102,8 +113,10 @@ impl WsStream {
         // When the connection closes, we need to verify if there are any tasks
         // waiting on poll_next. We need to wake them up.
         let ph = pharos.clone();
-        let wake = waker.clone();
+        let wake = delivery.clone();
         let swake = sink_waker.clone();
+        let close: Arc<CloseNotifier<CloseEvent>> = Arc::new(CloseNotifier::default());
+        let close2 = close.clone();
 
         let wake_on_close = async move {
             let mut rx;
@@ -119,12 +132,15 @@ impl WsStream {
                 }
             }
 
-            rx.next().await;
-
-            if let Some(w) = &*wake.borrow() {
-                w.wake_by_ref();
+            if let Some(WsEvent::Closed(evt)) = rx.next().await {
+                let (code, clean) = (evt.code, evt.was_clean);
+                if close2.notify(Some(evt)) {
+                    Metrics::default().on_close(Some(code), clean);
+                }
             }
 
+            wake.wake();
+
             if let Some(w) = &*swake.borrow() {
                 w.wake_by_ref();
             }
@@ -134,10 +150,11 @@ impl WsStream {
 
         Self {
             ws,
-            queue,
-            waker,
+            delivery,
             sink_waker,
             pharos,
+            client_close,
+            close,
             closer: None,
             _on_msg: Arc::new(on_msg),
             _on_open: on_open,
@@ -162,6 +179,116 @@ impl WsStream {
     pub fn wrapped(&self) -> &WebSocket {
         &self.ws
     }
+
+    /// Register a callback invoked once when the connection terminates, with its [`CloseEvent`]
+    ///
+    /// All the registered callbacks are invoked. If the connection is already terminated, the
+    /// callback is invoked immediately. If this is dropped before the connection terminates,
+    /// the callbacks receive `None`.
+    pub fn on_close<F>(&self, callback: F)
+    where
+        F: FnOnce(Option<CloseEvent>) + 'static,
+    {
+        self.close.register(Box::new(callback));
+    }
+
+    /// Wait for the connection termination, without consuming the stream (i.e. in a `select!` branch)
+    ///
+    /// Resolve with the [`CloseEvent`], or `None` as for [`WsStream::on_close`].
+    /// If the connection is already terminated, resolve immediately.
+    pub fn closed(&self) -> impl Future<Output = Option<CloseEvent>> {
+        self.close.closed()
+    }
+
+    #[inline]
+    pub(crate) fn close_notifier(&self) -> Arc<CloseNotifier<CloseEvent>> {
+        self.close.clone()
+    }
+
+    /// Start the close handshake with a code and a reason, without waiting for it to complete.
+    pub(crate) fn initiate_close(&self, code: u16, reason: &str) -> Result<(), WsError> {
+        self.ws
+            .close_with_code_and_reason(code, reason)
+            .map_err(|_| WsError::InvalidCloseCode { supplied: code })?;
+        self.client_close.store(true, Ordering::SeqCst);
+        notify(self.pharos.clone(), WsEvent::Closing);
+        Ok(())
+    }
+
+    /// Start the close handshake for a [`WsMessage::Close`]: a no-op if already closing by us
+    fn start_close(&self, frame: Option<&CloseFrame>) -> Result<(), WsError> {
+        match self.ready_state()? {
+            WsState::Open => {}
+            _ if self.client_close.load(Ordering::SeqCst) => return Ok(()),
+            _ => return Err(WsError::ConnectionNotOpen),
+        }
+
+        match frame {
+            Some(frame) => {
+                if frame.reason.len() > close_code::MAX_REASON_LEN {
+                    return Err(WsError::ReasonStringToLong);
+                }
+                self.initiate_close(frame.code, &frame.reason)
+            }
+            None => {
+                self.ws.close().map_err(|_| WsError::ConnectionNotOpen)?;
+                self.client_close.store(true, Ordering::SeqCst);
+                notify(self.pharos.clone(), WsEvent::Closing);
+                Ok(())
+            }
+        }
+    }
+
+    /// Send a message: the browser buffers it, so it's never needed after this returns
+    fn send_message(&self, item: &WsMessage) -> Result<(), WsError> {
+        if let WsMessage::Close(frame) = item {
+            return self.start_close(frame.as_ref());
+        }
+
+        match self.ready_state()? {
+            WsState::Open => {
+                // The send method can return 2 errors:
+                // - unpaired surrogates in UTF (we shouldn't get those in rust strings)
+                // - connection is already closed.
+                //
+                // So if this returns an error, we will return ConnectionNotOpen. In principle,
+                // we just checked that it's open, but this guarantees correctness.
+                match item {
+                    WsMessage::Binary(d) => self
+                        .ws
+                        .send_with_u8_array(d)
+                        .map_err(|_| WsError::ConnectionNotOpen)?,
+                    WsMessage::Text(s) => self
+                        .ws
+                        .send_with_str(s)
+                        .map_err(|_| WsError::ConnectionNotOpen)?,
+                    WsMessage::Close(..) => unreachable!("handled by start_close"),
+                }
+
+                Ok(())
+            }
+
+            // Connecting, Closing or Closed
+            _ => Err(WsError::ConnectionNotOpen),
+        }
+    }
+
+    /// Send a message, giving it back on failure
+    ///
+    /// Waits for the connection to open, as the [`Sink`] does. The browser buffers the message
+    /// once accepted, so it's returned on every failure: it can be resent, on a new connection,
+    /// without a copy.
+    pub async fn try_send(&mut self, msg: WsMessage) -> Result<(), (WsMessage, WsError)> {
+        let mut this = Pin::new(self);
+        if let Err(e) = future::poll_fn(|cx| this.as_mut().poll_ready(cx)).await {
+            return Err((msg, e));
+        }
+
+        match this.send_message(&msg) {
+            Ok(()) => Ok(()),
+            Err(e) => Err((msg, e)),
+        }
+    }
 }
 
 impl fmt::Debug for WsStream {
@@ -178,6 +305,7 @@ impl Drop for WsStream {
             Ok(WsState::Open) => {
                 // This can't fail. Only exceptions are related to invalid
                 // close codes and reason strings to long.
+                self.client_close.store(true, Ordering::SeqCst);
                 let _ = self.ws.close();
 
                 // Notify Observers. This event is not emitted by the websocket API.
@@ -194,6 +322,9 @@ impl Drop for WsStream {
         self.ws.set_onerror(None);
         self.ws.set_onopen(None);
         self.ws.set_onclose(None);
+
+        // The close event can't be received anymore
+        self.close.notify(None);
     }
 }
 
@@ -205,19 +336,24 @@ impl Stream for WsStream {
     // Currently requires an unfortunate copy from Js memory to WASM memory. Hopefully one
     // day we will be able to receive the MessageEvt directly in WASM.
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // As long as there is things in the queue, just keep reading
+        if let Some(msg) = self.delivery.pop() {
+            return Some(msg).into();
+        }
+
+        self.delivery.register(cx.waker());
+
+        // The buffered messages are replayed on resume
+        if self.delivery.is_paused() {
+            return Poll::Pending;
+        }
+
         // Once the queue is empty, check the state of the connection.
         // When it is closing or closed, no more messages will arrive, so
         // return Poll::Ready( None )
-        if self.queue.borrow().is_empty() {
-            *self.waker.borrow_mut() = Some(cx.waker().clone());
-
-            match self.ready_state() {
-                Ok(WsState::Open) | Ok(WsState::Connecting) => Poll::Pending,
-                _ => None.into(),
-            }
-        } else {
-            // As long as there is things in the queue, just keep reading
-            self.queue.borrow_mut().pop_front().map(Ok).into()
+        match self.ready_state() {
+            Ok(WsState::Open) | Ok(WsState::Connecting) => Poll::Pending,
+            _ => None.into(),
         }
     }
 }
@@ -234,36 +370,14 @@ impl Sink<WsMessage> for WsStream {
                 Poll::Pending
             }
             WsState::Open => Ok(()).into(),
+            // Closing by us: a close is a no-op, the other messages are rejected by `start_send`
+            _ if self.client_close.load(Ordering::SeqCst) => Ok(()).into(),
             _ => Err(WsError::ConnectionNotOpen).into(),
         }
     }
 
     fn start_send(self: Pin<&mut Self>, item: WsMessage) -> Result<(), Self::Error> {
-        match self.ready_state()? {
-            WsState::Open => {
-                // The send method can return 2 errors:
-                // - unpaired surrogates in UTF (we shouldn't get those in rust strings)
-                // - connection is already closed.
-                //
-                // So if this returns an error, we will return ConnectionNotOpen. In principle,
-                // we just checked that it's open, but this guarantees correctness.
-                match item {
-                    WsMessage::Binary(d) => self
-                        .ws
-                        .send_with_u8_array(&d)
-                        .map_err(|_| WsError::ConnectionNotOpen)?,
-                    WsMessage::Text(s) => self
-                        .ws
-                        .send_with_str(&s)
-                        .map_err(|_| WsError::ConnectionNotOpen)?,
-                }
-
-                Ok(())
-            }
-
-            // Connecting, Closing or Closed
-            _ => Err(WsError::ConnectionNotOpen),
-        }
+        self.send_message(&item)
     }
 
     fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
@@ -278,6 +392,7 @@ impl Sink<WsMessage> for WsStream {
 
         // First close the inner connection
         if state == WsState::Open {
+            self.client_close.store(true, Ordering::SeqCst);
             let _ = self.ws.close();
             notify(self.pharos.clone(), WsEvent::Closing);
         }